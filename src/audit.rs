@@ -0,0 +1,72 @@
+// audit.rs
+use serde::Serialize; // Entries are written as JSON lines.
+use std::collections::VecDeque; // Recent entries, oldest first.
+use std::fs::{File, OpenOptions}; // The audit log file, opened for appending.
+use std::io::{self, Write}; // Writing entries to the file.
+use std::path::Path; // Location of the audit log file.
+use std::sync::Mutex; // Written from every client handler thread.
+
+/// Most entries kept in memory for `/auditlog`.
+pub const AUDIT_RECENT_CAP: usize = 100;
+
+/// A moderation action: who did what to whom, and when.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: u64,         // Unix time (seconds) of the action.
+    pub actor: String,          // Username of the admin who acted.
+    pub action: &'static str,   // Command name, e.g. `mute`.
+    pub target: String,         // Username or setting acted on.
+    pub detail: Option<String>, // Extra context, e.g. a mute's duration.
+}
+
+/// Record of moderation actions. Entries are appended to the `--audit-log` file as JSONL
+/// when one is configured, and the most recent ones are always kept for `/auditlog`.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>, // `None` when no audit log file is configured.
+    recent: Mutex<VecDeque<AuditEntry>>, // At most `AUDIT_RECENT_CAP` entries, oldest first.
+}
+
+impl AuditLog {
+    /// Opens (or creates) the audit log at `path`, appending to any existing entries.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+            ..Self::default()
+        })
+    }
+
+    /// Records `entry`. A failed write is reported but doesn't stop the action.
+    pub fn record(&self, entry: AuditEntry) {
+        if let Some(file) = &self.file {
+            let written = serde_json::to_string(&entry)
+                .map_err(io::Error::from)
+                .and_then(|line| match file.lock() {
+                    Ok(mut file) => writeln!(file, "{}", line),
+                    Err(_) => Ok(()),
+                });
+            if let Err(e) = written {
+                eprintln!("Failed to write audit log entry: {}", e);
+            }
+        }
+        if let Ok(mut recent) = self.recent.lock() {
+            if recent.len() == AUDIT_RECENT_CAP {
+                recent.pop_front();
+            }
+            recent.push_back(entry);
+        }
+    }
+
+    /// Returns up to `count` of the most recent entries, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<AuditEntry> {
+        let Ok(recent) = self.recent.lock() else {
+            return Vec::new();
+        };
+        recent
+            .iter()
+            .skip(recent.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,152 @@
+// bot.rs
+use crate::client_handler::broadcast_message; // Posts the bot's messages like any other.
+use crate::config::ServerConfig; // Where the bot's name, greeting and triggers are set.
+use crate::errors::ChatResult; // Result type for lock errors.
+use crate::events::SystemEvent; // The events the bot reacts to.
+use crate::message::{ChatMessage, ChatMessageType}; // The bot's messages.
+use crate::state::ServerState; // Shared server state.
+use serde::Serialize; // Triggers are written out in state dumps.
+use std::str::FromStr; // Parsing triggers from the command line.
+use thiserror::Error; // Derives `Error` for `InvalidTrigger`.
+
+/// A word the bot answers, and its answer.
+#[derive(Serialize, Debug, Clone)]
+pub struct BotTrigger {
+    word: String,     // Matched whole and ignoring case.
+    response: String, // Posted when a message contains the word.
+}
+
+/// Error returned for a trigger that isn't `word=response`.
+#[derive(Debug, Error)]
+#[error("invalid bot trigger '{0}': expected <word>=<response>")]
+pub struct InvalidTrigger(String);
+
+impl FromStr for BotTrigger {
+    type Err = InvalidTrigger;
+
+    /// Parses `word=response`. The word must be a single word.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidTrigger(s.to_string());
+        let (word, response) = s.split_once('=').ok_or_else(invalid)?;
+        let (word, response) = (word.trim(), response.trim());
+        if word.is_empty() || word.contains(char::is_whitespace) || response.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            word: word.to_lowercase(),
+            response: response.to_string(),
+        })
+    }
+}
+
+/// A built-in bot that greets joining users and answers trigger words. It has no socket:
+/// it watches the server's events and posts through the same broadcast path as clients.
+pub struct Bot {
+    name: String,              // Username the bot posts as.
+    greeting: Option<String>,  // Posted when a user joins; `{user}` is their name.
+    triggers: Vec<BotTrigger>, // Words the bot answers, checked in order.
+}
+
+impl Bot {
+    /// Creates the bot configured with `--bot-greeting` and `--bot-trigger`,
+    /// or `None` if neither is set.
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        if config.bot_greeting.is_none() && config.bot_triggers.is_empty() {
+            return None;
+        }
+        Some(Self {
+            name: config.bot_name.clone(),
+            greeting: config.bot_greeting.clone(),
+            triggers: config.bot_triggers.clone(),
+        })
+    }
+
+    /// The username the bot posts as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Greets joins and answers chat messages that contain a trigger word.
+    pub fn handle_event(&self, state: &ServerState, event: &SystemEvent) -> ChatResult<()> {
+        let response = match event {
+            SystemEvent::Join { username, .. } => self
+                .greeting
+                .as_ref()
+                .map(|greeting| greeting.replace("{user}", username)),
+            SystemEvent::Message { seq, .. } => self.answer(state, *seq)?,
+            _ => None,
+        };
+        if let Some(content) = response {
+            self.post(state, content);
+        }
+        Ok(())
+    }
+
+    /// Returns the response to the first trigger word in the stored message `seq`, if any.
+    fn answer(&self, state: &ServerState, seq: u64) -> ChatResult<Option<String>> {
+        let content = {
+            let history = state.chat_history.read()?;
+            match history.iter().rev().find(|msg| msg.seq == Some(seq)) {
+                Some(msg) => msg.content.to_lowercase(),
+                None => return Ok(None),
+            }
+        };
+        let words: Vec<&str> = content
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        Ok(self
+            .triggers
+            .iter()
+            .find(|trigger| words.contains(&trigger.word.as_str()))
+            .map(|trigger| trigger.response.clone()))
+    }
+
+    /// Broadcasts `content` to everyone as a chat message from the bot.
+    fn post(&self, state: &ServerState, content: String) {
+        let message = ChatMessage {
+            message_type: ChatMessageType::Message,
+            username: Some(self.name.clone()),
+            content,
+            ..Default::default()
+        };
+        broadcast_message(state, None, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::ChatMessageType;
+    use crate::test_support::{TestClient, TestServer};
+
+    /// Reads up to the next message the bot posts and returns its text.
+    fn next_bot_message(client: &mut TestClient) -> String {
+        client
+            .recv_until(|msg| {
+                matches!(msg.message_type, ChatMessageType::Message)
+                    && msg.username.as_deref() == Some("greeter")
+            })
+            .content
+    }
+
+    #[test]
+    fn joining_user_is_greeted_and_trigger_words_are_answered() {
+        let server = TestServer::with_args(&[
+            "--bot-greeting",
+            "Welcome, {user}!",
+            "--bot-trigger",
+            "help=Type /list to see who's here",
+        ]);
+        let mut alice = server.join("alice");
+        assert_eq!(next_bot_message(&mut alice), "Welcome, alice!");
+        let mut bob = server.join("bob");
+        // The greeting to alice is in the history replayed to bob.
+        assert_eq!(next_bot_message(&mut bob), "Welcome, alice!");
+        assert_eq!(next_bot_message(&mut bob), "Welcome, bob!");
+        assert_eq!(next_bot_message(&mut alice), "Welcome, bob!");
+
+        alice.say("Can anyone HELP me?");
+        assert_eq!(next_bot_message(&mut bob), "Type /list to see who's here");
+        assert_eq!(next_bot_message(&mut alice), "Type /list to see who's here");
+    }
+}
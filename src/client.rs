@@ -1,250 +1,2310 @@
 // Module imports
-mod message; // Import the `message` module for ChatMessage and related types.
-use crate::message::{ChatMessage, ChatMessageType, CommandType}; // Re-export specific types from the module.
-use std::env; // For accessing command-line arguments.
-use std::io::{self, BufRead, Write}; // For handling input/output operations.
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-// For managing TCP connections.
+mod e2e;
+mod files;
+mod theme;
+mod transcript;
+
+use clap::Parser; // For parsing command-line arguments.
+use e2e::{E2eError, E2eSessions}; // End-to-end encrypted private messages.
+use rust_tcp_chat::compression::{decompress_message, COMPRESSION_FEATURE}; // Opt-in frame compression.
+use rust_tcp_chat::message::{
+    format_time, is_reserved_username, ChatMessage, ChatMessageType, Command, CommandAliases,
+    CommandType, ContentEncoding, Frame, HistoryDownload, ParseError, Rename, MAX_USERNAME_LEN,
+}; // Message types shared with the server.
+#[cfg(feature = "msgpack")]
+use rust_tcp_chat::msgpack::MsgpackTransport; // MessagePack wire format.
+use rust_tcp_chat::transport::{
+    parse_read_buffer_len, TcpTransport, Transport, DEFAULT_READ_BUFFER_LEN,
+}; // Frame transport and its TCP implementation.
+use std::collections::VecDeque; // Messages queued while reconnecting.
+use std::env; // For reading the prompt template from the environment.
+use std::fs::{self, File}; // For reading the banner and script files.
+use std::io::{self, BufRead, BufReader, IsTerminal, Write}; // For handling input/output operations.
+use std::net::TcpStream; // For managing TCP connections.
+use std::panic::{self, AssertUnwindSafe}; // Recovering from a crashed reader.
+use std::path::{Path, PathBuf}; // Paths of the transcript, banner and shared files.
+use std::process; // Exiting once the server ends the session.
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
 use std::thread; // For spawning threads to handle parallel tasks.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH}; // Reconnect backoff, session length and the `{time}` prompt placeholder.
+use theme::{Style, Theme}; // Colors for messages and the prompt.
+use transcript::{Direction, Transcript}; // Optional session log.
+
+/// Environment variable holding a custom prompt template, e.g. `{user} $ `.
+const PROMPT_ENV_VAR: &str = "CHAT_PROMPT";
+/// Prompt shown when `CHAT_PROMPT` is unset.
+const DEFAULT_PROMPT: &str = "[You]: ";
+/// Most messages held back while reconnecting; further input is dropped with a warning.
+const PENDING_QUEUE_CAP: usize = 100;
+/// Delay before the first reconnect attempt; doubled after each failed attempt.
+const RECONNECT_DELAY_INITIAL: Duration = Duration::from_millis(500);
+/// Upper bound for the reconnect delay.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(10);
+/// Name `/download-history` saves the history under, in the download directory.
+const HISTORY_FILE_NAME: &str = "chat-history.json";
+/// Most messages held back while paused; the oldest are dropped past this.
+const PAUSE_BUFFER_CAP: usize = 500;
+/// Banner printed on connect when `--banner` isn't given.
+const DEFAULT_BANNER: &str = r"
+   ____ _           _
+  / ___| |__   __ _| |_
+ | |   | '_ \ / _` | __|
+ | |___| | | | (_| | |_
+  \____|_| |_|\__,_|\__|
+
+  Connected to {server}. Type /quit to leave.
+";
+
+/// Prints a line of output, first returning to the start of the line so it overwrites the
+/// prompt. When stdout isn't a terminal there is no prompt, so the line is printed as-is.
+macro_rules! show {
+    ($($arg:tt)*) => {{
+        if stdout_is_tty() {
+            print!("\r");
+        }
+        println!($($arg)*);
+    }};
+}
+
+/// Like `show!`, but prints to stderr.
+macro_rules! eshow {
+    ($($arg:tt)*) => {{
+        if stdout_is_tty() {
+            eprint!("\r");
+        }
+        eprintln!($($arg)*);
+    }};
+}
+
+/// Whether stdout is a terminal. When it's piped or redirected, output is plain
+/// newline-delimited text: no prompt, cursor movement or styling.
+fn stdout_is_tty() -> bool {
+    static IS_TTY: OnceLock<bool> = OnceLock::new();
+    *IS_TTY.get_or_init(|| io::stdout().is_terminal())
+}
+
+/// The input prompt, rendered from a template with `{user}` and `{time}` placeholders.
+struct Prompt {
+    template: String, // Template text; unknown placeholders are printed as-is.
+    username: String, // Substituted for `{user}`.
+    enabled: bool,    // Off in scripted mode, where nobody is typing.
+    style: Style,     // The theme's prompt style.
+}
+
+impl Prompt {
+    /// Reads the template from `CHAT_PROMPT`, falling back to `[You]: `.
+    fn from_env(username: &str, enabled: bool, style: Style) -> Self {
+        Self {
+            template: env::var(PROMPT_ENV_VAR).unwrap_or_else(|_| DEFAULT_PROMPT.to_string()),
+            username: username.to_string(),
+            enabled,
+            style,
+        }
+    }
+
+    /// Expands the template with the current values of its placeholders.
+    fn render(&self) -> String {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let time = format_time(secs);
+        expand_template(
+            &self.template,
+            &[("user", self.username.as_str()), ("time", time.as_str())],
+        )
+    }
+}
+
+/// Replaces each `{name}` in `template` with its value from `vars`.
+/// Placeholders without a value, and unmatched braces, are kept literally.
+fn expand_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            let name = &after[..end];
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                output.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Expands the `{server}` placeholder in a banner and makes sure it ends with a newline.
+fn render_banner(banner: &str, server_addr: &str) -> String {
+    let mut rendered = expand_template(banner, &[("server", server_addr)]);
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Prints the input prompt to the terminal in a clean way.
+/// This function clears the current line (if any), moves the cursor to the beginning,
+/// and displays the prompt. Nothing is printed when stdout isn't a terminal or in scripted mode.
+fn print_prompt(prompt: &Prompt) -> std::io::Result<()> {
+    let Some(line) = prompt_line(prompt, stdout_is_tty()) else {
+        return Ok(());
+    };
+    print!("{}", line);
+    io::stdout().flush() // Flush the output buffer to ensure the prompt is displayed immediately.
+}
+
+/// The escapes and text that redraw the prompt, or `None` if no prompt is shown.
+fn prompt_line(prompt: &Prompt, tty: bool) -> Option<String> {
+    if !prompt.enabled || !tty {
+        return None;
+    }
+    // `\r`: Move cursor to the beginning of the current line.
+    // `\x1B[2K`: ANSI escape sequence to clear the entire line.
+    Some(format!("\r\x1B[2K{}", prompt.style.paint(&prompt.render())))
+}
+
+/// Features the server reported in its `Capabilities` message; `None` until it arrives.
+type ServerCapabilities = Arc<RwLock<Option<Vec<String>>>>;
+
+/// The sending side of the server connection. The reader thread swaps in a fresh
+/// connection after reconnecting; until then, typed messages wait in `pending`.
+#[derive(Default)]
+struct Connection {
+    writer: Option<Box<dyn Transport>>, // `None` while reconnecting.
+    pending: VecDeque<ChatMessage>,     // Messages typed while disconnected, oldest first.
+}
+
+/// A chat session, shared by the input loop and the reader thread.
+struct Session {
+    server_addr: String,               // Address to reconnect to.
+    username: String,                  // Username to join and rejoin with.
+    connection: Mutex<Connection>,     // Sending side; replaced by the reader after reconnecting.
+    quit_flag: AtomicBool,             // Set once the user quits; stops reading and reconnecting.
+    reconnect_requested: AtomicBool, // Set by `/reconnect` so the reader doesn't report a lost connection.
+    ended_by_server: AtomicBool,     // Set when the server ends the session; the client then exits.
+    e2e: Mutex<E2eSessions>,         // Keys for `/encrypt` conversations.
+    paused: Mutex<PausedMessages>,   // Messages held back by `/pause`.
+    last_seen_seq: Mutex<Option<u64>>, // Last history seq received; lets a rejoin skip it.
+    markdown: bool,                  // Render `*bold*`, `_italic_` and `` `code` `` in messages.
+    timestamps: AtomicBool,          // Prefix messages with their time; toggled by `/timestamps`.
+    theme: Theme,                    // Styles for displayed messages.
+    download_dir: PathBuf,           // Where shared files and downloaded history are saved.
+    compression: bool,               // Ask the server for compressed frames on every join.
+    started_at: Instant,             // When the session started, for `/info`.
+    sent: AtomicU64,                 // Messages and commands sent (or queued) this session.
+    received: AtomicU64,             // Messages received from the server this session.
+    read_buffer_bytes: usize,        // Read buffer capacity for new connections.
+    msgpack: bool,                   // Use MessagePack instead of JSON on new connections.
+    message_ttl: Option<u64>,        // TTL set on sent chat messages, in seconds.
+}
+
+/// Commands handled entirely by the client; they are never sent to the server.
+enum LocalCommand {
+    Pause,                    // Hold incoming messages instead of printing them.
+    Resume,                   // Print the held messages and stop holding new ones.
+    Info,                     // Print the connection details and this session's message counts.
+    Timestamps(Option<bool>), // Show or hide message times; `None` toggles.
+    Reconnect,                // Drop the connection and rejoin, e.g. after the network changed.
+}
+
+impl LocalCommand {
+    /// Recognizes input such as `/pause` or `/timestamps off`.
+    fn parse(input: &str) -> Option<Self> {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        match words.as_slice() {
+            ["/pause"] => Some(Self::Pause),
+            ["/resume"] => Some(Self::Resume),
+            ["/info"] => Some(Self::Info),
+            ["/reconnect"] => Some(Self::Reconnect),
+            ["/timestamps"] => Some(Self::Timestamps(None)),
+            ["/timestamps", "on"] => Some(Self::Timestamps(Some(true))),
+            ["/timestamps", "off"] => Some(Self::Timestamps(Some(false))),
+            _ => None,
+        }
+    }
+}
+
+/// Incoming messages held back while the display is paused.
+#[derive(Default)]
+struct PausedMessages {
+    paused: bool,                // Set by `/pause`, cleared by `/resume`.
+    held: VecDeque<ChatMessage>, // Held messages, oldest first.
+    dropped: usize,              // Held messages discarded because the buffer was full.
+}
+
+impl PausedMessages {
+    /// Holds `chat_msg` while paused; otherwise hands it back to be displayed.
+    fn hold(&mut self, chat_msg: ChatMessage) -> Option<ChatMessage> {
+        if !self.paused {
+            return Some(chat_msg);
+        }
+        if self.held.len() >= PAUSE_BUFFER_CAP {
+            self.held.pop_front();
+            self.dropped += 1;
+        }
+        self.held.push_back(chat_msg);
+        None
+    }
+}
+
+impl Connection {
+    /// Sends `message`, or queues it if the client is reconnecting.
+    /// A failed write also queues the message, since the reader thread is about to reconnect.
+    fn send(&mut self, message: ChatMessage, transcript: &Transcript) {
+        if let Some(writer) = self.writer.as_mut() {
+            match send_message(writer.as_mut(), &message) {
+                Ok(()) => {
+                    transcript.record(Direction::Sent, &message);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to send message: {}", e);
+                    self.writer = None;
+                }
+            }
+        }
+        if self.pending.len() >= PENDING_QUEUE_CAP {
+            show!(
+                "[Error]: {} messages are already waiting to be sent; this one was dropped.",
+                PENDING_QUEUE_CAP
+            );
+            return;
+        }
+        self.pending.push_back(message);
+        show!("Not connected; the message will be sent after reconnecting.");
+    }
+
+    /// Installs a new connection and sends the queued messages in order.
+    /// If a write fails, the unsent messages stay queued for the next reconnect.
+    fn resume(&mut self, writer: Box<dyn Transport>, transcript: &Transcript) {
+        self.writer = Some(writer);
+        while let Some(message) = self.pending.pop_front() {
+            let Some(writer) = self.writer.as_mut() else {
+                break;
+            };
+            if let Err(e) = send_message(writer.as_mut(), &message) {
+                eprintln!("Failed to send queued message: {}", e);
+                self.pending.push_front(message);
+                self.writer = None;
+                break;
+            }
+            transcript.record(Direction::Sent, &message);
+        }
+    }
+}
+
+/// Command-line options for the chat client.
+#[derive(Parser, Debug)]
+#[command(name = "chat-client")]
+struct ClientArgs {
+    /// Port of the chat server on 127.0.0.1.
+    #[arg(default_value = "8081")]
+    port: String,
+
+    /// Append every sent and received message to this file as JSONL.
+    #[arg(long)]
+    transcript: Option<PathBuf>,
+
+    /// Extra name for a command, e.g. `who=list`. Aliases also work without the slash.
+    /// May be given multiple times.
+    #[arg(long = "alias", value_name = "ALIAS=COMMAND")]
+    aliases: Vec<String>,
+
+    /// Print this file instead of the built-in banner on connect.
+    /// `{server}` in the file is replaced with the server address.
+    #[arg(long, value_name = "FILE")]
+    banner: Option<PathBuf>,
+
+    /// Don't print a banner on connect.
+    #[arg(long, conflicts_with = "banner")]
+    no_banner: bool,
+
+    /// Render `*bold*`, `_italic_` and `` `code` `` in messages with terminal styling.
+    /// Messages are still sent and logged as typed.
+    #[arg(long)]
+    markdown: bool,
+
+    /// Prefix messages with the time (UTC) they were sent. `/timestamps on|off` changes
+    /// this during the session.
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Directory to save files other users send with `/broadcast-file`, and the history
+    /// from `/download-history`, in.
+    #[arg(long, default_value = ".")]
+    download_dir: PathBuf,
+
+    /// Ask the server to compress large messages sent to this client.
+    #[arg(long)]
+    compression: bool,
+
+    /// Talk to the server in MessagePack instead of JSON, which takes less bandwidth.
+    /// The server must be built with the `msgpack` feature too.
+    #[cfg(feature = "msgpack")]
+    #[arg(long)]
+    msgpack: bool,
+
+    /// Join with this username instead of asking for one.
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Send the lines of this file (`-` for stdin) as if they were typed, then exit.
+    /// Requires `--username`.
+    #[arg(long, value_name = "FILE", requires = "username")]
+    script: Option<PathBuf>,
+
+    /// Pause between the lines of a `--script`, in milliseconds.
+    #[arg(long, default_value_t = 100)]
+    script_delay_ms: u64,
+
+    /// Capacity of the read buffer, in bytes (512 to 1048576). Longer messages are still
+    /// read, over several reads.
+    #[arg(long, default_value_t = DEFAULT_READ_BUFFER_LEN, value_parser = parse_read_buffer_len)]
+    read_buffer_bytes: usize,
+
+    /// Have the server drop the chat messages you send from its history after this many
+    /// seconds, so they aren't replayed to users who join later.
+    #[arg(long, value_name = "SECS")]
+    message_ttl_secs: Option<u64>,
+
+    /// Color messages with a theme: `dark`, `light`, or a JSON file mapping any of `message`,
+    /// `private`, `system`, `join`, `leave`, `error`, `reaction` and `prompt` to a style such
+    /// as `bold red`. An unreadable or invalid file is reported and no theme is used.
+    #[arg(long, value_name = "NAME|FILE")]
+    theme: Option<String>,
+}
 
 /// Main entry point for the client application.
 fn main() -> std::io::Result<()> {
-    const DEFAULT_PORT: &str = "8081";
-    // Retrieve the port from command-line arguments, or default to "8081" if none is provided.
-    let port = env::args()
-        .nth(1)
-        .unwrap_or_else(|| DEFAULT_PORT.to_string());
+    let args = ClientArgs::parse();
+    let port = args.port;
+
+    let mut aliases = CommandAliases::default();
+    for spec in &args.aliases {
+        aliases
+            .add(spec)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    // Open the transcript first so a bad path is reported before connecting.
+    let transcript = Arc::new(match &args.transcript {
+        Some(path) => Transcript::open(path).map_err(|e| {
+            eprintln!("Failed to open transcript {}: {}", path.display(), e);
+            e
+        })?,
+        None => Transcript::disabled(),
+    });
+
+    // Read the banner up front too, for the same reason.
+    let banner = match (&args.banner, args.no_banner) {
+        (_, true) => None,
+        (Some(path), false) => Some(fs::read_to_string(path).map_err(|e| {
+            eprintln!("Failed to read banner {}: {}", path.display(), e);
+            e
+        })?),
+        (None, false) => Some(DEFAULT_BANNER.to_string()),
+    };
+
+    // And the script.
+    let mut input: Box<dyn BufRead> = match &args.script {
+        Some(path) if path.as_os_str() != "-" => {
+            Box::new(BufReader::new(File::open(path).map_err(|e| {
+                eprintln!("Failed to open script {}: {}", path.display(), e);
+                e
+            })?))
+        }
+        _ => Box::new(io::stdin().lock()),
+    };
+    // Themes only apply on a terminal, like markdown styling.
+    let theme = match &args.theme {
+        Some(spec) if stdout_is_tty() => Theme::load(spec).unwrap_or_else(|e| {
+            eshow!("[Error]: {}; using no theme.", e);
+            Theme::default()
+        }),
+        _ => Theme::default(),
+    };
+
+    let input_delay = match args.script {
+        Some(_) => Duration::from_millis(args.script_delay_ms),
+        None => Duration::ZERO,
+    };
 
     // Create a connection to the server using `TcpStream`.
     // The `?` operator propagates errors to the caller (here it uses `std::io::Result`).
-    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).map_err(|e| {
-        log::error!("Failed to connect to server at 127.0.0.1:{}: {}", port, e);
+    let server_addr = format!("127.0.0.1:{}", port);
+    let stream = TcpStream::connect(&server_addr).map_err(|e| {
+        log::error!("Failed to connect to server at {}: {}", server_addr, e);
         e
     })?;
+    #[cfg(feature = "msgpack")]
+    let msgpack = args.msgpack;
+    #[cfg(not(feature = "msgpack"))]
+    let msgpack = false;
+    let mut transport = open_transport(stream, args.read_buffer_bytes, msgpack); // Exchange frames over the stream.
+
+    log::info!("Connected to the server at {}!", transport.peer_addr()?);
+    if let Some(banner) = &banner {
+        print!("{}", render_banner(banner, &server_addr));
+    }
 
-    log::info!("Connected to the server!");
+    // Prompt the user to input their username (unless given) and send a "join" message to the server.
+    let username = match args.username {
+        Some(username) => validate_username(&username)
+            .map(|()| username)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        None => prompt_for_username(&mut input)?, // Read the username from the input (stdin is locked by it).
+    };
+    send_join_message(transport.as_mut(), &username, None, &transcript)?; // Notify the server about the client joining.
+    let username = if username.is_empty() {
+        receive_guest_name(transport.as_mut())? // The server picks a guest's name.
+    } else {
+        username
+    };
+    if args.compression {
+        send_client_features(transport.as_mut())?;
+    }
+    let prompt = Arc::new(Prompt::from_env(
+        &username,
+        input_delay.is_zero(),
+        theme.prompt.clone(),
+    )); // Shared with the reader thread for redraws.
 
-    // Prompt the user to input their username and send a "join" message to the server.
-    let username = prompt_for_username()?; // Call the function to get the username.
-    send_join_message(&mut stream, &username)?; // Notify the server about the client joining.
+    // Clone the transport to create a copy for the reader thread.
+    // `try_clone()` duplicates the connection, allowing it to be used in multiple threads.
+    let transport_clone = transport.try_clone()?;
+    let session = Arc::new(Session {
+        server_addr,
+        username,
+        connection: Mutex::new(Connection {
+            writer: Some(transport),
+            ..Default::default()
+        }),
+        quit_flag: AtomicBool::new(false),
+        reconnect_requested: AtomicBool::new(false),
+        ended_by_server: AtomicBool::new(false),
+        e2e: Mutex::default(),
+        paused: Mutex::default(),
+        last_seen_seq: Mutex::default(),
+        markdown: args.markdown && stdout_is_tty(), // Styling would be noise in piped output.
+        timestamps: AtomicBool::new(args.timestamps),
+        theme,
+        download_dir: args.download_dir,
+        compression: args.compression,
+        read_buffer_bytes: args.read_buffer_bytes,
+        msgpack,
+        message_ttl: args.message_ttl_secs,
+        started_at: Instant::now(),
+        sent: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+    });
+    let session_clone = Arc::clone(&session);
+    let prompt_clone = Arc::clone(&prompt);
+    let transcript_clone = Arc::clone(&transcript);
+    let capabilities = ServerCapabilities::default();
+    let capabilities_clone = Arc::clone(&capabilities);
+    let handle = thread::spawn(move || {
+        run_reader(
+            transport_clone,
+            &session_clone,
+            &prompt_clone,
+            &transcript_clone,
+            &capabilities_clone,
+        );
+        // The input loop may be blocked on a line that never comes, so exit from here.
+        if session_clone.ended_by_server.load(Ordering::SeqCst) {
+            transcript_clone.flush();
+            process::exit(0);
+        }
+    });
 
-    // Clone the stream to create a copy for the reader thread.
-    // `try_clone()` duplicates the `TcpStream`, allowing it to be used in multiple threads.
-    let stream_clone = stream.try_clone()?;
-    let quit_flag = Arc::new(AtomicBool::new(false));
-    let quit_flag_clone = quit_flag.clone();
-    let handle = thread::spawn(move || handle_incoming_messages(stream_clone, quit_flag_clone));
+    // Handle user input (or the script) in the main thread.
+    handle_user_input(
+        &session,
+        &prompt,
+        &transcript,
+        &capabilities,
+        &aliases,
+        input,
+        input_delay,
+    )?;
 
-    // Handle user input in the main thread.
-    handle_user_input(&mut stream, &username, &quit_flag)?;
+    // Close the connection so the reader thread unblocks, even if stdin ended without `/quit`.
+    // The flag is set first so a reconnect in progress won't install a new connection.
+    session.quit_flag.store(true, Ordering::SeqCst);
+    if let Ok(mut connection) = session.connection.lock() {
+        if let Some(writer) = connection.writer.take() {
+            if let Err(e) = writer.shutdown() {
+                log::error!("Failed to close connection: {}", e);
+            }
+        }
+    }
 
     // Wait for the reader thread to finish before exiting.
     if let Err(e) = handle.join() {
         log::error!("Failed to join thread: {:?}", e);
     }
+    transcript.flush();
 
     Ok(())
 }
 
 /// Prompts the user for their username.
-fn prompt_for_username() -> std::io::Result<String> {
-    print!("Enter your username: "); // Prompt message.
+/// An empty name joins as a guest, if the server allows it.
+fn prompt_for_username(input: &mut dyn BufRead) -> std::io::Result<String> {
+    print!("Enter your username (or nothing to join as a guest): "); // Prompt message.
     io::stdout().flush()?; // Ensure the prompt is printed immediately by flushing the buffer.
     let mut username = String::new(); // Create a mutable `String` to store the username.
-    io::stdin().read_line(&mut username)?; // Read input from the user.
+    input.read_line(&mut username)?; // Read input from the user.
     let username = username.trim().to_string(); // Remove trailing whitespace and return the username.
+    if username.is_empty() {
+        return Ok(username);
+    }
 
     // Validate username
-    if username.is_empty() || username.len() > 20 {
-        log::error!("Invalid username. It must be between 1 and 20 characters.");
-        return prompt_for_username(); // Retry input.
+    if let Err(e) = validate_username(&username) {
+        log::error!("{}", e);
+        return prompt_for_username(input); // Retry input.
     }
 
     Ok(username)
 }
 
+/// Checks a username against the rules the client enforces before joining.
+fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_LEN {
+        return Err(format!(
+            "Invalid username. It must be between 1 and {} characters.",
+            MAX_USERNAME_LEN
+        ));
+    }
+    if is_reserved_username(username) {
+        return Err(format!("The username '{}' is reserved.", username));
+    }
+    Ok(())
+}
+
+/// Waits for the name the server gives a client that joined as a guest, and shows it.
+/// Fails if the server refuses the join, e.g. because it doesn't allow guests.
+fn receive_guest_name(transport: &mut dyn Transport) -> std::io::Result<String> {
+    while let Some(frame) = transport.read_frame()? {
+        let Ok(Frame {
+            message: chat_msg, ..
+        }) = Frame::decode(&frame)
+        else {
+            continue;
+        };
+        match (&chat_msg.message_type, chat_msg.username) {
+            (ChatMessageType::Join, Some(username)) if chat_msg.system => {
+                show!("{}", chat_msg.content);
+                return Ok(username);
+            }
+            (ChatMessageType::Error, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    chat_msg.content,
+                ));
+            }
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the server closed the connection before naming this guest",
+    ))
+}
+
 /// Sends a "join" message to the server.
-fn send_join_message(stream: &mut TcpStream, username: &str) -> std::io::Result<()> {
+/// When rejoining, `last_seen_seq` lets the server replay only the messages that were missed.
+fn send_join_message(
+    transport: &mut dyn Transport, // The server connection.
+    username: &str,                // The chosen username.
+    last_seen_seq: Option<u64>,    // Last history seq received before reconnecting.
+    transcript: &Transcript,       // Records the join message.
+) -> std::io::Result<()> {
     // Create a structured `ChatMessage` to indicate that the user has joined the chat.
     let join_msg = ChatMessage {
         message_type: ChatMessageType::Join, // Indicate a "join" message type.
         username: Some(username.to_string()), // Set the username.
         content: format!("{} has joined the chat", username), // Message content.
+        seq: last_seen_seq,
+        ..Default::default()
     };
-    send_message(stream, &join_msg) // Use the `send_message` helper to send the message.
+    send_message(transport, &join_msg)?; // Use the `send_message` helper to send the message.
+    transcript.record(Direction::Sent, &join_msg);
+    Ok(())
+}
+
+/// Tells the server which optional features this client supports; currently only
+/// compression, which the server only uses for clients that ask for it.
+fn send_client_features(transport: &mut dyn Transport) -> std::io::Result<()> {
+    let features = ChatMessage {
+        message_type: ChatMessageType::Capabilities,
+        content: serde_json::to_string(&[COMPRESSION_FEATURE])?,
+        ..Default::default()
+    };
+    send_message(transport, &features)
 }
 
-/// Handles user input from the terminal, sends messages or commands to the server,
-/// and manages the client's quit state.
+/// Handles user input from the terminal (or a `--script`), sends messages or commands to
+/// the server, and manages the client's quit state.
 fn handle_user_input(
-    stream: &mut TcpStream, // Mutable reference to the server connection (TCP stream).
-    username: &str,         // Username of the client sending messages.
-    quit_flag: &Arc<AtomicBool>, // Shared flag to signal when the client is quitting.
+    session: &Session,                 // The connection, username and quit flag.
+    prompt: &Prompt,                   // The input prompt.
+    transcript: &Transcript,           // Records each sent message.
+    capabilities: &ServerCapabilities, // Used to refuse commands the server doesn't support.
+    aliases: &CommandAliases,          // User-configured command aliases.
+    input: Box<dyn BufRead>,           // Lines to send: stdin, or the script file.
+    delay: Duration,                   // Pause after each sent line; zero when interactive.
 ) -> std::io::Result<()> {
-    let stdin = io::stdin(); // Access the standard input for reading user input.
-
-    /// Prints the input prompt `[You]: ` to the terminal in a clean way.
-    /// This function clears the current line (if any), moves the cursor to the beginning,
-    /// and displays the prompt.
-    fn print_prompt() -> std::io::Result<()> {
-        // `\r`: Move cursor to the beginning of the current line.
-        // `\x1B[2K`: ANSI escape sequence to clear the entire line.
-        print!("\r\x1B[2K[You]: ");
-        io::stdout().flush() // Flush the output buffer to ensure the prompt is displayed immediately.
-    }
+    let username = session.username.as_str();
 
-    print_prompt()?; // Display the initial prompt to the user.
+    print_prompt(prompt)?; // Display the initial prompt to the user.
 
     // Read input from the terminal in a loop, line by line.
-    for line in stdin.lock().lines() {
+    for line in input.lines() {
         let input = line?; // Read a line of input and handle potential I/O errors.
 
         // Skip processing for empty input and redisplay the prompt.
         if input.trim().is_empty() {
-            print_prompt()?; // Clear the line and show a clean prompt again.
+            print_prompt(prompt)?; // Clear the line and show a clean prompt again.
             continue; // Skip to the next iteration of the loop.
         }
 
+        // Client-only commands never reach the server.
+        if let Some(command) = LocalCommand::parse(&input) {
+            run_local_command(command, session);
+            print_prompt(prompt)?;
+            continue;
+        }
+
         // Parse the user's input into a structured `ChatMessage`.
-        let chat_msg = parse_user_input(&input, username);
+        let chat_msg = match parse_user_input(&input, username, aliases) {
+            Ok(chat_msg) => chat_msg,
+            Err(e) => {
+                show!("[Error]: {}", e); // Report invalid commands locally.
+                print_prompt(prompt)?;
+                continue;
+            }
+        };
 
-        // Attempt to send the parsed message to the server via the TCP stream.
-        if let Err(e) = send_message(stream, &chat_msg) {
-            eprintln!("Failed to send message: {}", e); // Log any errors while sending.
+        // Chat messages expire from the server's history after `--message-ttl-secs`.
+        let chat_msg = match chat_msg.message_type {
+            ChatMessageType::Message => ChatMessage {
+                ttl: session.message_ttl,
+                ..chat_msg
+            },
+            _ => chat_msg,
+        };
+
+        // Don't send commands for features the server has reported it lacks.
+        if let ChatMessageType::Command(command_type) = &chat_msg.message_type {
+            if let Some(missing) = missing_capability(command_type, capabilities) {
+                show!(
+                    "[Error]: This server doesn't support '{}' commands.",
+                    missing
+                );
+                print_prompt(prompt)?;
+                continue;
+            }
         }
 
+        // `/encrypt` becomes a key offer, and a `/msg` to an encrypted peer becomes ciphertext.
+        let chat_msg = match encrypt_outgoing(chat_msg, username, &session.e2e) {
+            Ok(chat_msg) => chat_msg,
+            Err(e) => {
+                show!("[Error]: {}", e);
+                print_prompt(prompt)?;
+                continue;
+            }
+        };
+
+        // `/broadcast-file` reads the file here and sends its contents.
+        let chat_msg = match load_outgoing_file(chat_msg, username) {
+            Ok(chat_msg) => chat_msg,
+            Err(e) => {
+                show!("[Error]: Failed to read the file: {}", e);
+                print_prompt(prompt)?;
+                continue;
+            }
+        };
+
         // Check if the user entered the `/quit` command.
         if matches!(
             chat_msg.message_type,
             ChatMessageType::Command(CommandType::Quit)
         ) {
             // Set the `quit_flag` to `true`, signaling other threads (e.g., the message handler) to exit.
-            quit_flag.store(true, Ordering::SeqCst);
-            println!("\rYou have disconnected from the chat."); // Inform the user of disconnection.
+            // Set before sending, so the server's echo of the quit isn't taken for `end_session`.
+            session.quit_flag.store(true, Ordering::SeqCst);
+            // Only tell a connected server; there's no point queueing a goodbye.
+            if let Ok(mut connection) = session.connection.lock() {
+                if let Some(writer) = connection.writer.as_mut() {
+                    match send_message(writer.as_mut(), &chat_msg) {
+                        Ok(()) => transcript.record(Direction::Sent, &chat_msg),
+                        Err(e) => eprintln!("Failed to send message: {}", e),
+                    }
+                }
+            }
+            show!("You have disconnected from the chat."); // Inform the user of disconnection.
             break; // Exit the loop, ending the user input handling.
         }
 
-        print_prompt()?; // Redisplay the prompt after processing the input.
+        // Send the parsed message to the server, or queue it while reconnecting.
+        session.sent.fetch_add(1, Ordering::Relaxed);
+        match session.connection.lock() {
+            Ok(mut connection) => connection.send(chat_msg, transcript),
+            Err(_) => eprintln!("Failed to send message: connection lock poisoned"),
+        }
+        thread::sleep(delay); // Paces scripted input.
+
+        print_prompt(prompt)?; // Redisplay the prompt after processing the input.
     }
 
     Ok(()) // Indicate successful completion of the function.
 }
 
+/// Runs a client-only command.
+fn run_local_command(command: LocalCommand, session: &Session) {
+    if let LocalCommand::Timestamps(enabled) = command {
+        let enabled = enabled.unwrap_or(!session.timestamps.load(Ordering::Relaxed));
+        session.timestamps.store(enabled, Ordering::Relaxed);
+        show!("Timestamps {}.", if enabled { "on" } else { "off" });
+        return;
+    }
+    if let LocalCommand::Reconnect = command {
+        request_reconnect(session);
+        return;
+    }
+    if let LocalCommand::Info = command {
+        let connected = session
+            .connection
+            .lock()
+            .is_ok_and(|connection| connection.writer.is_some());
+        show!(
+            "{}",
+            format_session_info(
+                &session.server_addr,
+                &session.username,
+                connected,
+                session.started_at.elapsed(),
+                session.sent.load(Ordering::Relaxed),
+                session.received.load(Ordering::Relaxed),
+            )
+        );
+        return;
+    }
+    let Ok(mut paused) = session.paused.lock() else {
+        return;
+    };
+    match command {
+        LocalCommand::Info | LocalCommand::Timestamps(_) | LocalCommand::Reconnect => {} // Handled above; they don't touch the paused messages.
+        LocalCommand::Pause => {
+            paused.paused = true;
+            show!("Paused. Incoming messages are held until /resume.");
+        }
+        LocalCommand::Resume => {
+            // Printed under the lock so newer messages can't jump ahead of the held ones.
+            paused.paused = false;
+            if paused.dropped > 0 {
+                show!(
+                    "{} older messages were dropped while paused.",
+                    paused.dropped
+                );
+                paused.dropped = 0;
+            }
+            for chat_msg in paused.held.drain(..) {
+                display_message(chat_msg, session);
+            }
+            show!("Resumed.");
+        }
+    }
+}
+
+/// Closes the current connection so the reader thread reconnects and rejoins, as it
+/// would after a dropped connection. Messages typed meanwhile are queued as usual.
+fn request_reconnect(session: &Session) {
+    let Ok(mut connection) = session.connection.lock() else {
+        return;
+    };
+    let Some(writer) = connection.writer.take() else {
+        show!("Already reconnecting.");
+        return;
+    };
+    session.reconnect_requested.store(true, Ordering::SeqCst);
+    show!("Closing the connection to {}...", session.server_addr);
+    // Shutting down the socket also wakes the reader, which shares it.
+    if let Err(e) = writer.shutdown() {
+        eshow!("[Error]: Failed to close the connection: {}", e);
+    }
+}
+
+/// Formats a Unix timestamp as the `[HH:MM] ` prefix shown with `--timestamps`, in UTC.
+fn format_time_prefix(timestamp: u64) -> String {
+    format!("[{}] ", format_time(timestamp))
+}
+
+/// Formats the `/info` summary of the session.
+fn format_session_info(
+    server_addr: &str, // The server this client talks to.
+    username: &str,    // This client's username.
+    connected: bool,   // `false` while reconnecting.
+    elapsed: Duration, // Time since the session started.
+    sent: u64,         // Messages and commands sent this session.
+    received: u64,     // Messages received this session.
+) -> String {
+    let secs = elapsed.as_secs();
+    format!(
+        "Server: {} ({})\nUsername: {}\nSession: {}h {:02}m {:02}s\nMessages: {} sent, {} received",
+        server_addr,
+        if connected {
+            "connected"
+        } else {
+            "reconnecting"
+        },
+        username,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        sent,
+        received
+    )
+}
+
 /// Handles incoming messages from the server in a separate thread.
-fn handle_incoming_messages(stream: TcpStream, quit_flag: Arc<AtomicBool>) {
-    let reader = io::BufReader::new(stream);
-    for line in reader.lines() {
-        if quit_flag.load(Ordering::SeqCst) {
+/// If the connection drops before the user quits, reconnects and rejoins.
+fn handle_incoming_messages(
+    mut transport: Box<dyn Transport>, // Reading half of the server connection.
+    session: &Session,                 // Used to reconnect and decrypt; stops on quit.
+    prompt: &Prompt,                   // Redrawn after each displayed message.
+    transcript: &Transcript,           // Records each displayed message.
+    capabilities: &ServerCapabilities, // Filled in from the server's `Capabilities` message.
+) {
+    loop {
+        let frame = transport.read_frame();
+        if session.quit_flag.load(Ordering::SeqCst) {
             break; // Exit if quit is signaled
         }
 
-        match line {
-            Ok(msg) => {
-                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&msg) {
-                    display_message(chat_msg);
+        let reason = match frame {
+            Ok(None) => "The server closed the connection.".to_string(),
+            Err(e) => format!("Lost connection to the server: {}", e), // e.g. an over-long frame.
+            Ok(Some(msg)) => {
+                if let Ok(Frame {
+                    message: chat_msg, ..
+                }) = Frame::decode(&msg)
+                {
+                    if chat_msg.encoding == ContentEncoding::Unknown {
+                        log::error!("Ignoring a message with an unknown content encoding");
+                        continue;
+                    }
+                    let chat_msg = if matches!(chat_msg.message_type, ChatMessageType::Compressed) {
+                        match decompress_message(&chat_msg) {
+                            Ok(chat_msg) => chat_msg,
+                            Err(e) => {
+                                log::error!("Failed to decompress message: {}", e);
+                                continue;
+                            }
+                        }
+                    } else {
+                        chat_msg
+                    };
+                    if let Some(seq) = history_seq(&chat_msg) {
+                        if let Ok(mut last_seen_seq) = session.last_seen_seq.lock() {
+                            *last_seen_seq = Some(seq);
+                        }
+                    }
+                    if matches!(chat_msg.message_type, ChatMessageType::AckRequest) {
+                        // The server is waiting before sending the next window of history.
+                        if let Err(e) = send_ack(transport.as_mut()) {
+                            log::error!("Failed to acknowledge history window: {}", e);
+                        }
+                        continue;
+                    }
+                    if matches!(chat_msg.message_type, ChatMessageType::Capabilities) {
+                        // Remember what the server supports; nothing to display.
+                        match serde_json::from_str(&chat_msg.content) {
+                            Ok(features) => {
+                                if let Ok(mut lock) = capabilities.write() {
+                                    *lock = Some(features);
+                                }
+                            }
+                            Err(e) => log::error!("Failed to parse capabilities: {}", e),
+                        }
+                        continue;
+                    }
+                    if matches!(chat_msg.message_type, ChatMessageType::Ping) {
+                        // Answer latency probes without disturbing the display.
+                        if let Err(e) = send_pong(transport.as_mut(), chat_msg.seq) {
+                            log::error!("Failed to answer ping: {}", e);
+                        }
+                        continue;
+                    }
+                    if is_session_end(&chat_msg) {
+                        transcript.record(Direction::Received, &chat_msg);
+                        end_session(session);
+                        break;
+                    }
+                    transcript.record(Direction::Received, &chat_msg); // Encrypted messages stay encrypted.
+                    session.received.fetch_add(1, Ordering::Relaxed);
+                    if is_e2e_message(&chat_msg) {
+                        handle_e2e_message(transport.as_mut(), session, &chat_msg);
+                    } else if is_shared_file(&chat_msg) {
+                        save_shared_file(session, &chat_msg);
+                    } else if is_history_download(&chat_msg) {
+                        save_history_download(session, &chat_msg);
+                    } else if matches!(chat_msg.message_type, ChatMessageType::Rename) {
+                        apply_rename(session, &chat_msg);
+                    } else if matches!(chat_msg.message_type, ChatMessageType::Expired) {
+                        apply_expiry(session, &chat_msg);
+                    } else {
+                        let chat_msg = match session.paused.lock() {
+                            Ok(mut paused) => paused.hold(chat_msg),
+                            Err(_) => Some(chat_msg),
+                        };
+                        match chat_msg {
+                            Some(chat_msg) => display_message(chat_msg, session),
+                            None => continue, // Held until `/resume`; nothing to redraw.
+                        }
+                    }
                 } else {
                     log::error!("Failed to parse message: {}", msg);
                 }
-                print!("[You]: ");
-                if let Err(e) = io::stdout().flush() {
+                if let Err(e) = print_prompt(prompt) {
                     log::error!("Failed to flush stdout: {}", e);
                 }
+                continue;
+            }
+        };
+
+        if !session.reconnect_requested.swap(false, Ordering::SeqCst) {
+            eshow!("[Error]: {}", reason);
+        }
+        match reconnect(session, transcript) {
+            Some(reader) => transport = reader,
+            None => break, // The user quit while reconnecting.
+        }
+    }
+}
+
+/// Returns `true` for the message the server sends a client whose session it is ending.
+fn is_session_end(chat_msg: &ChatMessage) -> bool {
+    matches!(chat_msg.message_type, ChatMessageType::SessionEnd)
+}
+
+/// Stops the session after the server ended it: no more reading or reconnecting,
+/// and the connection is closed. The reader thread then exits the client.
+fn end_session(session: &Session) {
+    show!("The server ended your session.");
+    session.quit_flag.store(true, Ordering::SeqCst);
+    session.ended_by_server.store(true, Ordering::SeqCst);
+    if let Ok(mut connection) = session.connection.lock() {
+        if let Some(writer) = connection.writer.take() {
+            let _ = writer.shutdown(); // The server is closing it anyway.
+        }
+    }
+}
+
+/// Runs the reader thread. If handling a message panics, the error is reported right away
+/// and the client reconnects, instead of the session silently going deaf until `/quit`.
+fn run_reader(
+    mut transport: Box<dyn Transport>, // Reading half of the server connection.
+    session: &Session,                 // Shared session state.
+    prompt: &Prompt,                   // Redrawn after each displayed message.
+    transcript: &Transcript,           // Records each displayed message.
+    capabilities: &ServerCapabilities, // Filled in from the server's `Capabilities` message.
+) {
+    loop {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle_incoming_messages(transport, session, prompt, transcript, capabilities)
+        }));
+        if result.is_ok() || session.quit_flag.load(Ordering::SeqCst) {
+            break;
+        }
+        eshow!("[Error]: Failed to handle a message from the server; reconnecting.");
+        match reconnect(session, transcript) {
+            Some(reader) => transport = reader,
+            None => break, // The user quit while reconnecting.
+        }
+    }
+}
+
+/// Follows another user's `/nick`, so encrypted conversations with them keep working.
+/// The "is now known as" notice is displayed separately.
+fn apply_rename(session: &Session, chat_msg: &ChatMessage) {
+    if !chat_msg.system {
+        return; // Only the server announces renames.
+    }
+    match serde_json::from_str::<Rename>(&chat_msg.content) {
+        Ok(rename) => {
+            if let Ok(mut e2e) = session.e2e.lock() {
+                e2e.rename(&rename.old_name, &rename.new_name);
+            }
+        }
+        Err(e) => log::error!("Failed to parse rename: {}", e),
+    }
+}
+
+/// Drops messages that expired from the server's history from those held by `/pause`,
+/// and tells the user which ones expired.
+fn apply_expiry(session: &Session, chat_msg: &ChatMessage) {
+    if !chat_msg.system {
+        return; // Only the server expires messages.
+    }
+    let seqs = match serde_json::from_str::<Vec<u64>>(&chat_msg.content) {
+        Ok(seqs) => seqs,
+        Err(e) => {
+            log::error!("Failed to parse expired messages: {}", e);
+            return;
+        }
+    };
+    if let Ok(mut paused) = session.paused.lock() {
+        paused
+            .held
+            .retain(|held| held.seq.is_none_or(|seq| !seqs.contains(&seq)));
+    }
+    let seqs: Vec<String> = seqs.iter().map(|seq| format!("#{}", seq)).collect();
+    show!(
+        "{}",
+        session
+            .theme
+            .system
+            .paint(&format!("[Expired: {}]", seqs.join(", ")))
+    );
+}
+
+/// Returns `true` for key offers and ciphertext relayed from another user.
+fn is_e2e_message(chat_msg: &ChatMessage) -> bool {
+    !chat_msg.system
+        && matches!(
+            chat_msg.message_type,
+            ChatMessageType::Command(CommandType::PublicKey | CommandType::Encrypted)
+        )
+}
+
+/// Stores a peer's public key, answering with this client's key if needed,
+/// or decrypts and displays an encrypted private message.
+fn handle_e2e_message(
+    transport: &mut dyn Transport, // Used to answer key offers.
+    session: &Session,             // This client's username and keys.
+    chat_msg: &ChatMessage,        // The relayed key or ciphertext.
+) {
+    let sender = chat_msg.username.as_deref().unwrap_or("unknown");
+    let Ok(mut sessions) = session.e2e.lock() else {
+        return;
+    };
+    let result = match chat_msg.message_type {
+        ChatMessageType::Command(CommandType::PublicKey) => {
+            sessions.accept(sender, &chat_msg.content).map(|answer| {
+                if answer {
+                    let offer = Command::PublicKey {
+                        target: sender.to_string(),
+                        key: sessions.offer(sender),
+                    };
+                    let offer = offer.into_message(&session.username);
+                    if let Err(e) = send_message(transport, &offer) {
+                        eshow!("[Error]: Failed to send encryption key: {}", e);
+                    }
+                }
+                show!("End-to-end encryption with {} is on.", sender);
+            })
+        }
+        _ => sessions.decrypt(sender, &chat_msg.content).map(|text| {
+            let text = if session.markdown {
+                render_markdown(&text)
+            } else {
+                text
+            };
+            show!("[{} → you, encrypted]: {}", sender, text)
+        }),
+    };
+    if let Err(e) = result {
+        show!("[Error]: {}", e);
+    }
+}
+
+/// Turns `/encrypt <user>` into a key offer, and a `/msg` to a single user this client shares
+/// a key with into an encrypted message. Anything else is returned unchanged.
+fn encrypt_outgoing(
+    chat_msg: ChatMessage,    // The parsed input.
+    username: &str,           // This client's username.
+    e2e: &Mutex<E2eSessions>, // Keys for `/encrypt` conversations.
+) -> Result<ChatMessage, E2eError> {
+    let command = match &chat_msg.message_type {
+        ChatMessageType::Command(command_type @ (CommandType::Encrypt | CommandType::Msg)) => {
+            Command::from_parts(command_type, &chat_msg.content)
+        }
+        _ => return Ok(chat_msg),
+    };
+    let Ok(mut sessions) = e2e.lock() else {
+        return Ok(chat_msg);
+    };
+    match command {
+        Ok(Command::Encrypt { username: target }) => {
+            show!("Offering end-to-end encryption to {}...", target);
+            let key = sessions.offer(&target);
+            Ok(Command::PublicKey { target, key }.into_message(username))
+        }
+        Ok(Command::Msg { targets, text })
+            if targets.len() == 1 && sessions.is_established(&targets[0]) =>
+        {
+            let payload = sessions.encrypt(&targets[0], &text)?;
+            let target = targets[0].clone();
+            Ok(Command::Encrypted { target, payload }.into_message(username))
+        }
+        _ => Ok(chat_msg),
+    }
+}
+
+/// Turns `/broadcast-file <path>` into a message carrying the file's contents.
+/// Anything else is returned unchanged.
+fn load_outgoing_file(
+    chat_msg: ChatMessage, // The parsed input.
+    username: &str,        // This client's username.
+) -> io::Result<ChatMessage> {
+    let ChatMessageType::Command(command_type @ CommandType::BroadcastFile) =
+        &chat_msg.message_type
+    else {
+        return Ok(chat_msg);
+    };
+    let Ok(Command::BroadcastFile { path }) = Command::from_parts(command_type, &chat_msg.content)
+    else {
+        return Ok(chat_msg);
+    };
+    let (name, data) = files::load(Path::new(&path))?;
+    Ok(Command::SharedFile { name, data }.into_message(username))
+}
+
+/// Returns `true` for a file another user sent with `/broadcast-file`.
+fn is_shared_file(chat_msg: &ChatMessage) -> bool {
+    !chat_msg.system
+        && matches!(
+            chat_msg.message_type,
+            ChatMessageType::Command(CommandType::SharedFile)
+        )
+}
+
+/// Saves a file another user sent to the download directory and says where it went.
+fn save_shared_file(session: &Session, chat_msg: &ChatMessage) {
+    let sender = chat_msg.username.as_deref().unwrap_or("unknown");
+    let result = match Command::from_parts(&CommandType::SharedFile, &chat_msg.content) {
+        Ok(Command::SharedFile { name, data }) => files::save(&session.download_dir, &name, &data),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed file")),
+    };
+    match result {
+        Ok((path, len)) => show!(
+            "{} sent a file: saved to {} ({} bytes).",
+            sender,
+            path.display(),
+            len
+        ),
+        Err(e) => show!("[Error]: Failed to save a file from {}: {}", sender, e),
+    }
+}
+
+/// Returns `true` for the server's reply to `/download-history`.
+fn is_history_download(chat_msg: &ChatMessage) -> bool {
+    chat_msg.system
+        && matches!(
+            chat_msg.message_type,
+            ChatMessageType::Command(CommandType::DownloadHistory)
+        )
+}
+
+/// Saves the history sent for `/download-history` to the download directory
+/// as a JSON array, and says where it went.
+fn save_history_download(session: &Session, chat_msg: &ChatMessage) {
+    let result = serde_json::from_str::<HistoryDownload>(&chat_msg.content).and_then(|download| {
+        let json = serde_json::to_vec_pretty(&download.messages)?;
+        Ok((download, json))
+    });
+    let (download, json) = match result {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            show!("[Error]: Failed to read the downloaded history: {}", e);
+            return;
+        }
+    };
+    match files::save_bytes(&session.download_dir, HISTORY_FILE_NAME, &json) {
+        Ok((path, _)) if download.messages.len() < download.total => show!(
+            "Saved the last {} of {} messages to {}; older ones were over the server's size limit.",
+            download.messages.len(),
+            download.total,
+            path.display()
+        ),
+        Ok((path, _)) => show!(
+            "Saved {} messages to {}.",
+            download.messages.len(),
+            path.display()
+        ),
+        Err(e) => show!("[Error]: Failed to save the history: {}", e),
+    }
+}
+
+/// Returns the history seq of a received message. Reactions, pings and pongs use
+/// `seq` for something else, so they don't count.
+fn history_seq(chat_msg: &ChatMessage) -> Option<u64> {
+    match chat_msg.message_type {
+        ChatMessageType::Reaction | ChatMessageType::Ping | ChatMessageType::Pong => None,
+        _ => chat_msg.seq,
+    }
+}
+
+/// Reconnects to the server, backing off between attempts until one succeeds or the user quits.
+/// The rejoin carries the last seen seq so only missed history is replayed.
+/// Once rejoined, the new sending side is shared with the input loop and the messages typed
+/// in the meantime are flushed. Returns the new reading half, or `None` if the user quit.
+fn reconnect(
+    session: &Session,       // Where to reconnect and as whom.
+    transcript: &Transcript, // Records the rejoin and flushed messages.
+) -> Option<Box<dyn Transport>> {
+    // A poisoned lock still holds a usable connection; giving up here would end the client.
+    session
+        .connection
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .writer = None; // Queue input until the new connection is up.
+    let last_seen_seq = session.last_seen_seq.lock().ok().and_then(|seq| *seq);
+    let mut delay = RECONNECT_DELAY_INITIAL;
+    loop {
+        if session.quit_flag.load(Ordering::SeqCst) {
+            return None;
+        }
+        show!("Reconnecting to {}...", session.server_addr);
+        match connect_and_rejoin(session, last_seen_seq, transcript) {
+            Ok((writer, reader)) => {
+                let mut connection = session
+                    .connection
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                // Checked under the lock so a quitting input loop never misses the new connection.
+                if session.quit_flag.load(Ordering::SeqCst) {
+                    let _ = writer.shutdown();
+                    return None;
+                }
+                show!("Reconnected.");
+                connection.resume(writer, transcript);
+                return Some(reader);
             }
             Err(e) => {
-                log::error!("Error reading message: {}", e);
-                break;
+                eshow!("[Error]: Reconnect failed: {}", e);
+                thread::sleep(delay);
+                delay = (delay * 2).min(RECONNECT_DELAY_MAX);
             }
         }
     }
 }
 
+/// Opens a new connection and sends the join message.
+/// Returns the sending and reading halves.
+fn connect_and_rejoin(
+    session: &Session,          // Where to connect and as whom.
+    last_seen_seq: Option<u64>, // Included in the join message.
+    transcript: &Transcript,    // Records the join message.
+) -> std::io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+    let stream = TcpStream::connect(&session.server_addr)?;
+    let mut transport = open_transport(stream, session.read_buffer_bytes, session.msgpack);
+    send_join_message(
+        transport.as_mut(),
+        &session.username,
+        last_seen_seq,
+        transcript,
+    )?;
+    if session.compression {
+        send_client_features(transport.as_mut())?;
+    }
+    let reader = transport.try_clone()?;
+    Ok((transport, reader))
+}
+
+/// Wraps a connected stream in the transport for the wire format in use:
+/// MessagePack with `--msgpack`, otherwise newline-delimited JSON.
+/// `TCP_NODELAY` is set on the stream first.
+fn open_transport(
+    stream: TcpStream,        // The connection to the server.
+    read_buffer_bytes: usize, // Read buffer capacity for JSON frames.
+    msgpack: bool,            // Whether to speak MessagePack.
+) -> Box<dyn Transport> {
+    // Typed lines are small, so send each one right away instead of waiting to batch it.
+    if let Err(e) = stream.set_nodelay(true) {
+        log::warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+    #[cfg(feature = "msgpack")]
+    if msgpack {
+        return Box::new(MsgpackTransport::new(stream));
+    }
+    #[cfg(not(feature = "msgpack"))]
+    let _ = msgpack; // Only JSON is available without the `msgpack` feature.
+    Box::new(TcpTransport::with_read_buffer(stream, read_buffer_bytes))
+}
+
+/// Returns the capability `command_type` needs if the server has reported that it lacks it.
+/// Commands are allowed while the server's capabilities are still unknown.
+fn missing_capability(
+    command_type: &CommandType,
+    capabilities: &ServerCapabilities,
+) -> Option<&'static str> {
+    let required = command_type.required_capability()?;
+    let lock = capabilities.read().ok()?;
+    let features = lock.as_ref()?;
+    (!features.iter().any(|feature| feature == required)).then_some(required)
+}
+
 /// Sends a `ChatMessage` to the server.
-fn send_message(stream: &mut TcpStream, message: &ChatMessage) -> std::io::Result<()> {
-    // Serialize the `ChatMessage` into JSON format.
-    let serialized_msg = serde_json::to_string(message)?;
-    // Write the serialized message to the TCP stream.
-    stream.write_all(format!("{}\n", serialized_msg).as_bytes())
+fn send_message(transport: &mut dyn Transport, message: &ChatMessage) -> std::io::Result<()> {
+    // Wrap the `ChatMessage` in a frame and serialize it to JSON.
+    let serialized_msg = Frame::encode(message)?;
+    // Write the serialized message to the server as a single frame.
+    transport.write_frame(&serialized_msg)
+}
+
+/// Acknowledges a history replay window so the server sends the next one.
+fn send_ack(transport: &mut dyn Transport) -> std::io::Result<()> {
+    let ack = ChatMessage {
+        message_type: ChatMessageType::Ack,
+        username: None,
+        content: String::new(),
+        ..Default::default()
+    };
+    send_message(transport, &ack)
+}
+
+/// Answers a server ping, echoing its round id.
+fn send_pong(transport: &mut dyn Transport, seq: Option<u64>) -> std::io::Result<()> {
+    let pong = ChatMessage {
+        message_type: ChatMessageType::Pong,
+        seq,
+        ..Default::default()
+    };
+    send_message(transport, &pong)
 }
 
-/// Displays a `ChatMessage` based on its type.
-fn display_message(chat_msg: ChatMessage) {
+/// Returns the time prefix of `chat_msg`, or an empty string if it has no timestamp
+/// or `session` has timestamps turned off.
+fn time_prefix(chat_msg: &ChatMessage, session: &Session) -> String {
+    // Messages stored in history carry the time they were sent.
+    match chat_msg.timestamp {
+        Some(timestamp) if session.timestamps.load(Ordering::Relaxed) => {
+            format_time_prefix(timestamp)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Formats a chat message from `username` as the line shown for it, after the `time` prefix.
+fn format_chat_line(chat_msg: &ChatMessage, username: &str, time: &str) -> String {
+    // Replies point at the message they answer.
+    let reply_to = chat_msg
+        .reply_to
+        .map(|parent| format!(" (re #{})", parent))
+        .unwrap_or_default();
+    // Show the seq so users can refer to the message, e.g. with `/react`.
+    match chat_msg.seq {
+        Some(seq) => format!(
+            "{}#{} [{}]{}: {}",
+            time, seq, username, reply_to, chat_msg.content
+        ),
+        None => format!("{}[{}]{}: {}", time, username, reply_to, chat_msg.content), // Display regular messages with the sender's username.
+    }
+}
+
+/// Displays a `ChatMessage` based on its type, styled and timestamped as `session` says.
+fn display_message(
+    mut chat_msg: ChatMessage, // The message to display.
+    session: &Session,         // Display settings: markdown, theme and timestamps.
+) {
+    let theme = &session.theme;
+    // Only text users wrote is styled; server notices are printed as sent.
+    if session.markdown && !chat_msg.system {
+        chat_msg.content = render_markdown(&chat_msg.content);
+    }
+
+    // Only messages the server flags as its own get the system style;
+    // anything else is shown with its sender so it can't pose as a server notice.
+    if !chat_msg.system && !matches!(chat_msg.message_type, ChatMessageType::Message) {
+        let sender = chat_msg.username.as_deref().unwrap_or("unknown");
+        if matches!(
+            chat_msg.message_type,
+            ChatMessageType::Command(CommandType::Msg)
+        ) {
+            let line = format!("[{} → you]: {}", sender, chat_msg.content);
+            show!("{}", theme.private.paint(&line)); // Display private messages.
+        } else {
+            let line = format!("[{}]: {}", sender, chat_msg.content);
+            show!("{}", theme.message.paint(&line));
+        }
+        return;
+    }
+
+    let time = time_prefix(&chat_msg, session);
+
     // Match the message type to determine how to display it.
     match chat_msg.message_type {
         ChatMessageType::Message => {
-            if let Some(username) = chat_msg.username {
-                println!("\r[{}]: {}", username, chat_msg.content); // Display regular messages with the sender's username.
+            if let Some(username) = &chat_msg.username {
+                // Quotes are shown above the message, one `> ` line per quoted line.
+                if let Some(quote) = &chat_msg.quote {
+                    let quoted = format!("[{}]: {}", quote.username, quote.content);
+                    for line in quoted.lines() {
+                        show!("{}", theme.message.paint(&format!("> {}", line)));
+                    }
+                }
+                let line = format_chat_line(&chat_msg, username, &time);
+                show!("{}", theme.message.paint(&line));
             }
         }
-        ChatMessageType::Join | ChatMessageType::Leave => {
-            println!("\r{}", chat_msg.content); // Display join/leave system messages.
+        ChatMessageType::Join => {
+            show!("{}", theme.join.paint(&(time + &chat_msg.content))); // Display join system messages.
+        }
+        ChatMessageType::Leave => {
+            show!("{}", theme.leave.paint(&(time + &chat_msg.content))); // Display leave system messages.
         }
         ChatMessageType::Command(CommandType::List) => {
-            println!("\r{}", chat_msg.content); // Display the list of users.
+            show!("{}", theme.system.paint(&chat_msg.content)); // Display the list of users.
+        }
+        ChatMessageType::Command(CommandType::Unread) => {
+            show!("{}", theme.system.paint(&chat_msg.content)); // Display the missed-message count.
+        }
+        ChatMessageType::Command(
+            CommandType::Admin
+            | CommandType::SetMaxLen
+            | CommandType::PingAll
+            | CommandType::DumpState
+            | CommandType::Nick
+            | CommandType::Last
+            | CommandType::React
+            | CommandType::Slap
+            | CommandType::Mute
+            | CommandType::Unmute
+            | CommandType::GrantAdmin
+            | CommandType::RevokeAdmin
+            | CommandType::Msg
+            | CommandType::Version
+            | CommandType::Encrypt
+            | CommandType::PublicKey
+            | CommandType::Encrypted
+            | CommandType::Find
+            | CommandType::Reply
+            | CommandType::Quote
+            | CommandType::Status
+            | CommandType::Pin
+            | CommandType::Unpin
+            | CommandType::Pins
+            | CommandType::Notify
+            | CommandType::Uptime
+            | CommandType::BroadcastFile
+            | CommandType::SharedFile
+            | CommandType::AuditLog
+            | CommandType::Schedule
+            | CommandType::Unschedule
+            | CommandType::HistoryMode
+            | CommandType::DownloadHistory
+            | CommandType::Recent
+            | CommandType::Reload,
+        ) => {
+            show!("{}", theme.system.paint(&chat_msg.content)); // Display command replies and confirmations.
+        }
+        ChatMessageType::Error => {
+            let line = format!("[Error]: {}", chat_msg.content);
+            show!("{}", theme.error.paint(&line)); // Display rejected requests.
+        }
+        ChatMessageType::Reaction => {
+            if let Some(seq) = chat_msg.seq {
+                let line = format!("  ↳ #{}: {}", seq, chat_msg.content);
+                show!("{}", theme.reaction.paint(&line)); // Display the reactions to a message.
+            }
         }
         ChatMessageType::Command(CommandType::Quit) => {
             if let Some(username) = chat_msg.username {
-                println!("\r{} has left the chat.", username); // Display quit messages.
+                let line = format!("{} has left the chat.", username);
+                show!("{}", theme.leave.paint(&line)); // Display quit messages.
             }
         }
+        ChatMessageType::Presence => {
+            // Show the live roster the server sends when users come and go.
+            if let Ok(users) = serde_json::from_str::<Vec<String>>(&chat_msg.content) {
+                let line = format!("[{} online: {}]", users.len(), users.join(", "));
+                show!("{}", theme.system.paint(&line));
+            }
+        }
+        ChatMessageType::AckRequest
+        | ChatMessageType::Ack
+        | ChatMessageType::Ping
+        | ChatMessageType::Pong
+        | ChatMessageType::Capabilities
+        | ChatMessageType::Compressed => {} // Flow control, nothing to display.
+        ChatMessageType::Rename => {} // Applied by `apply_rename`; the notice is shown instead.
+        ChatMessageType::Expired => {} // Handled by `apply_expiry`.
+        ChatMessageType::SessionEnd => {} // Handled by `end_session`.
     }
 }
 
-/// Enum to represent user commands.
-enum Command {
-    List,
-    Quit,
-}
+/// Renders `*bold*`, `_italic_` and `` `code` `` as ANSI styles. Styles may nest, except
+/// inside code. A marker only counts when it opens a word and a matching marker closes one
+/// later, so unmatched markers and names like `snake_case` are printed literally.
+fn render_markdown(text: &str) -> String {
+    const RESET: &str = "\x1B[0m";
+    let style = |marker: char| match marker {
+        '*' => "\x1B[1m", // Bold.
+        '_' => "\x1B[3m", // Italic.
+        _ => "\x1B[36m",  // Code, in cyan.
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let is_word = |i: usize| chars.get(i).is_some_and(|c| c.is_alphanumeric());
+    // Whether the marker at `i` could close a span: it follows text and ends a word.
+    let closes = |i: usize| i > 0 && !chars[i - 1].is_whitespace() && !is_word(i + 1);
 
-impl Command {
-    /// Parse a string input into a `Command`.
-    fn from_input(input: &str) -> Option<Self> {
-        match input.trim() {
-            "/list" => Some(Self::List),
-            "/quit" => Some(Self::Quit),
-            _ => None, // Return `None` for unrecognized commands.
+    let mut output = String::with_capacity(text.len());
+    let mut open: Vec<char> = Vec::new(); // Markers of the spans currently styled.
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if !matches!(c, '*' | '_' | '`') {
+            output.push(c);
+            i += 1;
+            continue;
+        }
+        if open.last() == Some(&c) && closes(i) {
+            // Close the innermost span and restore the styles of the outer ones.
+            open.pop();
+            output.push_str(RESET);
+            output.extend(open.iter().map(|&marker| style(marker)));
+            i += 1;
+            continue;
+        }
+        let opens = (i == 0 || !is_word(i - 1))
+            && chars.get(i + 1).is_some_and(|next| !next.is_whitespace())
+            && !open.contains(&c);
+        let close_at = (i + 2..chars.len()).find(|&j| chars[j] == c && closes(j));
+        match close_at {
+            Some(end) if opens && c == '`' => {
+                // Code spans are shown as written, markers included.
+                output.push_str(style(c));
+                output.extend(&chars[i + 1..end]);
+                output.push_str(RESET);
+                output.extend(open.iter().map(|&marker| style(marker)));
+                i = end + 1;
+            }
+            Some(_) if opens => {
+                open.push(c);
+                output.push_str(style(c));
+                i += 1;
+            }
+            _ => {
+                output.push(c);
+                i += 1;
+            }
         }
     }
-}
-
-/// Parses a command from user input.
-fn parse_command(input: &str) -> Option<Command> {
-    Command::from_input(input)
+    if !open.is_empty() {
+        output.push_str(RESET); // A span crossed another and was never closed.
+    }
+    output
 }
 
 /// Parses user input into a structured `ChatMessage`.
-fn parse_user_input(input: &str, username: &str) -> ChatMessage {
+/// Input starting with `/` must be a valid command. A configured alias without the slash
+/// is a command only when its arguments fit; anything else is a regular message.
+fn parse_user_input(
+    input: &str,              // The line the user typed.
+    username: &str,           // The user's username.
+    aliases: &CommandAliases, // Configured command aliases.
+) -> Result<ChatMessage, ParseError> {
     // Check if the input is empty or contains only whitespace.
     if input.trim().is_empty() {
-        return ChatMessage {
+        return Ok(ChatMessage {
             message_type: ChatMessageType::Message, // Treat it as a regular message.
             username: Some(username.to_string()),   // Include the sender's username.
             content: "Empty input provided.".to_string(), // Set a default message.
-        };
+            ..Default::default()
+        });
     }
 
     // Attempt to parse the input as a command.
-    if let Some(command) = parse_command(input) {
+    match Command::parse_with_aliases(input, aliases) {
         // Convert the `Command` into a `ChatMessage`.
-        match command {
-            Command::List => ChatMessage {
-                message_type: ChatMessageType::Command(CommandType::List),
-                username: None, // No username required for `/list`.
-                content: String::new(),
-            },
-            Command::Quit => ChatMessage {
-                message_type: ChatMessageType::Command(CommandType::Quit),
-                username: Some(username.to_string()), // Include the username for `/quit`.
-                content: String::new(),
-            },
-        }
-    } else {
+        Ok(command) => Ok(command.into_message(username)),
         // Fallback to a regular message if the input is not a command.
-        ChatMessage {
+        Err(ParseError::NotACommand) => Ok(ChatMessage {
             message_type: ChatMessageType::Message,
             username: Some(username.to_string()), // Include the sender's username.
             content: input.to_string(),           // Use the input as the message content.
+            ..Default::default()
+        }),
+        Err(e) => Err(e), // Unknown command or invalid arguments.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_tcp_chat::config::ServerConfig;
+    use rust_tcp_chat::runtime::{
+        bind_server, finish_shutdown, run_server, shutdown, start_services,
+    };
+    use rust_tcp_chat::transport::MemoryTransport;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+
+    /// A session with the defaults `main` uses, without a connection.
+    fn test_session() -> Session {
+        Session {
+            server_addr: "127.0.0.1:1".to_string(), // Nothing listens here, should it reconnect.
+            username: "alice".to_string(),
+            connection: Mutex::default(),
+            quit_flag: AtomicBool::new(false),
+            reconnect_requested: AtomicBool::new(false),
+            ended_by_server: AtomicBool::new(false),
+            e2e: Mutex::default(),
+            paused: Mutex::default(),
+            last_seen_seq: Mutex::default(),
+            markdown: false,
+            timestamps: AtomicBool::new(false),
+            theme: Theme::default(),
+            download_dir: env::temp_dir(),
+            compression: false,
+            started_at: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            read_buffer_bytes: DEFAULT_READ_BUFFER_LEN,
+            msgpack: false,
+            message_ttl: None,
+        }
+    }
+
+    #[test]
+    fn prompt_template_substitutes_known_placeholders() {
+        let vars = [("user", "alice"), ("time", "09:30")];
+        assert_eq!(
+            expand_template("[{time}] {user} $ ", &vars),
+            "[09:30] alice $ "
+        );
+        assert_eq!(expand_template("{user}{user}", &vars), "alicealice");
+        // Unknown placeholders and stray braces are kept as typed.
+        assert_eq!(expand_template("{room} {user}", &vars), "{room} alice");
+        assert_eq!(expand_template("{ {user} }", &vars), "{ alice }");
+        assert_eq!(expand_template("{user", &vars), "{user");
+        assert_eq!(expand_template("[You]: ", &vars), "[You]: ");
+    }
+
+    #[test]
+    fn banner_file_is_rendered_with_the_server_address() {
+        let path = env::temp_dir().join(format!("chat-banner-{}.txt", process::id()));
+        fs::write(&path, " _  _ _\n| || | |\n|_||_|_|  welcome to {server}").unwrap();
+        let banner = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            render_banner(&banner, "127.0.0.1:8081"),
+            " _  _ _\n| || | |\n|_||_|_|  welcome to 127.0.0.1:8081\n"
+        );
+        // A banner that already ends with a newline doesn't get a second one.
+        assert_eq!(render_banner("hi\n", "127.0.0.1:8081"), "hi\n");
+        assert!(render_banner(DEFAULT_BANNER, "127.0.0.1:8081").contains("127.0.0.1:8081"));
+    }
+
+    #[test]
+    fn theme_file_styles_the_message_types_it_names() {
+        let path = env::temp_dir().join(format!("chat-theme-{}.json", process::id()));
+        fs::write(
+            &path,
+            r#"{"join": "green", "error": "bold red", "prompt": "38;5;208"}"#,
+        )
+        .unwrap();
+        let theme = Theme::load(path.to_str().unwrap());
+        fs::write(&path, r#"{"join": "sparkly"}"#).unwrap();
+        let invalid = Theme::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let theme = theme.unwrap();
+        assert_eq!(
+            theme.join.paint("bob has joined the chat"),
+            "\x1B[32mbob has joined the chat\x1B[0m"
+        );
+        assert_eq!(theme.error.paint("No."), "\x1B[1;31mNo.\x1B[0m");
+        assert_eq!(theme.prompt.paint("$ "), "\x1B[38;5;208m$ \x1B[0m");
+        // Types the file doesn't name stay unstyled.
+        assert_eq!(theme.message.paint("[bob]: hi"), "[bob]: hi");
+        // A style reset partway, e.g. by markdown, is restored.
+        assert_eq!(
+            theme.join.paint("\x1B[1mbob\x1B[0m joined"),
+            "\x1B[32m\x1B[1mbob\x1B[0m\x1B[32m joined\x1B[0m"
+        );
+        assert!(invalid.unwrap_err().contains("unknown style 'sparkly'"));
+        assert!(Theme::load("/nonexistent/theme.json").is_err());
+        assert_ne!(Theme::load("dark").unwrap(), Theme::load("light").unwrap());
+    }
+
+    #[test]
+    fn timestamps_off_leaves_the_time_out_of_the_line() {
+        let session = test_session();
+        let message = ChatMessage {
+            username: Some("bob".to_string()),
+            content: "hi".to_string(),
+            seq: Some(3),
+            timestamp: Some(9 * 3600 + 30 * 60),
+            ..Default::default()
+        };
+        let line =
+            |session: &Session| format_chat_line(&message, "bob", &time_prefix(&message, session));
+
+        assert_eq!(line(&session), "#3 [bob]: hi");
+        session.timestamps.store(true, Ordering::Relaxed);
+        assert_eq!(line(&session), "[09:30] #3 [bob]: hi");
+        let untimed = ChatMessage {
+            timestamp: None,
+            ..message.clone()
+        };
+        assert_eq!(time_prefix(&untimed, &session), "");
+    }
+
+    #[test]
+    fn piped_output_has_no_prompt_or_escapes() {
+        let prompt = Prompt {
+            template: "{user} $ ".to_string(),
+            username: "alice".to_string(),
+            enabled: true,
+            style: Theme::load("dark").unwrap().prompt,
+        };
+        assert_eq!(prompt_line(&prompt, false), None);
+        let line = prompt_line(&prompt, true).unwrap();
+        assert!(line.starts_with("\r\x1B[2K"));
+        assert!(line.contains("alice $ "));
+
+        // Piped output gets the default theme and no markdown, so lines are printed as is.
+        let theme = Theme::default();
+        for style in [&theme.message, &theme.system, &theme.error, &theme.prompt] {
+            assert_eq!(style.paint("[bob]: *hi*"), "[bob]: *hi*");
+        }
+    }
+
+    #[test]
+    fn session_info_shows_the_connection_and_counters() {
+        assert_eq!(
+            format_session_info(
+                "127.0.0.1:8081",
+                "alice",
+                true,
+                Duration::from_secs(2 * 3600 + 5 * 60 + 9),
+                12,
+                40
+            ),
+            "Server: 127.0.0.1:8081 (connected)\nUsername: alice\nSession: 2h 05m 09s\n\
+             Messages: 12 sent, 40 received"
+        );
+        assert!(
+            format_session_info("chat:9000", "bob", false, Duration::ZERO, 0, 0)
+                .starts_with("Server: chat:9000 (reconnecting)\n")
+        );
+    }
+
+    #[test]
+    fn markdown_is_rendered_as_ansi_styles() {
+        const BOLD: &str = "\x1B[1m";
+        const ITALIC: &str = "\x1B[3m";
+        const CODE: &str = "\x1B[36m";
+        const RESET: &str = "\x1B[0m";
+
+        assert_eq!(
+            render_markdown("*hi* _there_ `ls`"),
+            format!("{BOLD}hi{RESET} {ITALIC}there{RESET} {CODE}ls{RESET}")
+        );
+        // Closing a nested span restores the outer style.
+        assert_eq!(
+            render_markdown("*very _much_ so*"),
+            format!("{BOLD}very {ITALIC}much{RESET}{BOLD} so{RESET}")
+        );
+        // Markers inside code are shown as written.
+        assert_eq!(render_markdown("`*args*`"), format!("{CODE}*args*{RESET}"));
+        // Unmatched markers, and markers inside words, are printed literally.
+        for text in [
+            "2 * 3 = 6",
+            "*oops",
+            "oops_",
+            "snake_case_name",
+            "a * b *",
+            "``",
+        ] {
+            assert_eq!(render_markdown(text), text);
         }
+        // A span crossing another is closed at the end of the message.
+        assert_eq!(
+            render_markdown("*a _b* c_"),
+            format!("{BOLD}a {ITALIC}b* c{RESET}{BOLD}{RESET}")
+        );
+    }
+
+    #[test]
+    fn sent_and_received_messages_are_appended_to_the_transcript() {
+        let path = env::temp_dir().join(format!("chat-transcript-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let transcript = Transcript::open(&path).unwrap();
+        let (mut server, mut client) = MemoryTransport::pair(
+            "10.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:8081".parse().unwrap(),
+        );
+
+        send_join_message(&mut client, "alice", None, &transcript).unwrap();
+        let frames = [
+            ChatMessage {
+                username: Some("bob".to_string()),
+                content: "hi alice".to_string(),
+                seq: Some(1),
+                ..Default::default()
+            },
+            ChatMessage {
+                message_type: ChatMessageType::SessionEnd,
+                system: true,
+                ..Default::default()
+            },
+        ];
+        for frame in &frames {
+            server.write_frame(&Frame::encode(frame).unwrap()).unwrap();
+        }
+        let prompt = Prompt {
+            template: String::new(),
+            username: "alice".to_string(),
+            enabled: false,
+            style: Style::default(),
+        };
+        let session = test_session();
+        let capabilities = ServerCapabilities::default();
+        run_reader(
+            Box::new(client),
+            &session,
+            &prompt,
+            &transcript,
+            &capabilities,
+        );
+        transcript.flush();
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+        let recorded: Vec<(&str, &str)> = lines
+            .iter()
+            .map(|line| {
+                assert!(line["local_time_ms"].as_u64().unwrap() > 0);
+                (
+                    line["direction"].as_str().unwrap(),
+                    line["message"]["content"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            recorded[..2],
+            [
+                ("sent", "alice has joined the chat"),
+                ("received", "hi alice")
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_typed_while_disconnected_are_sent_in_order_after_reconnecting() {
+        let say = |content: &str| ChatMessage {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let transcript = Transcript::disabled();
+        let mut connection = Connection::default(); // Disconnected.
+        connection.send(say("first"), &transcript);
+        connection.send(say("second"), &transcript);
+        for _ in 2..PENDING_QUEUE_CAP + 1 {
+            connection.send(say("filler"), &transcript); // The last one overflows.
+        }
+        assert_eq!(connection.pending.len(), PENDING_QUEUE_CAP);
+
+        let (server, mut client) = MemoryTransport::pair(
+            "10.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:8081".parse().unwrap(),
+        );
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        connection.resume(Box::new(server), &transcript);
+        connection.send(say("after"), &transcript);
+        let received: Vec<String> = (0..=PENDING_QUEUE_CAP)
+            .map(|_| {
+                let frame = client.read_frame().unwrap().unwrap();
+                Frame::decode(&frame).unwrap().message.content
+            })
+            .collect();
+        assert!(connection.pending.is_empty());
+        assert_eq!(received[..2], ["first", "second"]);
+        assert_eq!(received[PENDING_QUEUE_CAP], "after");
+    }
+
+    #[test]
+    fn script_lines_are_sent_in_order_until_quit() {
+        let (server, mut client) = MemoryTransport::pair(
+            "10.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:8081".parse().unwrap(),
+        );
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let session = test_session();
+        session.connection.lock().unwrap().writer = Some(Box::new(server));
+        let prompt = Prompt {
+            template: DEFAULT_PROMPT.to_string(),
+            username: "alice".to_string(),
+            enabled: false, // As in scripted mode.
+            style: Style::default(),
+        };
+        // Blank lines, local commands and invalid commands aren't sent; nothing after `/quit` is.
+        let script = "hello\n\n/list\n/info\n/bogus\nbye for now\n/quit\nnever sent\n";
+
+        handle_user_input(
+            &session,
+            &prompt,
+            &Transcript::disabled(),
+            &ServerCapabilities::default(),
+            &CommandAliases::default(),
+            Box::new(io::Cursor::new(script)),
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert!(session.quit_flag.load(Ordering::SeqCst));
+        drop(session);
+        let mut sent = Vec::new();
+        while let Some(frame) = client.read_frame().unwrap() {
+            let message = Frame::decode(&frame).unwrap().message;
+            sent.push((message.message_type, message.content));
+        }
+        assert_eq!(sent.len(), 4);
+        assert!(matches!(&sent[0], (ChatMessageType::Message, content) if content == "hello"));
+        assert!(matches!(
+            sent[1].0,
+            ChatMessageType::Command(CommandType::List)
+        ));
+        assert!(
+            matches!(&sent[2], (ChatMessageType::Message, content) if content == "bye for now")
+        );
+        assert!(matches!(
+            sent[3].0,
+            ChatMessageType::Command(CommandType::Quit)
+        ));
+    }
+
+    #[test]
+    fn encrypted_messages_reach_the_peer_while_the_server_sees_ciphertext() {
+        let config =
+            ServerConfig::try_parse_from(["chat-server", "--addr", "127.0.0.1:0"]).unwrap();
+        let (listener, addr) = bind_server(&config.addr).unwrap();
+        let state = start_services(config).unwrap();
+        let accept_loop = {
+            let state = Arc::clone(&state);
+            thread::spawn(move || run_server(listener, state).unwrap())
+        };
+        let join = |username: &str| {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut transport = TcpTransport::new(stream);
+            send_join_message(&mut transport, username, None, &Transcript::disabled()).unwrap();
+            transport
+        };
+        // Reads up to the next key offer or ciphertext relayed from the other client.
+        let next_e2e = |transport: &mut TcpTransport| loop {
+            let frame = transport.read_frame().unwrap().unwrap();
+            let chat_msg = Frame::decode(&frame).unwrap().message;
+            if is_e2e_message(&chat_msg) {
+                return chat_msg;
+            }
+        };
+        let alice_session = test_session();
+        let bob_session = Session {
+            username: "bob".to_string(),
+            ..test_session()
+        };
+        let mut alice = join("alice");
+        let mut bob = join("bob");
+
+        let offer = Command::parse("/encrypt bob")
+            .unwrap()
+            .into_message("alice");
+        let offer = encrypt_outgoing(offer, "alice", &alice_session.e2e).unwrap();
+        send_message(&mut alice, &offer).unwrap();
+        let offer = next_e2e(&mut bob);
+        handle_e2e_message(&mut bob, &bob_session, &offer); // Answers with bob's key.
+        let answer = next_e2e(&mut alice);
+        handle_e2e_message(&mut alice, &alice_session, &answer);
+
+        let secret = "meet at noon";
+        let msg = Command::parse(&format!("/msg bob {}", secret))
+            .unwrap()
+            .into_message("alice");
+        let encrypted = encrypt_outgoing(msg, "alice", &alice_session.e2e).unwrap();
+        send_message(&mut alice, &encrypted).unwrap();
+        let relayed = next_e2e(&mut bob);
+        assert!(matches!(
+            relayed.message_type,
+            ChatMessageType::Command(CommandType::Encrypted)
+        ));
+        assert!(!relayed.content.contains(secret));
+        let decrypted = bob_session
+            .e2e
+            .lock()
+            .unwrap()
+            .decrypt("alice", &relayed.content);
+        assert_eq!(decrypted.unwrap(), secret);
+        let history = state.chat_history.read().unwrap();
+        assert!(!history.iter().any(|msg| msg.content.contains(secret)));
+        drop(history);
+
+        shutdown(&state, addr);
+        finish_shutdown(&state, accept_loop.join().unwrap());
+    }
+
+    #[test]
+    fn messages_received_while_paused_are_held_in_order_until_resume() {
+        let say = |n: usize| ChatMessage {
+            content: format!("message {}", n),
+            ..Default::default()
+        };
+        let session = test_session();
+        assert!(session.paused.lock().unwrap().hold(say(0)).is_some()); // Not paused yet.
+
+        run_local_command(LocalCommand::Pause, &session);
+        for n in 1..=PAUSE_BUFFER_CAP + 2 {
+            assert!(session.paused.lock().unwrap().hold(say(n)).is_none());
+        }
+        {
+            let paused = session.paused.lock().unwrap();
+            assert_eq!(paused.dropped, 2); // The oldest two made room.
+            let held: Vec<String> = paused.held.iter().map(|m| m.content.clone()).collect();
+            let expected: Vec<String> = (3..=PAUSE_BUFFER_CAP + 2)
+                .map(|n| format!("message {}", n))
+                .collect();
+            assert_eq!(held, expected);
+        }
+
+        run_local_command(LocalCommand::Resume, &session);
+        let paused = session.paused.lock().unwrap();
+        assert!(!paused.paused && paused.held.is_empty() && paused.dropped == 0);
+    }
+
+    /// A connection whose reads panic, standing in for a bug in message handling.
+    struct PanickingTransport;
+
+    impl Transport for PanickingTransport {
+        fn read_frame(&mut self) -> io::Result<Option<String>> {
+            panic!("simulated reader bug");
+        }
+
+        fn write_frame(&mut self, _frame: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok("127.0.0.1:8081".parse().unwrap())
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(PanickingTransport))
+        }
+    }
+
+    #[test]
+    fn reader_reconnects_after_a_panic_instead_of_hanging() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let session = Arc::new(Session {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            ..test_session()
+        });
+        let (done, finished) = mpsc::channel();
+        {
+            let session = Arc::clone(&session);
+            thread::spawn(move || {
+                let prompt = Prompt {
+                    template: String::new(),
+                    username: "alice".to_string(),
+                    enabled: false,
+                    style: Style::default(),
+                };
+                run_reader(
+                    Box::new(PanickingTransport),
+                    &session,
+                    &prompt,
+                    &Transcript::disabled(),
+                    &ServerCapabilities::default(),
+                );
+                let _ = done.send(());
+            });
+        }
+
+        // The reader recovers by rejoining on a fresh connection...
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut server = TcpTransport::new(stream);
+        let join = Frame::decode(&server.read_frame().unwrap().unwrap()).unwrap();
+        assert!(matches!(join.message.message_type, ChatMessageType::Join));
+        assert_eq!(join.message.username.as_deref(), Some("alice"));
+        // ...and keeps reading from it.
+        let session_end = ChatMessage {
+            message_type: ChatMessageType::SessionEnd,
+            system: true,
+            ..Default::default()
+        };
+        server
+            .write_frame(&Frame::encode(&session_end).unwrap())
+            .unwrap();
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the reader didn't recover from the panic");
+        assert!(session.ended_by_server.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reconnect_command_sends_a_fresh_join_on_a_new_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let session = Arc::new(Session {
+            server_addr: listener.local_addr().unwrap().to_string(),
+            ..test_session()
+        });
+        let transcript = Transcript::disabled();
+        let stream = TcpStream::connect(&session.server_addr).unwrap();
+        let client = open_transport(stream, DEFAULT_READ_BUFFER_LEN, false);
+        let reader = client.try_clone().unwrap();
+        session
+            .connection
+            .lock()
+            .unwrap()
+            .resume(client, &transcript);
+        let (old_stream, _) = listener.accept().unwrap();
+        old_stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (done, finished) = mpsc::channel();
+        {
+            let session = Arc::clone(&session);
+            thread::spawn(move || {
+                let prompt = Prompt {
+                    template: String::new(),
+                    username: "alice".to_string(),
+                    enabled: false,
+                    style: Style::default(),
+                };
+                run_reader(
+                    reader,
+                    &session,
+                    &prompt,
+                    &Transcript::disabled(),
+                    &ServerCapabilities::default(),
+                );
+                let _ = done.send(());
+            });
+        }
+
+        run_local_command(LocalCommand::Reconnect, &session);
+        // The old connection is closed...
+        let mut old = TcpTransport::new(old_stream);
+        assert_eq!(old.read_frame().unwrap(), None);
+        // ...and the client joins again on a new one.
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut server = TcpTransport::new(stream);
+        let join = Frame::decode(&server.read_frame().unwrap().unwrap()).unwrap();
+        assert!(matches!(join.message.message_type, ChatMessageType::Join));
+        assert_eq!(join.message.username.as_deref(), Some("alice"));
+        assert!(!session.reconnect_requested.load(Ordering::SeqCst));
+
+        let session_end = ChatMessage {
+            message_type: ChatMessageType::SessionEnd,
+            system: true,
+            ..Default::default()
+        };
+        server
+            .write_frame(&Frame::encode(&session_end).unwrap())
+            .unwrap();
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the reader didn't move to the new connection");
+    }
+
+    #[test]
+    fn reader_stops_for_exit_when_the_server_ends_the_session() {
+        let (mut server, client) = MemoryTransport::pair(
+            "10.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:8081".parse().unwrap(),
+        );
+        let frames = [
+            // Another user quitting must not end this session.
+            ChatMessage {
+                message_type: ChatMessageType::Command(CommandType::Quit),
+                username: Some("bob".to_string()),
+                content: "bob has left".to_string(),
+                system: true,
+                ..Default::default()
+            },
+            ChatMessage {
+                message_type: ChatMessageType::SessionEnd,
+                content: "Too many malformed messages; disconnecting.".to_string(),
+                system: true,
+                ..Default::default()
+            },
+        ];
+        for frame in &frames {
+            server.write_frame(&Frame::encode(frame).unwrap()).unwrap();
+        }
+
+        let session = Arc::new(test_session());
+        let (done, finished) = mpsc::channel();
+        {
+            let session = Arc::clone(&session);
+            thread::spawn(move || {
+                let prompt = Prompt {
+                    template: String::new(),
+                    username: "alice".to_string(),
+                    enabled: false,
+                    style: Style::default(),
+                };
+                let capabilities = ServerCapabilities::default();
+                run_reader(
+                    Box::new(client),
+                    &session,
+                    &prompt,
+                    &Transcript::disabled(),
+                    &capabilities,
+                );
+                let _ = done.send(());
+            });
+        }
+
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the reader kept going after the session ended");
+        assert!(session.ended_by_server.load(Ordering::SeqCst)); // `main` exits on this.
+        assert!(session.quit_flag.load(Ordering::SeqCst));
+        assert_eq!(session.received.load(Ordering::SeqCst), 1); // Bob's quit was shown.
     }
 }
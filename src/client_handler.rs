@@ -1,108 +1,497 @@
 // client_handler.rs
+use crate::commands::{pinned_summary, CommandContext}; // Context passed to registered command handlers, and the pins sent on join.
+use crate::compression::{compress_frame, COMPRESSION_FEATURE}; // Opt-in frame compression.
+use crate::config::{HistoryOrder, MAX_MESSAGE_LEN_BOUNDS}; // History order and runtime limits.
 use crate::errors::{ChatResult, ChatServerError}; // Custom result and error types for handling errors.
-use crate::message::{ChatMessage, ChatMessageType, CommandType}; // Chat message structure and related enums.
-use std::collections::HashMap; // Used for storing client connections and usernames.
-use std::io::{Read, Write}; // For reading and writing to TCP streams.
-use std::net::{SocketAddr, TcpStream}; // Networking primitives for managing client connections.
-use std::sync::{Arc, RwLock}; // Thread-safe shared state using reference counting and read-write locks.
-
-/// Handles communication with a single client.
-/// Handles communication with a single client.
-pub fn handle_client(
-    mut stream: TcpStream, // TCP stream for communication with the client.
-    clients: Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared map of connected clients.
-    usernames: Arc<RwLock<HashMap<SocketAddr, String>>>, // Shared map of client usernames.
-    chat_history: Arc<RwLock<Vec<ChatMessage>>>, // Shared chat history.
+use crate::events::SystemEvent; // Events published for observers.
+use crate::message::{
+    is_reserved_username, ChatMessage, ChatMessageType, Command, CommandType, ContentEncoding,
+    Frame, HistoryMode, Priority, Quote, Rename, MAX_USERNAME_LEN,
+}; // Chat message structure and related enums.
+use crate::outbox::{spawn_writer, Outbox, QueuedTransport}; // Per-client outbound queue, its writer thread, and the handler's view of the connection.
+use crate::state::{
+    Broadcast, ClientConnection, PendingLeave, PingRound, Reactions, ScheduledMessage, ServerState,
+}; // Shared server state (clients, usernames, history, config).
+use crate::transport::Transport; // Frame-based connection to the client.
+use std::collections::HashMap; // Round-trip times collected by `/ping-all`.
+use std::io::ErrorKind; // Distinguishes read timeouts from disconnects.
+use std::net::SocketAddr; // Address used to identify each client.
+use std::ops::ControlFlow; // Whether to keep reading from a client after a message.
+use std::sync::atomic::{AtomicBool, Ordering}; // Shared counters and per-client flags.
+use std::sync::{Arc, Mutex, PoisonError}; // Shared server state, per-client heartbeat times and lock recovery.
+use std::thread; // For polling while waiting on ping replies.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH}; // For timestamps and ping timing.
+
+/// Start of the names given to guests, followed by a number.
+const GUEST_NAME_PREFIX: &str = "guest-";
+
+/// Handles communication with a single client over any `Transport`.
+/// Everything the handler writes goes through the client's outbox, see `QueuedTransport`.
+pub fn handle_client<T: Transport + 'static>(
+    transport: T,            // Connection to the client.
+    state: Arc<ServerState>, // Shared server state.
 ) -> ChatResult<()> {
-    let peer_addr = stream.peer_addr()?; // Get the client's address for identification.
+    let peer_addr = transport.peer_addr()?; // Get the client's address for identification.
     println!("Handling client: {:?}", peer_addr);
 
     // Add the client to the shared clients map.
-    register_client(&stream, &clients, &usernames, peer_addr)?;
+    let outbox = Arc::new(Outbox::default());
+    let connection_id = register_client(&transport, &state, peer_addr, Arc::clone(&outbox))?;
+    let mut transport = QueuedTransport::new(Box::new(transport), outbox);
 
-    // Retrieve and validate the username from the client.
-    let username = get_client_username(&mut stream, peer_addr)?;
+    // Retrieve and validate the username (and last seen seq, if reconnecting) from the client,
+    // then claim it, or a guest name if none was given.
+    // A client that fails to register is dropped before it ever appears to other users.
+    let (mut username, last_seen_seq, is_guest) =
+        match get_client_username(&mut transport, &state, peer_addr).and_then(
+            |(requested, last_seen_seq)| {
+                let username =
+                    claim_username(&mut transport, &state, peer_addr, requested.as_deref())?;
+                Ok((username, last_seen_seq, requested.is_none()))
+            },
+        ) {
+            Ok(registration) => registration,
+            Err(e) => {
+                state.emit(SystemEvent::Error {
+                    addr: peer_addr,
+                    error: e.to_string(),
+                });
+                cleanup_client(&state, peer_addr, connection_id);
+                return Err(e);
+            }
+        };
     println!("Client registered as '{}'", username);
 
-    // Send the chat history to the client after they connect.
-    send_chat_history(&mut stream, &chat_history)?;
+    let result = serve_client(
+        &mut transport,
+        &state,
+        peer_addr,
+        connection_id,
+        &mut username,
+        last_seen_seq,
+        is_guest,
+    );
+
+    // Clean up the client however the session ended, so a failed write during the welcome
+    // or a read error doesn't leave a registered client with no handler behind.
+    cleanup_client(&state, peer_addr, connection_id);
+    result
+}
+
+/// Serves a registered client: sends the welcome (guest name, capabilities, pins, history
+/// and offline messages), announces the join, then handles messages until the client leaves.
+/// Returns at the first error; `handle_client` cleans up either way.
+fn serve_client(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The client's address.
+    connection_id: u64,            // Id of the client's connection.
+    username: &mut String,         // The client's username; `/nick` may change it.
+    last_seen_seq: Option<u64>,    // Last seq the client saw before reconnecting.
+    is_guest: bool,                // Whether the server picked the username.
+) -> ChatResult<()> {
+    // A guest learns its name before anything else arrives.
+    if is_guest {
+        send_guest_name(transport, username)?;
+    }
+
+    // Tell the client what this server supports before anything else arrives.
+    send_capabilities(transport, state)?;
+    state.presence_changed.store(true, Ordering::SeqCst);
+
+    // Pinned messages come before the history, so they are seen first.
+    send_pinned_messages(transport, state)?;
+
+    // Send the chat history (or only the missed part of it) to the client after they connect.
+    send_chat_history(transport, state, peer_addr, last_seen_seq)?;
+    send_offline_messages(transport, state, username)?;
 
     // Notify all other clients that a new client has joined the chat.
-    broadcast_join_message(&clients, &usernames, peer_addr, &username, &chat_history)?;
-
-    // Start listening for messages from the client.
-    handle_client_messages(
-        &mut stream,
-        &clients,
-        &usernames,
-        &chat_history,
-        peer_addr,
-        &username,
-    )?;
+    broadcast_join_message(state, peer_addr, username)?;
+    state.emit(SystemEvent::Join {
+        addr: peer_addr,
+        username: username.clone(),
+    });
 
-    // Clean up the client after they disconnect.
-    cleanup_client(&clients, &usernames, peer_addr);
-    Ok(())
+    // Start listening for messages from the client. `/nick` may change the username.
+    handle_client_messages(transport, state, peer_addr, connection_id, username)
 }
 
-/// Registers the client in the shared `clients` map.
+/// Registers the client in the shared `clients` map and starts the writer thread
+/// that drains `outbox` into the connection. Returns the connection's id.
+/// An entry left behind by an earlier connection from the same address is replaced,
+/// and that connection is shut down and its username and admin rights dropped.
 fn register_client(
-    stream: &TcpStream,                                    // The client's TCP stream.
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared map of connected clients.
-    _usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>, // (Not used here) Shared map of usernames.
-    peer_addr: SocketAddr,                                 // The client's address.
-) -> ChatResult<()> {
-    let mut clients_lock = clients.write()?; // Acquire a write lock to modify the clients map.
-    clients_lock.insert(peer_addr, stream.try_clone()?); // Add the client with a cloned TCP stream.
-    Ok(())
+    transport: &dyn Transport, // The client's connection.
+    state: &ServerState,       // Shared server state.
+    peer_addr: SocketAddr,     // The client's address.
+    outbox: Arc<Outbox>,       // The client's outbound queue.
+) -> ChatResult<u64> {
+    let id = state.next_connection_id.fetch_add(1, Ordering::SeqCst) + 1;
+    spawn_writer(transport.try_clone()?, Arc::clone(&outbox));
+
+    let mut clients_lock = state.clients.write()?; // Acquire a write lock to modify the clients map.
+    let stale = clients_lock.insert(
+        peer_addr,
+        ClientConnection {
+            id,
+            transport: transport.try_clone()?, // Cloned connection, kept for shutdown.
+            outbox,
+            compression: AtomicBool::new(false), // Until the client opts in.
+            last_seen: Mutex::new(Instant::now()),
+            status: Mutex::default(),
+            notify: Mutex::default(),
+            recent_messages: Mutex::default(),
+        },
+    );
+    if stale.is_none() {
+        state.online.fetch_add(1, Ordering::SeqCst); // A replaced entry keeps the count.
+    }
+    if let Some(stale) = stale {
+        eprintln!("Replacing stale connection from {}", peer_addr);
+        stale.outbox.close();
+        let _ = stale.transport.shutdown(); // Unblock the old handler thread.
+        let username = state.usernames.write()?.remove(&peer_addr);
+        state.admins.write()?.remove(&peer_addr);
+        drop(clients_lock);
+        if let Some(username) = username {
+            state.emit(SystemEvent::Leave {
+                addr: peer_addr,
+                username,
+            });
+            state.presence_changed.store(true, Ordering::SeqCst);
+        }
+    }
+    Ok(id)
 }
 
-/// Reads and returns the username from the client.
-fn get_client_username(stream: &mut TcpStream, peer_addr: SocketAddr) -> ChatResult<String> {
-    let mut buffer = [0; 1024]; // Buffer to store the received data.
-    let raw_message = match stream.read(&mut buffer) {
-        Ok(n) if n > 0 => String::from_utf8_lossy(&buffer[..n]).trim().to_string(), // Convert to string.
+/// Reads the join message from the client and returns the username,
+/// along with the last seq the client saw if it is reconnecting.
+/// Fails with `RegistrationTimeout` if the join message doesn't arrive within
+/// `--registration-timeout-secs`, and rejects names that are reserved or break `--username-policy`.
+/// With `--guests`, a join without a username returns `None` for the server to pick one.
+fn get_client_username(
+    transport: &mut dyn Transport,
+    state: &ServerState,
+    peer_addr: SocketAddr,
+) -> ChatResult<(Option<String>, Option<u64>)> {
+    let timeout = Duration::from_secs(state.config.registration_timeout_secs);
+    transport.set_read_timeout(Some(timeout))?;
+    let raw_message = match transport.read_frame() {
+        Ok(Some(frame)) => frame.trim().to_string(), // Read the join frame.
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Err(ChatServerError::RegistrationTimeout(peer_addr.to_string()));
+        }
         _ => return Err(ChatServerError::ClientDisconnected(peer_addr.to_string())), // Handle client disconnection.
     };
+    transport.set_read_timeout(None)?; // Registered clients may stay idle indefinitely.
+
+    // Parse the JSON frame and extract the username.
+    let chat_message = match Frame::decode(&raw_message) {
+        Ok(frame) => frame.message,
+        Err(e) => {
+            let error = ChatServerError::from_decode(peer_addr, e);
+            if let Some(reason) = error.client_message() {
+                send_error(transport, reason)?;
+            }
+            return Err(error);
+        }
+    };
+    // Anything else sent first is a protocol violation, even if it carries a username.
+    // Nothing has been registered yet, so refusing here leaves no state behind.
+    if !matches!(chat_message.message_type, ChatMessageType::Join) {
+        send_error(
+            transport,
+            "You must send a join message before anything else.".to_string(),
+        )?;
+        return Err(ChatServerError::JoinRequired(peer_addr.to_string()));
+    }
+    let username = match chat_message.username.filter(|name| !name.trim().is_empty()) {
+        Some(username) => username,
+        None if state.config.guests => return Ok((None, chat_message.seq)),
+        None => {
+            send_error(
+                transport,
+                "A username is required; this server doesn't allow guests.".to_string(),
+            )?;
+            return Err(ChatServerError::MissingUsername(peer_addr.to_string()));
+        }
+    };
+
+    // Raw clients can send any length; don't store or echo an oversized name.
+    if let Some(reason) = username_length_violation(&username) {
+        send_error(transport, reason)?;
+        return Err(ChatServerError::UsernameTooLong(peer_addr.to_string()));
+    }
+    // Refuse names that could impersonate server notices.
+    if is_reserved_username(&username) || state.config.is_bot_name(&username) {
+        send_error(
+            transport,
+            format!("The username '{}' is reserved.", username),
+        )?;
+        return Err(ChatServerError::ReservedUsername(username));
+    }
+    if let Some(reason) = state.config.username_policy_violation(&username) {
+        send_error(transport, reason)?;
+        return Err(ChatServerError::UsernameNotAllowed(username));
+    }
+    Ok((Some(username), chat_message.seq))
+}
+
+/// Returns the message to reject `username` with if it is longer than `MAX_USERNAME_LEN`.
+fn username_length_violation(username: &str) -> Option<String> {
+    (username.chars().count() > MAX_USERNAME_LEN).then(|| {
+        format!(
+            "Usernames can be at most {} characters long.",
+            MAX_USERNAME_LEN
+        )
+    })
+}
+
+/// Records `requested` as the client's name unless another client already has it
+/// or the chat already has `--max-members` users. A guest (`None`) gets the next free
+/// `guest-N` name. Returns the name claimed.
+/// The checks and the insert happen under one write lock, so when two clients join
+/// with the same name at once, exactly one of them gets it, and the cap can't be overshot.
+fn claim_username(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The client's address.
+    requested: Option<&str>,       // The requested username; `None` for a guest.
+) -> ChatResult<String> {
+    let mut usernames_lock = state.usernames.write()?;
+    let username = match requested {
+        Some(username) => username.to_string(),
+        // Skip numbers whose name someone already took, e.g. with `/nick`.
+        None => loop {
+            let id = state.next_guest_id.fetch_add(1, Ordering::SeqCst) + 1;
+            let name = format!("{}{}", GUEST_NAME_PREFIX, id);
+            if !usernames_lock.values().any(|taken| *taken == name) {
+                break name;
+            }
+        },
+    };
+    let username = username.as_str();
+    if usernames_lock
+        .iter()
+        .any(|(addr, name)| *addr != peer_addr && name == username)
+    {
+        drop(usernames_lock);
+        send_error(transport, format!("The username '{}' is taken.", username))?;
+        return Err(ChatServerError::UsernameTaken(username.to_string()));
+    }
+    if let Some(max_members) = state.config.max_members {
+        if usernames_lock.len() >= max_members {
+            drop(usernames_lock);
+            send_error(
+                transport,
+                format!(
+                    "The chat is full ({} members). Please try again later.",
+                    max_members
+                ),
+            )?;
+            return Err(ChatServerError::ChatFull(username.to_string()));
+        }
+    }
+    usernames_lock.insert(peer_addr, username.to_string());
+    Ok(username.to_string())
+}
 
-    // Parse the JSON message and extract the username.
-    let chat_message: ChatMessage = serde_json::from_str(&raw_message)
-        .map_err(|_| ChatServerError::InvalidMessage(peer_addr.to_string()))?;
-    chat_message
-        .username
-        .ok_or_else(|| ChatServerError::MissingUsername(peer_addr.to_string())) // Ensure username exists.
+/// Tells a guest the name the server gave it.
+fn send_guest_name(transport: &mut dyn Transport, username: &str) -> ChatResult<()> {
+    let notice = ChatMessage {
+        message_type: ChatMessageType::Join,
+        username: Some(username.to_string()),
+        content: format!("You joined as {}.", username),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &notice)
 }
 
-/// Sends the chat history to the client.
+/// Sends the chat history to the client, as allowed by the `/history-mode`.
+/// A reconnecting client that reports its last seen seq is told how many messages it missed
+/// and only those are replayed.
+/// When a replay window is set, the history is sent in chunks of that size and the server
+/// waits for the client to acknowledge each chunk before sending the next one.
 fn send_chat_history(
-    stream: &mut TcpStream,                       // The client's TCP stream.
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>, // Shared chat history.
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The client's address.
+    last_seen_seq: Option<u64>,    // Last seq the client saw before reconnecting.
+) -> ChatResult<()> {
+    match *state.history_mode.read()? {
+        HistoryMode::Public => {}
+        HistoryMode::Private if last_seen_seq.is_some() => {}
+        HistoryMode::Private | HistoryMode::Off => return Ok(()),
+    }
+    // Take a snapshot so the lock isn't held while waiting on a slow client.
+    // History is a nicety, so a poisoned lock skips the replay instead of refusing the join.
+    let mut history = match state.chat_history.read() {
+        Ok(history) => history.clone(),
+        Err(_) => {
+            eprintln!(
+                "Skipping history replay for {}: the history lock is poisoned",
+                peer_addr
+            );
+            return Ok(());
+        }
+    };
+    if let Some(last_seen_seq) = last_seen_seq {
+        history.retain(|msg| msg.seq.is_some_and(|seq| seq > last_seen_seq));
+        send_unread_count(transport, history.len())?;
+    }
+    if state.config.history_order == HistoryOrder::NewestFirst {
+        history.reverse();
+    }
+
+    let window = match state.config.replay_window {
+        Some(window) if window > 0 => window,
+        _ => {
+            for msg in history.iter() {
+                send_message_to_client(transport, msg)?; // Send each message in the history to the client.
+            }
+            return Ok(());
+        }
+    };
+
+    let mut chunks = history.chunks(window).peekable();
+    while let Some(chunk) = chunks.next() {
+        for msg in chunk {
+            send_message_to_client(transport, msg)?;
+        }
+        // Pause after every window except the last one.
+        if chunks.peek().is_some() {
+            let ack_request = ChatMessage {
+                message_type: ChatMessageType::AckRequest,
+                username: None,
+                content: String::new(),
+                system: true,
+                ..Default::default()
+            };
+            send_message_to_client(transport, &ack_request)?;
+            wait_for_ack(transport, peer_addr)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the client the server's enabled features as a JSON array.
+fn send_capabilities(transport: &mut dyn Transport, state: &ServerState) -> ChatResult<()> {
+    let capabilities = ChatMessage {
+        message_type: ChatMessageType::Capabilities,
+        username: None,
+        content: serde_json::to_string(&state.capabilities())?,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &capabilities)
+}
+
+/// Sends the client the pinned messages, if any.
+fn send_pinned_messages(transport: &mut dyn Transport, state: &ServerState) -> ChatResult<()> {
+    let Some(content) = pinned_summary(state)? else {
+        return Ok(());
+    };
+    let pins = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Pins),
+        username: None,
+        content,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &pins)
+}
+
+/// Tells a reconnecting client how many messages it missed while away.
+fn send_unread_count(transport: &mut dyn Transport, missed: usize) -> ChatResult<()> {
+    let unread_msg = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Unread), // Unread-count report.
+        username: None, // No specific sender for this system message.
+        content: format!("You missed {} message(s) while away.", missed),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &unread_msg)
+}
+
+/// Delivers the private messages kept for `username` while they were offline,
+/// after a notice saying how many there are.
+fn send_offline_messages(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The client's username.
 ) -> ChatResult<()> {
-    let history = chat_history.read()?; // Acquire a read lock to access the chat history.
-    for msg in history.iter() {
-        send_message_to_client(stream, msg)?; // Send each message in the history to the client.
+    let messages = state.take_offline_messages(username)?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let notice = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Msg),
+        username: None,
+        content: format!(
+            "While you were away you got {} private message(s):",
+            messages.len()
+        ),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &notice)?;
+    for message in &messages {
+        send_message_to_client(transport, message)?;
     }
     Ok(())
 }
 
+/// Blocks until the client acknowledges the current history replay window.
+fn wait_for_ack(transport: &mut dyn Transport, peer_addr: SocketAddr) -> ChatResult<()> {
+    loop {
+        let raw_msg = match transport.read_frame() {
+            Ok(Some(frame)) => frame.trim().to_string(),
+            _ => return Err(ChatServerError::ClientDisconnected(peer_addr.to_string())),
+        };
+        match Frame::decode(&raw_msg).map(|frame| frame.message) {
+            Ok(ChatMessage {
+                message_type: ChatMessageType::Ack,
+                ..
+            }) => return Ok(()),
+            _ => eprintln!(
+                "Ignoring message received during history replay: {}",
+                raw_msg
+            ),
+        }
+    }
+}
+
 /// Broadcasts a "join" message to all clients.
 fn broadcast_join_message(
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared clients map.
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>,  // Shared usernames map.
-    peer_addr: SocketAddr,                                 // The address of the client joining.
-    username: &str,                                        // The username of the client joining.
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>,          // Shared chat history.
+    state: &ServerState,   // Shared server state.
+    peer_addr: SocketAddr, // The address of the client joining.
+    username: &str,        // The username of the client joining.
 ) -> ChatResult<()> {
-    // Add the username to the shared usernames map.
-    {
-        let mut usernames_lock = usernames.write()?;
-        usernames_lock.insert(peer_addr, username.to_string());
+    // The name belongs to a live client again, so it no longer resolves to whoever renamed away from it.
+    state.username_aliases.write()?.remove(username);
+
+    // A user rejoining within the grace period never appeared to leave, so don't announce either.
+    // Someone else taking the freed name is a new arrival, so the old user's leave goes out first.
+    let pending_leave = state.pending_leaves.write()?.remove(username);
+    if let Some(leave) = pending_leave {
+        if leave.sender.ip() == peer_addr.ip() {
+            println!("'{}' rejoined within the grace period", username);
+            return Ok(());
+        }
+        broadcast_system_message(
+            state,
+            leave.sender,
+            username,
+            leave.message_type,
+            format!("{} has left the chat", username),
+        )?;
     }
 
     // Broadcast a system "join" message to all clients.
     broadcast_system_message(
-        clients,
-        chat_history,
+        state,
         peer_addr,
         username,
         ChatMessageType::Join,
@@ -112,11 +501,10 @@ fn broadcast_join_message(
 }
 
 /// Broadcasts a system message to all clients.
-fn broadcast_system_message(
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared clients map.
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>,          // Shared chat history.
-    sender: SocketAddr,                                    // The sender's address.
-    username: &str,                                        // The sender's username.
+pub(crate) fn broadcast_system_message(
+    state: &ServerState,           // Shared server state.
+    sender: SocketAddr,            // The sender's address.
+    username: &str,                // The sender's username.
     message_type: ChatMessageType, // The type of message (e.g., join, leave).
     content: String,               // The message content.
 ) -> ChatResult<ChatMessage> {
@@ -124,38 +512,73 @@ fn broadcast_system_message(
         message_type,                         // Type of the system message.
         username: Some(username.to_string()), // Include the sender's username.
         content,                              // Include the message content.
+        system: true,
+        priority: Priority::High, // Announcements skip ahead of queued chat traffic.
+        ..Default::default()
     };
-    broadcast_message(clients, sender, &msg, chat_history); // Broadcast the message to all clients.
+    let msg = broadcast_message(state, Some(sender), msg); // Broadcast the message to all clients.
     Ok(msg)
 }
 
 /// Handles incoming messages from the client.
 fn handle_client_messages(
-    stream: &mut TcpStream,
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>,
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>,
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>,
+    transport: &mut dyn Transport,
+    state: &ServerState,
     peer_addr: SocketAddr,
-    username: &str,
+    connection_id: u64,
+    username: &mut String,
 ) -> ChatResult<()> {
-    let mut buffer = [0; 1024]; // Buffer to store incoming data.
+    let max_parse_failures = state.config.max_parse_failures;
+    let mut parse_failures = 0; // Consecutive unparseable frames.
     loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => break, // Connection closed by the client.
-            Ok(n) => {
-                let raw_msg = String::from_utf8_lossy(&buffer[..n]).trim().to_string(); // Convert bytes to string.
-                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&raw_msg) {
-                    handle_parsed_message(
-                        stream,
-                        clients,
-                        usernames,
-                        chat_history,
-                        peer_addr,
-                        username,
-                        chat_msg,
-                    )?;
-                } else {
-                    eprintln!("Failed to parse message: {}", raw_msg); // Log parsing error.
+        match transport.read_frame() {
+            Ok(None) => break, // Connection closed by the client.
+            Ok(Some(frame)) => {
+                state.record_heartbeat(peer_addr); // Any frame shows the client is still there.
+                let raw_msg = frame.trim().to_string();
+                match Frame::decode(&raw_msg) {
+                    Ok(Frame {
+                        message: chat_msg, ..
+                    }) => {
+                        parse_failures = 0;
+                        let flow = handle_parsed_message(
+                            transport,
+                            state,
+                            peer_addr,
+                            connection_id,
+                            username,
+                            chat_msg,
+                        )?;
+                        if flow.is_break() {
+                            break; // The client left; nothing more is read from it.
+                        }
+                    }
+                    Err(e) => {
+                        // Tell the client what was wrong, so a buggy client can be fixed.
+                        let error = ChatServerError::from_decode(peer_addr, e);
+                        eprintln!("{} (frame: {})", error, raw_msg); // Log parsing error.
+                        if let Some(reason) = error.client_message() {
+                            send_error(transport, reason)?;
+                        }
+                        state.emit(SystemEvent::Error {
+                            addr: peer_addr,
+                            error: error.to_string(),
+                        });
+
+                        // A client that keeps sending garbage is dropped rather than read forever.
+                        parse_failures += 1;
+                        if max_parse_failures > 0 && parse_failures >= max_parse_failures {
+                            eprintln!(
+                                "Disconnecting {}: {} unparseable messages in a row",
+                                peer_addr, parse_failures
+                            );
+                            end_session(
+                                transport,
+                                "Too many malformed messages; disconnecting.".to_string(),
+                            )?;
+                            break;
+                        }
+                    }
                 }
             }
             Err(_) => break, // Exit loop on read error.
@@ -164,190 +587,2522 @@ fn handle_client_messages(
     Ok(())
 }
 
+/// Ends the client's session from the server side: tells the client why, then sends it
+/// a `SessionEnd`, so it exits instead of reconnecting.
+fn end_session(
+    transport: &mut dyn Transport, // The client's connection.
+    reason: String,                // Shown to the client as an error.
+) -> ChatResult<()> {
+    send_error(transport, reason.clone())?;
+    let session_end = ChatMessage {
+        message_type: ChatMessageType::SessionEnd,
+        username: None,
+        content: reason,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &session_end)
+}
+
 /// Handles a parsed `ChatMessage` from the client.
+/// Returns `Break` once the client has left with a leave message or `/quit`: it is no longer
+/// registered and its name may already be someone else's, so nothing more may be read from it.
 fn handle_parsed_message(
-    stream: &mut TcpStream,
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>,
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>,
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>,
+    transport: &mut dyn Transport,
+    state: &ServerState,
     peer_addr: SocketAddr,
-    username: &str,
+    connection_id: u64,
+    username: &mut String,
     chat_msg: ChatMessage,
-) -> ChatResult<()> {
+) -> ChatResult<ControlFlow<()>> {
+    // Clients only ever send text; other encodings are for payloads the server sends.
+    match chat_msg.encoding {
+        ContentEncoding::Utf8 => {}
+        ContentEncoding::Unknown => {
+            send_error(transport, "Unknown content encoding.".to_string())?;
+            return Ok(ControlFlow::Continue(()));
+        }
+        encoding => {
+            send_error(
+                transport,
+                format!("Messages must be utf-8 text, not {}.", encoding.name()),
+            )?;
+            return Ok(ControlFlow::Continue(()));
+        }
+    }
+
     match chat_msg.message_type {
         ChatMessageType::Message => {
             // Broadcast a regular chat message.
             let msg = ChatMessage {
-                message_type: ChatMessageType::Message,
-                username: Some(username.to_string()),
                 content: chat_msg.content,
+                ttl: chat_msg.ttl,
+                ..Default::default()
             };
-            broadcast_message(clients, peer_addr, &msg, chat_history);
+            send_chat_message(transport, state, peer_addr, username, msg)?;
+        }
+        ChatMessageType::Command(command_type) => {
+            // Decode the command and its arguments, rejecting malformed ones.
+            match Command::from_parts(&command_type, &chat_msg.content) {
+                Ok(command) => {
+                    state.emit(SystemEvent::Command {
+                        addr: peer_addr,
+                        username: username.to_string(),
+                        command: command_type,
+                    });
+                    let quit = command == Command::Quit;
+                    handle_command(
+                        transport,
+                        state,
+                        peer_addr,
+                        connection_id,
+                        username,
+                        command,
+                    )?;
+                    if quit {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
+                Err(e) => {
+                    state.emit(SystemEvent::Error {
+                        addr: peer_addr,
+                        error: e.to_string(),
+                    });
+                    send_error(transport, e.to_string())?
+                }
+            }
         }
-        ChatMessageType::Command(CommandType::List) => {
-            // Respond to a `/list` command with a list of online users.
-            send_user_list(stream, usernames)?;
+        ChatMessageType::Pong => {
+            // Record the client's answer to a `/ping-all` round.
+            record_pong(state, peer_addr, chat_msg.seq)?;
         }
-        ChatMessageType::Command(CommandType::Quit) | ChatMessageType::Leave => {
-            // Handle client disconnection for `/quit` or leave message.
+        ChatMessageType::Leave => {
+            // Handle client disconnection for a leave message.
             handle_client_disconnect(
-                stream,
-                clients,
-                usernames,
+                transport,
+                state,
                 peer_addr,
                 username,
+                connection_id,
                 &chat_msg.message_type,
-                chat_history,
+            )?;
+            return Ok(ControlFlow::Break(()));
+        }
+        ChatMessageType::Capabilities => {
+            // The client may list the features it supports; compression is opt-in.
+            let features: Vec<String> = serde_json::from_str(&chat_msg.content).unwrap_or_default();
+            if let Some(client) = state.clients.read()?.get(&peer_addr) {
+                let compression = features.iter().any(|f| f == COMPRESSION_FEATURE);
+                client.compression.store(compression, Ordering::SeqCst);
+            }
+            // Either way, answer with the server's feature list.
+            send_capabilities(transport, state)?;
+        }
+        ChatMessageType::Join => {
+            // The client is already registered; a repeated join changes nothing and isn't broadcast.
+            println!("Ignoring repeated join from '{}'", username);
+            send_error(
+                transport,
+                format!(
+                    "You have already joined as '{}'. Use /nick to change your name.",
+                    username
+                ),
             )?;
         }
         _ => {
             eprintln!("Unhandled message type: {:?}", chat_msg.message_type); // Log unsupported message type.
         }
     }
-    Ok(())
+    Ok(ControlFlow::Continue(()))
 }
 
-/// Sends the list of online users to the client.
-fn send_user_list(
-    stream: &mut TcpStream, // The client's TCP stream.
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>, // Shared map of usernames.
+/// Dispatches a decoded command from the client.
+fn handle_command(
+    transport: &mut dyn Transport,
+    state: &ServerState,
+    peer_addr: SocketAddr,
+    connection_id: u64,
+    username: &mut String,
+    command: Command,
 ) -> ChatResult<()> {
-    let users = {
-        // Acquire a read lock on the usernames hashmap and collect all usernames.
-        let usernames_lock = usernames.read()?;
-        if usernames_lock.is_empty() {
-            // Debugging log if no users are found.
-            eprintln!("DEBUG: No users found in usernames map.");
-        } else {
-            // Log the usernames currently online for debugging purposes.
-            println!(
-                "DEBUG: Found users in usernames map: {:?}",
-                usernames_lock.values().cloned().collect::<Vec<_>>()
-            );
+    // Every command's required role is checked here, so handlers don't repeat it.
+    if state.role(&peer_addr) < command.command_type().required_role() {
+        return send_error(transport, "Insufficient privileges.".to_string());
+    }
+
+    // Commands in the registry are handled there; the rest are matched below.
+    let mut context = CommandContext {
+        transport: &mut *transport,
+        state,
+        peer_addr,
+        username,
+        connection_id,
+    };
+    if let Some(result) = state.commands.dispatch(&mut context, &command) {
+        return result;
+    }
+
+    match command {
+        Command::Admin { token } => {
+            // Authenticate the client as an admin with the configured token.
+            authenticate_admin(transport, state, peer_addr, username, &token)
+        }
+        Command::SetMaxLen(len) => {
+            // Update the maximum message length (admin only).
+            set_max_message_len(transport, state, username, len)
+        }
+        Command::PingAll => {
+            // Measure round-trip times to every other client (admin only).
+            ping_all(transport, state, peer_addr)
+        }
+        Command::DumpState => {
+            // Log a snapshot of the server state (admin only).
+            dump_state(transport, state, username)
+        }
+        Command::Nick { name } => {
+            // Change the client's username and announce it.
+            change_username(transport, state, peer_addr, username, name)
+        }
+        Command::Last { username: target } => {
+            // Show the last message sent by a user, under any of their former names.
+            send_last_message(transport, state, &target)
+        }
+        Command::React { seq, emoji } => {
+            // Record a reaction and broadcast the message's updated reactions.
+            add_reaction(transport, state, username, seq, emoji)
+        }
+        Command::Reply { seq, text } => {
+            // Broadcast a chat message that references an earlier one.
+            send_reply(transport, state, peer_addr, username, seq, text)
+        }
+        Command::Quote { seq, text } => {
+            // Broadcast a chat message carrying a copy of an earlier one.
+            send_quote(transport, state, peer_addr, username, seq, text)
+        }
+        Command::Mute {
+            username: target,
+            duration,
+        } => {
+            // Drop the target's messages for the duration (admin only).
+            mute_user(transport, state, username, &target, duration)
+        }
+        Command::Unmute { username: target } => {
+            // Lift a mute early (admin only).
+            unmute_user(transport, state, username, &target)
         }
-        // Collect all usernames into a vector.
-        usernames_lock.values().cloned().collect::<Vec<_>>()
+        // Registry commands that have no handler registered.
+        _ => send_error(
+            transport,
+            format!("/{} is not available.", command.command_type().name()),
+        ),
+    }
+}
+
+/// Checks a chat message against mutes and the length limit, then broadcasts it
+/// and stores it in history.
+fn send_chat_message(
+    transport: &mut dyn Transport, // The sending client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The sending client's address.
+    username: &str,                // The sending client's username.
+    msg: ChatMessage,              // The text, plus any reply, quote and TTL; the rest is set here.
+) -> ChatResult<()> {
+    // Muted users' messages are dropped; only they are told.
+    if state.is_muted(username) {
+        return send_error(transport, "You are muted.".to_string());
+    }
+
+    // Repeating the same text too often earns a temporary mute; the repeat is dropped.
+    if state.is_repeated_spam(peer_addr, &msg.content) {
+        return mute_spammer(transport, state, username);
+    }
+
+    // Reject messages over the current length limit.
+    let max_len = state.max_message_len.load(Ordering::SeqCst);
+    if msg.content.chars().count() > max_len {
+        return send_error(
+            transport,
+            format!("Message rejected: longer than {} characters.", max_len),
+        );
+    }
+
+    let msg = ChatMessage {
+        message_type: ChatMessageType::Message,
+        username: Some(username.to_string()),
+        ..msg
     };
+    let msg = broadcast_message(state, Some(peer_addr), msg);
+    state.emit(SystemEvent::Message {
+        addr: peer_addr,
+        username: username.to_string(),
+        seq: msg.seq.unwrap_or_default(),
+    });
+    Ok(())
+}
 
-    // Create a chat message containing the list of online users.
-    let list_msg = ChatMessage {
-        message_type: ChatMessageType::Command(CommandType::List), // Indicates a `/list` command response.
-        username: None, // No specific sender for this system message.
-        content: if users.is_empty() {
-            "No users online.".to_string() // Message for when no users are online.
-        } else {
-            format!("Online users: {}", users.join(", ")) // Format the usernames as a comma-separated string.
-        },
+/// Sends a chat message in reply to the chat message with `seq`,
+/// which must still be in history.
+fn send_reply(
+    transport: &mut dyn Transport, // The replying client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The replying client's address.
+    username: &str,                // The replying client's username.
+    seq: u64,                      // The seq of the message replied to.
+    text: String,                  // The reply.
+) -> ChatResult<()> {
+    let is_chat_message =
+        state.chat_history.read()?.iter().any(|msg| {
+            msg.seq == Some(seq) && matches!(msg.message_type, ChatMessageType::Message)
+        });
+    if !is_chat_message {
+        return send_error(transport, format!("No message with seq {}.", seq));
+    }
+    let msg = ChatMessage {
+        content: text,
+        reply_to: Some(seq),
+        ..Default::default()
     };
+    send_chat_message(transport, state, peer_addr, username, msg)
+}
 
-    // Send the message back to the client who requested the user list.
-    send_message_to_client(stream, &list_msg)
+/// Sends a chat message quoting the chat message with `seq`, which must still be in history.
+/// The quoted text is copied into the new message, so clients can show it even after
+/// the original has left history.
+fn send_quote(
+    transport: &mut dyn Transport, // The quoting client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The quoting client's address.
+    username: &str,                // The quoting client's username.
+    seq: u64,                      // The seq of the message quoted.
+    text: String,                  // The new message.
+) -> ChatResult<()> {
+    let quote = state.chat_history.read()?.iter().find_map(|msg| {
+        match (&msg.message_type, msg.seq, &msg.username) {
+            (ChatMessageType::Message, Some(msg_seq), Some(sender)) if msg_seq == seq => {
+                Some(Quote {
+                    seq,
+                    username: sender.clone(),
+                    content: msg.content.clone(),
+                })
+            }
+            _ => None,
+        }
+    });
+    let Some(quote) = quote else {
+        return send_error(transport, format!("No message with seq {}.", seq));
+    };
+    let msg = ChatMessage {
+        content: text,
+        quote: Some(quote),
+        ..Default::default()
+    };
+    send_chat_message(transport, state, peer_addr, username, msg)
 }
 
-/// Handles client disconnects by broadcasting a "leave" message and cleaning up.
-fn handle_client_disconnect(
-    stream: &mut TcpStream, // The disconnecting client's TCP stream.
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared map of connected clients.
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>, // Shared map of usernames.
-    peer_addr: SocketAddr,  // The address of the disconnecting client.
-    username: &str,         // The username of the disconnecting client.
-    message_type: &ChatMessageType, // The type of message indicating the disconnect.
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>, // Shared chat history.
+/// Renames the client, records the old name as an alias of the new one
+/// and announces the change to everyone, including the client itself.
+fn change_username(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The client's address.
+    username: &mut String,         // The client's current username, updated in place.
+    new_name: String,              // The requested username.
 ) -> ChatResult<()> {
-    // Broadcast a "leave" system message to all other clients.
-    let leave_msg = broadcast_system_message(
-        clients,
-        chat_history,
+    if let Some(reason) = username_length_violation(&new_name) {
+        return send_error(transport, reason);
+    }
+    if is_reserved_username(&new_name) || state.config.is_bot_name(&new_name) {
+        return send_error(
+            transport,
+            format!("The username '{}' is reserved.", new_name),
+        );
+    }
+    if let Some(reason) = state.config.username_policy_violation(&new_name) {
+        return send_error(transport, reason);
+    }
+
+    {
+        let mut usernames_lock = state.usernames.write()?;
+        if usernames_lock
+            .iter()
+            .any(|(addr, name)| *addr != peer_addr && *name == new_name)
+        {
+            drop(usernames_lock);
+            return send_error(transport, format!("The username '{}' is taken.", new_name));
+        }
+        usernames_lock.insert(peer_addr, new_name.clone());
+
+        // A mute follows the user to their new name.
+        let mut muted_lock = state.muted.write()?;
+        if let Some(expires_at) = muted_lock.remove(username.as_str()) {
+            muted_lock.insert(new_name.clone(), expires_at);
+        }
+        drop(muted_lock);
+
+        let mut aliases_lock = state.username_aliases.write()?;
+        aliases_lock.remove(&new_name); // The new name now belongs to this client.
+        if *username != new_name {
+            aliases_lock.insert(username.clone(), new_name.clone());
+        }
+    }
+
+    let old_name = std::mem::replace(username, new_name);
+    state.presence_changed.store(true, Ordering::SeqCst);
+    println!("'{}' is now known as '{}'", old_name, username);
+    let notice = broadcast_system_message(
+        state,
         peer_addr,
         username,
-        message_type.clone(), // Message type (e.g., leave or quit).
-        format!("{} has left the chat", username), // Content of the "leave" message.
+        ChatMessageType::Command(CommandType::Nick),
+        format!("{} is now known as {}", old_name, username),
     )?;
+    send_message_to_client(transport, &notice)?;
+    state.emit(SystemEvent::Rename {
+        addr: peer_addr,
+        old_name: old_name.clone(),
+        new_name: username.clone(),
+    });
+    broadcast_rename(state, &old_name, username)
+}
 
-    // Send the "leave" message to the disconnecting client.
-    send_message_to_client(stream, &leave_msg)?;
+/// Sends every client, the renamed one included, a structured `Rename` event so they can
+/// relabel messages they kept. With `--store-renames` it is also stored in history.
+fn broadcast_rename(state: &ServerState, old_name: &str, new_name: &str) -> ChatResult<()> {
+    let rename_msg = ChatMessage {
+        message_type: ChatMessageType::Rename,
+        username: Some(new_name.to_string()),
+        content: serde_json::to_string(&Rename {
+            old_name: old_name.to_string(),
+            new_name: new_name.to_string(),
+        })?,
+        system: true,
+        ..Default::default()
+    };
+    if state.config.store_renames {
+        broadcast_message(state, None, rename_msg);
+        return Ok(());
+    }
+    let broadcast = Broadcast {
+        exclude: None,
+        frame: Frame::encode(&rename_msg)?,
+        priority: rename_msg.priority,
+    };
+    if let Err(broadcast) = state.queue_broadcast(broadcast) {
+        deliver_broadcast(state, &broadcast);
+    }
+    Ok(())
+}
 
-    // Remove the client from shared state (clients map and usernames map).
-    {
-        let mut clients_lock = clients.write()?; // Acquire a write lock on the clients map.
-        clients_lock.remove(&peer_addr); // Remove the client.
+/// Sends the client the most recent chat message from `target`,
+/// matching messages sent under any name that resolves to the same user.
+fn send_last_message(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    target: &str,                  // The username to look up; former names are accepted.
+) -> ChatResult<()> {
+    let current = state.resolve_username(target);
+    let last = state
+        .chat_history
+        .read()?
+        .iter()
+        .rev()
+        .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+        .find(|msg| {
+            msg.username
+                .as_deref()
+                .is_some_and(|name| state.resolve_username(name) == current)
+        })
+        .cloned();
+
+    let content = match last {
+        Some(msg) => {
+            let sent_as = msg.username.unwrap_or_default();
+            if sent_as == current {
+                format!("Last message from {}: {}", current, msg.content)
+            } else {
+                format!(
+                    "Last message from {} (as {}): {}",
+                    current, sent_as, msg.content
+                )
+            }
+        }
+        None => format!("No messages from {}.", target),
+    };
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Last),
+        username: None,
+        content,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
+}
 
-        let mut usernames_lock = usernames.write()?; // Acquire a write lock on the usernames map.
-        usernames_lock.remove(&peer_addr); // Remove the username.
+/// Records `username`'s reaction to the chat message with `seq`
+/// and sends every client the message's updated reaction counts.
+fn add_reaction(
+    transport: &mut dyn Transport, // The reacting client's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The reacting client's username.
+    seq: u64,                      // The seq of the message reacted to.
+    emoji: String,                 // The reaction.
+) -> ChatResult<()> {
+    let is_chat_message =
+        state.chat_history.read()?.iter().any(|msg| {
+            msg.seq == Some(seq) && matches!(msg.message_type, ChatMessageType::Message)
+        });
+    if !is_chat_message {
+        return send_error(transport, format!("No message with seq {}.", seq));
     }
 
+    // The update is queued, or delivered, under the reactions lock, so the last aggregate
+    // every client receives is the final one.
+    let mut reactions_lock = state.reactions.write()?;
+    let summary = {
+        let reactions = reactions_lock.entry(seq).or_default();
+        if !reactions
+            .entry(emoji.clone())
+            .or_default()
+            .insert(username.to_string())
+        {
+            drop(reactions_lock);
+            return send_error(
+                transport,
+                format!("You already reacted with {} to message {}.", emoji, seq),
+            );
+        }
+        format_reactions(reactions)
+    };
+
+    // Reactions aren't kept in history, so they're only broadcast to every client.
+    let reaction_msg = ChatMessage {
+        message_type: ChatMessageType::Reaction,
+        username: None,
+        content: summary,
+        seq: Some(seq), // The message the reactions belong to.
+        system: true,
+        ..Default::default()
+    };
+    let broadcast = Broadcast {
+        exclude: None,
+        frame: Frame::encode(&reaction_msg)?,
+        priority: reaction_msg.priority,
+    };
+    if let Err(broadcast) = state.queue_broadcast(broadcast) {
+        deliver_broadcast(state, &broadcast);
+    }
+    drop(reactions_lock);
     Ok(())
 }
 
-/// Removes a client from the shared state after disconnection.
-fn cleanup_client(
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared map of connected clients.
-    usernames: &Arc<RwLock<HashMap<SocketAddr, String>>>,  // Shared map of usernames.
-    peer_addr: SocketAddr,                                 // The address of the client to remove.
-) {
-    // Remove the client from the clients map.
-    clients.write().ok().map(|mut lock| lock.remove(&peer_addr));
-    // Remove the client's username from the usernames map.
-    usernames
-        .write()
-        .ok()
-        .map(|mut lock| lock.remove(&peer_addr));
+/// Formats a message's reactions as e.g. `👍 x3  ❤️ x1`.
+fn format_reactions(reactions: &Reactions) -> String {
+    reactions
+        .iter()
+        .map(|(emoji, users)| format!("{} x{}", emoji, users.len()))
+        .collect::<Vec<_>>()
+        .join("  ")
 }
 
-/// Sends a message to a single client.
-fn send_message_to_client(
-    stream: &mut TcpStream, // The client's TCP stream.
-    message: &ChatMessage,  // The message to send.
+/// Mutes `target` until `duration` elapses, or until `/unmute` if no duration is given.
+/// Only admins may do this.
+fn mute_user(
+    transport: &mut dyn Transport, // The admin's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The admin's username.
+    target: &str,                  // The username to mute.
+    duration: Option<Duration>,    // How long the mute lasts.
 ) -> ChatResult<()> {
-    // Serialize the chat message to JSON format.
-    let serialized_msg = serde_json::to_string(message)?;
-    // Write the serialized message to the client's stream, followed by a newline.
-    stream.write_all(format!("{}\n", serialized_msg).as_bytes())?;
-    Ok(())
+    if !state.usernames.read()?.values().any(|name| name == target) {
+        return send_error(transport, format!("No user named '{}' is online.", target));
+    }
+    // A duration too long to represent as a deadline is rejected rather than overflowing.
+    let until = match duration.map(|d| Instant::now().checked_add(d)) {
+        Some(None) => {
+            return send_error(
+                transport,
+                "Mute rejected: the duration is too long; omit it to mute until unmuted."
+                    .to_string(),
+            );
+        }
+        until => until.flatten(),
+    };
+
+    state.muted.write()?.insert(target.to_string(), until);
+    let content = match duration {
+        Some(duration) => format!("{} is muted for {}s.", target, duration.as_secs()),
+        None => format!("{} is muted until unmuted.", target),
+    };
+    println!("{}", content);
+    let detail = match duration {
+        Some(duration) => format!("for {}s", duration.as_secs()),
+        None => "until unmuted".to_string(),
+    };
+    state.record_moderation(username, CommandType::Mute, target, Some(detail));
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Mute),
+        username: None,
+        content,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
 }
 
-/// Broadcasts a message to all clients except the sender and updates the chat history.
-fn broadcast_message(
-    clients: &Arc<RwLock<HashMap<SocketAddr, TcpStream>>>, // Shared map of connected clients.
-    sender: SocketAddr, // The address of the sender (to exclude from broadcasting).
-    message: &ChatMessage, // The message to broadcast.
-    chat_history: &Arc<RwLock<Vec<ChatMessage>>>, // Shared chat history.
-) {
-    // Add the message to the shared chat history.
-    {
-        let mut history_lock = chat_history.write().unwrap();
-        history_lock.push(message.clone());
+/// Mutes a client caught repeating the same message by `--spam-repeats`
+/// for `--spam-mute-secs`, and tells them why.
+fn mute_spammer(
+    transport: &mut dyn Transport, // The spammer's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The spammer's username.
+) -> ChatResult<()> {
+    let duration = Duration::from_secs(state.config.spam_mute_secs);
+    // A mute too long to represent as a deadline lasts until an admin lifts it.
+    let until = Instant::now().checked_add(duration);
+    state.muted.write()?.insert(username.to_string(), until);
+    println!("{} is auto-muted for repeated messages", username);
+    let length = match until {
+        Some(_) => format!("for {}s", duration.as_secs()),
+        None => "until an admin unmutes you".to_string(),
+    };
+    state.record_moderation(
+        "server",
+        CommandType::Mute,
+        username,
+        Some(format!("{}: repeated messages", length)),
+    );
+    send_error(
+        transport,
+        format!(
+            "You are muted {} for sending the same message repeatedly.",
+            length
+        ),
+    )
+}
+
+/// Lifts a mute. Only admins may do this.
+fn unmute_user(
+    transport: &mut dyn Transport, // The admin's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The admin's username.
+    target: &str,                  // The username to unmute.
+) -> ChatResult<()> {
+    if state.muted.write()?.remove(target).is_none() {
+        return send_error(transport, format!("{} is not muted.", target));
     }
 
-    // Serialize the message for transmission.
-    let serialized = serde_json::to_string(message).unwrap_or_default();
-    let mut failed_clients = vec![]; // List to track clients that fail to receive the message.
+    println!("{} is no longer muted", target);
+    state.record_moderation(username, CommandType::Unmute, target, None);
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Unmute),
+        username: None,
+        content: format!("{} is no longer muted.", target),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
+}
 
-    // Use a read lock to access the clients map for broadcasting.
-    {
-        let clients_lock = clients.read().unwrap();
-        for (&addr, client) in clients_lock.iter() {
-            if addr != sender {
-                // Skip the sender.
-                if let Ok(mut writable_client) = client.try_clone() {
-                    if let Err(_) =
-                        writable_client.write_all(format!("{}\n", serialized).as_bytes())
-                    {
-                        failed_clients.push(addr); // Add failed clients to the list.
-                    }
-                } else {
-                    failed_clients.push(addr); // Add clients that failed to clone.
-                }
-            }
+/// Grants admin privileges to the client if it presents the configured admin token.
+fn authenticate_admin(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The client's address.
+    username: &str,                // The client's username.
+    token: &str,                   // The token presented by the client.
+) -> ChatResult<()> {
+    match &state.config.admin_token {
+        Some(admin_token) if admin_token == token.trim() => {
+            state.admins.write()?.insert(peer_addr);
+            println!("'{}' authenticated as admin", username);
+            let reply = ChatMessage {
+                message_type: ChatMessageType::Command(CommandType::Admin),
+                username: None,
+                content: "You are now an admin.".to_string(),
+                system: true,
+                ..Default::default()
+            };
+            send_message_to_client(transport, &reply)
         }
+        _ => send_error(transport, "Invalid admin token.".to_string()),
     }
+}
 
-    // Remove any clients that failed during broadcasting.
-    if !failed_clients.is_empty() {
-        let mut clients_lock = clients.write().unwrap();
-        for addr in failed_clients {
-            eprintln!("Removing failed client: {}", addr);
-            clients_lock.remove(&addr);
-        }
+/// Updates the server's maximum message length at runtime. Only admins may do this.
+fn set_max_message_len(
+    transport: &mut dyn Transport, // The client's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The client's username.
+    new_len: usize,                // The requested limit.
+) -> ChatResult<()> {
+    if !MAX_MESSAGE_LEN_BOUNDS.contains(&new_len) {
+        return send_error(
+            transport,
+            format!(
+                "Usage: /setmaxlen <n> where n is between {} and {}.",
+                MAX_MESSAGE_LEN_BOUNDS.start(),
+                MAX_MESSAGE_LEN_BOUNDS.end()
+            ),
+        );
+    }
+
+    let old_len = state.max_message_len.swap(new_len, Ordering::SeqCst);
+    println!(
+        "'{}' changed the maximum message length from {} to {}",
+        username, old_len, new_len
+    );
+    state.record_moderation(
+        username,
+        CommandType::SetMaxLen,
+        "max message length",
+        Some(format!("{} -> {}", old_len, new_len)),
+    );
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::SetMaxLen),
+        username: None,
+        content: format!("Maximum message length set to {}.", new_len),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
+}
+
+/// Pings every other connected client and reports round-trip statistics to the admin.
+/// The requester isn't pinged, since its own handler thread is busy waiting for the replies.
+fn ping_all(
+    transport: &mut dyn Transport, // The admin's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The admin's address.
+) -> ChatResult<()> {
+    // Snapshot the clients to ping and open a new round for their replies.
+    let targets: Vec<(SocketAddr, String)> = state
+        .usernames
+        .read()?
+        .iter()
+        .filter(|(addr, _)| **addr != peer_addr)
+        .map(|(addr, name)| (*addr, name.clone()))
+        .collect();
+    let round_id = state.next_ping_id.fetch_add(1, Ordering::SeqCst) + 1;
+    state.ping_rounds.write()?.insert(
+        round_id,
+        PingRound {
+            sent_at: Instant::now(),
+            rtts: HashMap::new(),
+        },
+    );
+
+    let ping = ChatMessage {
+        message_type: ChatMessageType::Ping,
+        seq: Some(round_id), // Echoed back in the client's pong.
+        system: true,
+        priority: Priority::High, // Queued chat traffic shouldn't inflate the round-trip time.
+        ..Default::default()
+    };
+    for (addr, _) in &targets {
+        if let Err(e) = send_message_to_addr(state, *addr, &ping) {
+            eprintln!("Failed to ping {}: {}", addr, e); // Reported as a non-responder below.
+        }
+    }
+
+    // Wait until every client has answered or the timeout elapses.
+    let deadline = Instant::now() + Duration::from_millis(state.config.ping_timeout_ms);
+    while Instant::now() < deadline {
+        let answered = state
+            .ping_rounds
+            .read()?
+            .get(&round_id)
+            .map_or(0, |round| round.rtts.len());
+        if answered >= targets.len() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let rtts = state
+        .ping_rounds
+        .write()?
+        .remove(&round_id)
+        .map(|round| round.rtts)
+        .unwrap_or_default();
+
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::PingAll),
+        username: None,
+        content: format_ping_summary(&targets, &rtts),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
+}
+
+/// Logs a pretty-printed snapshot of the server state. Only admins may do this.
+fn dump_state(
+    transport: &mut dyn Transport, // The admin's connection.
+    state: &ServerState,           // Shared server state.
+    username: &str,                // The admin's username.
+) -> ChatResult<()> {
+    let snapshot = serde_json::to_string_pretty(&state.snapshot()?)?;
+    println!("State snapshot requested by '{}':\n{}", username, snapshot);
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::DumpState),
+        username: None,
+        content: "State snapshot written to the server log.".to_string(),
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &reply)
+}
+
+/// Summarizes a `/ping-all` round: min/avg/max round-trip time and any non-responders.
+fn format_ping_summary(
+    targets: &[(SocketAddr, String)],     // Clients that were pinged.
+    rtts: &HashMap<SocketAddr, Duration>, // Round-trip times of those that answered.
+) -> String {
+    if targets.is_empty() {
+        return "No other clients to ping.".to_string();
+    }
+
+    let mut summary = format!("Pinged {} client(s)", targets.len());
+    let times: Vec<f64> = rtts
+        .values()
+        .map(|rtt| rtt.as_secs_f64() * 1000.0)
+        .collect();
+    if !times.is_empty() {
+        let min = times.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = times.iter().cloned().fold(0.0, f64::max);
+        let avg = times.iter().sum::<f64>() / times.len() as f64;
+        summary.push_str(&format!(
+            ": min {:.1} ms, avg {:.1} ms, max {:.1} ms",
+            min, avg, max
+        ));
+    }
+
+    let mut missing: Vec<&str> = targets
+        .iter()
+        .filter(|(addr, _)| !rtts.contains_key(addr))
+        .map(|(_, name)| name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        summary.push_str(&format!(". No response from: {}", missing.join(", ")));
+    }
+    summary.push('.');
+    summary
+}
+
+/// Records a client's answer to a `/ping-all` round.
+fn record_pong(
+    state: &ServerState,   // Shared server state.
+    peer_addr: SocketAddr, // The answering client's address.
+    round_id: Option<u64>, // The round id echoed in the pong.
+) -> ChatResult<()> {
+    let Some(round_id) = round_id else {
+        return Ok(()); // Not an answer to a ping round.
+    };
+    if let Some(round) = state.ping_rounds.write()?.get_mut(&round_id) {
+        round.rtts.insert(peer_addr, round.sent_at.elapsed());
+    }
+    Ok(())
+}
+
+/// Sends an error notice to a single client.
+pub(crate) fn send_error(transport: &mut dyn Transport, content: String) -> ChatResult<()> {
+    let error_msg = ChatMessage {
+        message_type: ChatMessageType::Error, // Indicates a rejected request.
+        username: None,                       // No specific sender for this system message.
+        content,
+        system: true,
+        ..Default::default()
+    };
+    send_message_to_client(transport, &error_msg)
+}
+
+/// Handles client disconnects by broadcasting a "leave" message and cleaning up.
+pub(crate) fn handle_client_disconnect(
+    transport: &mut dyn Transport, // The disconnecting client's connection.
+    state: &ServerState,           // Shared server state.
+    peer_addr: SocketAddr,         // The address of the disconnecting client.
+    username: &str,                // The username of the disconnecting client.
+    connection_id: u64,            // Id of the disconnecting client's connection.
+    message_type: &ChatMessageType, // The type of message indicating the disconnect.
+) -> ChatResult<()> {
+    // The shutdown handler is already closing every socket, so don't race it on this one.
+    if state.is_shutting_down() {
+        println!("Ignoring disconnect from '{}' during shutdown", username);
+        return Ok(());
+    }
+
+    let grace = Duration::from_millis(state.config.rejoin_grace_ms);
+    let leave_msg = if grace.is_zero() {
+        // Broadcast a "leave" system message to all other clients.
+        broadcast_system_message(
+            state,
+            peer_addr,
+            username,
+            message_type.clone(), // Message type (e.g., leave or quit).
+            format!("{} has left the chat", username), // Content of the "leave" message.
+        )?
+    } else {
+        // Hold the announcement back; `announce_pending_leaves` sends it once the grace period
+        // ends, unless the user rejoins first.
+        state.pending_leaves.write()?.insert(
+            username.to_string(),
+            PendingLeave {
+                sender: peer_addr,
+                message_type: message_type.clone(),
+                // A grace too long to represent as a deadline holds the leave until the user rejoins.
+                due: Instant::now().checked_add(grace),
+            },
+        );
+        ChatMessage {
+            message_type: message_type.clone(),
+            username: Some(username.to_string()),
+            content: format!("{} has left the chat", username),
+            system: true,
+            ..Default::default()
+        }
+    };
+
+    // Send the "leave" message to the disconnecting client.
+    send_message_to_client(transport, &leave_msg)?;
+
+    // Remove the client from shared state (clients, usernames and admins).
+    cleanup_client(state, peer_addr, connection_id);
+
+    Ok(())
+}
+
+/// Broadcasts the held-back leave announcements whose grace period has ended.
+pub fn announce_pending_leaves(state: &ServerState) -> ChatResult<()> {
+    let now = Instant::now();
+    let due: Vec<(String, PendingLeave)> = {
+        let mut pending_lock = state.pending_leaves.write()?;
+        let names: Vec<String> = pending_lock
+            .iter()
+            .filter(|(_, leave)| leave.due.is_some_and(|due| due <= now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names
+            .into_iter()
+            .filter_map(|name| pending_lock.remove_entry(&name))
+            .collect()
+    };
+
+    for (username, leave) in due {
+        broadcast_system_message(
+            state,
+            leave.sender,
+            &username,
+            leave.message_type,
+            format!("{} has left the chat", username),
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes the messages whose TTL has elapsed from history, along with their reactions.
+/// With `--expiry-notices`, clients are sent the seqs of the expired messages.
+pub fn expire_messages(state: &ServerState) -> ChatResult<()> {
+    let now = unix_timestamp();
+    let mut expired = Vec::new();
+    state.chat_history.write()?.retain(|msg| {
+        let expires_at = msg
+            .timestamp
+            .zip(msg.ttl)
+            .map(|(timestamp, ttl)| timestamp.saturating_add(ttl));
+        match (expires_at, msg.seq) {
+            (Some(expires_at), Some(seq)) if expires_at <= now => {
+                expired.push(seq);
+                false
+            }
+            _ => true,
+        }
+    });
+    if expired.is_empty() {
+        return Ok(());
+    }
+    println!("Expired {} message(s) from history", expired.len());
+    let mut reactions_lock = state.reactions.write()?;
+    for seq in &expired {
+        reactions_lock.remove(seq);
+    }
+    drop(reactions_lock);
+
+    if !state.config.expiry_notices {
+        return Ok(());
+    }
+    let notice = ChatMessage {
+        message_type: ChatMessageType::Expired,
+        username: None,
+        content: serde_json::to_string(&expired)?,
+        system: true,
+        ..Default::default()
+    };
+    let broadcast = Broadcast {
+        exclude: None,
+        frame: Frame::encode(&notice)?,
+        priority: notice.priority,
+    };
+    if let Err(broadcast) = state.queue_broadcast(broadcast) {
+        deliver_broadcast(state, &broadcast);
+    }
+    Ok(())
+}
+
+/// Broadcasts the scheduled announcements that have come due, to every client,
+/// and stores them in history like other server notices.
+pub fn send_due_announcements(state: &ServerState) -> ChatResult<()> {
+    let now = Instant::now();
+    let due: Vec<ScheduledMessage> = {
+        let mut scheduled_lock = state.scheduled.write()?;
+        let ids: Vec<u64> = scheduled_lock
+            .iter()
+            .filter(|(_, scheduled)| scheduled.due <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| scheduled_lock.remove(id))
+            .collect()
+    };
+
+    for scheduled in due {
+        println!("Sending announcement scheduled by '{}'", scheduled.author);
+        let msg = ChatMessage {
+            message_type: ChatMessageType::Command(CommandType::Schedule),
+            username: Some(scheduled.author.clone()),
+            content: format!("Announcement from {}: {}", scheduled.author, scheduled.text),
+            system: true,
+            priority: Priority::High,
+            ..Default::default()
+        };
+        broadcast_message(state, None, msg);
+    }
+    Ok(())
+}
+
+/// Removes a client from the shared state after disconnection.
+/// Does nothing if `peer_addr` now belongs to a newer connection, so a late cleanup
+/// can't remove a client that reused the address.
+fn cleanup_client(
+    state: &ServerState,   // Shared server state.
+    peer_addr: SocketAddr, // The address of the client to remove.
+    connection_id: u64,    // Id of the connection to remove.
+) {
+    // The process is exiting; leave the maps to the shutdown handler.
+    if state.is_shutting_down() {
+        return;
+    }
+
+    // Keep the clients map locked until the username and admin entries are gone too,
+    // so a new connection from the same address can't register in between.
+    let Ok(mut clients_lock) = state.clients.write() else {
+        return;
+    };
+    if clients_lock
+        .get(&peer_addr)
+        .is_some_and(|client| client.id != connection_id)
+    {
+        return; // Replaced by a newer connection.
+    }
+    // Remove the client from the clients map and stop its writer thread once it has written
+    // what is already queued, such as the reason for a disconnect.
+    // Every disconnect path ends here, and only the call that removes the entry counts it,
+    // so the online count drops exactly once per connection.
+    if let Some(client) = clients_lock.remove(&peer_addr) {
+        client.outbox.finish();
+        state.online.fetch_sub(1, Ordering::SeqCst);
+    }
+    // Remove the client's username from the usernames map.
+    let username = state
+        .usernames
+        .write()
+        .ok()
+        .and_then(|mut lock| lock.remove(&peer_addr));
+    // Drop any admin privileges held by this connection.
+    state
+        .admins
+        .write()
+        .ok()
+        .map(|mut lock| lock.remove(&peer_addr));
+    drop(clients_lock);
+
+    if let Some(username) = username {
+        state.emit(SystemEvent::Leave {
+            addr: peer_addr,
+            username,
+        });
+        state.presence_changed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Sends every client the list of online users if it changed since the last call.
+/// Presence updates aren't stored in history; a joining client gets the next one.
+pub fn broadcast_presence(state: &ServerState) -> ChatResult<()> {
+    if !state.presence_changed.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let presence_msg = ChatMessage {
+        message_type: ChatMessageType::Presence,
+        username: None,
+        content: serde_json::to_string(&state.online_usernames()?)?,
+        system: true,
+        ..Default::default()
+    };
+    let broadcast = Broadcast {
+        exclude: None,
+        frame: Frame::encode(&presence_msg)?,
+        priority: presence_msg.priority,
+    };
+    if let Err(broadcast) = state.queue_broadcast(broadcast) {
+        deliver_broadcast(state, &broadcast);
+    }
+    Ok(())
+}
+
+/// Probes every client with a ping and prunes those whose connection has failed.
+/// A dead socket usually accepts one write before erroring, so a client whose peer has gone
+/// is pruned by the probe after the one that first hits it. With `--heartbeat-timeout-secs`,
+/// clients that haven't answered for twice the timeout are pruned too, even if their
+/// socket still accepts writes.
+pub fn reap_stale_clients(state: &ServerState) -> ChatResult<()> {
+    if state.is_shutting_down() {
+        return Ok(());
+    }
+
+    let probe = Frame::encode(&ChatMessage {
+        message_type: ChatMessageType::Ping, // No round id; the pong is ignored.
+        system: true,
+        priority: Priority::High,
+        ..Default::default()
+    })?;
+    let stale: Vec<(SocketAddr, u64)> = state
+        .clients
+        .read()?
+        .iter()
+        .filter(|(_, client)| {
+            !client.outbox.push(probe.clone(), Priority::High) || state.is_stale(client, 2)
+        })
+        .map(|(addr, client)| (*addr, client.id))
+        .collect();
+
+    for (addr, id) in stale {
+        println!("Reaping stale client: {}", addr);
+        if let Some(client) = state.clients.read()?.get(&addr).filter(|c| c.id == id) {
+            let _ = client.transport.shutdown(); // Unblock the client's handler thread.
+        }
+        cleanup_client(state, addr, id);
+    }
+    Ok(())
+}
+
+/// Sends a message to a single client.
+pub(crate) fn send_message_to_client(
+    transport: &mut dyn Transport, // The client's connection.
+    message: &ChatMessage,         // The message to send.
+) -> ChatResult<()> {
+    // Wrap the chat message in a frame and serialize it to JSON.
+    let serialized_msg = Frame::encode(message)?;
+    // Write the serialized message to the client as a single frame.
+    transport.write_frame(&serialized_msg)?;
+    Ok(())
+}
+
+/// Queues a message for the client at `addr` through the shared clients map.
+pub(crate) fn send_message_to_addr(
+    state: &ServerState,   // Shared server state.
+    addr: SocketAddr,      // The recipient's address.
+    message: &ChatMessage, // The message to send.
+) -> ChatResult<()> {
+    let serialized = Frame::encode(message)?;
+    let clients_lock = state.clients.read()?;
+    let queued = clients_lock.get(&addr).is_some_and(|client| {
+        let frame = if client.compression.load(Ordering::SeqCst) {
+            compress_frame(&serialized)
+        } else {
+            serialized
+        };
+        client.outbox.push(frame, message.priority)
+    });
+    if !queued {
+        return Err(ChatServerError::ClientDisconnected(addr.to_string()));
+    }
+    Ok(())
+}
+
+/// Broadcasts a message to all clients except the sender and updates the chat history.
+/// The message is stamped with a sequence number and timestamp, and the stamped copy is returned.
+pub(crate) fn broadcast_message(
+    state: &ServerState,        // Shared server state.
+    sender: Option<SocketAddr>, // The address of the sender (to exclude from broadcasting), if any.
+    message: ChatMessage,       // The message to broadcast.
+) -> ChatMessage {
+    let mut message = message;
+
+    // Stamp the message and add it to the shared chat history.
+    // The sequence number is assigned under the history lock so history stays in seq order,
+    // and the broadcast is queued under it too so every client receives messages in seq order.
+    // A thread that panicked holding the lock can't have left a message half-added,
+    // so a poisoned history is still usable and chat carries on.
+    let queued = {
+        let mut history_lock = state
+            .chat_history
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        message.seq = Some(state.next_seq.fetch_add(1, Ordering::SeqCst) + 1);
+        message.timestamp = Some(unix_timestamp());
+        history_lock.push(message.clone());
+        state.queue_broadcast(Broadcast {
+            exclude: sender,
+            frame: Frame::encode(&message).unwrap_or_default(),
+            priority: message.priority,
+        })
+    };
+    if let Err(broadcast) = queued {
+        deliver_broadcast(state, &broadcast); // No broadcaster thread is running.
+    }
+
+    message
+}
+
+/// Pushes a broadcast frame to every client's outbox, except the excluded one.
+/// Run by the broadcaster thread, so all outboxes receive broadcasts in the same order.
+pub fn deliver_broadcast(state: &ServerState, broadcast: &Broadcast) {
+    let mut failed_clients = vec![]; // List to track clients that fail to receive the message.
+    let mut compressed = None; // Compressed once, for the first client that opted in.
+
+    // Use a read lock to access the clients map for broadcasting.
+    // Each client's writer thread does the actual write, so a slow client can't stall the sender.
+    {
+        let clients_lock = state.clients.read().unwrap();
+        for (&addr, client) in clients_lock.iter() {
+            if Some(addr) != broadcast.exclude {
+                // Skip the sender.
+                let frame = if client.compression.load(Ordering::SeqCst) {
+                    compressed
+                        .get_or_insert_with(|| compress_frame(&broadcast.frame))
+                        .clone()
+                } else {
+                    broadcast.frame.clone()
+                };
+                if !client.outbox.push(frame, broadcast.priority) {
+                    failed_clients.push((addr, client.id)); // The client's writer has stopped after a failed write.
+                }
+            }
+        }
+    }
+
+    // Remove any clients that failed during broadcasting.
+    // `cleanup_client` skips an address reused by a new connection since.
+    for (addr, id) in failed_clients {
+        eprintln!("Removing failed client: {}", addr);
+        cleanup_client(state, addr, id);
+    }
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandRegistry;
+    use crate::compression::decompress_message;
+    use crate::test_support::{test_config, TestClient};
+    use crate::transport::MemoryTransport;
+    use std::sync::Barrier;
+    use std::thread::JoinHandle;
+
+    /// Address the test client appears to connect from.
+    const CLIENT_ADDR: &str = "10.0.0.1:5000";
+
+    /// Runs `handle_client` on a thread, over a memory pipe whose other end is returned.
+    fn connect(state: &Arc<ServerState>) -> (MemoryTransport, JoinHandle<ChatResult<()>>) {
+        let server_addr = "127.0.0.1:8081".parse().unwrap();
+        let (server_end, client_end) =
+            MemoryTransport::pair(server_addr, CLIENT_ADDR.parse().unwrap());
+        client_end
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let state = Arc::clone(state);
+        let handler = thread::spawn(move || handle_client(server_end, state));
+        (client_end, handler)
+    }
+
+    /// Sends `message` in a frame.
+    fn send(transport: &mut MemoryTransport, message: ChatMessage) {
+        transport
+            .write_frame(&Frame::encode(&message).unwrap())
+            .unwrap();
+    }
+
+    /// Sends the join message for `username`.
+    fn join(transport: &mut MemoryTransport, username: &str) {
+        send(
+            transport,
+            ChatMessage {
+                message_type: ChatMessageType::Join,
+                username: Some(username.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Reads the next message the handler sent over `transport`.
+    fn recv(transport: &mut MemoryTransport) -> ChatMessage {
+        let frame = transport
+            .read_frame()
+            .unwrap()
+            .expect("handler closed the pipe");
+        Frame::decode(&frame).unwrap().message
+    }
+
+    /// Stores a chat message from `username` in history, as if it had been broadcast.
+    fn store(state: &ServerState, username: &str, content: &str) {
+        broadcast_message(
+            state,
+            None,
+            ChatMessage {
+                username: Some(username.to_string()),
+                content: content.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn handle_client_registers_and_stores_a_message_over_memory_transport() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut client, handler) = connect(&state);
+        join(&mut client, "alice");
+        assert!(matches!(
+            recv(&mut client).message_type,
+            ChatMessageType::Capabilities
+        ));
+        let addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+        assert_eq!(
+            state
+                .usernames
+                .read()
+                .unwrap()
+                .get(&addr)
+                .map(String::as_str),
+            Some("alice")
+        );
+
+        send(
+            &mut client,
+            ChatMessage {
+                content: "hello".to_string(),
+                ..Default::default()
+            },
+        );
+        drop(client); // Disconnects once the message has been read.
+        handler.join().unwrap().unwrap();
+
+        let history = state.chat_history.read().unwrap();
+        let stored = history
+            .iter()
+            .find(|msg| matches!(msg.message_type, ChatMessageType::Message))
+            .expect("message not stored");
+        assert_eq!(stored.username.as_deref(), Some("alice"));
+        assert_eq!(stored.content, "hello");
+        assert_eq!(stored.seq, Some(2)); // After the join announcement.
+        assert!(state.clients.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_failing_after_registration_is_cleaned_up() {
+        let state = Arc::new(ServerState::new(test_config(&["--replay-window", "1"])));
+        store(&state, "bob", "first");
+        store(&state, "bob", "second");
+        let (mut client, handler) = connect(&state);
+        join(&mut client, "alice");
+        while !matches!(recv(&mut client).message_type, ChatMessageType::AckRequest) {}
+        assert_eq!(state.clients.read().unwrap().len(), 1);
+
+        // Leaving mid-replay fails the handler before it reaches the message loop.
+        drop(client);
+        assert!(handler.join().unwrap().is_err());
+        assert!(state.clients.read().unwrap().is_empty());
+        assert!(state.usernames.read().unwrap().is_empty());
+        assert_eq!(state.online.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn every_disconnect_path_drops_the_online_count_once() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--max-parse-failures",
+            "1",
+            "--heartbeat-timeout-secs",
+            "1",
+        ])));
+        let online = || state.online.load(Ordering::SeqCst);
+        let mut clients: Vec<_> = ["quit", "eof", "garbage", "silent"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let (mut client, handler) =
+                    TestClient::in_memory(&state, &format!("10.0.0.{}:5000", i + 1));
+                client.join(name);
+                client.sync();
+                (client, handler)
+            })
+            .collect();
+        assert_eq!(online(), 4);
+
+        // A silent client is reaped, which also ends its handler.
+        let (_silent, silent_handler) = clients.pop().unwrap();
+        let silent_addr: SocketAddr = "10.0.0.4:5000".parse().unwrap();
+        *state.clients.read().unwrap()[&silent_addr]
+            .last_seen
+            .lock()
+            .unwrap() = Instant::now() - Duration::from_secs(3);
+        reap_stale_clients(&state).unwrap();
+        let _ = silent_handler.join().unwrap();
+        assert_eq!(online(), 3);
+
+        // Garbage ends the session from the server's side.
+        let (mut garbage, garbage_handler) = clients.pop().unwrap();
+        garbage.send_raw("not json");
+        garbage_handler.join().unwrap().unwrap();
+        assert_eq!(online(), 2);
+
+        // A client that closes the connection without quitting.
+        let (eof, eof_handler) = clients.pop().unwrap();
+        drop(eof);
+        eof_handler.join().unwrap().unwrap();
+        assert_eq!(online(), 1);
+
+        let (mut quit, quit_handler) = clients.pop().unwrap();
+        quit.command("/quit");
+        quit.recv_reply(CommandType::Quit);
+        drop(quit);
+        quit_handler.join().unwrap().unwrap();
+        assert_eq!(online(), 0);
+        assert!(state.clients.read().unwrap().is_empty());
+        assert!(state.usernames.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_that_never_joins_is_dropped_after_the_registration_timeout() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--registration-timeout-secs",
+            "1",
+        ])));
+        let (mut client, handler) = connect(&state);
+        let started = Instant::now();
+        let result = handler.join().unwrap();
+        assert!(
+            matches!(&result, Err(ChatServerError::RegistrationTimeout(addr)) if addr == CLIENT_ADDR),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(client.read_frame().unwrap(), None); // The connection was closed.
+        assert!(state.clients.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mute_longer_than_a_deadline_can_hold_is_rejected() {
+        let state = ServerState::new(test_config(&[]));
+        let bob_addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+        state
+            .usernames
+            .write()
+            .unwrap()
+            .insert(bob_addr, "bob".to_string());
+        let (mut server_end, mut admin) =
+            MemoryTransport::pair("127.0.0.1:8081".parse().unwrap(), bob_addr);
+
+        mute_user(&mut server_end, &state, "admin", "bob", Some(Duration::MAX)).unwrap();
+        assert!(matches!(
+            recv(&mut admin).message_type,
+            ChatMessageType::Error
+        ));
+        assert!(state.muted.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_sending_garbage_gets_a_session_end() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--max-parse-failures",
+            "2",
+        ])));
+        let (mut client, handler) = connect(&state);
+        join(&mut client, "alice");
+        for _ in 0..2 {
+            client.write_frame("not json").unwrap();
+        }
+        handler.join().unwrap().unwrap();
+
+        let mut last_two = Vec::new();
+        while let Ok(Some(frame)) = client.read_frame() {
+            last_two.push(Frame::decode(&frame).unwrap().message);
+            if last_two.len() > 2 {
+                last_two.remove(0);
+            }
+        }
+        assert!(matches!(last_two[0].message_type, ChatMessageType::Error));
+        assert!(matches!(
+            last_two[1].message_type,
+            ChatMessageType::SessionEnd
+        ));
+    }
+
+    #[test]
+    fn only_consecutive_parse_failures_count_toward_the_limit() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--max-parse-failures",
+            "3",
+        ])));
+        let (mut alice, handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        alice.join("alice");
+        alice.sync();
+        for _ in 0..2 {
+            alice.send_raw("not json");
+        }
+        alice.say("a valid message resets the count");
+        for _ in 0..3 {
+            alice.send_raw("{\"broken\": ");
+        }
+        handler.join().unwrap().unwrap();
+
+        let mut received = alice.recv_to_end();
+        let last = received.pop().unwrap();
+        assert!(matches!(last.message_type, ChatMessageType::SessionEnd));
+        let errors = received
+            .iter()
+            .filter(|msg| matches!(msg.message_type, ChatMessageType::Error))
+            .count();
+        assert_eq!(errors, 6); // One for each bad frame, then the reason for disconnecting.
+        assert!(state.clients.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_replay_waits_for_an_ack_after_each_window() {
+        let state = Arc::new(ServerState::new(test_config(&["--replay-window", "2"])));
+        for i in 1..=5 {
+            store(&state, "bob", &format!("message {}", i));
+        }
+        let (mut client, _handler) = connect(&state);
+        join(&mut client, "alice");
+        assert!(matches!(
+            recv(&mut client).message_type,
+            ChatMessageType::Capabilities
+        ));
+
+        for window in [&[1, 2][..], &[3, 4]] {
+            for &i in window {
+                assert_eq!(recv(&mut client).content, format!("message {}", i));
+            }
+            assert!(matches!(
+                recv(&mut client).message_type,
+                ChatMessageType::AckRequest
+            ));
+            // Nothing more is sent until the client acknowledges the window.
+            client
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            assert!(client.read_frame().is_err());
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            send(
+                &mut client,
+                ChatMessage {
+                    message_type: ChatMessageType::Ack,
+                    ..Default::default()
+                },
+            );
+        }
+        // The last window isn't followed by an ack request.
+        assert_eq!(recv(&mut client).content, "message 5");
+        send(&mut client, Command::Version.into_message("alice"));
+        assert!(matches!(
+            recv(&mut client).message_type,
+            ChatMessageType::Command(CommandType::Version)
+        ));
+    }
+
+    #[test]
+    fn poisoned_history_lock_skips_replay_but_not_the_join() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        store(&state, "carol", "before the panic");
+        let poisoner = Arc::clone(&state);
+        let _ = thread::spawn(move || {
+            let _history = poisoner.chat_history.write().unwrap();
+            panic!("poisoning the history lock");
+        })
+        .join();
+        assert!(state.chat_history.is_poisoned());
+
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        let welcome = alice.sync();
+        assert!(welcome.iter().all(|msg| msg.content != "before the panic"));
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+
+        bob.say("still chatting");
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.username.as_deref(), Some("bob"));
+        assert_eq!(received.content, "still chatting");
+    }
+
+    #[test]
+    fn newest_first_replays_the_most_recent_message_first() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--history-order",
+            "newest-first",
+        ])));
+        for i in 1..=3 {
+            store(&state, "bob", &format!("message {}", i));
+        }
+        let (mut client, _) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.join("alice");
+        let replayed: Vec<String> = client
+            .sync()
+            .into_iter()
+            .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+            .map(|msg| msg.content)
+            .collect();
+        assert_eq!(replayed, ["message 3", "message 2", "message 1"]);
+    }
+
+    #[test]
+    fn reconnecting_client_is_told_what_it_missed_and_gets_only_that() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        for i in 1..=5 {
+            store(&state, "bob", &format!("message {}", i));
+        }
+        let (mut client, _) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.send(ChatMessage {
+            message_type: ChatMessageType::Join,
+            username: Some("alice".to_string()),
+            seq: Some(2), // Last seen before reconnecting.
+            ..Default::default()
+        });
+        let welcome = client.sync();
+        let unread = welcome
+            .iter()
+            .find(|msg| {
+                matches!(
+                    msg.message_type,
+                    ChatMessageType::Command(CommandType::Unread)
+                )
+            })
+            .expect("no unread count");
+        assert_eq!(unread.content, "You missed 3 message(s) while away.");
+        let replayed: Vec<u64> = welcome.iter().filter_map(|msg| msg.seq).collect();
+        assert_eq!(replayed, [3, 4, 5]);
+    }
+
+    #[test]
+    fn quitting_during_shutdown_leaves_consistent_maps() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let mut clients = Vec::new();
+        for i in 0..8 {
+            let (mut client, handler) =
+                TestClient::in_memory(&state, &format!("10.0.0.{}:5000", i));
+            client.join(&format!("user{}", i));
+            client.sync();
+            clients.push((client, handler));
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap(); // Woken by `shutdown`.
+        let quitters: Vec<_> = clients
+            .into_iter()
+            .map(|(mut client, handler)| {
+                thread::spawn(move || {
+                    let _ = client.try_send(Command::Quit.into_message("")); // May find the pipe closed.
+                    handler.join()
+                })
+            })
+            .collect();
+        crate::runtime::shutdown(&state, listener.local_addr().unwrap());
+
+        for quitter in quitters {
+            assert!(quitter.join().unwrap().is_ok(), "a handler panicked");
+        }
+        // Clients that quit before the shutdown are gone; the rest are left to it, whole.
+        let clients = state.clients.read().unwrap();
+        assert_eq!(state.online.load(Ordering::SeqCst), clients.len());
+        assert!(state
+            .usernames
+            .read()
+            .unwrap()
+            .keys()
+            .all(|addr| clients.contains_key(addr)));
+    }
+
+    /// Joins as `username` with the token `admin_state` configures, and becomes an admin.
+    fn join_as_admin(state: &Arc<ServerState>, addr: &str, username: &str) -> TestClient {
+        let (mut client, _) = TestClient::in_memory(state, addr);
+        client.join(username);
+        client.command(&format!("/admin {}", ADMIN_TOKEN));
+        client.recv_reply(CommandType::Admin);
+        client
+    }
+
+    /// Admin token of the state `admin_state` creates.
+    const ADMIN_TOKEN: &str = "secret";
+
+    /// A server state with `args` and `ADMIN_TOKEN` as the admin token.
+    fn admin_state(args: &[&str]) -> Arc<ServerState> {
+        let mut args = args.to_vec();
+        args.extend(["--admin-token", ADMIN_TOKEN]);
+        Arc::new(ServerState::new(test_config(&args)))
+    }
+
+    #[test]
+    fn admin_commands_are_refused_to_users_and_run_for_admins() {
+        let state = admin_state(&[]);
+        let (mut user, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        user.join("bob");
+        user.sync();
+        user.command("/dumpstate");
+        let error = user.recv();
+        assert!(matches!(error.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "Insufficient privileges.");
+
+        let mut admin = join_as_admin(&state, CLIENT_ADDR, "alice");
+        admin.command("/dumpstate");
+        assert!(!admin.recv_reply(CommandType::DumpState).content.is_empty());
+    }
+
+    #[test]
+    fn setmaxlen_changes_which_messages_are_accepted() {
+        let state = admin_state(&[]);
+        let mut admin = join_as_admin(&state, CLIENT_ADDR, "alice");
+        admin.command("/setmaxlen 50");
+        admin.recv_reply(CommandType::SetMaxLen);
+
+        admin.say(&"x".repeat(60));
+        assert!(matches!(admin.recv().message_type, ChatMessageType::Error));
+        admin.say(&"y".repeat(40));
+        admin.sync();
+        let history = state.chat_history.read().unwrap();
+        let stored: Vec<&str> = history
+            .iter()
+            .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+            .map(|msg| msg.content.as_str())
+            .collect();
+        assert_eq!(stored, ["y".repeat(40)]);
+    }
+
+    #[test]
+    fn muted_user_is_not_broadcast_until_the_mute_expires() {
+        let state = admin_state(&[]);
+        let mut admin = join_as_admin(&state, "10.0.0.1:5000", "alice");
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        admin.command("/mute bob 1s");
+        admin.recv_reply(CommandType::Mute);
+
+        bob.say("can anyone hear me?");
+        let notice = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(notice.content, "You are muted.");
+        thread::sleep(Duration::from_millis(1100));
+        bob.say("back again");
+        let received = admin.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.content, "back again");
+        let history = state.chat_history.read().unwrap();
+        assert!(!history
+            .iter()
+            .any(|msg| msg.content == "can anyone hear me?"));
+    }
+
+    #[test]
+    fn repeating_the_same_message_triggers_an_auto_mute() {
+        let state = Arc::new(ServerState::new(test_config(&["--spam-repeats", "3"])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+
+        // Near-identical copies count as repeats; the third is dropped and earns the mute.
+        for copy in ["Buy now!!", "buy   now", "BUY NOW"] {
+            bob.say(copy);
+        }
+        let error = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            "You are muted for 60s for sending the same message repeatedly."
+        );
+        assert!(state.is_muted("bob"));
+        bob.say("but this is different");
+        assert_eq!(bob.recv().content, "You are muted.");
+
+        let received: Vec<String> = alice
+            .sync()
+            .into_iter()
+            .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+            .map(|msg| msg.content)
+            .collect();
+        assert_eq!(received, ["Buy now!!", "buy   now"]);
+    }
+
+    #[test]
+    fn spam_mute_too_long_for_a_deadline_is_permanent() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--spam-repeats",
+            "2",
+            "--spam-mute-secs",
+            "18446744073709551615",
+        ])));
+        let (mut bob, handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        bob.join("bob");
+        bob.sync();
+
+        bob.say("again");
+        bob.say("again");
+        let error = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            "You are muted until an admin unmutes you for sending the same message repeatedly."
+        );
+        assert_eq!(state.muted.read().unwrap().get("bob"), Some(&None));
+        bob.say("still there?");
+        assert_eq!(bob.recv().content, "You are muted.");
+        assert!(!handler.is_finished());
+    }
+
+    #[test]
+    fn nothing_is_read_from_a_client_after_it_leaves() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, handler) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+
+        bob.send(ChatMessage {
+            message_type: ChatMessageType::Leave,
+            ..Default::default()
+        });
+        // The server may already have closed the connection, so the write can fail.
+        let _ = bob.try_send(ChatMessage {
+            content: "still here?".to_string(),
+            ..Default::default()
+        });
+        assert!(handler.join().unwrap().is_ok());
+
+        assert!(!alice.sync().iter().any(|msg| msg.content == "still here?"));
+        let history = state.chat_history.read().unwrap();
+        assert!(!history.iter().any(|msg| msg.content == "still here?"));
+    }
+
+    #[test]
+    fn command_without_a_handler_is_not_available() {
+        let mut state = ServerState::new(test_config(&[]));
+        state.commands = CommandRegistry::default();
+        let (mut client, _) = TestClient::in_memory(&Arc::new(state), CLIENT_ADDR);
+        client.join("alice");
+        client.command("/uptime");
+        let error = client.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "/uptime is not available.");
+    }
+
+    /// Joins `username` from `addr`, then has it leave with a leave message.
+    fn join_and_leave(state: &Arc<ServerState>, addr: &str, username: &str) {
+        let (mut client, handler) = TestClient::in_memory(state, addr);
+        client.join(username);
+        client.sync();
+        client.send(ChatMessage {
+            message_type: ChatMessageType::Leave,
+            ..Default::default()
+        });
+        handler.join().unwrap().unwrap();
+    }
+
+    /// The join and leave announcements `client` has received, in order.
+    fn presence_announcements(client: &mut TestClient) -> Vec<String> {
+        client
+            .sync()
+            .into_iter()
+            .filter(|msg| {
+                matches!(
+                    msg.message_type,
+                    ChatMessageType::Join | ChatMessageType::Leave
+                )
+            })
+            .map(|msg| msg.content)
+            .collect()
+    }
+
+    #[test]
+    fn another_host_taking_the_name_within_the_grace_period_announces_both() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        join_and_leave(&state, "10.0.0.2:5000", "bob");
+        alice.sync(); // Reads bob's join announcement.
+
+        let (mut impostor, _) = TestClient::in_memory(&state, "10.0.0.3:5000");
+        impostor.join("bob");
+        impostor.sync();
+        assert_eq!(
+            presence_announcements(&mut alice),
+            ["bob has left the chat", "bob has joined the chat"]
+        );
+        assert!(state.pending_leaves.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn longest_grace_holds_the_leave_without_panicking() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--rejoin-grace-ms",
+            "18446744073709551615",
+        ])));
+        join_and_leave(&state, CLIENT_ADDR, "bob");
+
+        announce_pending_leaves(&state).unwrap();
+        assert!(state.pending_leaves.read().unwrap().contains_key("bob"));
+    }
+
+    #[test]
+    fn ping_all_names_the_client_that_didnt_answer() {
+        let state = admin_state(&["--ping-timeout-ms", "300"]);
+        let mut admin = join_as_admin(&state, "10.0.0.1:5000", "alice");
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        let (mut carol, _) = TestClient::in_memory(&state, "10.0.0.3:5000");
+        carol.join("carol");
+        carol.sync();
+        let responder = thread::spawn(move || {
+            let ping = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Ping));
+            bob.send(ChatMessage {
+                message_type: ChatMessageType::Pong,
+                seq: ping.seq,
+                ..Default::default()
+            });
+            bob
+        });
+
+        admin.command("/ping-all");
+        let summary = admin.recv_reply(CommandType::PingAll).content;
+        responder.join().unwrap();
+        assert!(
+            summary.starts_with("Pinged 2 client(s): min "),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with("No response from: carol."), "{}", summary);
+    }
+
+    #[test]
+    fn clients_cant_pose_as_the_server() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut impostor, _) = TestClient::in_memory(&state, "10.0.0.9:5000");
+        impostor.join("Server");
+        assert!(matches!(
+            impostor.recv().message_type,
+            ChatMessageType::Error
+        ));
+        assert!(state.usernames.read().unwrap().is_empty());
+
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut mallory, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        mallory.join("mallory");
+        mallory.sync();
+        mallory.send(ChatMessage {
+            username: Some("server".to_string()),
+            content: "The server is shutting down, send your password".to_string(),
+            system: true,
+            ..Default::default()
+        });
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert!(!received.system);
+        assert_eq!(received.username.as_deref(), Some("mallory"));
+    }
+
+    #[test]
+    fn reactions_are_aggregated_and_each_user_counts_once() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        store(&state, "carol", "lunch?"); // seq 1.
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        let next_reaction = |client: &mut TestClient| {
+            let msg =
+                client.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Reaction));
+            assert_eq!(msg.seq, Some(1));
+            msg.content
+        };
+
+        bob.command("/react 1 👍");
+        assert_eq!(next_reaction(&mut alice), "👍 x1");
+        alice.command("/react 1 👍");
+        assert_eq!(next_reaction(&mut alice), "👍 x2");
+        assert_eq!(next_reaction(&mut bob), "👍 x1");
+        assert_eq!(next_reaction(&mut bob), "👍 x2");
+
+        alice.command("/react 1 👍");
+        assert!(matches!(alice.recv().message_type, ChatMessageType::Error));
+        alice.command("/react 1 ❤️");
+        assert_eq!(next_reaction(&mut bob), "❤️ x1  👍 x2");
+        assert_eq!(next_reaction(&mut alice), "❤️ x1  👍 x2");
+        alice.command("/react 2 👍");
+        assert!(matches!(alice.recv().message_type, ChatMessageType::Error));
+    }
+
+    #[test]
+    fn join_after_registration_is_refused_without_a_broadcast() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+        let history_len = state.chat_history.read().unwrap().len();
+
+        bob.join("robert");
+        let error = bob.recv();
+        assert!(matches!(error.message_type, ChatMessageType::Error));
+        assert!(error.content.contains("already joined as 'bob'"));
+        bob.say("still here"); // The connection keeps working.
+        let next = alice.recv();
+        assert!(matches!(next.message_type, ChatMessageType::Message));
+        assert_eq!(next.content, "still here");
+        assert_eq!(state.chat_history.read().unwrap().len(), history_len + 1);
+        bob.command("/list");
+        assert_eq!(
+            bob.recv_reply(CommandType::List).content,
+            "Online users: alice, bob"
+        );
+    }
+
+    #[test]
+    fn only_one_of_two_simultaneous_joins_gets_the_name() {
+        for _ in 0..20 {
+            let state = Arc::new(ServerState::new(test_config(&[])));
+            let barrier = Barrier::new(2);
+            let mut clients = ["10.0.0.1:5000", "10.0.0.2:5000"]
+                .map(|addr| TestClient::in_memory(&state, addr).0);
+            let taken: Vec<bool> = thread::scope(|scope| {
+                let joins: Vec<_> = clients
+                    .iter_mut()
+                    .map(|client| {
+                        let barrier = &barrier;
+                        scope.spawn(move || {
+                            barrier.wait();
+                            client.join("bob");
+                            // The winner is welcomed; the loser may see the winner's
+                            // announcement before its error.
+                            let reply = client.recv_until(|msg| {
+                                matches!(
+                                    msg.message_type,
+                                    ChatMessageType::Capabilities | ChatMessageType::Error
+                                )
+                            });
+                            reply.content == "The username 'bob' is taken."
+                        })
+                    })
+                    .collect();
+                joins.into_iter().map(|join| join.join().unwrap()).collect()
+            });
+
+            assert_eq!(taken.iter().filter(|&&taken| taken).count(), 1);
+            let usernames = state.usernames.read().unwrap();
+            assert_eq!(usernames.len(), 1);
+            assert!(usernames.values().all(|name| name == "bob"));
+        }
+    }
+
+    #[test]
+    fn broadcasts_are_compressed_only_for_clients_that_opted_in() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.send(ChatMessage {
+            message_type: ChatMessageType::Capabilities,
+            content: serde_json::to_string(&[COMPRESSION_FEATURE]).unwrap(),
+            ..Default::default()
+        });
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+        let (mut carol, _) = TestClient::in_memory(&state, "10.0.0.3:5000");
+        carol.join("carol");
+        carol.sync();
+        alice.sync();
+        bob.sync();
+
+        let content = "la ".repeat(160); // Within the length limit, but long enough to compress.
+        carol.say(&content);
+        let compressed = alice.recv();
+        assert!(matches!(
+            compressed.message_type,
+            ChatMessageType::Compressed
+        ));
+        let message = decompress_message(&compressed).unwrap();
+        assert!(matches!(message.message_type, ChatMessageType::Message));
+        assert_eq!(message.content, content);
+        let plain = bob.recv();
+        assert!(matches!(plain.message_type, ChatMessageType::Message));
+        assert_eq!(plain.content, content);
+    }
+
+    #[test]
+    fn history_mode_decides_what_joining_clients_get_replayed() {
+        let state = admin_state(&[]);
+        store(&state, "carol", "first"); // seq 1.
+        store(&state, "carol", "second"); // seq 2.
+        let mut admin = join_as_admin(&state, CLIENT_ADDR, "alice");
+        // Joins as `name`, reporting `last_seen_seq` as a returning client would,
+        // and returns the chat messages replayed.
+        let replayed = |name: &str, last_seen_seq: Option<u64>| -> Vec<String> {
+            let (mut client, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+            client.send(ChatMessage {
+                message_type: ChatMessageType::Join,
+                username: Some(name.to_string()),
+                seq: last_seen_seq,
+                ..Default::default()
+            });
+            let replayed = client
+                .sync()
+                .into_iter()
+                .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+                .map(|msg| msg.content)
+                .collect();
+            client.command("/quit");
+            client.recv_reply(CommandType::Quit);
+            replayed
+        };
+
+        assert_eq!(replayed("bob", None), ["first", "second"]);
+        for (mode, new, returning) in [
+            ("private", vec![], vec!["second"]),
+            ("off", vec![], vec![]),
+            ("public", vec!["first", "second"], vec!["second"]),
+        ] {
+            admin.command(&format!("/history-mode {}", mode));
+            admin.recv_reply(CommandType::HistoryMode);
+            assert_eq!(replayed("bob", None), new, "{} for a new client", mode);
+            assert_eq!(
+                replayed("bob", Some(1)),
+                returning,
+                "{} for a returning client",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn nick_broadcasts_a_rename_event_with_both_names() {
+        for store_renames in [false, true] {
+            let args: &[&str] = if store_renames {
+                &["--store-renames"]
+            } else {
+                &[]
+            };
+            let state = Arc::new(ServerState::new(test_config(args)));
+            let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+            alice.join("alice");
+            alice.sync();
+            let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+            bob.join("bob");
+            bob.sync();
+
+            bob.command("/nick robert");
+            let expected = Rename {
+                old_name: "bob".to_string(),
+                new_name: "robert".to_string(),
+            };
+            for client in [&mut alice, &mut bob] {
+                let event =
+                    client.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Rename));
+                assert!(event.system);
+                assert_eq!(event.username.as_deref(), Some("robert"));
+                let rename: Rename = serde_json::from_str(&event.content).unwrap();
+                assert_eq!(rename, expected);
+            }
+            let stored = state
+                .chat_history
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|msg| matches!(msg.message_type, ChatMessageType::Rename))
+                .count();
+            assert_eq!(stored, usize::from(store_renames));
+        }
+    }
+
+    #[test]
+    fn join_past_the_member_cap_is_rejected_until_someone_leaves() {
+        let state = Arc::new(ServerState::new(test_config(&["--max-members", "2"])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, bob_handler) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+
+        let (mut carol, handler) = TestClient::in_memory(&state, "10.0.0.3:5000");
+        carol.join("carol");
+        let error = carol.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            "The chat is full (2 members). Please try again later."
+        );
+        assert!(matches!(
+            handler.join().unwrap(),
+            Err(ChatServerError::ChatFull(_))
+        ));
+        assert_eq!(state.usernames.read().unwrap().len(), 2);
+
+        bob.command("/quit");
+        bob.recv_reply(CommandType::Quit);
+        drop(bob);
+        bob_handler.join().unwrap().unwrap();
+        let (mut carol, _) = TestClient::in_memory(&state, "10.0.0.3:5000");
+        carol.join("carol");
+        carol.sync();
+        carol.command("/list");
+        assert_eq!(
+            carol.recv_reply(CommandType::List).content,
+            "Online users: alice, carol"
+        );
+    }
+
+    #[test]
+    fn silent_client_is_listed_as_stale_before_it_is_reaped() {
+        let state = Arc::new(ServerState::new(test_config(&[
+            "--heartbeat-timeout-secs",
+            "1",
+        ])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        let bob_addr: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        // Pretends bob last answered `ago` before now.
+        let silent_for = |ago: Duration| {
+            let clients = state.clients.read().unwrap();
+            *clients[&bob_addr].last_seen.lock().unwrap() = Instant::now() - ago;
+        };
+        let mut list = || {
+            alice.command("/list");
+            alice.recv_reply(CommandType::List).content
+        };
+
+        silent_for(Duration::from_millis(1500)); // Past the timeout.
+        assert_eq!(list(), "Online users: alice, bob (stale)");
+        reap_stale_clients(&state).unwrap();
+        assert!(state.clients.read().unwrap().contains_key(&bob_addr));
+
+        silent_for(Duration::from_millis(2500)); // Past twice the timeout.
+        reap_stale_clients(&state).unwrap();
+        assert!(!state.clients.read().unwrap().contains_key(&bob_addr));
+        assert_eq!(list(), "Online users: alice");
+    }
+
+    #[test]
+    fn message_sent_before_joining_is_rejected_without_state() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut client, handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.send(ChatMessage {
+            username: Some("alice".to_string()),
+            content: "hi".to_string(),
+            ..Default::default()
+        });
+        let error = client.recv();
+        assert!(matches!(error.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            "You must send a join message before anything else."
+        );
+        assert!(matches!(
+            handler.join().unwrap(),
+            Err(ChatServerError::JoinRequired(_))
+        ));
+        assert!(client.recv_to_end().is_empty());
+        assert!(state.clients.read().unwrap().is_empty());
+        assert!(state.usernames.read().unwrap().is_empty());
+        assert!(state.chat_history.read().unwrap().is_empty());
+        assert_eq!(state.online.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn oversized_username_is_rejected_without_being_stored() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut client, handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.join(&"x".repeat(10_000));
+        let error = client.recv();
+        assert!(matches!(error.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            format!(
+                "Usernames can be at most {} characters long.",
+                MAX_USERNAME_LEN
+            )
+        );
+        assert!(matches!(
+            handler.join().unwrap(),
+            Err(ChatServerError::UsernameTooLong(_))
+        ));
+        assert!(state.usernames.read().unwrap().is_empty());
+        assert!(state.chat_history.read().unwrap().is_empty()); // No join announcement.
+
+        // A name right at the limit is fine, however many bytes it takes.
+        let name = "é".repeat(MAX_USERNAME_LEN);
+        let (mut client, _) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.join(&name);
+        client.sync();
+        let addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+        assert_eq!(state.usernames.read().unwrap()[&addr], name);
+    }
+
+    #[test]
+    fn guests_are_given_unique_names_skipping_taken_ones() {
+        let state = Arc::new(ServerState::new(test_config(&["--guests"])));
+        let (mut taken, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        taken.join("guest-1");
+        taken.sync();
+        let guest = |addr: &str| {
+            let (mut client, _) = TestClient::in_memory(&state, addr);
+            client.join("");
+            let notice = client.recv();
+            assert!(matches!(notice.message_type, ChatMessageType::Join));
+            assert!(notice.system);
+            let name = notice.username.unwrap();
+            assert_eq!(notice.content, format!("You joined as {}.", name));
+            client.sync();
+            (client, name)
+        };
+
+        let (mut first, first_name) = guest("10.0.0.2:5000");
+        let (_second, second_name) = guest("10.0.0.3:5000");
+        assert_eq!([first_name, second_name], ["guest-2", "guest-3"]);
+        first.command("/list");
+        assert_eq!(
+            first.recv_reply(CommandType::List).content,
+            "Online users: guest-1, guest-2, guest-3"
+        );
+
+        // Without `--guests` a name is required.
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut client, handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        client.join("");
+        assert_eq!(
+            client.recv().content,
+            "A username is required; this server doesn't allow guests."
+        );
+        assert!(handler.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn message_is_pruned_from_history_once_its_ttl_elapses() {
+        let state = Arc::new(ServerState::new(test_config(&["--expiry-notices"])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+
+        bob.say("kept");
+        bob.send(ChatMessage {
+            content: "gone soon".to_string(),
+            ttl: Some(1),
+            ..Default::default()
+        });
+        alice.recv_until(|msg| msg.content == "gone soon");
+        let contents = || -> Vec<String> {
+            let history = state.chat_history.read().unwrap();
+            history.iter().map(|msg| msg.content.clone()).collect()
+        };
+        expire_messages(&state).unwrap(); // The TTL hasn't elapsed yet.
+        assert!(contents().contains(&"gone soon".to_string()));
+
+        // Pretends the message was sent two seconds ago.
+        let seq = {
+            let mut history = state.chat_history.write().unwrap();
+            let msg = history.iter_mut().find(|msg| msg.ttl.is_some()).unwrap();
+            msg.timestamp = msg.timestamp.map(|timestamp| timestamp - 2);
+            msg.seq.unwrap()
+        };
+        expire_messages(&state).unwrap();
+        assert!(!contents().contains(&"gone soon".to_string()));
+        assert!(contents().contains(&"kept".to_string()));
+        let notice = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Expired));
+        assert_eq!(notice.content, format!("[{}]", seq));
+    }
+
+    #[test]
+    fn message_with_an_unknown_encoding_is_rejected() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        alice.sync(); // Reads bob's join announcement.
+        let history_len = state.chat_history.read().unwrap().len();
+
+        for (encoding, reason) in [
+            ("latin-1", "Unknown content encoding."),
+            ("base64", "Messages must be utf-8 text, not base64."),
+        ] {
+            let message = ChatMessage {
+                content: "aGk=".to_string(),
+                ..Default::default()
+            };
+            let mut frame: serde_json::Value =
+                serde_json::from_str(&Frame::encode(&message).unwrap()).unwrap();
+            frame["message"]["encoding"] = encoding.into();
+            alice.send_raw(&frame.to_string());
+            let error = alice.recv();
+            assert!(matches!(error.message_type, ChatMessageType::Error));
+            assert_eq!(error.content, reason);
+        }
+        assert!(bob
+            .sync()
+            .iter()
+            .all(|msg| !matches!(msg.message_type, ChatMessageType::Message)));
+        assert_eq!(state.chat_history.read().unwrap().len(), history_len);
+    }
+
+    #[test]
+    fn reconnecting_from_the_same_address_replaces_the_stale_connection() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+        let (mut old, old_handler) = TestClient::in_memory(&state, CLIENT_ADDR);
+        old.join("bob");
+        old.sync();
+        let old_id = state.clients.read().unwrap()[&addr].id;
+
+        // The old connection hasn't been cleaned up when the new one arrives.
+        let (mut new, _) = TestClient::in_memory(&state, CLIENT_ADDR);
+        new.join("bob");
+        new.sync();
+        old_handler.join().unwrap().unwrap(); // Its connection was shut down, ending it.
+        cleanup_client(&state, addr, old_id); // A late cleanup of the old connection.
+
+        let new_id = state.clients.read().unwrap()[&addr].id;
+        assert_ne!(new_id, old_id);
+        assert_eq!(state.usernames.read().unwrap()[&addr], "bob");
+        assert_eq!(state.online.load(Ordering::SeqCst), 1);
+        new.command("/list");
+        assert_eq!(
+            new.recv_reply(CommandType::List).content,
+            "Online users: bob"
+        );
+    }
+
+    #[test]
+    fn replies_reference_a_message_in_history() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        store(&state, "carol", "lunch?"); // seq 1.
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+
+        bob.command("/reply 1 sure, noon?");
+        let reply = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(reply.reply_to, Some(1));
+        assert_eq!(reply.content, "sure, noon?");
+        assert_eq!(reply.username.as_deref(), Some("bob"));
+        let stored = state.chat_history.read().unwrap().last().cloned().unwrap();
+        assert_eq!(stored.reply_to, Some(1));
+
+        // Seq 2 is alice's join announcement, not a chat message.
+        let history_len = state.chat_history.read().unwrap().len();
+        for seq in [2, 99] {
+            bob.command(&format!("/reply {} hello?", seq));
+            let error = bob.recv();
+            assert!(matches!(error.message_type, ChatMessageType::Error));
+            assert_eq!(error.content, format!("No message with seq {}.", seq));
+        }
+        assert_eq!(state.chat_history.read().unwrap().len(), history_len);
+    }
+
+    #[test]
+    fn quote_attaches_the_original_message() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        store(&state, "carol", "who's in for lunch?"); // seq 1.
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+
+        bob.command("/quote 1 me!");
+        let quoting = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(quoting.content, "me!");
+        assert_eq!(
+            quoting.quote,
+            Some(Quote {
+                seq: 1,
+                username: "carol".to_string(),
+                content: "who's in for lunch?".to_string(),
+            })
+        );
+        let stored = state.chat_history.read().unwrap().last().cloned().unwrap();
+        assert_eq!(stored.quote, quoting.quote);
+
+        // Seq 2 is alice's join announcement, not a chat message.
+        let history_len = state.chat_history.read().unwrap().len();
+        for seq in [2, 99] {
+            bob.command(&format!("/quote {} me too", seq));
+            let error = bob.recv();
+            assert!(matches!(error.message_type, ChatMessageType::Error));
+            assert_eq!(error.content, format!("No message with seq {}.", seq));
+        }
+        assert_eq!(state.chat_history.read().unwrap().len(), history_len);
+    }
+
+    #[test]
+    fn last_finds_messages_sent_under_a_former_name() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        alice.say("before the rename");
+        alice.command("/nick alicia");
+        alice.recv_reply(CommandType::Nick);
+
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        bob.command("/last alicia");
+        assert_eq!(
+            bob.recv_reply(CommandType::Last).content,
+            "Last message from alicia (as alice): before the rename"
+        );
+        bob.command("/last alice");
+        assert_eq!(
+            bob.recv_reply(CommandType::Last).content,
+            "Last message from alicia (as alice): before the rename"
+        );
     }
 }
@@ -0,0 +1,1855 @@
+// commands.rs
+use crate::client_handler::{
+    broadcast_system_message, deliver_broadcast, handle_client_disconnect, send_error,
+    send_message_to_addr, send_message_to_client, unix_timestamp,
+}; // Shared helpers for replying to and broadcasting on behalf of a client.
+use crate::config::MAX_MESSAGE_LEN_BOUNDS; // Values `/reload` may set the message length limit to.
+use crate::errors::ChatResult; // Custom result type for error handling.
+use crate::message::{
+    format_time, ChatMessage, ChatMessageType, Command, CommandType, Frame, HistoryDownload,
+    HistoryMode, NotifyMode, Priority, EVERYONE_TARGET, PROTOCOL_VERSION,
+}; // Commands and the replies they produce.
+use crate::state::{Broadcast, ScheduledMessage, ServerState}; // Shared server state.
+use crate::transport::Transport; // Frame-based connection to the client.
+use base64::engine::general_purpose::STANDARD as BASE64; // Shared files travel as base64 text.
+use base64::Engine; // Provides `decode` on the engine.
+use std::collections::HashMap; // Handlers by command name.
+use std::net::SocketAddr; // Address used to identify each client.
+use std::path::Path; // File names of shared files.
+use std::sync::atomic::Ordering; // Reading the current message length limit.
+use std::time::{Duration, Instant}; // Server uptime and the file cooldown.
+
+/// Everything a command handler needs to know about the client that sent the command.
+pub struct CommandContext<'a> {
+    pub transport: &'a mut dyn Transport, // The client's connection.
+    pub state: &'a ServerState,           // Shared server state.
+    pub peer_addr: SocketAddr,            // The client's address.
+    pub username: &'a str,                // The client's username.
+    pub connection_id: u64,               // Id of the client's connection.
+}
+
+/// Users listed per `/list` page. Smaller servers get everyone in one line, as before.
+const LIST_PAGE_SIZE: usize = 50;
+
+/// Most messages `/find` returns.
+const FIND_RESULT_LIMIT: usize = 20;
+
+/// Entries `/auditlog` shows when no count is given.
+const AUDIT_LOG_DEFAULT_COUNT: usize = 10;
+
+/// Events `/recent` shows when no count is given.
+const RECENT_DEFAULT_COUNT: usize = 10;
+
+/// Longest status `/status` accepts, in characters.
+const MAX_STATUS_LEN: usize = 60;
+
+/// Most messages that can be pinned at once.
+const MAX_PINS: usize = 10;
+
+/// Longest delay `/schedule` accepts: one week.
+const MAX_SCHEDULE_DELAY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Builds a `CommandHandler` that runs `$body` with the fields bound by `$pattern`.
+/// The registry only passes a handler commands of the type it was registered for,
+/// so a command that doesn't match `$pattern` never reaches it.
+macro_rules! handler {
+    (|$ctx:ident, $pattern:pat_param| $body:expr) => {
+        Box::new(
+            |$ctx: &mut CommandContext, command: &Command| match command {
+                $pattern => $body,
+                _ => Ok(()),
+            },
+        )
+    };
+}
+
+/// A handler for a registered command.
+pub type CommandHandler =
+    Box<dyn Fn(&mut CommandContext, &Command) -> ChatResult<()> + Send + Sync>;
+
+/// Maps command names to their handlers, so commands can be added without
+/// touching the dispatch code in `client_handler`.
+#[derive(Default)]
+pub struct CommandRegistry {
+    handlers: HashMap<&'static str, CommandHandler>, // Handlers by command name.
+}
+
+impl CommandRegistry {
+    /// Creates a registry with the server's built-in commands.
+    pub fn with_builtin_commands() -> Self {
+        let mut registry = Self::default();
+        registry.register(
+            CommandType::List,
+            handler!(|ctx, Command::List { page }| send_user_list(ctx, *page)),
+        );
+        registry.register(
+            CommandType::Quit,
+            Box::new(|ctx, _| {
+                handle_client_disconnect(
+                    ctx.transport,
+                    ctx.state,
+                    ctx.peer_addr,
+                    ctx.username,
+                    ctx.connection_id,
+                    &ChatMessageType::Command(CommandType::Quit),
+                )
+            }),
+        );
+        registry.register(
+            CommandType::Slap,
+            handler!(|ctx, Command::Slap { target }| slap(ctx, target)),
+        );
+        registry.register(
+            CommandType::GrantAdmin,
+            handler!(|ctx, Command::GrantAdmin { username }| grant_admin(ctx, username)),
+        );
+        registry.register(
+            CommandType::RevokeAdmin,
+            handler!(|ctx, Command::RevokeAdmin { username }| revoke_admin(ctx, username)),
+        );
+        registry.register(
+            CommandType::Status,
+            handler!(|ctx, Command::Status { text }| set_status(ctx, text.clone())),
+        );
+        registry.register(
+            CommandType::Pin,
+            handler!(|ctx, Command::Pin { seq }| pin_message(ctx, *seq)),
+        );
+        registry.register(
+            CommandType::Unpin,
+            handler!(|ctx, Command::Unpin { seq }| unpin_message(ctx, *seq)),
+        );
+        registry.register(CommandType::Pins, Box::new(|ctx, _| send_pins(ctx)));
+        registry.register(
+            CommandType::Notify,
+            handler!(|ctx, Command::Notify(mode)| set_notify_mode(ctx, *mode)),
+        );
+        registry.register(CommandType::Version, Box::new(|ctx, _| send_version(ctx)));
+        registry.register(CommandType::Uptime, Box::new(|ctx, _| send_uptime(ctx)));
+        registry.register(
+            CommandType::DownloadHistory,
+            Box::new(|ctx, _| send_history_download(ctx)),
+        );
+        registry.register(
+            CommandType::Find,
+            handler!(|ctx, Command::Find { text }| find_messages(ctx, text)),
+        );
+        registry.register(
+            CommandType::PublicKey,
+            handler!(|ctx, Command::PublicKey { target, key }| {
+                relay_to_user(ctx, CommandType::PublicKey, target, key)
+            }),
+        );
+        registry.register(
+            CommandType::Encrypted,
+            handler!(|ctx, Command::Encrypted { target, payload }| {
+                relay_to_user(ctx, CommandType::Encrypted, target, payload)?;
+                reply(
+                    ctx,
+                    CommandType::Encrypted,
+                    format!("To {} (encrypted).", target),
+                )
+            }),
+        );
+        registry.register(
+            CommandType::SharedFile,
+            handler!(|ctx, Command::SharedFile { name, data }| share_file(ctx, name, data)),
+        );
+        registry.register(
+            CommandType::AuditLog,
+            handler!(|ctx, Command::AuditLog { count }| send_audit_log(ctx, *count)),
+        );
+        registry.register(CommandType::Reload, Box::new(|ctx, _| reload_config(ctx)));
+        registry.register(
+            CommandType::Recent,
+            handler!(|ctx, Command::Recent { count }| send_recent_connections(ctx, *count)),
+        );
+        registry.register(
+            CommandType::Schedule,
+            handler!(
+                |ctx, Command::Schedule { delay, text }| schedule_announcement(ctx, *delay, text)
+            ),
+        );
+        registry.register(
+            CommandType::Unschedule,
+            handler!(|ctx, Command::Unschedule { id }| cancel_announcement(ctx, *id)),
+        );
+        registry.register(
+            CommandType::HistoryMode,
+            handler!(|ctx, Command::HistoryMode(mode)| set_history_mode(ctx, *mode)),
+        );
+        registry.register(
+            CommandType::Msg,
+            handler!(|ctx, Command::Msg { targets, text }| send_private_message(
+                ctx, targets, text
+            )),
+        );
+        registry
+    }
+
+    /// Registers `handler` for the command, replacing any previous handler.
+    pub fn register(&mut self, command_type: CommandType, handler: CommandHandler) {
+        self.handlers.insert(command_type.name(), handler);
+    }
+
+    /// Runs the handler registered for `command`.
+    /// Returns `None` if the command has no handler in the registry.
+    pub fn dispatch(
+        &self,
+        context: &mut CommandContext, // The client that sent the command.
+        command: &Command,            // The decoded command.
+    ) -> Option<ChatResult<()>> {
+        let handler = self.handlers.get(command.command_type().name())?;
+        Some(handler(context, command))
+    }
+}
+
+/// Sends the list of online users to the client.
+fn send_user_list(ctx: &mut CommandContext, page: Option<usize>) -> ChatResult<()> {
+    // Only users with a live connection are listed, so dead sockets don't show up as ghosts.
+    // Users who stopped answering heartbeats are listed, but marked until they are reaped.
+    let users: Vec<String> = ctx
+        .state
+        .online_users()?
+        .into_iter()
+        .map(|(name, stale, status)| {
+            let name = if stale {
+                format!("{} (stale)", name)
+            } else {
+                name
+            };
+            match status {
+                Some(status) => format!("{} ({})", name, status),
+                None => name,
+            }
+        })
+        .collect();
+
+    let content = if users.is_empty() {
+        "No users online.".to_string() // Message for when no users are online.
+    } else if users.len() <= LIST_PAGE_SIZE && page.is_none_or(|page| page == 1) {
+        format!("Online users: {}", users.join(", ")) // Format the usernames as a comma-separated string.
+    } else {
+        // Large servers get one page at a time, so the reply stays a manageable size.
+        let pages = users.len().div_ceil(LIST_PAGE_SIZE);
+        let page = page.unwrap_or(1);
+        if page > pages {
+            return send_error(
+                ctx.transport,
+                format!("There are only {} page(s) of users.", pages),
+            );
+        }
+        let start = (page - 1) * LIST_PAGE_SIZE;
+        let shown = &users[start..users.len().min(start + LIST_PAGE_SIZE)];
+        let more = if page < pages {
+            format!(" Use /list {} for more.", page + 1)
+        } else {
+            String::new()
+        };
+        format!(
+            "Online users (page {} of {}, {} total): {}.{}",
+            page,
+            pages,
+            users.len(),
+            shown.join(", "),
+            more
+        )
+    };
+
+    // Sent as a server reply to the requester only. It never goes through
+    // `broadcast_message`, so it isn't stored in history or replayed to others.
+    reply(ctx, CommandType::List, content)
+}
+
+/// Sets or, with no text, clears the client's status. Only `/list` shows it;
+/// nothing is broadcast.
+fn set_status(ctx: &mut CommandContext, text: Option<String>) -> ChatResult<()> {
+    if let Some(text) = &text {
+        if text.chars().count() > MAX_STATUS_LEN {
+            return send_error(
+                ctx.transport,
+                format!(
+                    "Status rejected: longer than {} characters.",
+                    MAX_STATUS_LEN
+                ),
+            );
+        }
+    }
+    let content = match &text {
+        Some(text) => format!("Your status is now: {}", text),
+        None => "Your status is cleared.".to_string(),
+    };
+    if let Some(client) = ctx.state.clients.read()?.get(&ctx.peer_addr) {
+        *client.status.lock()? = text;
+    }
+    reply(ctx, CommandType::Status, content)
+}
+
+/// Sets what the client wants to be notified of for the rest of its session,
+/// or shows the current setting when `mode` is `None`.
+fn set_notify_mode(ctx: &mut CommandContext, mode: Option<NotifyMode>) -> ChatResult<()> {
+    let current = {
+        let clients = ctx.state.clients.read()?;
+        let Some(client) = clients.get(&ctx.peer_addr) else {
+            return Ok(()); // Disconnected meanwhile.
+        };
+        let mut notify = client.notify.lock()?;
+        if let Some(mode) = mode {
+            *notify = mode;
+        }
+        *notify
+    };
+    let effect = match current {
+        NotifyMode::All => "every message",
+        NotifyMode::Mentions => "messages that mention you, and private messages",
+        NotifyMode::Private => "private messages only",
+        NotifyMode::Off => "nothing",
+    };
+    let mut content = match mode {
+        Some(_) => format!("Notifications are now {}: {}.", current.name(), effect),
+        None => format!("Notifications are {}: {}.", current.name(), effect),
+    };
+    // Nothing reads the preference yet, so say so rather than let the user expect quiet.
+    if current != NotifyMode::All {
+        content.push_str(" This is only stored for now; every message is still delivered.");
+    }
+    reply(ctx, CommandType::Notify, content)
+}
+
+/// Pins a chat message from history and announces it to everyone. The pin keeps its own
+/// copy, so it stays after the message expires or history is trimmed.
+fn pin_message(ctx: &mut CommandContext, seq: u64) -> ChatResult<()> {
+    let message = ctx
+        .state
+        .chat_history
+        .read()?
+        .iter()
+        .find(|msg| matches!(msg.message_type, ChatMessageType::Message) && msg.seq == Some(seq))
+        .cloned();
+    let Some(message) = message else {
+        return send_error(ctx.transport, format!("No message with seq {}.", seq));
+    };
+    {
+        let mut pinned = ctx.state.pinned.write()?;
+        if pinned.contains_key(&seq) {
+            drop(pinned);
+            return send_error(ctx.transport, format!("#{} is already pinned.", seq));
+        }
+        if pinned.len() >= MAX_PINS {
+            drop(pinned);
+            return send_error(
+                ctx.transport,
+                format!(
+                    "At most {} messages can be pinned; /unpin one first.",
+                    MAX_PINS
+                ),
+            );
+        }
+        pinned.insert(seq, message.clone());
+    }
+
+    println!("'{}' pinned #{}", ctx.username, seq);
+    ctx.state
+        .record_moderation(ctx.username, CommandType::Pin, &format!("#{}", seq), None);
+    let notice = broadcast_system_message(
+        ctx.state,
+        ctx.peer_addr,
+        ctx.username,
+        ChatMessageType::Command(CommandType::Pin),
+        format!("{} pinned {}", ctx.username, format_pin(&message)),
+    )?;
+    send_message_to_client(ctx.transport, &notice)
+}
+
+/// Unpins a message and announces it to everyone.
+fn unpin_message(ctx: &mut CommandContext, seq: u64) -> ChatResult<()> {
+    if ctx.state.pinned.write()?.remove(&seq).is_none() {
+        return send_error(ctx.transport, format!("#{} isn't pinned.", seq));
+    }
+    println!("'{}' unpinned #{}", ctx.username, seq);
+    ctx.state
+        .record_moderation(ctx.username, CommandType::Unpin, &format!("#{}", seq), None);
+    let notice = broadcast_system_message(
+        ctx.state,
+        ctx.peer_addr,
+        ctx.username,
+        ChatMessageType::Command(CommandType::Unpin),
+        format!("{} unpinned #{}", ctx.username, seq),
+    )?;
+    send_message_to_client(ctx.transport, &notice)
+}
+
+/// Lists the pinned messages for the client.
+fn send_pins(ctx: &mut CommandContext) -> ChatResult<()> {
+    let content = pinned_summary(ctx.state)?.unwrap_or_else(|| "No pinned messages.".to_string());
+    reply(ctx, CommandType::Pins, content)
+}
+
+/// Lists the pinned messages, oldest first, or returns `None` if there are none.
+/// Also sent to every joining client.
+pub(crate) fn pinned_summary(state: &ServerState) -> ChatResult<Option<String>> {
+    let pinned = state.pinned.read()?;
+    if pinned.is_empty() {
+        return Ok(None);
+    }
+    let lines: Vec<String> = pinned.values().map(format_pin).collect();
+    Ok(Some(format!("Pinned messages:\n{}", lines.join("\n"))))
+}
+
+/// Formats a pinned message as `#seq [username]: text`.
+fn format_pin(msg: &ChatMessage) -> String {
+    format!(
+        "#{} [{}]: {}",
+        msg.seq.unwrap_or_default(),
+        msg.username.as_deref().unwrap_or_default(),
+        msg.content
+    )
+}
+
+/// Sends the client the stored history as one message for it to save, keeping only the
+/// most recent messages for which the encoded frame fits in `--max-history-download-bytes`.
+/// Unlike the replay on join, it is queued like any other message, so clients that opted in
+/// get it compressed.
+fn send_history_download(ctx: &mut CommandContext) -> ChatResult<()> {
+    let history = ctx.state.chat_history.read()?.clone();
+    let max_bytes = ctx.state.config.max_history_download_bytes as usize;
+    let download_message = |messages: Vec<ChatMessage>| -> ChatResult<ChatMessage> {
+        let download = HistoryDownload {
+            messages,
+            total: history.len(),
+        };
+        Ok(ChatMessage {
+            message_type: ChatMessageType::Command(CommandType::DownloadHistory),
+            username: None,
+            content: serde_json::to_string(&download)?,
+            system: true,
+            ..Default::default()
+        })
+    };
+
+    // The download travels as a JSON string inside the frame, so every message costs its
+    // JSON once escaped, plus a comma. Escaping is per character, so the frame's size is
+    // the size of an empty download plus those costs, without encoding each candidate.
+    let mut size = Frame::encode(&download_message(Vec::new())?)?.len();
+    let mut kept = 0;
+    for msg in history.iter().rev() {
+        let escaped = serde_json::to_string(&serde_json::to_string(msg)?)?.len() - 2; // Without the quotes.
+        size += escaped + usize::from(kept > 0); // The comma before every message but the first.
+        if size > max_bytes {
+            break;
+        }
+        kept += 1;
+    }
+    let message = download_message(history[history.len() - kept..].to_vec())?;
+    send_message_to_addr(ctx.state, ctx.peer_addr, &message)
+}
+
+/// Sends the client the most recent chat messages containing `text`, ignoring case,
+/// oldest first and at most `FIND_RESULT_LIMIT` of them.
+fn find_messages(ctx: &mut CommandContext, text: &str) -> ChatResult<()> {
+    let needle = text.to_lowercase();
+    let mut matches: Vec<String> = ctx
+        .state
+        .chat_history
+        .read()?
+        .iter()
+        .rev()
+        .filter(|msg| matches!(msg.message_type, ChatMessageType::Message))
+        .filter(|msg| msg.content.to_lowercase().contains(&needle))
+        .take(FIND_RESULT_LIMIT)
+        .map(|msg| {
+            format!(
+                "#{} {} [{}]: {}",
+                msg.seq.unwrap_or_default(),
+                format_time(msg.timestamp.unwrap_or_default()),
+                msg.username.as_deref().unwrap_or("unknown"),
+                msg.content
+            )
+        })
+        .collect();
+
+    let content = if matches.is_empty() {
+        format!("No messages match '{}'.", text)
+    } else {
+        matches.reverse();
+        let header = if matches.len() == FIND_RESULT_LIMIT {
+            format!("Latest {} messages matching '{}':", FIND_RESULT_LIMIT, text)
+        } else {
+            format!("Messages matching '{}':", text)
+        };
+        format!("{}\n{}", header, matches.join("\n"))
+    };
+    reply(ctx, CommandType::Find, content)
+}
+
+/// Tells the client which server and protocol versions it is talking to.
+fn send_version(ctx: &mut CommandContext) -> ChatResult<()> {
+    reply(
+        ctx,
+        CommandType::Version,
+        format!(
+            "Server version {} (protocol {})",
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION
+        ),
+    )
+}
+
+/// Tells the client how long the server has been running and how many clients are connected.
+fn send_uptime(ctx: &mut CommandContext) -> ChatResult<()> {
+    let connected = ctx.state.clients.read()?.len();
+    reply(
+        ctx,
+        CommandType::Uptime,
+        format!(
+            "Up {} with {} client{} connected.",
+            format_duration(ctx.state.uptime()),
+            connected,
+            if connected == 1 { "" } else { "s" }
+        ),
+    )
+}
+
+/// Formats a duration as e.g. `2d 3h 4m 5s`, leaving out leading zero units.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86_400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units.iter().position(|(value, _)| *value > 0).unwrap_or(3);
+    units[first..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Broadcasts a classic IRC-style slap aimed at another online user.
+fn slap(ctx: &mut CommandContext, target: &str) -> ChatResult<()> {
+    let is_online = ctx
+        .state
+        .usernames
+        .read()?
+        .values()
+        .any(|name| name == target);
+    if !is_online {
+        return send_error(
+            ctx.transport,
+            format!("No user named '{}' is online.", target),
+        );
+    }
+
+    let notice = broadcast_system_message(
+        ctx.state,
+        ctx.peer_addr,
+        ctx.username,
+        ChatMessageType::Command(CommandType::Slap),
+        format!(
+            "* {} slaps {} around a bit with a large trout",
+            ctx.username, target
+        ),
+    )?;
+    send_message_to_client(ctx.transport, &notice)
+}
+
+/// Makes another online user an admin. Only admins may do this.
+fn grant_admin(ctx: &mut CommandContext, target: &str) -> ChatResult<()> {
+    let Some(target_addr) = find_user(ctx.state, target)? else {
+        return send_error(
+            ctx.transport,
+            format!("No user named '{}' is online.", target),
+        );
+    };
+    if !ctx.state.admins.write()?.insert(target_addr) {
+        return send_error(ctx.transport, format!("{} is already an admin.", target));
+    }
+
+    println!(
+        "'{}' granted admin privileges to '{}'",
+        ctx.username, target
+    );
+    ctx.state
+        .record_moderation(ctx.username, CommandType::GrantAdmin, target, None);
+    notify_user(
+        ctx.state,
+        target_addr,
+        CommandType::GrantAdmin,
+        format!("{} made you an admin.", ctx.username),
+    );
+    reply(
+        ctx,
+        CommandType::GrantAdmin,
+        format!("{} is now an admin.", target),
+    )
+}
+
+/// Removes another user's admin privileges. Only admins may do this,
+/// and the last remaining admin can't be revoked.
+fn revoke_admin(ctx: &mut CommandContext, target: &str) -> ChatResult<()> {
+    let Some(target_addr) = find_user(ctx.state, target)? else {
+        return send_error(
+            ctx.transport,
+            format!("No user named '{}' is online.", target),
+        );
+    };
+    {
+        let mut admins_lock = ctx.state.admins.write()?;
+        if !admins_lock.contains(&target_addr) {
+            drop(admins_lock);
+            return send_error(ctx.transport, format!("{} is not an admin.", target));
+        }
+        if admins_lock.len() == 1 {
+            drop(admins_lock);
+            return send_error(
+                ctx.transport,
+                format!("{} is the last admin and can't be revoked.", target),
+            );
+        }
+        admins_lock.remove(&target_addr);
+    }
+
+    println!(
+        "'{}' revoked admin privileges from '{}'",
+        ctx.username, target
+    );
+    ctx.state
+        .record_moderation(ctx.username, CommandType::RevokeAdmin, target, None);
+    if target_addr != ctx.peer_addr {
+        notify_user(
+            ctx.state,
+            target_addr,
+            CommandType::RevokeAdmin,
+            format!("{} revoked your admin privileges.", ctx.username),
+        );
+    }
+    reply(
+        ctx,
+        CommandType::RevokeAdmin,
+        format!("{} is no longer an admin.", target),
+    )
+}
+
+/// Privately delivers `text` to each of `targets`, or to every other online user for
+/// `@everyone`. Unlike a chat message it isn't stored in history. Offline targets get it when
+/// they next join if `--offline-message-cap` allows; others are reported back to the sender.
+fn send_private_message(
+    ctx: &mut CommandContext,
+    targets: &[String],
+    text: &str,
+) -> ChatResult<()> {
+    if ctx.state.is_muted(ctx.username) {
+        return send_error(ctx.transport, "You are muted.".to_string());
+    }
+    let max_len = ctx.state.max_message_len.load(Ordering::SeqCst);
+    if text.chars().count() > max_len {
+        return send_error(
+            ctx.transport,
+            format!("Message rejected: longer than {} characters.", max_len),
+        );
+    }
+
+    // Resolve each target once, following renames.
+    let mut recipients: Vec<(SocketAddr, String)> = Vec::new();
+    let mut missing = Vec::new();
+    let mut offline = Vec::new(); // Named targets that aren't online.
+    if targets.iter().any(|target| target == EVERYONE_TARGET) {
+        recipients.extend(
+            ctx.state
+                .usernames
+                .read()?
+                .iter()
+                .filter(|(addr, _)| **addr != ctx.peer_addr)
+                .map(|(addr, name)| (*addr, name.clone())),
+        );
+        recipients.sort_by(|a, b| a.1.cmp(&b.1));
+    } else {
+        for target in targets {
+            let name = ctx.state.resolve_username(target);
+            match find_user(ctx.state, &name)? {
+                Some(addr) if !recipients.iter().any(|(known, _)| *known == addr) => {
+                    recipients.push((addr, name))
+                }
+                Some(_) => {} // Listed twice.
+                None if !offline.contains(&name) => offline.push(name),
+                None => {} // Listed twice.
+            }
+        }
+    }
+
+    let message = ChatMessage {
+        message_type: ChatMessageType::Command(CommandType::Msg),
+        username: Some(ctx.username.to_string()),
+        content: text.to_string(),
+        ..Default::default()
+    };
+    let mut delivered = Vec::new();
+    for (addr, name) in recipients {
+        match send_message_to_addr(ctx.state, addr, &message) {
+            Ok(()) => delivered.push(name),
+            Err(_) => missing.push(name),
+        }
+    }
+    let mut saved = Vec::new();
+    let mut full = Vec::new(); // Offline targets with no room for another message.
+    for name in offline {
+        let message = ChatMessage {
+            timestamp: Some(unix_timestamp()),
+            ..message.clone()
+        };
+        if ctx.state.store_offline_message(&name, message)? {
+            saved.push(name);
+        } else if ctx.state.config.offline_message_cap > 0 {
+            full.push(name);
+        } else {
+            missing.push(name);
+        }
+    }
+
+    if !delivered.is_empty() {
+        reply(
+            ctx,
+            CommandType::Msg,
+            format!("To {}: {}", delivered.join(", "), text),
+        )?;
+    }
+    if !saved.is_empty() {
+        reply(
+            ctx,
+            CommandType::Msg,
+            format!(
+                "Saved for {}: they'll get it when they next join.",
+                saved.join(", ")
+            ),
+        )?;
+    }
+    if !full.is_empty() {
+        send_error(
+            ctx.transport,
+            format!(
+                "Not delivered to {}: not online, with too many messages already waiting.",
+                full.join(", ")
+            ),
+        )?;
+    }
+    if !missing.is_empty() {
+        return send_error(
+            ctx.transport,
+            format!("Not delivered to {}: not online.", missing.join(", ")),
+        );
+    }
+    if delivered.is_empty() && saved.is_empty() && full.is_empty() {
+        return send_error(ctx.transport, "No one else is online.".to_string());
+    }
+    Ok(())
+}
+
+/// Queues an announcement that the scheduler thread broadcasts to everyone after `delay`.
+fn schedule_announcement(ctx: &mut CommandContext, delay: Duration, text: &str) -> ChatResult<()> {
+    if delay > MAX_SCHEDULE_DELAY {
+        return send_error(
+            ctx.transport,
+            format!(
+                "Announcements can be scheduled at most {}s ahead.",
+                MAX_SCHEDULE_DELAY.as_secs()
+            ),
+        );
+    }
+    let id = ctx.state.next_schedule_id.fetch_add(1, Ordering::SeqCst) + 1;
+    ctx.state.scheduled.write()?.insert(
+        id,
+        ScheduledMessage {
+            due: Instant::now() + delay,
+            author: ctx.username.to_string(),
+            text: text.to_string(),
+        },
+    );
+    println!(
+        "'{}' scheduled announcement #{} in {}s",
+        ctx.username,
+        id,
+        delay.as_secs()
+    );
+    reply(
+        ctx,
+        CommandType::Schedule,
+        format!(
+            "Announcement #{} will be sent in {}. Cancel it with /unschedule {}.",
+            id,
+            format_duration(delay),
+            id
+        ),
+    )
+}
+
+/// Cancels an announcement that hasn't been sent yet.
+fn cancel_announcement(ctx: &mut CommandContext, id: u64) -> ChatResult<()> {
+    if ctx.state.scheduled.write()?.remove(&id).is_none() {
+        return send_error(ctx.transport, format!("No scheduled announcement #{}.", id));
+    }
+    println!("'{}' cancelled announcement #{}", ctx.username, id);
+    reply(
+        ctx,
+        CommandType::Unschedule,
+        format!("Announcement #{} cancelled.", id),
+    )
+}
+
+/// Changes which joining clients get the chat history replayed.
+/// Clients already connected aren't affected.
+fn set_history_mode(ctx: &mut CommandContext, mode: HistoryMode) -> ChatResult<()> {
+    let old_mode = std::mem::replace(&mut *ctx.state.history_mode.write()?, mode);
+    println!(
+        "'{}' changed the history mode from {} to {}",
+        ctx.username,
+        old_mode.name(),
+        mode.name()
+    );
+    ctx.state.record_moderation(
+        ctx.username,
+        CommandType::HistoryMode,
+        "history mode",
+        Some(format!("{} -> {}", old_mode.name(), mode.name())),
+    );
+    let effect = match mode {
+        HistoryMode::Public => "every joining client gets the history",
+        HistoryMode::Private => "only returning clients get the messages they missed",
+        HistoryMode::Off => "no joining client gets the history",
+    };
+    reply(
+        ctx,
+        CommandType::HistoryMode,
+        format!("History mode is now {}: {}.", mode.name(), effect),
+    )
+}
+
+/// Shows the admin the most recent moderation actions, oldest first.
+fn send_audit_log(ctx: &mut CommandContext, count: Option<usize>) -> ChatResult<()> {
+    let entries = ctx
+        .state
+        .audit
+        .recent(count.unwrap_or(AUDIT_LOG_DEFAULT_COUNT));
+    if entries.is_empty() {
+        return reply(
+            ctx,
+            CommandType::AuditLog,
+            "No moderation actions recorded.".to_string(),
+        );
+    }
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let detail = entry
+                .detail
+                .as_ref()
+                .map(|detail| format!(" ({})", detail))
+                .unwrap_or_default();
+            format!(
+                "{} {} /{} {}{}",
+                format_time(entry.timestamp),
+                entry.actor,
+                entry.action,
+                entry.target,
+                detail
+            )
+        })
+        .collect();
+    reply(
+        ctx,
+        CommandType::AuditLog,
+        format!("Recent moderation actions:\n{}", lines.join("\n")),
+    )
+}
+
+/// Re-reads the `--config` file and applies the settings that can change while the server
+/// runs: for now, the maximum message length. Other settings that changed are listed as
+/// taking effect after a restart.
+fn reload_config(ctx: &mut CommandContext) -> ChatResult<()> {
+    let config = match ctx.state.config.reload() {
+        Ok(config) => config,
+        Err(e) => {
+            return send_error(
+                ctx.transport,
+                format!("Failed to reload the configuration: {}", e),
+            );
+        }
+    };
+    if !MAX_MESSAGE_LEN_BOUNDS.contains(&config.max_message_len) {
+        return send_error(
+            ctx.transport,
+            format!(
+                "Failed to reload the configuration: --max-message-len must be between {} and {}.",
+                MAX_MESSAGE_LEN_BOUNDS.start(),
+                MAX_MESSAGE_LEN_BOUNDS.end()
+            ),
+        );
+    }
+
+    let new_len = config.max_message_len;
+    let old_len = ctx.state.max_message_len.swap(new_len, Ordering::SeqCst);
+    ctx.state.record_moderation(
+        ctx.username,
+        CommandType::Reload,
+        "config",
+        Some(format!("max message length {} -> {}", old_len, new_len)),
+    );
+
+    // Everything else is compared with the configuration the server started with.
+    let running = serde_json::to_value(&ctx.state.config)?;
+    let reloaded = serde_json::to_value(&config)?;
+    let restart: Vec<String> = match (running.as_object(), reloaded.as_object()) {
+        (Some(running), Some(reloaded)) => running
+            .iter()
+            .filter(|(name, value)| {
+                *name != "max_message_len" && reloaded.get(name.as_str()) != Some(value)
+            })
+            .map(|(name, _)| format!("--{}", name.replace('_', "-")))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut content = format!(
+        "Configuration reloaded. Maximum message length: {}.",
+        new_len
+    );
+    if !restart.is_empty() {
+        content.push_str(&format!(
+            " Changes to {} take effect after a restart.",
+            restart.join(", ")
+        ));
+    }
+    reply(ctx, CommandType::Reload, content)
+}
+
+/// Lists the most recent joins and leaves, oldest first, to help debug flaky clients.
+fn send_recent_connections(ctx: &mut CommandContext, count: Option<usize>) -> ChatResult<()> {
+    let events = ctx
+        .state
+        .connections
+        .recent(count.unwrap_or(RECENT_DEFAULT_COUNT));
+    if events.is_empty() {
+        return reply(
+            ctx,
+            CommandType::Recent,
+            "No joins or leaves recorded.".to_string(),
+        );
+    }
+    let lines: Vec<String> = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{} {} {} ({})",
+                format_time(event.timestamp),
+                event.username,
+                event.kind,
+                event.addr
+            )
+        })
+        .collect();
+    reply(
+        ctx,
+        CommandType::Recent,
+        format!("Recent joins and leaves:\n{}", lines.join("\n")),
+    )
+}
+
+/// Sends a file to every other client, within the size cap and cooldown.
+/// Files aren't stored in history, so only clients online now receive them.
+fn share_file(ctx: &mut CommandContext, name: &str, data: &str) -> ChatResult<()> {
+    let Some(name) = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+    else {
+        return send_error(ctx.transport, format!("Invalid file name '{}'.", name));
+    };
+    let Ok(bytes) = BASE64.decode(data) else {
+        return send_error(ctx.transport, "The file contents are invalid.".to_string());
+    };
+    let max_bytes = ctx.state.config.max_file_bytes;
+    if bytes.len() > max_bytes {
+        return send_error(
+            ctx.transport,
+            format!("File rejected: larger than {} bytes.", max_bytes),
+        );
+    }
+
+    {
+        let cooldown = Duration::from_secs(ctx.state.config.file_cooldown_secs);
+        let mut last_share = ctx.state.last_file_share.write()?;
+        if let Some(wait) = last_share.and_then(|at| cooldown.checked_sub(at.elapsed())) {
+            drop(last_share);
+            return send_error(
+                ctx.transport,
+                format!(
+                    "Please wait {}s before sending another file.",
+                    wait.as_secs() + 1
+                ),
+            );
+        }
+        *last_share = Some(Instant::now());
+    }
+
+    let file_msg = Command::SharedFile {
+        name: name.to_string(),
+        data: data.to_string(),
+    }
+    .into_message(ctx.username);
+    let broadcast = Broadcast {
+        exclude: Some(ctx.peer_addr),
+        frame: Frame::encode(&file_msg)?,
+        priority: file_msg.priority,
+    };
+    if let Err(broadcast) = ctx.state.queue_broadcast(broadcast) {
+        deliver_broadcast(ctx.state, &broadcast);
+    }
+    println!(
+        "'{}' sent {} ({} bytes) to everyone",
+        ctx.username,
+        name,
+        bytes.len()
+    );
+    reply(
+        ctx,
+        CommandType::SharedFile,
+        format!("Sent {} ({} bytes) to everyone.", name, bytes.len()),
+    )
+}
+
+/// Passes an end-to-end encryption payload on to `target` without looking inside it.
+/// Nothing is stored, so the server only ever handles keys and ciphertext.
+fn relay_to_user(
+    ctx: &mut CommandContext,
+    command_type: CommandType, // `PublicKey` or `Encrypted`.
+    target: &str,              // The recipient's username; former names are accepted.
+    payload: &str,             // Opaque base64 data from the sender.
+) -> ChatResult<()> {
+    let name = ctx.state.resolve_username(target);
+    let Some(addr) = find_user(ctx.state, &name)? else {
+        return send_error(
+            ctx.transport,
+            format!("No user named '{}' is online.", target),
+        );
+    };
+    let message = ChatMessage {
+        message_type: ChatMessageType::Command(command_type),
+        username: Some(ctx.username.to_string()),
+        content: payload.to_string(),
+        ..Default::default()
+    };
+    send_message_to_addr(ctx.state, addr, &message)
+}
+
+/// Returns the address of the online user named `username`.
+fn find_user(state: &ServerState, username: &str) -> ChatResult<Option<SocketAddr>> {
+    Ok(state
+        .usernames
+        .read()?
+        .iter()
+        .find(|(_, name)| *name == username)
+        .map(|(addr, _)| *addr))
+}
+
+/// Sends the command's reply to the client that issued it.
+/// If the client has disconnected the error is returned and ends its session. Any other
+/// write failure is only logged: the reply is lost, but the session carries on.
+fn reply(ctx: &mut CommandContext, command_type: CommandType, content: String) -> ChatResult<()> {
+    let name = command_type.name();
+    let reply = ChatMessage {
+        message_type: ChatMessageType::Command(command_type),
+        username: None,
+        content,
+        system: true,
+        ..Default::default()
+    };
+    match send_message_to_client(ctx.transport, &reply) {
+        Err(e) if !e.is_disconnect() => {
+            eprintln!(
+                "Failed to send the /{} reply to {}: {}",
+                name, ctx.peer_addr, e
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Privately notifies the user at `addr` that a command affected them.
+/// Failures are only logged: the command itself has already taken effect.
+fn notify_user(state: &ServerState, addr: SocketAddr, command_type: CommandType, content: String) {
+    let notice = ChatMessage {
+        message_type: ChatMessageType::Command(command_type),
+        username: None,
+        content,
+        system: true,
+        priority: Priority::High,
+        ..Default::default()
+    };
+    if let Err(e) = send_message_to_addr(state, addr, &notice) {
+        eprintln!("Failed to notify {}: {}", addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_handler::broadcast_message;
+    use crate::test_support::{test_config, TestClient, TestServer};
+    use crate::transport::MemoryTransport;
+    use std::io::{self, ErrorKind};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::{env, fs, process};
+
+    #[test]
+    fn registered_command_is_dispatched_to_its_handler() {
+        let state = ServerState::new(test_config(&[]));
+        let (mut transport, _client_end) = MemoryTransport::pair(
+            "127.0.0.1:8081".parse().unwrap(),
+            "10.0.0.1:5000".parse().unwrap(),
+        );
+        let mut ctx = CommandContext {
+            transport: &mut transport,
+            state: &state,
+            peer_addr: "10.0.0.1:5000".parse().unwrap(),
+            username: "alice",
+            connection_id: 1,
+        };
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = CommandRegistry::default();
+        let seen = Arc::clone(&calls);
+        registry.register(
+            CommandType::Slap,
+            Box::new(move |ctx, command| {
+                seen.lock()
+                    .unwrap()
+                    .push((ctx.username.to_string(), command.clone()));
+                Ok(())
+            }),
+        );
+
+        let slap = Command::Slap {
+            target: "bob".to_string(),
+        };
+        assert!(matches!(registry.dispatch(&mut ctx, &slap), Some(Ok(()))));
+        assert_eq!(*calls.lock().unwrap(), [("alice".to_string(), slap)]);
+        assert!(registry.dispatch(&mut ctx, &Command::Quit).is_none());
+    }
+
+    /// A connection whose writes fail with `error` while it is set.
+    struct FailingTransport {
+        inner: MemoryTransport,               // The working connection underneath.
+        error: Arc<Mutex<Option<ErrorKind>>>, // Error writes fail with, if any.
+    }
+
+    impl Transport for FailingTransport {
+        fn read_frame(&mut self) -> io::Result<Option<String>> {
+            self.inner.read_frame()
+        }
+
+        fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+            match *self.error.lock().unwrap() {
+                Some(kind) => Err(io::Error::new(kind, "write failed")),
+                None => self.inner.write_frame(frame),
+            }
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.peer_addr()
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.inner.shutdown()
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.inner.set_read_timeout(timeout)
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            self.inner.try_clone()
+        }
+    }
+
+    #[test]
+    fn failed_list_reply_ends_the_session_only_on_a_disconnect() {
+        let state = ServerState::new(test_config(&[]));
+        let (inner, mut client_end) = MemoryTransport::pair(
+            "127.0.0.1:8081".parse().unwrap(),
+            "10.0.0.1:5000".parse().unwrap(),
+        );
+        let error = Arc::new(Mutex::new(None));
+        let mut transport = FailingTransport {
+            inner,
+            error: Arc::clone(&error),
+        };
+        let mut ctx = CommandContext {
+            transport: &mut transport,
+            state: &state,
+            peer_addr: "10.0.0.1:5000".parse().unwrap(),
+            username: "alice",
+            connection_id: 1,
+        };
+        let registry = CommandRegistry::with_builtin_commands();
+        let list = Command::List { page: None };
+
+        *error.lock().unwrap() = Some(ErrorKind::TimedOut);
+        assert!(matches!(registry.dispatch(&mut ctx, &list), Some(Ok(()))));
+        *error.lock().unwrap() = None; // The next reply goes through.
+        assert!(matches!(registry.dispatch(&mut ctx, &list), Some(Ok(()))));
+        let frame = client_end.read_frame().unwrap().unwrap();
+        assert!(matches!(
+            Frame::decode(&frame).unwrap().message.message_type,
+            ChatMessageType::Command(CommandType::List)
+        ));
+
+        *error.lock().unwrap() = Some(ErrorKind::BrokenPipe);
+        assert!(matches!(registry.dispatch(&mut ctx, &list), Some(Err(_))));
+    }
+
+    #[test]
+    fn admins_can_be_granted_and_revoked_but_not_the_last_one() {
+        let server = TestServer::with_args(&["--admin-token", "secret"]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let mut bob = server.connect("bob");
+        let error = |client: &mut TestClient| {
+            client
+                .recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error))
+                .content
+        };
+
+        bob.command("/grantadmin bob");
+        assert_eq!(error(&mut bob), "Insufficient privileges.");
+        alice.command("/grantadmin bob");
+        assert_eq!(
+            alice.recv_reply(CommandType::GrantAdmin).content,
+            "bob is now an admin."
+        );
+        assert_eq!(
+            bob.recv_reply(CommandType::GrantAdmin).content,
+            "alice made you an admin."
+        );
+
+        bob.command("/revokeadmin alice");
+        assert_eq!(
+            bob.recv_reply(CommandType::RevokeAdmin).content,
+            "alice is no longer an admin."
+        );
+        assert_eq!(
+            alice.recv_reply(CommandType::RevokeAdmin).content,
+            "bob revoked your admin privileges."
+        );
+        bob.command("/revokeadmin bob");
+        assert_eq!(
+            error(&mut bob),
+            "bob is the last admin and can't be revoked."
+        );
+        assert_eq!(server.state.admins.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn msg_reaches_every_target_and_reports_the_offline_ones() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        let mut carol = server.connect("carol");
+        let mut dave = server.connect("dave");
+        let private = |client: &mut TestClient| client.recv_reply(CommandType::Msg);
+
+        alice.command("/msg bob,carol,zed,bob hi both");
+        for client in [&mut bob, &mut carol] {
+            let received = private(client);
+            assert_eq!(received.username.as_deref(), Some("alice"));
+            assert_eq!(received.content, "hi both");
+        }
+        assert_eq!(private(&mut alice).content, "To bob, carol: hi both");
+        let error = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "Not delivered to zed: not online.");
+
+        alice.command("/msg @everyone lunch?");
+        assert_eq!(private(&mut alice).content, "To bob, carol, dave: lunch?");
+        assert_eq!(private(&mut dave).content, "lunch?");
+        // Private messages aren't chat history.
+        let history = server.state.chat_history.read().unwrap();
+        assert!(!history.iter().any(|msg| msg.content.contains("lunch")));
+    }
+
+    #[test]
+    fn msg_to_an_offline_user_is_delivered_when_they_join() {
+        let server = TestServer::with_args(&["--offline-message-cap", "2"]);
+        let mut alice = server.connect("alice");
+
+        for text in ["are you there?", "call me back"] {
+            alice.command(&format!("/msg bob {}", text));
+            assert_eq!(
+                alice.recv_reply(CommandType::Msg).content,
+                "Saved for bob: they'll get it when they next join."
+            );
+        }
+        alice.command("/msg bob one more");
+        let error = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(
+            error.content,
+            "Not delivered to bob: not online, with too many messages already waiting."
+        );
+
+        let mut bob = server.join("bob");
+        let received: Vec<ChatMessage> = bob
+            .sync()
+            .into_iter()
+            .filter(|msg| matches!(msg.message_type, ChatMessageType::Command(CommandType::Msg)))
+            .collect();
+        assert_eq!(received.len(), 3);
+        assert_eq!(
+            received[0].content,
+            "While you were away you got 2 private message(s):"
+        );
+        for (msg, text) in received[1..].iter().zip(["are you there?", "call me back"]) {
+            assert_eq!(msg.username.as_deref(), Some("alice"));
+            assert_eq!(msg.content, text);
+            assert!(msg.timestamp.is_some());
+        }
+        // Delivered messages aren't kept for the next join.
+        assert!(server
+            .state
+            .take_offline_messages("bob")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn version_is_reported_only_to_the_requester() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.command("/version");
+        let reply = alice.recv_reply(CommandType::Version);
+        assert_eq!(
+            reply.content,
+            format!(
+                "Server version {} (protocol {})",
+                env!("CARGO_PKG_VERSION"),
+                PROTOCOL_VERSION
+            )
+        );
+        assert!(reply.system);
+        // Alice's reply never reached bob, whose first reply is to the `/uptime` below.
+        bob.command("/uptime");
+        let first_reply = bob.recv_until(|msg| {
+            matches!(
+                msg.message_type,
+                ChatMessageType::Command(CommandType::Version | CommandType::Uptime)
+            )
+        });
+        assert!(matches!(
+            first_reply.message_type,
+            ChatMessageType::Command(CommandType::Uptime)
+        ));
+    }
+
+    #[test]
+    fn scheduled_announcement_is_sent_after_the_delay_unless_cancelled() {
+        let server = TestServer::with_args(&["--admin-token", "secret"]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let mut bob = server.connect("bob");
+
+        let scheduled_at = Instant::now();
+        alice.command("/schedule 1 Restarting at noon");
+        assert_eq!(
+            alice.recv_reply(CommandType::Schedule).content,
+            "Announcement #1 will be sent in 1s. Cancel it with /unschedule 1."
+        );
+        alice.command("/schedule 1 Never mind");
+        alice.recv_reply(CommandType::Schedule);
+        alice.command("/unschedule 2");
+        assert_eq!(
+            alice.recv_reply(CommandType::Unschedule).content,
+            "Announcement #2 cancelled."
+        );
+
+        let announcement = bob.recv_reply(CommandType::Schedule);
+        assert!(scheduled_at.elapsed() >= Duration::from_secs(1));
+        assert_eq!(
+            announcement.content,
+            "Announcement from alice: Restarting at noon"
+        );
+        assert!(server.state.scheduled.read().unwrap().is_empty());
+        assert!(bob
+            .sync()
+            .iter()
+            .all(|msg| !msg.content.contains("Never mind")));
+        alice.command("/unschedule 2");
+        let error = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "No scheduled announcement #2.");
+    }
+
+    #[test]
+    fn recent_lists_joins_and_leaves_in_order() {
+        let server = TestServer::with_args(&["--admin-token", "secret", "--rejoin-grace-ms", "0"]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let mut bob = server.connect("bob");
+        bob.command("/quit");
+        bob.recv_reply(CommandType::Quit);
+        drop(bob);
+        alice.recv_until(|msg| msg.content == "bob has left the chat");
+
+        alice.command("/recent");
+        let reply = alice.recv_reply(CommandType::Recent).content;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "Recent joins and leaves:");
+        let events: Vec<&str> = lines[1..]
+            .iter()
+            .map(|line| line.split_once(' ').unwrap().1) // Without the time.
+            .map(|event| event.split(" (").next().unwrap()) // Or the address.
+            .collect();
+        assert_eq!(events, ["alice joined", "bob joined", "bob left"]);
+    }
+
+    #[test]
+    fn reload_applies_the_max_message_length_from_the_config_file() {
+        let path = env::temp_dir().join(format!("chat-config-{}.conf", process::id()));
+        fs::write(&path, "# Test server\n--max-message-len 300\n").unwrap();
+        let server = TestServer::with_args(&[
+            "--admin-token",
+            "secret",
+            "--config",
+            path.to_str().unwrap(),
+        ]);
+        assert_eq!(server.state.max_message_len.load(Ordering::SeqCst), 300);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+
+        fs::write(&path, "--max-message-len 20\n--replay-window 5\n").unwrap();
+        alice.command("/reload");
+        let reply = alice.recv_reply(CommandType::Reload).content;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reply,
+            "Configuration reloaded. Maximum message length: 20. \
+             Changes to --replay-window take effect after a restart."
+        );
+        assert_eq!(server.state.max_message_len.load(Ordering::SeqCst), 20);
+        alice.say(&"x".repeat(30));
+        assert!(matches!(alice.recv().message_type, ChatMessageType::Error));
+
+        // Once the file is gone the limit stays as it was.
+        alice.command("/reload");
+        let error = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert!(error
+            .content
+            .starts_with("Failed to reload the configuration: can't read config file"));
+        assert_eq!(server.state.max_message_len.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn moderation_actions_are_written_to_the_audit_log() {
+        let path = env::temp_dir().join(format!("chat-audit-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let server = TestServer::with_args(&[
+            "--admin-token",
+            "secret",
+            "--audit-log",
+            path.to_str().unwrap(),
+        ]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let _bob = server.connect("bob");
+
+        alice.command("/mute bob 10m");
+        alice.recv_reply(CommandType::Mute);
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(entry["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(entry["actor"], "alice");
+        assert_eq!(entry["action"], "mute");
+        assert_eq!(entry["target"], "bob");
+        assert_eq!(entry["detail"], "for 600s");
+
+        alice.command("/auditlog");
+        let reply = alice.recv_reply(CommandType::AuditLog).content;
+        assert!(reply.ends_with("alice /mute bob (for 600s)"), "{}", reply);
+    }
+
+    #[test]
+    fn shared_file_reaches_everyone_else_within_the_size_cap() {
+        let server = TestServer::with_args(&[
+            "--admin-token",
+            "secret",
+            "--max-file-bytes",
+            "16",
+            "--file-cooldown-secs",
+            "0",
+        ]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let mut receivers = [server.connect("bob"), server.connect("carol")];
+        let share = |client: &mut TestClient, contents: &[u8]| {
+            let command = Command::SharedFile {
+                name: "notes.txt".to_string(),
+                data: BASE64.encode(contents),
+            };
+            client.send(command.into_message(""));
+        };
+
+        share(&mut alice, b"meeting at noon");
+        assert_eq!(
+            alice.recv_reply(CommandType::SharedFile).content,
+            "Sent notes.txt (15 bytes) to everyone."
+        );
+        for receiver in &mut receivers {
+            let file = receiver.recv_reply(CommandType::SharedFile);
+            assert_eq!(file.username.as_deref(), Some("alice"));
+            let Ok(Command::SharedFile { name, data }) =
+                Command::from_parts(&CommandType::SharedFile, &file.content)
+            else {
+                panic!("invalid shared file: {}", file.content);
+            };
+            assert_eq!(name, "notes.txt");
+            assert_eq!(BASE64.decode(data).unwrap(), b"meeting at noon");
+        }
+
+        share(&mut alice, b"meeting at noon!!");
+        let error = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "File rejected: larger than 16 bytes.");
+        for receiver in &mut receivers {
+            assert!(receiver.sync().iter().all(|msg| !matches!(
+                msg.message_type,
+                ChatMessageType::Command(CommandType::SharedFile)
+            )));
+        }
+    }
+
+    #[test]
+    fn status_is_shown_next_to_the_user_in_list() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        let list = |client: &mut TestClient| {
+            client.command("/list");
+            client.recv_reply(CommandType::List).content
+        };
+
+        bob.command("/status out for lunch");
+        assert_eq!(
+            bob.recv_reply(CommandType::Status).content,
+            "Your status is now: out for lunch"
+        );
+        assert_eq!(list(&mut alice), "Online users: alice, bob (out for lunch)");
+
+        bob.command(&format!("/status {}", "z".repeat(MAX_STATUS_LEN + 1)));
+        let error = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "Status rejected: longer than 60 characters.");
+        assert_eq!(list(&mut alice), "Online users: alice, bob (out for lunch)");
+
+        bob.command("/status");
+        assert_eq!(
+            bob.recv_reply(CommandType::Status).content,
+            "Your status is cleared."
+        );
+        assert_eq!(list(&mut alice), "Online users: alice, bob");
+        // Setting a status isn't announced to anyone else.
+        assert!(alice
+            .sync()
+            .iter()
+            .all(|msg| !msg.content.contains("lunch")));
+    }
+
+    #[test]
+    fn pins_are_sent_to_joiners_listed_and_unpinned() {
+        let server = TestServer::with_args(&["--admin-token", "secret"]);
+        let mut alice = server.connect("alice");
+        alice.command("/admin secret");
+        alice.recv_reply(CommandType::Admin);
+        let mut bob = server.connect("bob");
+        bob.say("standup moved to 10:00");
+        let seq = alice
+            .recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message))
+            .seq
+            .unwrap();
+        let error = |client: &mut TestClient| {
+            client
+                .recv_until(|msg| matches!(msg.message_type, ChatMessageType::Error))
+                .content
+        };
+
+        bob.command(&format!("/pin {}", seq));
+        assert_eq!(error(&mut bob), "Insufficient privileges.");
+        alice.command(&format!("/pin {}", seq));
+        let pinned = format!("#{} [bob]: standup moved to 10:00", seq);
+        assert_eq!(
+            alice.recv_reply(CommandType::Pin).content,
+            format!("alice pinned {}", pinned)
+        );
+        alice.command(&format!("/pin {}", seq));
+        assert_eq!(error(&mut alice), format!("#{} is already pinned.", seq));
+
+        // A joining client gets the pins before the history replay.
+        let mut carol = server.join("carol");
+        let welcome = carol.sync();
+        let position =
+            |predicate: &dyn Fn(&ChatMessage) -> bool| welcome.iter().position(predicate).unwrap();
+        let pins = position(&|msg| {
+            matches!(
+                msg.message_type,
+                ChatMessageType::Command(CommandType::Pins)
+            )
+        });
+        let replayed = position(&|msg| msg.content == "standup moved to 10:00");
+        assert!(pins < replayed);
+        assert_eq!(
+            welcome[pins].content,
+            format!("Pinned messages:\n{}", pinned)
+        );
+        carol.command("/pins");
+        assert_eq!(
+            carol.recv_reply(CommandType::Pins).content,
+            format!("Pinned messages:\n{}", pinned)
+        );
+
+        alice.command(&format!("/unpin {}", seq));
+        assert_eq!(
+            alice.recv_reply(CommandType::Unpin).content,
+            format!("alice unpinned #{}", seq)
+        );
+        carol.command("/pins");
+        assert_eq!(
+            carol.recv_reply(CommandType::Pins).content,
+            "No pinned messages."
+        );
+        alice.command(&format!("/unpin {}", seq));
+        assert_eq!(error(&mut alice), format!("#{} isn't pinned.", seq));
+    }
+
+    #[test]
+    fn list_of_many_users_is_paginated() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let names: Vec<String> = (0..120).map(|i| format!("u{:03}", i)).collect();
+        let mut clients: Vec<TestClient> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let addr = format!("10.0.{}.{}:5000", i / 100, i % 100 + 1);
+                let (mut client, _) = TestClient::in_memory(&state, &addr);
+                client.join(name);
+                client.sync();
+                client
+            })
+            .collect();
+        let alice = &mut clients[0];
+        let mut list = |command: &str| {
+            alice.command(command);
+            alice.recv_until(|msg| {
+                matches!(
+                    msg.message_type,
+                    ChatMessageType::Command(CommandType::List) | ChatMessageType::Error
+                )
+            })
+        };
+
+        assert_eq!(
+            list("/list").content,
+            format!(
+                "Online users (page 1 of 3, 120 total): {}. Use /list 2 for more.",
+                names[..LIST_PAGE_SIZE].join(", ")
+            )
+        );
+        assert_eq!(
+            list("/list 3").content,
+            format!(
+                "Online users (page 3 of 3, 120 total): {}.",
+                names[2 * LIST_PAGE_SIZE..].join(", ")
+            )
+        );
+        let error = list("/list 4");
+        assert!(matches!(error.message_type, ChatMessageType::Error));
+        assert_eq!(error.content, "There are only 3 page(s) of users.");
+    }
+
+    #[test]
+    fn notify_preference_lasts_for_the_session() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        let notify = |client: &mut TestClient, command: &str| {
+            client.command(command);
+            client.recv_reply(CommandType::Notify).content
+        };
+
+        assert_eq!(
+            notify(&mut alice, "/notify"),
+            "Notifications are all: every message."
+        );
+        assert_eq!(
+            notify(&mut alice, "/notify mentions"),
+            "Notifications are now mentions: messages that mention you, and private messages. \
+             This is only stored for now; every message is still delivered."
+        );
+        alice.say("still here");
+        assert_eq!(
+            notify(&mut alice, "/notify"),
+            "Notifications are mentions: messages that mention you, and private messages. \
+             This is only stored for now; every message is still delivered."
+        );
+        // The preference doesn't filter what is delivered.
+        bob.say("no mention here");
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.content, "no mention here");
+        // Other users keep their own preference.
+        assert_eq!(
+            notify(&mut bob, "/notify"),
+            "Notifications are all: every message."
+        );
+
+        // A new session starts from the default again.
+        drop(alice);
+        while server.state.online.load(Ordering::SeqCst) > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut alice = server.connect("alice");
+        assert_eq!(
+            notify(&mut alice, "/notify"),
+            "Notifications are all: every message."
+        );
+    }
+
+    #[test]
+    fn list_reply_is_a_system_message_kept_out_of_history() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let history_len = server.state.chat_history.read().unwrap().len();
+
+        alice.command("/list");
+        let reply = alice.recv_reply(CommandType::List);
+        assert_eq!(reply.content, "Online users: alice");
+        assert!(reply.system);
+        assert_eq!(reply.seq, None);
+        let history = server.state.chat_history.read().unwrap();
+        assert_eq!(history.len(), history_len);
+        assert!(history
+            .iter()
+            .all(|msg| !msg.content.starts_with("Online users")));
+    }
+
+    #[test]
+    fn uptime_is_reported_and_increases() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut uptime = || {
+            alice.command("/uptime");
+            alice.recv_reply(CommandType::Uptime).content
+        };
+
+        let first = uptime();
+        assert!(first.starts_with("Up "), "{}", first);
+        thread::sleep(Duration::from_millis(1100));
+        let second = uptime();
+        assert!(second.starts_with("Up "), "{}", second);
+        assert_ne!(first, second);
+        assert!(server.state.uptime() >= Duration::from_millis(1100));
+        assert_eq!(format_duration(Duration::from_secs(93_784)), "1d 2h 3m 4s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m 5s");
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+    }
+
+    #[test]
+    fn find_returns_only_matching_messages_up_to_the_cap() {
+        let server = TestServer::start();
+        for i in 1..=FIND_RESULT_LIMIT + 5 {
+            for (user, content) in [
+                ("bob", format!("Deploy {} done", i)),
+                ("carol", "lunch?".to_string()),
+            ] {
+                broadcast_message(
+                    &server.state,
+                    None,
+                    ChatMessage {
+                        username: Some(user.to_string()),
+                        content,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        let mut alice = server.connect("alice");
+
+        alice.command("/find DEPLOY");
+        let found = alice.recv_reply(CommandType::Find).content;
+        let mut lines = found.lines();
+        assert_eq!(
+            lines.next(),
+            Some(format!("Latest {} messages matching 'DEPLOY':", FIND_RESULT_LIMIT).as_str())
+        );
+        let lines: Vec<&str> = lines.collect();
+        assert_eq!(lines.len(), FIND_RESULT_LIMIT);
+        assert!(lines.iter().all(|line| line.contains("[bob]: Deploy ")));
+        assert!(lines[0].ends_with("Deploy 6 done")); // Oldest of the latest matches first.
+        assert!(lines[FIND_RESULT_LIMIT - 1]
+            .ends_with(&format!("Deploy {} done", FIND_RESULT_LIMIT + 5)));
+
+        alice.command("/find deploy 3 done");
+        let found = alice.recv_reply(CommandType::Find).content;
+        assert_eq!(found.lines().count(), 2);
+        assert!(found.starts_with("Messages matching 'deploy 3 done':\n#"));
+
+        alice.command("/find dinner");
+        assert_eq!(
+            alice.recv_reply(CommandType::Find).content,
+            "No messages match 'dinner'."
+        );
+    }
+
+    #[test]
+    fn history_download_frame_fits_the_limit_after_escaping() {
+        let server = TestServer::with_args(&["--max-history-download-bytes", "2000"]);
+        for i in 0..20 {
+            broadcast_message(
+                &server.state,
+                None,
+                ChatMessage {
+                    username: Some("bob".to_string()),
+                    content: format!("\"quoted\" \\ message {}", i), // Escaped twice in the frame.
+                    ..Default::default()
+                },
+            );
+        }
+        let mut alice = server.join("alice");
+        alice.command("/download-history");
+        let reply = alice.recv_until(|msg| {
+            matches!(
+                msg.message_type,
+                ChatMessageType::Command(CommandType::DownloadHistory)
+            )
+        });
+        let download: HistoryDownload = serde_json::from_str(&reply.content).unwrap();
+        assert!(Frame::encode(&reply).unwrap().len() <= 2000);
+        assert_eq!(download.total, 21); // Including alice's join.
+        assert!(!download.messages.is_empty() && download.messages.len() < download.total);
+        assert!(download.messages.last().unwrap().content.contains("joined"));
+
+        // One more message would not have fit.
+        let mut bigger = download.clone();
+        let history = server.state.chat_history.read().unwrap().clone();
+        bigger.messages.insert(
+            0,
+            history[history.len() - download.messages.len() - 1].clone(),
+        );
+        let bigger_reply = ChatMessage {
+            content: serde_json::to_string(&bigger).unwrap(),
+            ..reply
+        };
+        assert!(Frame::encode(&bigger_reply).unwrap().len() > 2000);
+    }
+}
@@ -0,0 +1,51 @@
+// compression.rs
+use crate::message::{ChatMessage, ChatMessageType, ContentEncoding, Frame, FrameFlags}; // The envelope compressed frames travel in.
+use base64::engine::general_purpose::STANDARD as BASE64; // Compressed bytes travel as base64 text.
+use base64::Engine; // Provides `encode`/`decode` on the engine.
+use flate2::read::DeflateDecoder; // Inflates received frames.
+use flate2::write::DeflateEncoder; // Deflates outgoing frames.
+use flate2::Compression; // Compression level.
+use std::io::{self, Read, Write}; // Streaming through the encoder and decoder.
+
+/// Feature a client lists in its `Capabilities` message to opt into compressed frames.
+pub const COMPRESSION_FEATURE: &str = "compression";
+
+/// Frames shorter than this are sent as they are; compressing them saves too little
+/// to pay for the base64 and the envelope.
+pub const COMPRESSION_MIN_LEN: usize = 512;
+
+/// Wraps a serialized frame in a `Compressed` message, unless it is too short to
+/// benefit or compressing doesn't make it smaller, in which case it is returned unchanged.
+pub fn compress_frame(frame: &str) -> String {
+    if frame.len() < COMPRESSION_MIN_LEN {
+        return frame.to_string();
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let Ok(bytes) = encoder
+        .write_all(frame.as_bytes())
+        .and_then(|()| encoder.finish())
+    else {
+        return frame.to_string();
+    };
+    let envelope = ChatMessage {
+        message_type: ChatMessageType::Compressed,
+        content: BASE64.encode(bytes),
+        encoding: ContentEncoding::Base64,
+        system: true,
+        ..Default::default()
+    };
+    match serde_json::to_string(&Frame::new(envelope, FrameFlags::COMPRESSED)) {
+        Ok(compressed) if compressed.len() < frame.len() => compressed,
+        _ => frame.to_string(),
+    }
+}
+
+/// Unwraps a `Compressed` message into the message its frame carries.
+pub fn decompress_message(message: &ChatMessage) -> io::Result<ChatMessage> {
+    let bytes = BASE64
+        .decode(&message.content)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut frame = String::new();
+    DeflateDecoder::new(bytes.as_slice()).read_to_string(&mut frame)?;
+    Ok(Frame::decode(&frame)?.message)
+}
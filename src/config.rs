@@ -0,0 +1,401 @@
+// config.rs
+use crate::bot::BotTrigger;
+use crate::cidr::Cidr;
+use crate::transport::{parse_read_buffer_len, DEFAULT_READ_BUFFER_LEN};
+use clap::{Parser, ValueEnum};
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use std::fs;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Default maximum number of characters in a chat message.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 500;
+
+/// Values an admin may set the maximum message length to at runtime.
+pub const MAX_MESSAGE_LEN_BOUNDS: RangeInclusive<usize> = 1..=10_000;
+
+/// Runtime configuration for the chat server, parsed from the command line.
+#[derive(Parser, Serialize, Debug, Clone, Default)]
+#[command(name = "chat-server", args_override_self = true)]
+pub struct ServerConfig {
+    /// Read more options from this file, one per line as on the command line,
+    /// e.g. `--max-message-len 300`. Lines starting with `#` are ignored. Options given on
+    /// the command line take precedence. Admins can re-read the file with `/reload`.
+    #[arg(long = "config", value_name = "FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Address to bind the server to. Use port 0 to pick an ephemeral port.
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    pub addr: String,
+
+    /// Also accept browser clients over WebSocket on this address, e.g. `127.0.0.1:8082`.
+    /// They speak the same JSON protocol, one frame per WebSocket text message.
+    #[cfg(feature = "websocket")]
+    #[arg(long, value_name = "ADDR")]
+    pub ws_addr: Option<String>,
+
+    /// Replay history in windows of this many messages, waiting for a client ack between windows.
+    #[arg(long)]
+    pub replay_window: Option<usize>,
+
+    /// Order in which stored history is replayed to joining clients.
+    #[arg(long, value_enum, default_value_t = HistoryOrder::OldestFirst)]
+    pub history_order: HistoryOrder,
+
+    /// Maximum number of characters allowed in a chat message.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    pub max_message_len: usize,
+
+    /// Capacity of each client's read buffer, in bytes (512 to 1048576). Longer frames are
+    /// still read, over several reads; a buffer that fits a maximum-length message (up to
+    /// 4 bytes per character, plus the JSON envelope) reads most messages in one go.
+    #[arg(long, default_value_t = DEFAULT_READ_BUFFER_LEN, value_parser = parse_read_buffer_len)]
+    pub read_buffer_bytes: usize,
+
+    /// Token clients present with `/admin <token>` to gain admin privileges.
+    /// Admin commands are unavailable when unset.
+    #[arg(long)]
+    #[serde(skip_serializing)] // Never written to logs or state dumps.
+    pub admin_token: Option<String>,
+
+    /// How long `/ping-all` waits for clients to answer, in milliseconds.
+    #[arg(long, default_value_t = 2000)]
+    pub ping_timeout_ms: u64,
+
+    /// How long a new connection may take to send its join message, in seconds.
+    #[arg(long, default_value_t = 10)]
+    pub registration_timeout_secs: u64,
+
+    /// How long a leave announcement is held back, in milliseconds. If the user rejoins
+    /// within this window, neither the leave nor the join is announced. 0 announces immediately.
+    #[arg(long, default_value_t = 2000)]
+    pub rejoin_grace_ms: u64,
+
+    /// How often to probe clients and prune dead connections, in seconds. 0 disables the reaper.
+    #[arg(long, default_value_t = 30)]
+    pub reap_interval_secs: u64,
+
+    /// Show a client as stale in `/list` once nothing, not even a pong to the reaper's pings,
+    /// has arrived from it for this many seconds, and disconnect it after twice as long.
+    /// Should be longer than `--reap-interval-secs`. Disabled when unset.
+    #[arg(long)]
+    pub heartbeat_timeout_secs: Option<u64>,
+
+    /// Broadcast the list of online users to every client when it changes, at most once
+    /// per this many milliseconds. Disabled when unset.
+    #[arg(long)]
+    pub presence_debounce_ms: Option<u64>,
+
+    /// Largest file an admin may send to everyone with `/broadcast-file`, in bytes.
+    #[arg(long, default_value_t = 256 * 1024)]
+    pub max_file_bytes: usize,
+
+    /// Minimum time between two `/broadcast-file` transfers, in seconds. 0 disables the limit.
+    #[arg(long, default_value_t = 10)]
+    pub file_cooldown_secs: u64,
+
+    /// Largest reply `/download-history` sends, in bytes of the encoded frame. Older messages
+    /// are left out past this. At most 512 KiB, so the reply fits in a single frame even
+    /// once compressed and base64-encoded for clients that opted in.
+    #[arg(long, default_value_t = 256 * 1024, value_parser = clap::value_parser!(u64).range(1..=512 * 1024))]
+    pub max_history_download_bytes: u64,
+
+    /// Leave Nagle's algorithm on for client connections. By default `TCP_NODELAY` is set,
+    /// so small chat frames go out immediately instead of being batched.
+    #[arg(long)]
+    pub no_tcp_nodelay: bool,
+
+    /// Enable TCP keepalive on client connections: the OS probes a connection after it has
+    /// been idle this many seconds, then again every this many seconds, and drops it once
+    /// the probes go unanswered. Off when unset.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive_secs: Option<u64>,
+
+    /// Most users that may be in the chat at once; further joins are refused. Unlimited when unset.
+    /// The server has a single shared chat, so this is the member cap of its one room.
+    #[arg(long)]
+    pub max_members: Option<usize>,
+
+    /// Store `/nick` rename events in history, so joining clients can relabel the
+    /// replayed messages of users who have since changed their name.
+    #[arg(long)]
+    pub store_renames: bool,
+
+    /// Let clients join without a username; each is given a free name such as `guest-3`.
+    #[arg(long)]
+    pub guests: bool,
+
+    /// Tell clients which messages expired from history, so they can drop them too.
+    /// Messages expire only when their sender set a TTL.
+    #[arg(long)]
+    pub expiry_notices: bool,
+
+    /// Keep up to this many private messages for each offline user and deliver them when
+    /// they next join. 0 reports offline users as not online instead.
+    #[arg(long, default_value_t = 0)]
+    pub offline_message_cap: usize,
+
+    /// Disconnect a client after this many consecutive unparseable frames. 0 never disconnects.
+    #[arg(long, default_value_t = 5)]
+    pub max_parse_failures: u32,
+
+    /// Append every moderation action (mutes, admin changes, limit changes) to this file as JSONL.
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Auto-mute a client that sends the same message this many times within
+    /// `--spam-window-secs`. Case, spacing and punctuation are ignored when comparing.
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u64).range(2..))]
+    pub spam_repeats: Option<u64>,
+
+    /// Window for `--spam-repeats`, in seconds.
+    #[arg(long, default_value_t = 10)]
+    pub spam_window_secs: u64,
+
+    /// How long a `--spam-repeats` auto-mute lasts, in seconds.
+    #[arg(long, default_value_t = 60)]
+    pub spam_mute_secs: u64,
+
+    /// Keep chat history in this JSON file: it is loaded at startup, and saved every
+    /// `--autosave-secs` and on shutdown, so a crash loses at most that much history.
+    #[arg(long, value_name = "PATH")]
+    pub history_file: Option<PathBuf>,
+
+    /// How often to save the history to `--history-file`, in seconds. 0 saves only on shutdown.
+    #[arg(long, default_value_t = 30)]
+    pub autosave_secs: u64,
+
+    /// Only accept usernames matching this regular expression in full,
+    /// e.g. `[A-Za-z0-9_]{3,16}`. Any name is accepted when unset.
+    #[arg(long, value_name = "REGEX")]
+    pub username_policy: Option<UsernamePolicy>,
+
+    /// Explanation sent to clients whose username breaks `--username-policy`,
+    /// e.g. "letters, digits and underscores, 3-16 characters". Defaults to the pattern.
+    #[arg(long, value_name = "TEXT", requires = "username_policy")]
+    pub username_policy_hint: Option<String>,
+
+    /// Name the built-in bot posts as. Nobody else may use it while the bot is enabled.
+    #[arg(long, default_value = "greeter")]
+    pub bot_name: String,
+
+    /// Have the bot greet every joining user with this message; `{user}` is replaced with
+    /// their name. The bot is enabled by this or `--bot-trigger`.
+    #[arg(long, value_name = "TEXT")]
+    pub bot_greeting: Option<String>,
+
+    /// Have the bot answer messages containing a word, e.g. `help=Type /list to see who's here`.
+    /// Words match whole and ignoring case. May be given multiple times.
+    #[arg(long = "bot-trigger", value_name = "WORD=RESPONSE")]
+    pub bot_triggers: Vec<BotTrigger>,
+
+    /// Only accept connections from this address or CIDR range, e.g. `10.0.0.0/8`.
+    /// May be given multiple times. When unset, any address not denied may connect.
+    #[arg(long = "allow", value_name = "CIDR")]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse connections from this address or CIDR range, even if it is allowed.
+    /// May be given multiple times.
+    #[arg(long = "deny", value_name = "CIDR")]
+    pub deny: Vec<Cidr>,
+
+    /// The command line the configuration was parsed from, applied again over the
+    /// `--config` file on `/reload`.
+    #[arg(skip)]
+    #[serde(skip_serializing)]
+    pub command_line: Vec<String>,
+}
+
+impl ServerConfig {
+    /// Parses the command line, with the options from its `--config` file, if any.
+    /// Exits with a usage error if either is invalid.
+    pub fn load() -> Self {
+        Self::parse_command_line(std::env::args().collect()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parses `args`, a command line starting with the program name, with the options
+    /// from its `--config` file, if any.
+    pub fn parse_command_line(args: Vec<String>) -> Result<Self, clap::Error> {
+        let mut config = Self::try_parse_from(&args)?;
+        config.command_line = args;
+        match &config.config_file {
+            Some(path) => Self::parse_with_file(path, &config.command_line),
+            None => Ok(config),
+        }
+    }
+
+    /// Re-reads the `--config` file, keeping the command line options as they were.
+    pub fn reload(&self) -> Result<Self, String> {
+        let path = self
+            .config_file
+            .as_ref()
+            .ok_or("the server was started without --config")?;
+        Self::parse_with_file(path, &self.command_line).map_err(|e| {
+            // Only the first line; the rest is advice for the command line.
+            let message = e.to_string();
+            let first_line = message.lines().next().unwrap_or_default();
+            first_line.trim_start_matches("error: ").to_string()
+        })
+    }
+
+    /// Parses the options in the file at `path` followed by `command_line`,
+    /// so options on the command line override the file's.
+    fn parse_with_file(path: &Path, command_line: &[String]) -> Result<Self, clap::Error> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            clap::Error::raw(
+                clap::error::ErrorKind::Io,
+                format!("can't read config file {}: {}\n", path.display(), e),
+            )
+        })?;
+        let mut args: Vec<String> = command_line.iter().take(1).cloned().collect();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // The value is the rest of the line, so it may contain spaces.
+            match line.split_once(char::is_whitespace) {
+                Some((flag, value)) => args.extend([flag.to_string(), value.trim().to_string()]),
+                None => args.push(line.to_string()),
+            }
+        }
+        args.extend(command_line.iter().skip(1).cloned());
+        let mut config = Self::try_parse_from(args)?;
+        config.command_line = command_line.to_vec();
+        Ok(config)
+    }
+
+    /// Returns `true` if a client at `ip` may connect under `--allow` and `--deny`.
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+
+    /// Returns `true` if the bot is enabled and `username` is its name, ignoring case.
+    pub fn is_bot_name(&self, username: &str) -> bool {
+        let bot_enabled = self.bot_greeting.is_some() || !self.bot_triggers.is_empty();
+        bot_enabled && self.bot_name.eq_ignore_ascii_case(username)
+    }
+
+    /// Returns the message to reject `username` with if it breaks `--username-policy`.
+    pub fn username_policy_violation(&self, username: &str) -> Option<String> {
+        let policy = self.username_policy.as_ref()?;
+        if policy.allows(username) {
+            return None;
+        }
+        let rule = self
+            .username_policy_hint
+            .clone()
+            .unwrap_or_else(|| format!("it must match {}", policy.pattern));
+        Some(format!(
+            "The username '{}' isn't allowed on this server: {}.",
+            username,
+            rule.trim_end_matches('.')
+        ))
+    }
+}
+
+/// A regular expression every username must match in full.
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    pattern: String, // The pattern as given, for messages and state dumps.
+    regex: Regex,    // The pattern anchored at both ends.
+}
+
+impl UsernamePolicy {
+    /// Returns `true` if the whole of `username` matches the pattern.
+    pub fn allows(&self, username: &str) -> bool {
+        self.regex.is_match(username)
+    }
+}
+
+impl FromStr for UsernamePolicy {
+    type Err = regex::Error;
+
+    /// Compiles `pattern`, anchored so that a partial match doesn't count.
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", pattern))?,
+        })
+    }
+}
+
+impl Serialize for UsernamePolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+/// Order in which chat history is replayed to a joining client.
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryOrder {
+    /// Replay the oldest stored message first.
+    #[default]
+    OldestFirst,
+    /// Replay the most recent stored message first.
+    NewestFirst,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::test_config;
+
+    #[test]
+    fn allow_and_deny_lists_match_addresses_and_ranges() {
+        let allowed =
+            |args: &[&str], ip: &str| test_config(args).is_ip_allowed(ip.parse().unwrap());
+
+        // Without lists, everyone may connect.
+        assert!(allowed(&[], "203.0.113.7"));
+
+        let allow_only = ["--allow", "10.0.0.0/8", "--allow", "192.168.1.5"];
+        assert!(allowed(&allow_only, "10.20.30.40"));
+        assert!(allowed(&allow_only, "192.168.1.5"));
+        assert!(!allowed(&allow_only, "192.168.1.6"));
+        assert!(!allowed(&allow_only, "11.0.0.1"));
+        assert!(allowed(&allow_only, "::ffff:10.1.2.3")); // IPv4-mapped IPv6.
+
+        let deny_only = ["--deny", "198.51.100.0/24"];
+        assert!(!allowed(&deny_only, "198.51.100.200"));
+        assert!(allowed(&deny_only, "198.51.101.1"));
+
+        // A denied range wins over an allowed one.
+        let both = ["--allow", "10.0.0.0/8", "--deny", "10.1.0.0/16"];
+        assert!(allowed(&both, "10.2.0.1"));
+        assert!(!allowed(&both, "10.1.0.1"));
+    }
+
+    #[test]
+    fn username_policy_accepts_only_full_matches() {
+        let config = test_config(&["--username-policy", "[A-Za-z0-9_]{3,16}"]);
+        for name in ["bob", "alice_99", "ABCDEFGHIJKLMNOP"] {
+            assert_eq!(config.username_policy_violation(name), None, "{}", name);
+        }
+        for name in ["al", "bob!", "bob smith", "ABCDEFGHIJKLMNOPQ", "éve"] {
+            assert!(config.username_policy_violation(name).is_some(), "{}", name);
+        }
+        assert_eq!(
+            config.username_policy_violation("al").unwrap(),
+            "The username 'al' isn't allowed on this server: it must match [A-Za-z0-9_]{3,16}."
+        );
+
+        let hinted = test_config(&[
+            "--username-policy",
+            "[a-z]+",
+            "--username-policy-hint",
+            "lowercase letters only.",
+        ]);
+        assert_eq!(
+            hinted.username_policy_violation("Bob").unwrap(),
+            "The username 'Bob' isn't allowed on this server: lowercase letters only."
+        );
+        // Without a policy, any name is fine.
+        assert_eq!(test_config(&[]).username_policy_violation("b o b!"), None);
+    }
+}
@@ -0,0 +1,113 @@
+// e2e.rs
+use base64::engine::general_purpose::STANDARD as BASE64; // Keys and ciphertext travel as base64 text.
+use base64::Engine; // Provides `encode`/`decode` on the engine.
+use crypto_box::aead::{Aead, AeadCore, OsRng}; // Authenticated encryption and the OS random source.
+use crypto_box::{Nonce, PublicKey, SalsaBox, SecretKey}; // NaCl `crypto_box` (X25519 + XSalsa20-Poly1305).
+use std::collections::{HashMap, HashSet}; // Sessions and key offers by username.
+use thiserror::Error; // Derives `Error` for `E2eError`.
+
+/// Length of a `crypto_box` nonce, which is sent in front of each ciphertext.
+const NONCE_LEN: usize = 24;
+
+/// Errors from end-to-end encryption.
+#[derive(Debug, Error)]
+pub enum E2eError {
+    #[error("'{0}' sent an invalid public key")]
+    InvalidKey(String),
+    #[error("no encrypted conversation with '{0}'; use /encrypt first")]
+    NoSession(String),
+    #[error("couldn't decrypt a message from '{0}'")]
+    DecryptFailed(String),
+    #[error("couldn't encrypt the message")]
+    EncryptFailed,
+}
+
+/// This client's key pair and the shared boxes for users it has exchanged keys with.
+/// Keys only live for the session; the server relays them but never sees a secret key.
+pub struct E2eSessions {
+    secret_key: SecretKey,                            // Generated at startup.
+    sessions: HashMap<String, (PublicKey, SalsaBox)>, // Peer keys and shared boxes by username.
+    offered: HashSet<String>,                         // Users this client has sent its key to.
+}
+
+impl Default for E2eSessions {
+    /// Creates a fresh key pair.
+    fn default() -> Self {
+        Self {
+            secret_key: SecretKey::generate(&mut OsRng),
+            sessions: HashMap::new(),
+            offered: HashSet::new(),
+        }
+    }
+}
+
+impl E2eSessions {
+    /// Returns this client's public key for `username` and remembers that it was offered.
+    pub fn offer(&mut self, username: &str) -> String {
+        self.offered.insert(username.to_string());
+        BASE64.encode(self.secret_key.public_key().as_bytes())
+    }
+
+    /// Stores the public key `username` sent. Returns `true` if this client should answer
+    /// with `offer`: either it hasn't offered its key yet, or the peer's key changed
+    /// (e.g. they restarted) and the peer needs this client's key again.
+    pub fn accept(&mut self, username: &str, key: &str) -> Result<bool, E2eError> {
+        let invalid = || E2eError::InvalidKey(username.to_string());
+        let bytes = BASE64.decode(key).map_err(|_| invalid())?;
+        let public_key = PublicKey::from_slice(&bytes).map_err(|_| invalid())?;
+        let shared_box = SalsaBox::new(&public_key, &self.secret_key);
+        let previous = self
+            .sessions
+            .insert(username.to_string(), (public_key.clone(), shared_box));
+        let key_changed = previous.is_some_and(|(known, _)| known != public_key);
+        Ok(key_changed || !self.offered.contains(username))
+    }
+
+    /// Returns `true` once keys have been exchanged with `username`.
+    pub fn is_established(&self, username: &str) -> bool {
+        self.sessions.contains_key(username)
+    }
+
+    /// Encrypts `plaintext` for `username` as base64 of the nonce followed by the ciphertext.
+    pub fn encrypt(&self, username: &str, plaintext: &str) -> Result<String, E2eError> {
+        let (_, session) = self
+            .sessions
+            .get(username)
+            .ok_or_else(|| E2eError::NoSession(username.to_string()))?;
+        let nonce = SalsaBox::generate_nonce(&mut OsRng);
+        let ciphertext = session
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| E2eError::EncryptFailed)?;
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(BASE64.encode(payload))
+    }
+
+    /// Moves the keys exchanged with `old_name` to `new_name` after they change their username.
+    pub fn rename(&mut self, old_name: &str, new_name: &str) {
+        if let Some(session) = self.sessions.remove(old_name) {
+            self.sessions.insert(new_name.to_string(), session);
+        }
+        if self.offered.remove(old_name) {
+            self.offered.insert(new_name.to_string());
+        }
+    }
+
+    /// Decrypts a payload produced by `encrypt` on `username`'s side.
+    pub fn decrypt(&self, username: &str, payload: &str) -> Result<String, E2eError> {
+        let (_, session) = self
+            .sessions
+            .get(username)
+            .ok_or_else(|| E2eError::NoSession(username.to_string()))?;
+        let failed = || E2eError::DecryptFailed(username.to_string());
+        let bytes = BASE64.decode(payload).map_err(|_| failed())?;
+        if bytes.len() < NONCE_LEN {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = session
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| failed())?;
+        String::from_utf8(plaintext).map_err(|_| failed())
+    }
+}
@@ -1,5 +1,7 @@
 // errors.rs
-use std::io;
+use serde_json::error::Category;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
 use std::sync::PoisonError;
 use thiserror::Error;
 
@@ -15,16 +17,122 @@ pub enum ChatServerError {
     PoisonedLock,
     #[error("No available ports")]
     NoAvailablePorts,
-    #[error("Invalid message")]
-    InvalidMessage(String),
+    #[error("Malformed JSON from {0}: {1}")]
+    MalformedJson(String, String),
+    #[error("Message from {0} doesn't follow the protocol: {1}")]
+    SchemaViolation(String, String),
+    #[error("Client sent another message before joining: {0}")]
+    JoinRequired(String),
     #[error("Missing username")]
     MissingUsername(String),
+    #[error("Reserved username: {0}")]
+    ReservedUsername(String),
+    #[error("Username not allowed by policy: {0}")]
+    UsernameNotAllowed(String),
+    #[error("Username too long; refused {0}")]
+    UsernameTooLong(String),
+    #[error("Username taken: {0}")]
+    UsernameTaken(String),
+    #[error("Chat is full; refused {0}")]
+    ChatFull(String),
+    #[error("Client did not register in time: {0}")]
+    RegistrationTimeout(String),
 }
 
 pub type ChatResult<T> = Result<T, ChatServerError>;
 
+impl ChatServerError {
+    /// Classifies a frame from the client at `addr` that failed to decode: text that isn't
+    /// JSON at all, or JSON that doesn't describe a message, such as one missing a field.
+    pub fn from_decode(addr: SocketAddr, e: serde_json::Error) -> Self {
+        match e.classify() {
+            Category::Data => ChatServerError::SchemaViolation(addr.to_string(), e.to_string()),
+            Category::Syntax | Category::Eof | Category::Io => {
+                ChatServerError::MalformedJson(addr.to_string(), e.to_string())
+            }
+        }
+    }
+
+    /// The explanation sent back to a client whose frame failed to decode.
+    /// `None` for errors that aren't about the client's frame.
+    pub fn client_message(&self) -> Option<String> {
+        match self {
+            ChatServerError::MalformedJson(_, detail) => {
+                Some(format!("Malformed JSON: {}", detail))
+            }
+            ChatServerError::SchemaViolation(_, detail) => {
+                Some(format!("Invalid message: {}", detail))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the error shows the peer's connection is gone, rather than
+    /// a failure the connection may recover from.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            ChatServerError::IoError(e) => matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::WriteZero
+            ),
+            ChatServerError::ClientDisconnected(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl<T> From<PoisonError<T>> for ChatServerError {
     fn from(_: PoisonError<T>) -> Self {
         ChatServerError::PoisonedLock
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Frame;
+
+    #[test]
+    fn decode_errors_tell_malformed_json_from_schema_violations() {
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let decode =
+            |text: &str| ChatServerError::from_decode(addr, Frame::decode(text).unwrap_err());
+
+        for text in ["not json", r#"{"message": {"content": "hi""#] {
+            let error = decode(text);
+            assert!(
+                matches!(&error, ChatServerError::MalformedJson(from, _) if *from == addr.to_string()),
+                "{}: {:?}",
+                text,
+                error
+            );
+            assert!(error
+                .client_message()
+                .unwrap()
+                .starts_with("Malformed JSON: "));
+        }
+        // Valid JSON, but a message needs a type and the content must be text.
+        for text in [
+            r#"{"version": 2, "message": {"content": "hi"}}"#,
+            r#"{"message_type": "Message", "content": 42}"#,
+        ] {
+            let error = decode(text);
+            assert!(
+                matches!(&error, ChatServerError::SchemaViolation(from, _) if *from == addr.to_string()),
+                "{}: {:?}",
+                text,
+                error
+            );
+            assert!(error
+                .client_message()
+                .unwrap()
+                .starts_with("Invalid message: "));
+        }
+        assert_eq!(ChatServerError::PoisonedLock.client_message(), None);
+    }
+}
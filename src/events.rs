@@ -0,0 +1,155 @@
+// events.rs
+use crate::audit::AuditEntry; // Details of `Moderation` events.
+use crate::message::CommandType; // Identifies the command in `Command` events.
+use std::collections::VecDeque; // Recent connection events, oldest first.
+use std::net::SocketAddr; // Address identifying the client an event concerns.
+use std::sync::Mutex; // Recorded from every client handler thread.
+use std::time::{SystemTime, UNIX_EPOCH}; // Timestamps of connection events.
+
+/// Most connection events kept for `/recent`.
+pub const RECENT_CONNECTIONS_CAP: usize = 100;
+
+/// Notable things happening on the server, published for observers such as loggers,
+/// metrics or plugins so those side effects stay out of the client handlers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SystemEvent {
+    /// A client registered with a username, got the history and was announced to the others.
+    Join { addr: SocketAddr, username: String },
+    /// A registered client was removed from the server.
+    Leave { addr: SocketAddr, username: String },
+    /// A chat message was broadcast and stored with `seq`.
+    Message {
+        addr: SocketAddr,
+        username: String,
+        seq: u64,
+    },
+    /// A client changed its username with `/nick`.
+    Rename {
+        addr: SocketAddr,
+        old_name: String,
+        new_name: String,
+    },
+    /// A client issued a well-formed command.
+    Command {
+        addr: SocketAddr,
+        username: String,
+        command: CommandType,
+    },
+    /// A client sent something the server couldn't accept.
+    Error { addr: SocketAddr, error: String },
+    /// An admin took a moderation action; it has also been written to the audit log.
+    Moderation(AuditEntry),
+}
+
+/// A client joining or leaving, as reported by `/recent`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionEvent {
+    pub timestamp: u64,     // Unix time (seconds) of the event.
+    pub kind: &'static str, // `joined` or `left`.
+    pub username: String,   // Username of the client.
+    pub addr: SocketAddr,   // Address the client connected from.
+}
+
+/// The most recent joins and leaves, kept so admins can debug flaky connections.
+#[derive(Default)]
+pub struct ConnectionLog {
+    recent: Mutex<VecDeque<ConnectionEvent>>, // At most `RECENT_CONNECTIONS_CAP` events, oldest first.
+}
+
+impl ConnectionLog {
+    /// Records `event` if it is a join or a leave; other events are ignored.
+    pub fn record(&self, event: &SystemEvent) {
+        let (kind, addr, username) = match event {
+            SystemEvent::Join { addr, username } => ("joined", addr, username),
+            SystemEvent::Leave { addr, username } => ("left", addr, username),
+            _ => return,
+        };
+        if let Ok(mut recent) = self.recent.lock() {
+            if recent.len() == RECENT_CONNECTIONS_CAP {
+                recent.pop_front();
+            }
+            recent.push_back(ConnectionEvent {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                kind,
+                username: username.clone(),
+                addr: *addr,
+            });
+        }
+    }
+
+    /// Returns up to `count` of the most recent events, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<ConnectionEvent> {
+        let Ok(recent) = self.recent.lock() else {
+            return Vec::new();
+        };
+        recent
+            .iter()
+            .skip(recent.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ServerState;
+    use crate::test_support::{test_config, TestClient};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn joining_emits_a_join_event_with_the_username() {
+        let mut state = ServerState::new(test_config(&[]));
+        let events = state.subscribe();
+        let state = Arc::new(state);
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            event,
+            SystemEvent::Join {
+                addr: "10.0.0.1:5000".parse().unwrap(),
+                username: "alice".to_string(),
+            }
+        );
+        let recent = state.connections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].username, "alice");
+    }
+
+    #[test]
+    fn connection_log_keeps_the_latest_events_in_order() {
+        let log = ConnectionLog::default();
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        for i in 0..RECENT_CONNECTIONS_CAP + 5 {
+            let username = format!("user{}", i);
+            log.record(&SystemEvent::Join { addr, username });
+        }
+        log.record(&SystemEvent::Leave {
+            addr,
+            username: "alice".to_string(),
+        });
+
+        let recent = log.recent(usize::MAX);
+        assert_eq!(recent.len(), RECENT_CONNECTIONS_CAP);
+        assert_eq!(recent[0].username, "user6"); // The oldest ones were dropped.
+        let last_two: Vec<(&str, String)> = log
+            .recent(2)
+            .into_iter()
+            .map(|event| (event.kind, event.username))
+            .collect();
+        assert_eq!(
+            last_two,
+            [
+                ("joined", format!("user{}", RECENT_CONNECTIONS_CAP + 4)),
+                ("left", "alice".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+// files.rs
+use base64::engine::general_purpose::STANDARD as BASE64; // File contents travel as base64 text.
+use base64::Engine; // Provides `encode`/`decode` on the engine.
+use std::fs::{self, OpenOptions}; // Reading shared files and creating received ones.
+use std::io::{self, ErrorKind, Write}; // Writing received files.
+use std::path::{Path, PathBuf}; // Locations of shared and received files.
+
+/// Reads the file at `path` for sharing. Returns its file name and base64 contents.
+pub fn load(path: &Path) -> io::Result<(String, String)> {
+    let name = file_name(&path.to_string_lossy())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not a file path"))?;
+    let bytes = fs::read(path)?;
+    Ok((name, BASE64.encode(bytes)))
+}
+
+/// Saves a received file in `dir`. Existing files are never overwritten: a numbered name
+/// such as `1-notes.txt` is used instead. Returns where the file was saved and its size.
+pub fn save(dir: &Path, name: &str, data: &str) -> io::Result<(PathBuf, usize)> {
+    let bytes = BASE64
+        .decode(data)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    save_bytes(dir, name, &bytes)
+}
+
+/// Like `save`, for contents that aren't base64-encoded.
+pub fn save_bytes(dir: &Path, name: &str, bytes: &[u8]) -> io::Result<(PathBuf, usize)> {
+    let name = file_name(name)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid file name"))?;
+    for attempt in 0u32.. {
+        let path = match attempt {
+            0 => dir.join(&name),
+            n => dir.join(format!("{}-{}", n, name)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                return Ok((path, bytes.len()));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("ran out of file name suffixes")
+}
+
+/// The last component of `name`, so a sender can't choose where a file is saved.
+fn file_name(name: &str) -> Option<String> {
+    let name = Path::new(name.trim()).file_name()?.to_string_lossy();
+    (!name.starts_with('.')).then(|| name.to_string())
+}
@@ -0,0 +1,38 @@
+// history.rs
+use crate::errors::ChatResult; // Custom result type for error handling.
+use crate::message::ChatMessage; // History entries.
+use crate::state::ServerState; // Holds the history being saved or restored.
+use std::fs; // Reading and writing the history file.
+use std::io; // A missing history file isn't an error.
+use std::path::Path; // Location of the history file.
+use std::sync::atomic::Ordering; // Restoring the last assigned seq.
+
+/// Loads the history saved at `path` into `state`, so seqs continue where they left off.
+/// A missing file leaves the history empty.
+pub fn restore(state: &ServerState, path: &Path) -> ChatResult<()> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let messages: Vec<ChatMessage> = serde_json::from_str(&text)?;
+    let last_seq = messages.iter().filter_map(|msg| msg.seq).max();
+    state
+        .next_seq
+        .fetch_max(last_seq.unwrap_or_default(), Ordering::SeqCst);
+    *state.chat_history.write()? = messages;
+    Ok(())
+}
+
+/// Writes the history to `path` as a JSON array. The history is copied under a short read
+/// lock and serialized after it is released, so broadcasts aren't held up by the write.
+/// The file is replaced atomically, so a crash mid-save leaves the previous save intact.
+pub fn save(state: &ServerState, path: &Path) -> ChatResult<()> {
+    let messages = state.chat_history.read()?.clone();
+    let json = serde_json::to_string(&messages)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, json)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
@@ -0,0 +1,25 @@
+// lib.rs
+// Modules shared by the `chat-server` and `chat-client` binaries.
+pub mod compression;
+pub mod message;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod transport;
+
+// The chat server; `chat-server` is a thin wrapper around `runtime`.
+mod audit;
+mod bot;
+mod cidr;
+mod client_handler;
+mod commands;
+pub mod config;
+pub mod errors;
+mod events;
+mod history;
+mod outbox;
+pub mod runtime;
+pub mod state;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "websocket")]
+mod websocket;
@@ -1,13 +1,29 @@
 // message.rs
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum ChatMessageType {
+    #[default]
     Message,
     Join,
     Leave,
     Command(CommandType),
+    AckRequest,   // Sent by the server at the end of a history replay window.
+    Ack,          // Sent by the client to request the next history replay window.
+    Error,        // Sent by the server to a single client when a request is rejected.
+    Ping,         // Sent by the server; `seq` carries the ping round id.
+    Pong,         // Sent by the client in reply to a ping, echoing its `seq`.
+    Reaction,     // Sent by the server; `seq` is the reacted-to message, `content` the aggregate.
+    Capabilities, // Sent by the client to ask, and by the server with a JSON array of features.
+    Presence,     // Sent by the server when the roster changes; `content` is a JSON array of users.
+    Compressed, // Sent by the server to opted-in clients; `content` is a deflated, base64 message.
+    Rename,     // Sent by the server after a `/nick`; `content` is a JSON `Rename`.
+    Expired, // Sent by the server when messages expire from history; `content` is a JSON array of their seqs.
+    SessionEnd, // Sent by the server to a client whose session it ends; `content` says why. The client exits.
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -15,11 +31,1268 @@ pub enum ChatMessageType {
 pub enum CommandType {
     List,
     Quit,
+    Unread,     // Sent by the server to report how many messages a reconnecting client missed.
+    Admin,      // Authenticates as an admin; `content` carries the admin token.
+    SetMaxLen,  // Admin-only; `content` carries the new maximum message length.
+    PingAll,    // Admin-only; measures round-trip time to every connected client.
+    DumpState,  // Admin-only; logs a snapshot of the server state.
+    Nick,       // Changes the sender's username; `content` carries the new name.
+    Last,       // Shows the last message sent by a user; `content` carries the username.
+    React,      // Reacts to a message; `content` carries the seq and the emoji.
+    Slap,       // Slaps another user with a trout; `content` carries the username.
+    Mute,       // Admin-only; `content` carries the username and an optional duration.
+    Unmute,     // Admin-only; `content` carries the username.
+    GrantAdmin, // Admin-only; `content` carries the username to promote.
+    RevokeAdmin, // Admin-only; `content` carries the username to demote.
+    Msg,        // Private message; `content` carries comma-separated targets, then the text.
+    Version,    // Asks for the server and protocol versions.
+    Encrypt,    // Client-only; starts an end-to-end encrypted conversation with a user.
+    PublicKey,  // Relayed to one user; `content` carries the target, then a base64 public key.
+    Encrypted,  // Relayed to one user; `content` carries the target, then base64 ciphertext.
+    Find,       // Searches chat history; `content` carries the search text.
+    Reply,      // Replies to a message; `content` carries the parent's seq, then the text.
+    Uptime,     // Asks how long the server has been running.
+    BroadcastFile, // Client-only; reads a file and sends it to everyone as `SharedFile`.
+    SharedFile, // Admin-only; `content` carries the file name, then the base64 file contents.
+    AuditLog,   // Admin-only; `content` carries how many recent moderation actions to show.
+    Schedule,   // Admin-only; `content` carries the delay in seconds, then the announcement.
+    Unschedule, // Admin-only; `content` carries the id of the announcement to cancel.
+    HistoryMode, // Admin-only; `content` carries who gets history replayed on join.
+    DownloadHistory, // Asks for the chat history; the reply's `content` is a JSON `HistoryDownload`.
+    Recent,          // Admin-only; `content` carries how many recent joins and leaves to show.
+    Reload,          // Admin-only; re-reads the `--config` file.
+    Quote,           // Quotes a message; `content` carries its seq, then the text.
+    Status,          // Sets the sender's status shown in `/list`; empty `content` clears it.
+    Pin,             // Admin-only; `content` carries the seq of the message to pin.
+    Unpin,           // Admin-only; `content` carries the seq of the message to unpin.
+    Pins,            // Lists the pinned messages; also sent by the server to joining clients.
+    Notify,          // Sets what the sender wants to be notified of; empty `content` shows it.
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A client's privilege level. Each command declares the lowest role allowed to run it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin, // Authenticated with `/admin` or promoted with `/grantadmin`.
+}
+
+/// Which joining clients get the chat history replayed; changed at runtime with `/history-mode`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryMode {
+    #[default]
+    Public, // Every joining client gets the history.
+    Private, // Only clients that were here before get the messages they missed.
+    Off,     // No client gets the history.
+}
+
+impl HistoryMode {
+    /// The name the mode is typed as.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Private => "private",
+            Self::Off => "off",
+        }
+    }
+
+    /// Looks up a mode by the name it is typed as.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "public" => Some(Self::Public),
+            "private" => Some(Self::Private),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// What a user wants to be notified of; set for the session with `/notify`.
+/// Only stored for now: no delivery path reads it, so every message is still sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyMode {
+    #[default]
+    All, // Every message.
+    Mentions, // Messages that mention the user, and private messages.
+    Private,  // Private messages only.
+    Off,      // Nothing.
+}
+
+impl NotifyMode {
+    /// The name the mode is typed as.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Mentions => "mentions",
+            Self::Private => "private",
+            Self::Off => "off",
+        }
+    }
+
+    /// Looks up a mode by the name it is typed as.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(Self::All),
+            "mentions" => Some(Self::Mentions),
+            "private" => Some(Self::Private),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Content of a `Rename` message: a user's name before and after a `/nick`.
+/// Clients that keep messages around can use it to relabel the old name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rename {
+    pub old_name: String,
+    pub new_name: String,
+}
+
+/// An earlier chat message quoted by a `/quote` message, copied as it was when quoted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub seq: u64,         // The quoted message's seq.
+    pub username: String, // Who sent the quoted message.
+    pub content: String,  // The quoted message's text.
+}
+
+/// Content of the server's reply to `/download-history`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HistoryDownload {
+    pub messages: Vec<ChatMessage>, // The most recent messages that fit the size cap, oldest first.
+    pub total: usize,               // How many messages the server has stored.
+}
+
+/// Delivery priority of a message in each client's outbound queue.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High, // Delivered ahead of any queued normal-priority messages.
+}
+
+impl Priority {
+    /// Returns `true` for the default priority, which is omitted from the wire format.
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ChatMessage {
     pub message_type: ChatMessageType,
     pub username: Option<String>,
     pub content: String,
+    // Server-assigned sequence number, set when the message is stored in history.
+    // On a client's join message it carries the last seq the client saw, if reconnecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // Server-assigned Unix timestamp (seconds), set when the message is stored in history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    // Set only on messages the server itself originates (join/leave notices, replies, errors).
+    // Clients render the system style only for flagged messages.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub system: bool,
+    // Outbound queue priority; absent on the wire means normal.
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // Seq of the message this one replies to, set by the server on `/reply` messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    // How `content` is encoded; absent on the wire means UTF-8 text.
+    #[serde(default, skip_serializing_if = "ContentEncoding::is_utf8")]
+    pub encoding: ContentEncoding,
+    // Seconds a chat message stays in history, set by the sender; absent means it never expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    // The message quoted by a `/quote` message, set by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Quote>,
+}
+
+/// How a message's `content` is encoded, so receivers know how to interpret it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ContentEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8, // Plain text.
+    #[serde(rename = "base64")]
+    Base64, // Binary data, e.g. the deflated frame in a `Compressed` message.
+    #[serde(other)]
+    Unknown, // Any encoding this version doesn't know; never sent, and rejected on receipt.
+}
+
+impl ContentEncoding {
+    /// Returns `true` for the default encoding, which is omitted from the wire format.
+    pub fn is_utf8(&self) -> bool {
+        *self == ContentEncoding::Utf8
+    }
+
+    /// The name the encoding has on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Base64 => "base64",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Version of the wire format; bumped on incompatible changes to `Frame` or `ChatMessage`.
+/// Version 2 wraps every message in a `Frame`; version 1 sent bare messages.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Protocol-level flags of a `Frame`, as a bit set. Bits this version doesn't know are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct FrameFlags(u32);
+
+impl FrameFlags {
+    /// The message is a `Compressed` message carrying another frame.
+    pub const COMPRESSED: Self = Self(1);
+
+    /// Returns `true` if every flag in `other` is set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if no flag is set.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// What travels on the wire: a `ChatMessage` wrapped in an envelope of protocol-level fields,
+/// so those can change without touching the chat payload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Frame {
+    pub version: u32, // Protocol version of the sender.
+    #[serde(default, skip_serializing_if = "FrameFlags::is_empty")]
+    pub flags: FrameFlags,
+    pub message: ChatMessage, // The payload.
+}
+
+impl Frame {
+    /// Wraps `message` in a frame of the current protocol version.
+    pub fn new(message: ChatMessage, flags: FrameFlags) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            flags,
+            message,
+        }
+    }
+
+    /// Serializes `message` as a frame with no flags, ready to be written to a transport.
+    pub fn encode(message: &ChatMessage) -> serde_json::Result<String> {
+        serde_json::to_string(&Self::new(message.clone(), FrameFlags::default()))
+    }
+
+    /// Parses a frame read from a transport. A bare `ChatMessage`, as sent by
+    /// version 1 peers such as older clients, is accepted as a version 1 frame.
+    pub fn decode(text: &str) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        if value
+            .get("message")
+            .is_some_and(serde_json::Value::is_object)
+        {
+            serde_json::from_value(value)
+        } else {
+            Ok(Self {
+                version: 1,
+                flags: FrameFlags::default(),
+                message: serde_json::from_value(value)?,
+            })
+        }
+    }
+}
+
+/// Formats a Unix timestamp as `HH:MM` UTC, as times are shown by both the client and server.
+pub fn format_time(timestamp: u64) -> String {
+    format!("{:02}:{:02}", timestamp / 3600 % 24, timestamp / 60 % 60)
+}
+
+/// Longest username, in characters, that the client and server accept.
+pub const MAX_USERNAME_LEN: usize = 20;
+
+/// Usernames that could be mistaken for server notices; compared case-insensitively.
+pub const RESERVED_USERNAMES: &[&str] = &["server", "system", "admin"];
+
+/// Returns `true` if `username` is reserved and can't be used by a client.
+pub fn is_reserved_username(username: &str) -> bool {
+    RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(username.trim()))
+}
+
+/// Maximum number of characters in a reaction.
+pub const MAX_REACTION_LEN: usize = 16;
+
+/// `/msg` target that addresses every other online user.
+pub const EVERYONE_TARGET: &str = "@everyone";
+
+/// User-configured alternate names for commands, e.g. `who` for `/list`.
+/// Aliases can't shadow built-in command names.
+#[derive(Debug, Clone, Default)]
+pub struct CommandAliases {
+    aliases: HashMap<String, CommandType>, // Command types by alias.
+}
+
+impl CommandAliases {
+    /// Adds an alias from a spec such as `who=list` (the command may include its slash).
+    pub fn add(&mut self, spec: &str) -> Result<(), ParseError> {
+        let invalid = || ParseError::InvalidAlias(spec.to_string());
+        let (alias, command) = spec.split_once('=').ok_or_else(invalid)?;
+        let (alias, command) = (alias.trim(), command.trim());
+        let command = command.strip_prefix('/').unwrap_or(command);
+        if alias.is_empty()
+            || alias.contains(char::is_whitespace)
+            || alias.starts_with('/')
+            || CommandType::from_name(alias).is_some()
+        {
+            return Err(invalid());
+        }
+        let command_type = CommandType::from_name(command).ok_or_else(invalid)?;
+        self.aliases.insert(alias.to_string(), command_type);
+        Ok(())
+    }
+}
+
+/// A command entered by a user, together with its arguments.
+/// Shared by the client (parsing user input) and the server (decoding command messages),
+/// so adding a command only means extending this enum and `CommandType`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // A `None` page shows everyone on a small server, or the first page on a large one.
+    List {
+        page: Option<usize>,
+    },
+    Quit,
+    Admin {
+        token: String,
+    },
+    SetMaxLen(usize),
+    PingAll,
+    DumpState,
+    Nick {
+        name: String,
+    },
+    Last {
+        username: String,
+    },
+    React {
+        seq: u64,
+        emoji: String,
+    },
+    Slap {
+        target: String,
+    },
+    // A `None` duration mutes until `/unmute`.
+    Mute {
+        username: String,
+        duration: Option<Duration>,
+    },
+    Unmute {
+        username: String,
+    },
+    GrantAdmin {
+        username: String,
+    },
+    RevokeAdmin {
+        username: String,
+    },
+    // Targets are usernames or `EVERYONE_TARGET`.
+    Msg {
+        targets: Vec<String>,
+        text: String,
+    },
+    Version,
+    Uptime,
+    Encrypt {
+        username: String,
+    },
+    // Sent by the client on its own during `/encrypt`; can't be typed.
+    PublicKey {
+        target: String,
+        key: String,
+    },
+    // Sent by the client in place of a `/msg` to a user it shares a key with; can't be typed.
+    Encrypted {
+        target: String,
+        payload: String,
+    },
+    Find {
+        text: String,
+    },
+    Reply {
+        seq: u64,
+        text: String,
+    },
+    Quote {
+        seq: u64,
+        text: String,
+    },
+    // `None` clears the status.
+    Status {
+        text: Option<String>,
+    },
+    Pin {
+        seq: u64,
+    },
+    Unpin {
+        seq: u64,
+    },
+    Pins,
+    // A `None` mode shows the current one.
+    Notify(Option<NotifyMode>),
+    BroadcastFile {
+        path: String,
+    },
+    // Sent by the client on its own for `/broadcast-file`; can't be typed.
+    SharedFile {
+        name: String,
+        data: String,
+    },
+    // A `None` count shows the server's default number of entries.
+    AuditLog {
+        count: Option<usize>,
+    },
+    // Like `AuditLog`, a `None` count shows the default number of events.
+    Recent {
+        count: Option<usize>,
+    },
+    Schedule {
+        delay: Duration,
+        text: String,
+    },
+    Unschedule {
+        id: u64,
+    },
+    HistoryMode(HistoryMode),
+    Reload,
+    DownloadHistory,
+}
+
+/// Errors produced when parsing a `Command`.
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error("Input is not a command")]
+    NotACommand,
+    #[error("Unknown command: /{0}")]
+    UnknownCommand(String),
+    #[error("Usage: {0}")]
+    InvalidArguments(&'static str),
+    #[error("Unbalanced quotes in arguments")]
+    UnbalancedQuotes,
+    #[error("Invalid alias '{0}': expected <alias>=<command> naming an existing command")]
+    InvalidAlias(String),
+}
+
+impl CommandType {
+    /// Looks up the command a user can type as `/name`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "list" => Some(Self::List),
+            "quit" => Some(Self::Quit),
+            "admin" => Some(Self::Admin),
+            "setmaxlen" => Some(Self::SetMaxLen),
+            "ping-all" => Some(Self::PingAll),
+            "dumpstate" => Some(Self::DumpState),
+            "nick" => Some(Self::Nick),
+            "last" => Some(Self::Last),
+            "react" => Some(Self::React),
+            "slap" => Some(Self::Slap),
+            "mute" => Some(Self::Mute),
+            "unmute" => Some(Self::Unmute),
+            "grantadmin" => Some(Self::GrantAdmin),
+            "revokeadmin" => Some(Self::RevokeAdmin),
+            "msg" => Some(Self::Msg),
+            "version" => Some(Self::Version),
+            "encrypt" => Some(Self::Encrypt),
+            "find" => Some(Self::Find),
+            "reply" => Some(Self::Reply),
+            "uptime" => Some(Self::Uptime),
+            "broadcast-file" => Some(Self::BroadcastFile),
+            "auditlog" => Some(Self::AuditLog),
+            "schedule" => Some(Self::Schedule),
+            "unschedule" => Some(Self::Unschedule),
+            "history-mode" => Some(Self::HistoryMode),
+            "download-history" => Some(Self::DownloadHistory),
+            "recent" => Some(Self::Recent),
+            "reload" => Some(Self::Reload),
+            "quote" => Some(Self::Quote),
+            "status" => Some(Self::Status),
+            "pin" => Some(Self::Pin),
+            "unpin" => Some(Self::Unpin),
+            "pins" => Some(Self::Pins),
+            "notify" => Some(Self::Notify),
+            _ => None,
+        }
+    }
+
+    /// The name the command is typed as, without the leading slash.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::List => "list",
+            Self::Quit => "quit",
+            Self::Unread => "unread",
+            Self::Admin => "admin",
+            Self::SetMaxLen => "setmaxlen",
+            Self::PingAll => "ping-all",
+            Self::DumpState => "dumpstate",
+            Self::Nick => "nick",
+            Self::Last => "last",
+            Self::React => "react",
+            Self::Slap => "slap",
+            Self::Mute => "mute",
+            Self::Unmute => "unmute",
+            Self::GrantAdmin => "grantadmin",
+            Self::RevokeAdmin => "revokeadmin",
+            Self::Msg => "msg",
+            Self::Version => "version",
+            Self::Encrypt => "encrypt",
+            Self::PublicKey => "publickey",
+            Self::Encrypted => "encrypted",
+            Self::Find => "find",
+            Self::Reply => "reply",
+            Self::Uptime => "uptime",
+            Self::BroadcastFile => "broadcast-file",
+            Self::SharedFile => "sharedfile",
+            Self::AuditLog => "auditlog",
+            Self::Schedule => "schedule",
+            Self::Unschedule => "unschedule",
+            Self::HistoryMode => "history-mode",
+            Self::DownloadHistory => "download-history",
+            Self::Recent => "recent",
+            Self::Reload => "reload",
+            Self::Quote => "quote",
+            Self::Status => "status",
+            Self::Pin => "pin",
+            Self::Unpin => "unpin",
+            Self::Pins => "pins",
+            Self::Notify => "notify",
+        }
+    }
+
+    /// The server capability the command depends on, if any.
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            Self::Admin
+            | Self::SetMaxLen
+            | Self::PingAll
+            | Self::DumpState
+            | Self::Mute
+            | Self::Unmute
+            | Self::GrantAdmin
+            | Self::RevokeAdmin
+            | Self::BroadcastFile
+            | Self::SharedFile
+            | Self::AuditLog
+            | Self::Schedule
+            | Self::Unschedule
+            | Self::HistoryMode
+            | Self::Recent
+            | Self::Reload
+            | Self::Pin
+            | Self::Unpin => Some("admin"),
+            Self::Encrypt | Self::PublicKey | Self::Encrypted => Some("e2e"),
+            _ => None,
+        }
+    }
+
+    /// The lowest role allowed to run the command; the server checks it before dispatch.
+    pub fn required_role(&self) -> Role {
+        match self {
+            Self::SetMaxLen
+            | Self::PingAll
+            | Self::DumpState
+            | Self::Mute
+            | Self::Unmute
+            | Self::GrantAdmin
+            | Self::RevokeAdmin
+            | Self::BroadcastFile
+            | Self::SharedFile
+            | Self::AuditLog
+            | Self::Schedule
+            | Self::Unschedule
+            | Self::HistoryMode
+            | Self::Recent
+            | Self::Reload
+            | Self::Pin
+            | Self::Unpin => Role::Admin,
+            _ => Role::User,
+        }
+    }
+
+    /// Usage text shown when the command's arguments are invalid.
+    fn usage(&self) -> &'static str {
+        match self {
+            Self::List => "/list [page]",
+            Self::Quit => "/quit",
+            Self::Unread => "/unread is sent by the server only",
+            Self::Admin => "/admin <token>",
+            Self::SetMaxLen => "/setmaxlen <n>",
+            Self::PingAll => "/ping-all",
+            Self::DumpState => "/dumpstate",
+            Self::Nick => "/nick <name>",
+            Self::Last => "/last <username>",
+            Self::React => "/react <seq> <emoji>",
+            Self::Slap => "/slap <username>",
+            Self::Mute => "/mute <username> [duration, e.g. 30s, 10m, 1h]",
+            Self::Unmute => "/unmute <username>",
+            Self::GrantAdmin => "/grantadmin <username>",
+            Self::RevokeAdmin => "/revokeadmin <username>",
+            Self::Msg => "/msg <username>[,<username>...]|@everyone <message>",
+            Self::Version => "/version",
+            Self::Encrypt => "/encrypt <username>",
+            Self::PublicKey => "/publickey is sent by the client only",
+            Self::Encrypted => "/encrypted is sent by the client only",
+            Self::Find => "/find <text>",
+            Self::Reply => "/reply <seq> <message>",
+            Self::Uptime => "/uptime",
+            Self::BroadcastFile => "/broadcast-file <path>",
+            Self::SharedFile => "/sharedfile is sent by the client only",
+            Self::AuditLog => "/auditlog [n]",
+            Self::Schedule => "/schedule <seconds> <announcement>",
+            Self::Unschedule => "/unschedule <id>",
+            Self::HistoryMode => "/history-mode <public|private|off>",
+            Self::DownloadHistory => "/download-history",
+            Self::Recent => "/recent [n]",
+            Self::Reload => "/reload",
+            Self::Quote => "/quote <seq> <message>",
+            Self::Status => "/status [text]",
+            Self::Pin => "/pin <seq>",
+            Self::Unpin => "/unpin <seq>",
+            Self::Pins => "/pins",
+            Self::Notify => "/notify [all|mentions|private|off]",
+        }
+    }
+}
+
+impl Command {
+    /// Parses user input such as `/setmaxlen 50` into a `Command`.
+    /// Returns `ParseError::NotACommand` for input that doesn't start with `/`.
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let input = input.trim();
+        let rest = input.strip_prefix('/').ok_or(ParseError::NotACommand)?;
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let command_type = CommandType::from_name(name)
+            .ok_or_else(|| ParseError::UnknownCommand(name.to_string()))?;
+        Self::from_parts(&command_type, args)
+    }
+
+    /// Like `parse`, but also resolves user-configured `aliases`.
+    /// An alias may be typed with or without the slash; without it, the input only counts
+    /// as a command if its arguments are valid for that command, so ordinary sentences
+    /// starting with the alias word are still sent as messages.
+    pub fn parse_with_aliases(input: &str, aliases: &CommandAliases) -> Result<Self, ParseError> {
+        let input = input.trim();
+        let (slashed, rest) = match input.strip_prefix('/') {
+            Some(rest) => (true, rest),
+            None => (false, input),
+        };
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let Some(command_type) = aliases.aliases.get(name) else {
+            return Self::parse(input);
+        };
+        if slashed {
+            return Self::from_parts(command_type, args);
+        }
+        match Self::from_parts(command_type, args) {
+            // Commands without arguments ignore extra words, so those must be absent.
+            Ok(command) if args.trim().is_empty() || !command.args().is_empty() => Ok(command),
+            _ => Err(ParseError::NotACommand),
+        }
+    }
+
+    /// Builds a `Command` from a command type and its raw arguments,
+    /// as received in a `ChatMessage` (the arguments travel in `content`).
+    pub fn from_parts(command_type: &CommandType, args: &str) -> Result<Self, ParseError> {
+        let args = args.trim();
+        let invalid = || ParseError::InvalidArguments(command_type.usage());
+        // Arguments other than the admin token may be quoted to include spaces.
+        let tokens = || tokenize(args);
+        let single = || match tokens()?.as_slice() {
+            [arg] if !arg.is_empty() => Ok(arg.clone()),
+            _ => Err(invalid()),
+        };
+        match command_type {
+            CommandType::List if args.is_empty() => Ok(Self::List { page: None }),
+            CommandType::List => match args.parse() {
+                Ok(page) if page > 0 => Ok(Self::List { page: Some(page) }),
+                _ => Err(invalid()),
+            },
+            CommandType::Quit => Ok(Self::Quit),
+            CommandType::PingAll => Ok(Self::PingAll),
+            CommandType::DumpState => Ok(Self::DumpState),
+            CommandType::Version => Ok(Self::Version),
+            CommandType::Uptime => Ok(Self::Uptime),
+            CommandType::DownloadHistory => Ok(Self::DownloadHistory),
+            CommandType::Reload => Ok(Self::Reload),
+            CommandType::Pins => Ok(Self::Pins),
+            CommandType::Admin if !args.is_empty() => Ok(Self::Admin {
+                token: args.to_string(),
+            }),
+            // The status is kept as typed; no text clears it.
+            CommandType::Status => Ok(Self::Status {
+                text: (!args.is_empty()).then(|| args.to_string()),
+            }),
+            // The search text is matched as typed, spaces included.
+            CommandType::Find if !args.is_empty() => Ok(Self::Find {
+                text: args.to_string(),
+            }),
+            CommandType::Nick => Ok(Self::Nick { name: single()? }),
+            CommandType::Last => Ok(Self::Last {
+                username: single()?,
+            }),
+            CommandType::Slap => Ok(Self::Slap { target: single()? }),
+            CommandType::Mute => match tokens()?.as_slice() {
+                [username] if !username.is_empty() => Ok(Self::Mute {
+                    username: username.clone(),
+                    duration: None,
+                }),
+                [username, duration] if !username.is_empty() => Ok(Self::Mute {
+                    username: username.clone(),
+                    duration: Some(parse_duration(duration).ok_or_else(invalid)?),
+                }),
+                _ => Err(invalid()),
+            },
+            CommandType::Unmute => Ok(Self::Unmute {
+                username: single()?,
+            }),
+            CommandType::GrantAdmin => Ok(Self::GrantAdmin {
+                username: single()?,
+            }),
+            CommandType::RevokeAdmin => Ok(Self::RevokeAdmin {
+                username: single()?,
+            }),
+            CommandType::Encrypt => Ok(Self::Encrypt {
+                username: single()?,
+            }),
+            CommandType::BroadcastFile => Ok(Self::BroadcastFile { path: single()? }),
+            CommandType::AuditLog if args.is_empty() => Ok(Self::AuditLog { count: None }),
+            CommandType::AuditLog => match args.parse() {
+                Ok(count) if count > 0 => Ok(Self::AuditLog { count: Some(count) }),
+                _ => Err(invalid()),
+            },
+            CommandType::Recent if args.is_empty() => Ok(Self::Recent { count: None }),
+            CommandType::Recent => match args.parse() {
+                Ok(count) if count > 0 => Ok(Self::Recent { count: Some(count) }),
+                _ => Err(invalid()),
+            },
+            CommandType::SharedFile => match tokens()?.as_slice() {
+                [name, data] if !name.is_empty() => Ok(Self::SharedFile {
+                    name: name.clone(),
+                    data: data.clone(),
+                }),
+                _ => Err(invalid()),
+            },
+            CommandType::PublicKey => match tokens()?.as_slice() {
+                [target, key] if !target.is_empty() => Ok(Self::PublicKey {
+                    target: target.clone(),
+                    key: key.clone(),
+                }),
+                _ => Err(invalid()),
+            },
+            CommandType::Encrypted => match tokens()?.as_slice() {
+                [target, payload] if !target.is_empty() => Ok(Self::Encrypted {
+                    target: target.clone(),
+                    payload: payload.clone(),
+                }),
+                _ => Err(invalid()),
+            },
+            CommandType::React => match tokens()?.as_slice() {
+                [seq, emoji] if !emoji.is_empty() && emoji.chars().count() <= MAX_REACTION_LEN => {
+                    Ok(Self::React {
+                        seq: seq.parse().map_err(|_| invalid())?,
+                        emoji: emoji.clone(),
+                    })
+                }
+                _ => Err(invalid()),
+            },
+            CommandType::Msg => {
+                // The text is sent as typed, so only the target list is split off.
+                let (targets, text) = args.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                let targets: Vec<String> = targets
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|target| !target.is_empty())
+                    .map(String::from)
+                    .collect();
+                let text = text.trim();
+                if targets.is_empty() || text.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Self::Msg {
+                    targets,
+                    text: text.to_string(),
+                })
+            }
+            CommandType::Reply | CommandType::Quote => {
+                // Like `/msg`, the text is sent as typed after the seq.
+                let (seq, text) = args.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(invalid());
+                }
+                let seq = seq.parse().map_err(|_| invalid())?;
+                let text = text.to_string();
+                match command_type {
+                    CommandType::Reply => Ok(Self::Reply { seq, text }),
+                    _ => Ok(Self::Quote { seq, text }),
+                }
+            }
+            CommandType::Schedule => {
+                // Like `/msg`, the announcement is sent as typed after the delay.
+                let (delay, text) = args.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Self::Schedule {
+                    delay: Duration::from_secs(delay.parse().map_err(|_| invalid())?),
+                    text: text.to_string(),
+                })
+            }
+            CommandType::Unschedule => args
+                .parse()
+                .map(|id| Self::Unschedule { id })
+                .map_err(|_| invalid()),
+            CommandType::Pin => args
+                .parse()
+                .map(|seq| Self::Pin { seq })
+                .map_err(|_| invalid()),
+            CommandType::Unpin => args
+                .parse()
+                .map(|seq| Self::Unpin { seq })
+                .map_err(|_| invalid()),
+            CommandType::SetMaxLen => args.parse().map(Self::SetMaxLen).map_err(|_| invalid()),
+            CommandType::HistoryMode => HistoryMode::from_name(args)
+                .map(Self::HistoryMode)
+                .ok_or_else(invalid),
+            CommandType::Notify if args.is_empty() => Ok(Self::Notify(None)),
+            CommandType::Notify => NotifyMode::from_name(args)
+                .map(|mode| Self::Notify(Some(mode)))
+                .ok_or_else(invalid),
+            CommandType::Unread => Err(ParseError::UnknownCommand(command_type.name().to_string())),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The wire tag for this command.
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            Self::List { .. } => CommandType::List,
+            Self::Quit => CommandType::Quit,
+            Self::Admin { .. } => CommandType::Admin,
+            Self::SetMaxLen(_) => CommandType::SetMaxLen,
+            Self::PingAll => CommandType::PingAll,
+            Self::DumpState => CommandType::DumpState,
+            Self::Nick { .. } => CommandType::Nick,
+            Self::Last { .. } => CommandType::Last,
+            Self::React { .. } => CommandType::React,
+            Self::Slap { .. } => CommandType::Slap,
+            Self::Mute { .. } => CommandType::Mute,
+            Self::Unmute { .. } => CommandType::Unmute,
+            Self::GrantAdmin { .. } => CommandType::GrantAdmin,
+            Self::RevokeAdmin { .. } => CommandType::RevokeAdmin,
+            Self::Msg { .. } => CommandType::Msg,
+            Self::Version => CommandType::Version,
+            Self::Uptime => CommandType::Uptime,
+            Self::BroadcastFile { .. } => CommandType::BroadcastFile,
+            Self::SharedFile { .. } => CommandType::SharedFile,
+            Self::AuditLog { .. } => CommandType::AuditLog,
+            Self::Recent { .. } => CommandType::Recent,
+            Self::Reload => CommandType::Reload,
+            Self::Schedule { .. } => CommandType::Schedule,
+            Self::Unschedule { .. } => CommandType::Unschedule,
+            Self::HistoryMode(_) => CommandType::HistoryMode,
+            Self::DownloadHistory => CommandType::DownloadHistory,
+            Self::Encrypt { .. } => CommandType::Encrypt,
+            Self::PublicKey { .. } => CommandType::PublicKey,
+            Self::Encrypted { .. } => CommandType::Encrypted,
+            Self::Find { .. } => CommandType::Find,
+            Self::Reply { .. } => CommandType::Reply,
+            Self::Quote { .. } => CommandType::Quote,
+            Self::Status { .. } => CommandType::Status,
+            Self::Pin { .. } => CommandType::Pin,
+            Self::Unpin { .. } => CommandType::Unpin,
+            Self::Pins => CommandType::Pins,
+            Self::Notify(_) => CommandType::Notify,
+        }
+    }
+
+    /// The command's arguments as sent in a message's `content`.
+    pub fn args(&self) -> String {
+        match self {
+            Self::Quit
+            | Self::PingAll
+            | Self::DumpState
+            | Self::Version
+            | Self::Uptime
+            | Self::DownloadHistory
+            | Self::Reload
+            | Self::Pins => String::new(),
+            Self::Admin { token } => token.clone(),
+            Self::Find { text } => text.clone(),
+            Self::Status { text } => text.clone().unwrap_or_default(),
+            Self::SetMaxLen(len) => len.to_string(),
+            Self::Nick { name } => quote_arg(name),
+            Self::Last { username } => quote_arg(username),
+            Self::React { seq, emoji } => format!("{} {}", seq, quote_arg(emoji)),
+            Self::Slap { target } => quote_arg(target),
+            Self::Mute { username, duration } => match duration {
+                Some(duration) => format!("{} {}s", quote_arg(username), duration.as_secs()),
+                None => quote_arg(username),
+            },
+            Self::Unmute { username }
+            | Self::GrantAdmin { username }
+            | Self::RevokeAdmin { username }
+            | Self::Encrypt { username } => quote_arg(username),
+            Self::PublicKey { target, key } => format!("{} {}", quote_arg(target), key),
+            Self::Encrypted { target, payload } => format!("{} {}", quote_arg(target), payload),
+            Self::Msg { targets, text } => format!("{} {}", targets.join(","), text),
+            Self::Reply { seq, text } | Self::Quote { seq, text } => format!("{} {}", seq, text),
+            Self::BroadcastFile { path } => quote_arg(path),
+            Self::SharedFile { name, data } => format!("{} {}", quote_arg(name), data),
+            Self::List { page } => page.map(|page| page.to_string()).unwrap_or_default(),
+            Self::AuditLog { count } | Self::Recent { count } => {
+                count.map(|count| count.to_string()).unwrap_or_default()
+            }
+            Self::Schedule { delay, text } => format!("{} {}", delay.as_secs(), text),
+            Self::Unschedule { id } => id.to_string(),
+            Self::Pin { seq } | Self::Unpin { seq } => seq.to_string(),
+            Self::HistoryMode(mode) => mode.name().to_string(),
+            Self::Notify(mode) => mode.map(|mode| mode.name().to_string()).unwrap_or_default(),
+        }
+    }
+
+    /// Wraps the command in a `ChatMessage` sent on behalf of `username`.
+    pub fn into_message(self, username: &str) -> ChatMessage {
+        ChatMessage {
+            message_type: ChatMessageType::Command(self.command_type()),
+            username: Some(username.to_string()),
+            content: self.args(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Splits command arguments on whitespace, keeping double-quoted segments together.
+/// Inside or outside quotes, `\"` is a literal quote and `\\` a literal backslash.
+fn tokenize(args: &str) -> Result<Vec<String>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false; // Distinguishes an empty quoted argument from no argument.
+    let mut in_quotes = false;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ ('"' | '\\')) => current.push(escaped),
+                Some(other) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+                continue;
+            }
+            c => current.push(c),
+        }
+        in_token = true;
+    }
+    if in_quotes {
+        return Err(ParseError::UnbalancedQuotes);
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+/// Quotes `arg` if needed so that `tokenize` reads it back as a single argument.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parses a duration such as `30`, `30s`, `10m` or `1h`; bare numbers are seconds.
+fn parse_duration(input: &str) -> Option<Duration> {
+    let (amount, unit_secs) = match input.char_indices().last()? {
+        (i, 's') => (&input[..i], 1),
+        (i, 'm') => (&input[..i], 60),
+        (i, 'h') => (&input[..i], 3600),
+        _ => (input, 1),
+    };
+    let amount: u64 = amount.parse().ok()?;
+    Some(Duration::from_secs(amount.checked_mul(unit_secs)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_time_shows_utc_hours_and_minutes() {
+        assert_eq!(format_time(0), "00:00");
+        assert_eq!(format_time(13 * 3600 + 7 * 60 + 59), "13:07");
+        assert_eq!(format_time(3 * 86400 + 23 * 3600 + 59 * 60), "23:59"); // Days wrap.
+    }
+
+    /// Parses `input`, panicking with the input if it isn't a valid command.
+    fn parse(input: &str) -> Command {
+        Command::parse(input).unwrap_or_else(|e| panic!("{}: {}", input, e))
+    }
+
+    #[test]
+    fn commands_extract_their_arguments() {
+        let text = |s: &str| s.to_string();
+        assert_eq!(parse("/list"), Command::List { page: None });
+        assert_eq!(parse("/list 2"), Command::List { page: Some(2) });
+        assert_eq!(parse("/quit"), Command::Quit);
+        assert_eq!(
+            parse("/admin a token"),
+            Command::Admin {
+                token: text("a token")
+            }
+        );
+        assert_eq!(parse("/setmaxlen 50"), Command::SetMaxLen(50));
+        assert_eq!(parse("/nick  bob "), Command::Nick { name: text("bob") });
+        assert_eq!(
+            parse("/last bob"),
+            Command::Last {
+                username: text("bob")
+            }
+        );
+        assert_eq!(
+            parse("/react 7 :)"),
+            Command::React {
+                seq: 7,
+                emoji: text(":)")
+            }
+        );
+        assert_eq!(
+            parse("/mute bob 5m"),
+            Command::Mute {
+                username: text("bob"),
+                duration: Some(Duration::from_secs(300))
+            }
+        );
+        assert_eq!(
+            parse("/mute bob"),
+            Command::Mute {
+                username: text("bob"),
+                duration: None
+            }
+        );
+        assert_eq!(
+            parse("/msg bob,carol  hi  there"),
+            Command::Msg {
+                targets: vec![text("bob"), text("carol")],
+                text: text("hi  there")
+            }
+        );
+        assert_eq!(
+            parse("/reply 3 sure thing"),
+            Command::Reply {
+                seq: 3,
+                text: text("sure thing")
+            }
+        );
+        assert_eq!(
+            parse("/schedule 60 stand-up"),
+            Command::Schedule {
+                delay: Duration::from_secs(60),
+                text: text("stand-up")
+            }
+        );
+        assert_eq!(
+            parse("/find two words"),
+            Command::Find {
+                text: text("two words")
+            }
+        );
+        assert_eq!(parse("/status"), Command::Status { text: None });
+        assert_eq!(parse("/pin 4"), Command::Pin { seq: 4 });
+        assert_eq!(
+            parse("/history-mode off"),
+            Command::HistoryMode(HistoryMode::Off)
+        );
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        assert_eq!(Command::parse("hello"), Err(ParseError::NotACommand));
+        assert_eq!(
+            Command::parse("/frobnicate"),
+            Err(ParseError::UnknownCommand("frobnicate".to_string()))
+        );
+        // Users can't type what only the server sends.
+        assert_eq!(
+            Command::parse("/unread"),
+            Err(ParseError::UnknownCommand("unread".to_string()))
+        );
+        for input in [
+            "/list 0",
+            "/list two",
+            "/admin",
+            "/setmaxlen lots",
+            "/nick",
+            "/nick two names",
+            "/react 7",
+            "/react seven :)",
+            "/mute",
+            "/mute bob forever",
+            "/msg bob",
+            "/msg , hi",
+            "/reply 3",
+            "/reply three hi",
+            "/schedule soon hi",
+            "/find",
+            "/pin",
+            "/history-mode sometimes",
+        ] {
+            assert!(
+                matches!(Command::parse(input), Err(ParseError::InvalidArguments(_))),
+                "{} was accepted",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn quoted_arguments_stay_together() {
+        let tokens = |args: &str| tokenize(args).unwrap();
+        assert_eq!(tokens("a  b"), ["a", "b"]);
+        assert_eq!(
+            tokens(r#""hello there, friend" x"#),
+            ["hello there, friend", "x"]
+        );
+        assert_eq!(tokens(r#"say" it "loud"#), ["say it loud"]);
+        assert_eq!(tokens(r#""" x"#), ["", "x"]); // An empty quoted argument is kept.
+        assert_eq!(tokens(r#""a \"b\" c" d\\e"#), [r#"a "b" c"#, r"d\e"]);
+        assert_eq!(tokens(r#"a \"b"#), ["a", r#""b"#]); // An escaped quote opens nothing.
+        assert_eq!(tokens(r"C:\dir"), [r"C:\dir"]); // Other escapes are kept as typed.
+        assert_eq!(tokenize(r#""open"#), Err(ParseError::UnbalancedQuotes));
+        let arg = r#"say "hi" \ bye"#;
+        assert_eq!(tokens(&quote_arg(arg)), [arg]);
+
+        assert_eq!(
+            parse(r#"/nick "Bobby Tables""#),
+            Command::Nick {
+                name: "Bobby Tables".to_string()
+            }
+        );
+        assert_eq!(
+            parse(r#"/mute "Bobby Tables" 10"#),
+            Command::Mute {
+                username: "Bobby Tables".to_string(),
+                duration: Some(Duration::from_secs(10))
+            }
+        );
+        assert_eq!(
+            Command::parse(r#"/nick "Bobby"#),
+            Err(ParseError::UnbalancedQuotes)
+        );
+    }
+
+    #[test]
+    fn aliases_resolve_without_swallowing_ordinary_messages() {
+        let mut aliases = CommandAliases::default();
+        for spec in ["who=list", "w=/msg", "bye=quit"] {
+            aliases.add(spec).unwrap();
+        }
+        let parse = |input: &str| Command::parse_with_aliases(input, &aliases);
+        assert_eq!(parse("who"), Ok(Command::List { page: None }));
+        assert_eq!(parse("/who 2"), Ok(Command::List { page: Some(2) }));
+        assert_eq!(parse("bye"), Ok(Command::Quit));
+        assert_eq!(
+            parse("w bob hi there"),
+            Ok(Command::Msg {
+                targets: vec!["bob".to_string()],
+                text: "hi there".to_string()
+            })
+        );
+        assert_eq!(parse("/list"), Ok(Command::List { page: None }));
+        // Sentences that merely start with an alias are still messages.
+        assert_eq!(parse("who is around?"), Err(ParseError::NotACommand));
+        assert_eq!(parse("bye for now"), Err(ParseError::NotACommand));
+        assert_eq!(parse("hello"), Err(ParseError::NotACommand));
+
+        for spec in ["list=quit", "x=nope", "who", "/x=list", "two words=list"] {
+            assert_eq!(
+                aliases.add(spec),
+                Err(ParseError::InvalidAlias(spec.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn commands_round_trip_through_a_message() {
+        for input in [
+            "/list 2",
+            "/mute bob 90",
+            "/msg bob,carol hi there",
+            "/react 7 :)",
+        ] {
+            let command = parse(input);
+            let message = command.clone().into_message("alice");
+            let ChatMessageType::Command(command_type) = &message.message_type else {
+                panic!("{} isn't sent as a command", input);
+            };
+            assert_eq!(
+                Command::from_parts(command_type, &message.content),
+                Ok(command)
+            );
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_with_their_flags_and_message() {
+        let message = ChatMessage {
+            username: Some("alice".to_string()),
+            content: "hi".to_string(),
+            seq: Some(7),
+            ..Default::default()
+        };
+        let text =
+            serde_json::to_string(&Frame::new(message.clone(), FrameFlags::COMPRESSED)).unwrap();
+        let frame = Frame::decode(&text).unwrap();
+        assert_eq!(frame.version, PROTOCOL_VERSION);
+        assert!(frame.flags.contains(FrameFlags::COMPRESSED));
+        assert_eq!(frame.message.username, message.username);
+        assert_eq!(frame.message.content, "hi");
+        assert_eq!(frame.message.seq, Some(7));
+
+        // A frame without flags leaves them out; bits this version doesn't know are ignored.
+        let mut envelope: serde_json::Value =
+            serde_json::from_str(&Frame::encode(&message).unwrap()).unwrap();
+        assert!(envelope.get("flags").is_none());
+        envelope["version"] = 3.into();
+        envelope["flags"] = 6.into();
+        let unknown = Frame::decode(&envelope.to_string()).unwrap();
+        assert_eq!(unknown.version, 3);
+        assert!(!unknown.flags.contains(FrameFlags::COMPRESSED));
+
+        // A bare message from a version 1 peer is read as a version 1 frame.
+        let bare = serde_json::to_string(&message).unwrap();
+        let frame = Frame::decode(&bare).unwrap();
+        assert_eq!(frame.version, 1);
+        assert!(frame.flags.is_empty());
+        assert_eq!(frame.message.content, "hi");
+    }
 }
@@ -0,0 +1,145 @@
+// msgpack.rs
+use crate::transport::{Transport, DEFAULT_MAX_FRAME_LEN}; // Frame transport implemented here, and its frame limit.
+use std::io::{self, BufReader, Read, Write}; // Buffered reading and writing of frames.
+use std::net::{Shutdown, SocketAddr, TcpStream}; // The underlying connection.
+use std::time::Duration; // Read timeouts.
+
+/// `Transport` that sends frames as MessagePack instead of JSON text, for connections where
+/// bandwidth matters. Each frame is a 4-byte big-endian length followed by that many bytes
+/// of MessagePack.
+///
+/// Frames are still handed to and taken from callers as JSON text, so the rest of the
+/// code doesn't change; only the bytes on the wire do. Because the length of any allowed
+/// frame fits in three bytes, the first byte a client sends is always 0, which a JSON
+/// frame never starts with; `is_msgpack_client` uses that to tell the two apart.
+pub struct MsgpackTransport {
+    reader: BufReader<TcpStream>, // Buffered reader; the inner stream is also used for writes.
+    max_frame_len: usize,         // Longest frame accepted from the peer, in bytes.
+}
+
+impl MsgpackTransport {
+    /// Wraps a connected `TcpStream`.
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            reader: BufReader::new(stream),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Returns `true` if the client on `stream` speaks MessagePack, judging by its first byte,
+/// which is left unread. Waits at most `timeout` for the byte to arrive.
+pub fn is_msgpack_client(stream: &TcpStream, timeout: Duration) -> io::Result<bool> {
+    stream.set_read_timeout(Some(timeout))?;
+    let mut first = [0u8];
+    let peeked = stream.peek(&mut first);
+    stream.set_read_timeout(None)?;
+    Ok(peeked? == 1 && first[0] == 0)
+}
+
+/// Converts a MessagePack encoding or decoding error into an `InvalidData` I/O error.
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Transport for MsgpackTransport {
+    /// Frames longer than `max_frame_len` fail with `InvalidData` before they are read.
+    fn read_frame(&mut self) -> io::Result<Option<String>> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None), // Connection closed by the peer.
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_frame_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame exceeds {} bytes", self.max_frame_len),
+            ));
+        }
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes)?;
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).map_err(invalid_data)?;
+        serde_json::to_string(&value)
+            .map(Some)
+            .map_err(invalid_data)
+    }
+
+    /// Fails with `InvalidData` if `frame` isn't JSON.
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        let value: serde_json::Value = serde_json::from_str(frame).map_err(invalid_data)?;
+        let bytes = rmp_serde::to_vec(&value).map_err(invalid_data)?;
+        let len = u32::try_from(bytes.len()).map_err(invalid_data)?;
+        let mut packet = Vec::with_capacity(4 + bytes.len());
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(&bytes);
+        self.reader.get_mut().write_all(&packet) // One write, so frames never interleave.
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.reader.get_ref().peer_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.reader.get_ref().shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.get_ref().set_read_timeout(timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            reader: BufReader::new(self.reader.get_ref().try_clone()?),
+            max_frame_len: self.max_frame_len,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ChatMessage, Frame};
+    use std::net::TcpListener;
+
+    #[test]
+    fn message_round_trips_in_fewer_bytes_than_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut transport = MsgpackTransport::new(stream);
+        let message = ChatMessage {
+            username: Some("alice".to_string()),
+            content: "hello there".to_string(),
+            seq: Some(42),
+            timestamp: Some(1_700_000_000),
+            ..Default::default()
+        };
+        let json = Frame::encode(&message).unwrap();
+
+        transport.write_frame(&json).unwrap();
+        let mut len = [0u8; 4];
+        peer.read_exact(&mut len).unwrap();
+        assert_eq!(len[0], 0); // How `is_msgpack_client` recognizes the format.
+        let mut packet = len.to_vec();
+        packet.resize(4 + u32::from_be_bytes(len) as usize, 0);
+        peer.read_exact(&mut packet[4..]).unwrap();
+        let json_len = json.len() + 1; // With the newline that ends a JSON frame.
+        assert!(
+            packet.len() < json_len,
+            "{} bytes as MessagePack, {} as JSON",
+            packet.len(),
+            json_len
+        );
+
+        // The same bytes sent back decode to the same message.
+        peer.write_all(&packet).unwrap();
+        let frame = transport.read_frame().unwrap().unwrap();
+        let decoded = Frame::decode(&frame).unwrap().message;
+        assert_eq!(decoded.username, message.username);
+        assert_eq!(decoded.content, message.content);
+        assert_eq!(decoded.seq, message.seq);
+        assert_eq!(decoded.timestamp, message.timestamp);
+    }
+}
@@ -0,0 +1,272 @@
+// outbox.rs
+use crate::message::Priority; // Priority used to order queued frames.
+use crate::transport::Transport; // Connection the writer thread drains into.
+use std::collections::VecDeque; // FIFO queues for each priority level.
+use std::io; // Results of the `Transport` methods.
+use std::net::SocketAddr; // Address of the client behind a `QueuedTransport`.
+use std::sync::{Arc, Condvar, Mutex}; // Shared queue and wake-up signal for the writer thread.
+use std::thread; // For spawning the writer thread.
+use std::time::Duration; // Read timeouts, passed through to the connection.
+
+/// Frames waiting to be written to a client, split by priority.
+#[derive(Default)]
+pub struct OutboundQueue {
+    high: VecDeque<String>,   // High-priority frames, always drained first.
+    normal: VecDeque<String>, // Normal-priority frames.
+    closed: bool,             // Set once the client is gone; no more frames are accepted.
+}
+
+impl OutboundQueue {
+    /// Queues a frame behind others of the same priority.
+    /// Returns `false` if the queue has been closed.
+    pub fn push(&mut self, frame: String, priority: Priority) -> bool {
+        if self.closed {
+            return false;
+        }
+        match priority {
+            Priority::High => self.high.push_back(frame),
+            Priority::Normal => self.normal.push_back(frame),
+        }
+        true
+    }
+
+    /// Takes the next frame to write: the oldest high-priority frame if any,
+    /// otherwise the oldest normal-priority one.
+    pub fn pop(&mut self) -> Option<String> {
+        self.high.pop_front().or_else(|| self.normal.pop_front())
+    }
+}
+
+/// A client's outbound queue, shared between the handlers that enqueue frames
+/// and the writer thread that sends them.
+#[derive(Default)]
+pub struct Outbox {
+    queue: Mutex<OutboundQueue>, // Pending frames.
+    ready: Condvar,              // Signalled when a frame is queued or the outbox closes.
+}
+
+impl Outbox {
+    /// Queues a frame for the writer thread. Returns `false` if the outbox has been closed.
+    pub fn push(&self, frame: String, priority: Priority) -> bool {
+        let Ok(mut queue) = self.queue.lock() else {
+            return false;
+        };
+        let queued = queue.push(frame, priority);
+        self.ready.notify_one();
+        queued
+    }
+
+    /// Closes the outbox to new frames; the writer thread writes the frames already queued,
+    /// then stops. Used when a client leaves, so its last replies still reach it.
+    pub fn finish(&self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.closed = true;
+        }
+        self.ready.notify_one();
+    }
+
+    /// Closes the outbox, dropping any frames not yet written and stopping the writer thread.
+    pub fn close(&self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.closed = true;
+            queue.high.clear();
+            queue.normal.clear();
+        }
+        self.ready.notify_one();
+    }
+
+    /// Returns `true` once the outbox has been closed, e.g. after a failed write.
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().map_or(true, |queue| queue.closed)
+    }
+
+    /// Blocks until a frame is available, or returns `None` once the outbox is closed
+    /// and drained.
+    fn next_frame(&self) -> Option<String> {
+        let mut queue = self.queue.lock().ok()?;
+        loop {
+            if let Some(frame) = queue.pop() {
+                return Some(frame);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.ready.wait(queue).ok()?;
+        }
+    }
+}
+
+/// A client's connection as its handler sees it: frames are read from the connection, but
+/// written through the client's outbox. The writer thread is then the only one writing to
+/// the socket, so the handler's replies and other threads' broadcasts can't interleave, and
+/// the client receives frames in the order they were queued.
+///
+/// Replies are queued as high priority, so they aren't held up behind bulk chat traffic.
+pub struct QueuedTransport {
+    inner: Box<dyn Transport>, // The connection, used for everything but writes.
+    outbox: Arc<Outbox>,       // The client's outbox, drained by its writer thread.
+}
+
+impl QueuedTransport {
+    /// Wraps `inner`, whose writes must all go through `outbox` from now on.
+    pub fn new(inner: Box<dyn Transport>, outbox: Arc<Outbox>) -> Self {
+        Self { inner, outbox }
+    }
+}
+
+impl Transport for QueuedTransport {
+    fn read_frame(&mut self) -> io::Result<Option<String>> {
+        self.inner.read_frame()
+    }
+
+    /// Fails with `BrokenPipe` once the outbox is closed, e.g. after a failed write.
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        if self.outbox.push(frame.to_string(), Priority::High) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the client's outbox is closed",
+            ))
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            inner: self.inner.try_clone()?,
+            outbox: Arc::clone(&self.outbox),
+        }))
+    }
+}
+
+/// Spawns the writer thread that drains `outbox` into `transport` until the outbox closes
+/// or a write fails. A failed write closes the outbox so later pushes report the client as gone.
+///
+/// It also shuts the connection down: the failed write may have sent part of a frame, and
+/// the peer must see the connection end rather than the next frame glued onto that fragment.
+pub fn spawn_writer(mut transport: Box<dyn Transport>, outbox: Arc<Outbox>) {
+    thread::spawn(move || {
+        while let Some(frame) = outbox.next_frame() {
+            if let Err(e) = transport.write_frame(&frame) {
+                eprintln!("Failed to write to client: {}", e);
+                outbox.close(); // Closed first, so the peer never sees the end before pushes fail.
+                let _ = transport.shutdown(); // Also wakes the client's reader, which then cleans up.
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    /// A TCP connection whose writes send only the first half of the frame, then fail.
+    struct TruncatingTransport {
+        stream: TcpStream, // The server's end of the connection.
+    }
+
+    impl Transport for TruncatingTransport {
+        fn read_frame(&mut self) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+            self.stream
+                .write_all(&frame.as_bytes()[..frame.len() / 2])?;
+            Err(io::Error::other("write failed mid-frame"))
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.stream.peer_addr()
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.stream.shutdown(Shutdown::Both)
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.stream.set_read_timeout(timeout)
+        }
+
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self {
+                stream: self.stream.try_clone()?,
+            }))
+        }
+    }
+
+    #[test]
+    fn high_priority_frames_drain_before_earlier_normal_ones() {
+        let mut queue = OutboundQueue::default();
+        queue.push("bulk 1".to_string(), Priority::Normal);
+        queue.push("bulk 2".to_string(), Priority::Normal);
+        queue.push("announcement".to_string(), Priority::High);
+        queue.push("whisper".to_string(), Priority::High);
+
+        let drained: Vec<String> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(drained, ["announcement", "whisper", "bulk 1", "bulk 2"]);
+    }
+
+    #[test]
+    fn handler_writes_are_queued_and_finish_drains_them() {
+        let (server_end, mut client_end) = MemoryTransport::pair(
+            "127.0.0.1:8081".parse().unwrap(),
+            "10.0.0.1:5000".parse().unwrap(),
+        );
+        client_end
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let outbox = Arc::new(Outbox::default());
+        spawn_writer(server_end.try_clone().unwrap(), Arc::clone(&outbox));
+        let mut handler_end = QueuedTransport::new(Box::new(server_end), Arc::clone(&outbox));
+
+        handler_end.write_frame("reply").unwrap();
+        outbox.push("broadcast".to_string(), Priority::Normal);
+        outbox.finish();
+        assert!(handler_end.write_frame("too late").is_err());
+        drop(handler_end); // The writer's handle is then the last one.
+
+        assert_eq!(client_end.read_frame().unwrap().as_deref(), Some("reply"));
+        assert_eq!(
+            client_end.read_frame().unwrap().as_deref(),
+            Some("broadcast")
+        );
+        assert_eq!(client_end.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn write_failing_mid_frame_shuts_the_connection_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let outbox = Arc::new(Outbox::default());
+        spawn_writer(
+            Box::new(TruncatingTransport { stream }),
+            Arc::clone(&outbox),
+        );
+
+        assert!(outbox.push("0123456789".to_string(), Priority::Normal));
+        // The peer sees the fragment and then the end of the connection, never another frame.
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"01234");
+        assert!(outbox.is_closed());
+        assert!(!outbox.push("next".to_string(), Priority::Normal));
+    }
+}
@@ -0,0 +1,867 @@
+// runtime.rs
+use crate::audit::AuditLog; // Record of moderation actions.
+use crate::bot::Bot; // Optional greeting and auto-response bot.
+use crate::client_handler::{
+    announce_pending_leaves, broadcast_presence, deliver_broadcast, expire_messages, handle_client,
+    reap_stale_clients, send_due_announcements,
+}; // Per-connection handler and background upkeep.
+use crate::config::ServerConfig; // Server configuration parsed from the command line.
+use crate::errors::{self, ChatResult}; // Custom result type for error handling.
+use crate::events::SystemEvent; // Events published by the client handlers.
+use crate::history; // Saving and restoring `--history-file`.
+#[cfg(feature = "msgpack")]
+use crate::msgpack; // MessagePack wire format.
+#[cfg(feature = "websocket")]
+use crate::state::Listener; // Records the gateway's accept loop for shutdown.
+use crate::state::{Broadcast, ServerState}; // Shared state for clients, usernames and chat history.
+use crate::transport::TcpTransport; // TCP implementation of the frame transport.
+#[cfg(feature = "websocket")]
+use crate::websocket; // WebSocket gateway for browser clients.
+use socket2::{SockRef, TcpKeepalive}; // Keepalive settings std doesn't expose.
+use std::io; // For classifying accept errors.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream}; // Networking utilities.
+use std::sync::atomic::Ordering; // Memory ordering for the shutdown flag.
+use std::sync::mpsc::Receiver; // Receiving end of the event and broadcast channels.
+use std::sync::Arc; // Shared ownership of the server state across threads.
+use std::thread::{self, JoinHandle}; // For spawning threads for each client.
+use std::time::{Duration, Instant}; // Backoff delays and the shutdown deadline.
+
+/// Delay before retrying after the first failed accept; doubled on each consecutive failure.
+const ACCEPT_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+/// Upper bound for the accept retry delay.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// How often held-back leave announcements are checked.
+const LEAVE_ANNOUNCER_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the scheduler checks for announcements that have come due.
+const SCHEDULER_INTERVAL: Duration = Duration::from_millis(250);
+/// How often history is checked for messages whose TTL has elapsed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long shutdown waits for client handler threads to finish.
+const SHUTDOWN_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates the shared state from `config` and starts the background threads that serve it:
+/// broadcasting, reaping, announcements, expiry, autosave, the bot and the WebSocket gateway.
+/// The audit log is opened and `--history-file` restored first; failing either is fatal.
+pub fn start_services(config: ServerConfig) -> ChatResult<Arc<ServerState>> {
+    let mut state = ServerState::new(config);
+    if let Some(path) = state.config.audit_log.clone() {
+        state.audit = AuditLog::open(&path).map_err(|e| {
+            log::error!("Failed to open audit log {}: {}", path.display(), e);
+            e
+        })?;
+    }
+    if let Some(path) = &state.config.history_file {
+        history::restore(&state, path).map_err(|e| {
+            log::error!("Failed to load history from {}: {}", path.display(), e);
+            e
+        })?;
+    }
+    spawn_event_logger(state.subscribe());
+    let bot = Bot::from_config(&state.config).map(|bot| (bot, state.subscribe()));
+    let broadcasts = state.start_broadcasts();
+    let state = Arc::new(state);
+
+    if let Some((bot, events)) = bot {
+        spawn_bot(bot, Arc::clone(&state), events);
+    }
+
+    spawn_broadcaster(Arc::clone(&state), broadcasts);
+    spawn_reaper(Arc::clone(&state));
+    spawn_leave_announcer(Arc::clone(&state));
+    spawn_presence_announcer(Arc::clone(&state));
+    spawn_scheduler(Arc::clone(&state));
+    spawn_expirer(Arc::clone(&state));
+    spawn_autosaver(Arc::clone(&state));
+    #[cfg(feature = "websocket")]
+    if let Some(addr) = state.config.ws_addr.clone() {
+        let (listener, ws_addr) = bind_server(&addr)?;
+        log::info!("Accepting WebSocket clients on {}", ws_addr);
+        spawn_websocket_gateway(listener, Arc::clone(&state))?;
+    }
+    Ok(state)
+}
+
+/// Starts shutting the server down: closes every client connection so the handler threads
+/// stop reading, then wakes the accept loop listening on `local_addr` so `run_server` returns,
+/// along with the ones in `state.listeners`. Only the first call does anything.
+pub fn shutdown(state: &ServerState, local_addr: SocketAddr) {
+    if state.is_shutting_down.swap(true, Ordering::SeqCst) {
+        return; // Prevent multiple shutdown triggers.
+    }
+    log::info!("Shutting down server...");
+
+    // Close all client connections so their handler threads stop reading.
+    if let Ok(clients_lock) = state.clients.read() {
+        for (_, client) in clients_lock.iter() {
+            if let Err(e) = client.transport.shutdown() {
+                log::error!("Failed to shutdown client connection: {}", e);
+            }
+        }
+    }
+
+    // The accept loops are blocked waiting for a connection, so give each one.
+    wake_accept_loop(local_addr);
+    if let Ok(listeners) = state.listeners.lock() {
+        for listener in listeners.iter() {
+            wake_accept_loop(listener.addr);
+        }
+    }
+}
+
+/// Connects to the accept loop listening on `local_addr`, so it sees the shutdown flag.
+fn wake_accept_loop(local_addr: SocketAddr) {
+    if let Err(e) = TcpStream::connect(wake_addr(local_addr)) {
+        log::error!("Failed to wake the accept loop on {}: {}", local_addr, e);
+    }
+}
+
+/// Finishes a shutdown once `run_server` has returned: waits for the remaining client
+/// handlers, including those of `state.listeners`, then saves the history to
+/// `--history-file`, if set.
+pub fn finish_shutdown(state: &ServerState, mut handlers: Vec<JoinHandle<()>>) {
+    let listeners = match state.listeners.lock() {
+        Ok(mut listeners) => std::mem::take(&mut *listeners),
+        Err(_) => Vec::new(),
+    };
+    let deadline = Instant::now() + SHUTDOWN_JOIN_TIMEOUT;
+    for listener in listeners {
+        // A loop that missed its wake-up is left to end with the process.
+        while !listener.accept_loop.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if !listener.accept_loop.is_finished() {
+            log::warn!("The accept loop on {} didn't stop", listener.addr);
+            continue;
+        }
+        match listener.accept_loop.join() {
+            Ok(listener_handlers) => handlers.extend(listener_handlers),
+            Err(_) => log::error!("The accept loop on {} panicked", listener.addr),
+        }
+    }
+    log::info!("All clients have been disconnected.");
+    join_handlers(handlers, SHUTDOWN_JOIN_TIMEOUT);
+    if let Some(path) = &state.config.history_file {
+        match history::save(state, path) {
+            Ok(()) => log::info!("Saved history to {}", path.display()),
+            Err(e) => log::error!("Failed to save history to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Binds the server to `addr` and returns the listener with the address it actually bound to.
+/// Binding to port 0 lets the OS pick a free ephemeral port.
+pub fn bind_server(addr: &str) -> ChatResult<(TcpListener, SocketAddr)> {
+    // Binding errors are fatal: the server can't run without a listener.
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        log::error!("Failed to bind to {}: {}", addr, e);
+        errors::ChatServerError::NoAvailablePorts
+    })?;
+    let local_addr = listener.local_addr()?;
+    Ok((listener, local_addr))
+}
+
+/// Runs the accept loop, spawning a handler thread for every client,
+/// until the server starts shutting down. Returns the handler threads still running.
+pub fn run_server(
+    listener: TcpListener,   // Listener returned by `bind_server`.
+    state: Arc<ServerState>, // Shared server state.
+) -> ChatResult<Vec<JoinHandle<()>>> {
+    Ok(accept_clients(listener, state, serve_tcp_client))
+}
+
+/// Accepts connections on `listener` until the server starts shutting down, handing each
+/// allowed one to `serve` on its own thread. Returns the handler threads still running.
+///
+/// Consecutive accept failures back off from `ACCEPT_BACKOFF_INITIAL` up to
+/// `ACCEPT_BACKOFF_MAX`. To reproduce, start the server under `ulimit -n 32` and open
+/// more clients than that: without the backoff the loop pins a core at 100% on
+/// `EMFILE`; with it the "Failed to accept connection" log slows to once a second.
+fn accept_clients(
+    listener: TcpListener,   // Listener to accept on.
+    state: Arc<ServerState>, // Shared server state.
+    serve: fn(TcpStream, Arc<ServerState>) -> ChatResult<()>, // Serves one accepted client.
+) -> Vec<JoinHandle<()>> {
+    let mut backoff = ACCEPT_BACKOFF_INITIAL;
+    let mut handlers: Vec<JoinHandle<()>> = Vec::new();
+    for stream in listener.incoming() {
+        // If shutdown is triggered, exit the loop. The connection that woke it, usually the
+        // shutdown handler's own (see `wake_addr`), is dropped here and never registered.
+        if state.is_shutting_down() {
+            break;
+        }
+
+        match stream {
+            Ok(stream) => {
+                backoff = ACCEPT_BACKOFF_INITIAL; // A successful accept ends any error streak.
+
+                // Refused addresses are dropped before a handler thread is spawned.
+                match stream.peer_addr() {
+                    Ok(peer_addr) if state.config.is_ip_allowed(peer_addr.ip()) => {}
+                    Ok(peer_addr) => {
+                        log::warn!("Refused connection from {}", peer_addr);
+                        continue; // Dropping the stream closes the socket.
+                    }
+                    Err(e) => {
+                        log::error!("Failed to read peer address: {}", e);
+                        continue;
+                    }
+                }
+                apply_socket_options(&stream, &state.config);
+
+                // Clone the shared state for each new thread.
+                let state = Arc::clone(&state);
+
+                // Spawn a new thread to handle the client, forgetting the ones that finished.
+                handlers.retain(|handler| !handler.is_finished());
+                handlers.push(thread::spawn(move || {
+                    let state_clone = Arc::clone(&state);
+                    if let Err(e) = serve(stream, state) {
+                        // Errors from sockets torn down by the shutdown handler are expected.
+                        if state_clone.is_shutting_down() {
+                            log::debug!("Client handler stopped during shutdown: {}", e);
+                        } else {
+                            log::error!("Error handling client: {}", e);
+                        }
+                    }
+                }));
+            }
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                if state.is_shutting_down() {
+                    break; // Exit loop if shutdown is triggered.
+                }
+                // Errors such as fd exhaustion persist until resources free up, so back off
+                // instead of spinning; errors tied to a single aborted connection don't need it.
+                if !is_connection_error(&e) {
+                    thread::sleep(backoff);
+                    backoff = next_accept_backoff(backoff);
+                }
+            }
+        }
+    }
+
+    handlers
+}
+
+/// Starts the thread that accepts WebSocket clients on `listener`, and records it in
+/// `state.listeners` so `shutdown` wakes it and `finish_shutdown` waits for its clients.
+#[cfg(feature = "websocket")]
+fn spawn_websocket_gateway(listener: TcpListener, state: Arc<ServerState>) -> ChatResult<()> {
+    let addr = listener.local_addr()?;
+    let accept_state = Arc::clone(&state);
+    let accept_loop =
+        thread::spawn(move || accept_clients(listener, accept_state, serve_websocket_client));
+    state.listeners.lock()?.push(Listener { addr, accept_loop });
+    Ok(())
+}
+
+/// Handles a client connected to the WebSocket gateway. It completes the handshake within
+/// `--registration-timeout-secs` and is then handled like a TCP client, sharing the same
+/// users and history.
+#[cfg(feature = "websocket")]
+fn serve_websocket_client(stream: TcpStream, state: Arc<ServerState>) -> ChatResult<()> {
+    let timeout = Duration::from_secs(state.config.registration_timeout_secs);
+    match websocket::WebSocketTransport::accept(stream, timeout) {
+        Ok(transport) => handle_client(transport, state),
+        Err(e) => {
+            log::warn!("WebSocket handshake failed: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Sets `TCP_NODELAY` and keepalive on an accepted connection, as configured.
+/// A connection whose options can't be set is still served, just with the OS defaults.
+fn apply_socket_options(stream: &TcpStream, config: &ServerConfig) {
+    if let Err(e) = stream.set_nodelay(!config.no_tcp_nodelay) {
+        log::warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+    if let Some(secs) = config.tcp_keepalive_secs {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(secs))
+            .with_interval(Duration::from_secs(secs));
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            log::warn!("Failed to enable TCP keepalive: {}", e);
+        }
+    }
+}
+
+/// Handles a client connected to the main listener, in the wire format it speaks:
+/// JSON lines, or MessagePack when built with the `msgpack` feature.
+fn serve_tcp_client(stream: TcpStream, state: Arc<ServerState>) -> ChatResult<()> {
+    #[cfg(feature = "msgpack")]
+    {
+        let timeout = Duration::from_secs(state.config.registration_timeout_secs);
+        match msgpack::is_msgpack_client(&stream, timeout) {
+            Ok(true) => return handle_client(msgpack::MsgpackTransport::new(stream), state),
+            Ok(false) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let peer_addr = stream.peer_addr()?.to_string();
+                return Err(errors::ChatServerError::RegistrationTimeout(peer_addr));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    handle_client(
+        TcpTransport::with_read_buffer(stream, state.config.read_buffer_bytes),
+        state,
+    )
+}
+
+/// Address the shutdown handler connects to in order to wake the accept loop.
+/// A listener on an unspecified address is reached through loopback.
+fn wake_addr(local_addr: SocketAddr) -> SocketAddr {
+    let ip = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, local_addr.port())
+}
+
+/// Waits up to `timeout` for the client handler threads to finish.
+/// Threads still running after that are left to end with the process.
+fn join_handlers(handlers: Vec<JoinHandle<()>>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while handlers.iter().any(|handler| !handler.is_finished()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut unfinished = 0;
+    for handler in handlers {
+        if !handler.is_finished() {
+            unfinished += 1;
+        } else if handler.join().is_err() {
+            log::error!("A client handler thread panicked");
+        }
+    }
+    if unfinished > 0 {
+        log::warn!(
+            "{} client handlers didn't finish before shutdown",
+            unfinished
+        );
+    }
+}
+
+/// Returns the delay after the next consecutive accept failure: double `backoff`,
+/// up to `ACCEPT_BACKOFF_MAX`.
+fn next_accept_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(ACCEPT_BACKOFF_MAX)
+}
+
+/// Returns `true` for accept errors caused by a single failed connection,
+/// which can be retried immediately.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Starts the thread that periodically prunes dead client connections,
+/// unless `--reap-interval-secs` is 0.
+fn spawn_reaper(state: Arc<ServerState>) {
+    let interval = Duration::from_secs(state.config.reap_interval_secs);
+    if interval.is_zero() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = reap_stale_clients(&state) {
+            log::error!("Failed to reap stale clients: {}", e);
+        }
+    });
+}
+
+/// Starts the thread that announces held-back leaves once their grace period ends,
+/// unless `--rejoin-grace-ms` is 0.
+fn spawn_leave_announcer(state: Arc<ServerState>) {
+    if state.config.rejoin_grace_ms == 0 {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(LEAVE_ANNOUNCER_INTERVAL);
+        if let Err(e) = announce_pending_leaves(&state) {
+            log::error!("Failed to announce pending leaves: {}", e);
+        }
+    });
+}
+
+/// Starts the thread that broadcasts the online users when they change, at most once
+/// per `--presence-debounce-ms`, so a burst of joins and leaves sends a single update.
+fn spawn_presence_announcer(state: Arc<ServerState>) {
+    let Some(debounce_ms) = state.config.presence_debounce_ms else {
+        return;
+    };
+    let interval = Duration::from_millis(debounce_ms.max(1));
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = broadcast_presence(&state) {
+            log::error!("Failed to broadcast presence: {}", e);
+        }
+    });
+}
+
+/// Starts the thread that sends `/schedule` announcements once they come due.
+fn spawn_scheduler(state: Arc<ServerState>) {
+    thread::spawn(move || loop {
+        thread::sleep(SCHEDULER_INTERVAL);
+        if let Err(e) = send_due_announcements(&state) {
+            log::error!("Failed to send scheduled announcements: {}", e);
+        }
+    });
+}
+
+/// Starts the thread that removes messages from history once their TTL has elapsed.
+fn spawn_expirer(state: Arc<ServerState>) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_INTERVAL);
+        if let Err(e) = expire_messages(&state) {
+            log::error!("Failed to expire messages: {}", e);
+        }
+    });
+}
+
+/// Starts the thread that saves the history to `--history-file` every `--autosave-secs`,
+/// unless either is unset. Saves are skipped while the history hasn't changed.
+fn spawn_autosaver(state: Arc<ServerState>) {
+    let Some(path) = state.config.history_file.clone() else {
+        return;
+    };
+    let interval = Duration::from_secs(state.config.autosave_secs);
+    if interval.is_zero() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut saved = None; // Last seq and length saved; expiry shrinks history without a new seq.
+        loop {
+            thread::sleep(interval);
+            let current = state
+                .chat_history
+                .read()
+                .map(|history| (state.next_seq.load(Ordering::SeqCst), history.len()))
+                .ok();
+            if current == saved {
+                continue;
+            }
+            match history::save(&state, &path) {
+                Ok(()) => saved = current,
+                Err(e) => log::error!("Failed to save history to {}: {}", path.display(), e),
+            }
+        }
+    });
+}
+
+/// Starts the single thread that fans broadcasts out to every client's outbox.
+/// With one thread doing all fan-out, every client receives broadcasts in the same order.
+fn spawn_broadcaster(state: Arc<ServerState>, broadcasts: Receiver<Broadcast>) {
+    thread::spawn(move || {
+        for broadcast in broadcasts {
+            deliver_broadcast(&state, &broadcast);
+        }
+    });
+}
+
+/// Starts the thread that runs the built-in bot on the server's events.
+fn spawn_bot(bot: Bot, state: Arc<ServerState>, events: Receiver<SystemEvent>) {
+    log::info!("Bot '{}' is enabled", bot.name());
+    thread::spawn(move || {
+        for event in events {
+            if let Err(e) = bot.handle_event(&state, &event) {
+                log::error!("Bot failed to respond: {}", e);
+            }
+        }
+    });
+}
+
+/// Starts the thread that logs every `SystemEvent` at debug level.
+fn spawn_event_logger(events: Receiver<SystemEvent>) {
+    thread::spawn(move || {
+        for event in events {
+            log::debug!("Event: {:?}", event);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ChatMessageType, CommandType};
+    use crate::test_support::{test_config, TestClient, TestServer};
+    use std::{env, fs, process};
+
+    #[test]
+    fn persistent_accept_errors_back_off_up_to_the_cap() {
+        // Running out of file descriptors (EMFILE) lasts, so the loop must wait...
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(24)));
+        // ...while a connection aborted before it was accepted is retried at once.
+        assert!(is_connection_error(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+
+        let mut backoff = ACCEPT_BACKOFF_INITIAL;
+        let mut delays = Vec::new();
+        for _ in 0..10 {
+            delays.push(backoff.as_millis());
+            backoff = next_accept_backoff(backoff);
+        }
+        assert_eq!(delays, [10, 20, 40, 80, 160, 320, 640, 1000, 1000, 1000]);
+    }
+
+    #[test]
+    fn shutdown_wakes_the_idle_accept_loop_without_a_phantom_client() {
+        let config = test_config(&[]);
+        let (listener, addr) = bind_server(&config.addr).unwrap();
+        let state = start_services(config).unwrap();
+        let accept_state = Arc::clone(&state);
+        let accept_loop = thread::spawn(move || run_server(listener, accept_state).unwrap());
+
+        shutdown(&state, addr);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !accept_loop.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(accept_loop.is_finished(), "accept loop still blocked");
+        assert!(accept_loop.join().unwrap().is_empty()); // No handler for the wake-up.
+        assert!(state.clients.read().unwrap().is_empty());
+        assert_eq!(state.online.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            wake_addr("0.0.0.0:8081".parse().unwrap()),
+            "127.0.0.1:8081".parse().unwrap()
+        );
+        assert_eq!(
+            wake_addr("[::]:8081".parse().unwrap()),
+            "[::1]:8081".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn accepted_streams_get_the_configured_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = |args: &[&str]| {
+            let _client = TcpStream::connect(addr).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            apply_socket_options(&stream, &test_config(args));
+            stream
+        };
+
+        let stream = accept(&["--tcp-keepalive-secs", "30"]);
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        let stream = accept(&["--no-tcp-nodelay"]);
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn clients_join_chat_and_list_users() {
+        let server = TestServer::start();
+        let mut alice = server.connect("alice");
+        let mut bob = server.join("bob");
+        let joined = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Join));
+        assert_eq!(joined.content, "bob has joined the chat");
+
+        bob.say("hello");
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.username.as_deref(), Some("bob"));
+        assert_eq!(received.content, "hello");
+        assert!(received.seq.is_some());
+
+        alice.command("/list");
+        let list = alice.recv_until(|msg| {
+            matches!(
+                msg.message_type,
+                ChatMessageType::Command(CommandType::List)
+            )
+        });
+        assert!(
+            list.content.starts_with("Online users: "),
+            "{}",
+            list.content
+        );
+        assert!(list.content.contains("alice") && list.content.contains("bob"));
+
+        // Shutting down closes every connection; `recv_to_end` fails the test if one stays open.
+        server.stop();
+        alice.recv_to_end();
+        bob.recv_to_end();
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn websocket_client_chats_with_a_tcp_client() {
+        use crate::message::{ChatMessage, Frame};
+        use tungstenite::stream::MaybeTlsStream;
+        use tungstenite::Message;
+
+        let server = TestServer::with_args(&["--ws-addr", "127.0.0.1:0"]);
+        let ws_addr = server.state.listeners.lock().unwrap()[0].addr;
+        let mut alice = server.connect("alice");
+        let (mut browser, _) = tungstenite::connect(format!("ws://{}", ws_addr)).unwrap();
+        if let MaybeTlsStream::Plain(stream) = browser.get_ref() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        let mut send = |message: ChatMessage| {
+            let frame = Frame::encode(&message).unwrap();
+            browser.send(Message::Text(frame)).unwrap();
+        };
+        send(ChatMessage {
+            message_type: ChatMessageType::Join,
+            username: Some("bob".to_string()),
+            ..Default::default()
+        });
+        send(ChatMessage {
+            content: "hi from the browser".to_string(),
+            ..Default::default()
+        });
+
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.username.as_deref(), Some("bob"));
+        assert_eq!(received.content, "hi from the browser");
+
+        alice.say("hi from the terminal");
+        browser
+            .send(Message::Ping(b"still there?".to_vec()))
+            .unwrap();
+        let (mut received, mut ponged) = (None, false);
+        while received.is_none() || !ponged {
+            match browser.read().unwrap() {
+                Message::Text(text) => {
+                    let message = Frame::decode(&text).unwrap().message;
+                    if matches!(message.message_type, ChatMessageType::Message) {
+                        received = Some(message);
+                    }
+                }
+                Message::Pong(payload) => ponged = payload == b"still there?",
+                _ => {}
+            }
+        }
+        let received = received.unwrap();
+        assert_eq!(received.username.as_deref(), Some("alice"));
+        assert_eq!(received.content, "hi from the terminal");
+
+        // Shutdown stops the gateway too: its client is disconnected and its handler joined.
+        let state = Arc::clone(&server.state);
+        server.stop();
+        assert!(state.listeners.lock().unwrap().is_empty());
+        let closed = loop {
+            if let Err(e) = browser.read() {
+                break e;
+            }
+        };
+        assert!(
+            !matches!(&closed, tungstenite::Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock),
+            "gateway client still connected"
+        );
+        assert!(TcpStream::connect(ws_addr).is_err());
+    }
+
+    #[test]
+    fn autosave_writes_new_messages_within_one_interval() {
+        let path = env::temp_dir().join(format!("chat-history-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let server = TestServer::with_args(&[
+            "--history-file",
+            path.to_str().unwrap(),
+            "--autosave-secs",
+            "1",
+        ]);
+        let mut alice = server.connect("alice");
+        alice.say("remember me");
+        alice.sync();
+
+        // One interval, plus some slack for a busy machine.
+        let deadline = Instant::now() + Duration::from_millis(1500);
+        let saved = loop {
+            let saved = fs::read_to_string(&path).unwrap_or_default();
+            if saved.contains("remember me") || Instant::now() >= deadline {
+                break saved;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        // Checked before the server stops, which saves the history too.
+        assert!(
+            saved.contains("remember me"),
+            "not saved in time: {}",
+            saved
+        );
+        server.stop();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn join_triggers_a_presence_update_with_the_new_count() {
+        let server = TestServer::with_args(&["--presence-debounce-ms", "20"]);
+        let mut alice = server.connect("alice");
+        let mut roster = || {
+            let presence =
+                alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Presence));
+            assert!(presence.system);
+            serde_json::from_str::<Vec<String>>(&presence.content).unwrap()
+        };
+        assert_eq!(roster(), ["alice"]);
+
+        let _bob = server.connect("bob");
+        assert_eq!(roster(), ["alice", "bob"]);
+        let history = server.state.chat_history.read().unwrap();
+        assert!(history
+            .iter()
+            .all(|msg| !matches!(msg.message_type, ChatMessageType::Presence)));
+    }
+
+    #[test]
+    fn concurrent_broadcasts_reach_every_client_in_the_same_order() {
+        const SENDERS: usize = 4;
+        const MESSAGES: usize = 25;
+        let server = TestServer::start();
+        let mut observers = vec![server.connect("olivia"), server.connect("oscar")];
+        let senders: Vec<_> = (0..SENDERS)
+            .map(|i| server.connect(&format!("sender{}", i)))
+            .collect();
+        for observer in &mut observers {
+            observer.sync(); // Reads the join announcements.
+        }
+
+        let threads: Vec<_> = senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut sender)| {
+                thread::spawn(move || {
+                    for n in 0..MESSAGES {
+                        sender.say(&format!("{}-{}", i, n));
+                    }
+                    sender.sync(); // Waits until the server has read every message.
+                })
+            })
+            .collect();
+        let orders: Vec<Vec<(Option<u64>, String)>> = observers
+            .iter_mut()
+            .map(|observer| {
+                (0..SENDERS * MESSAGES)
+                    .map(|_| {
+                        let msg = observer
+                            .recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+                        (msg.seq, msg.content)
+                    })
+                    .collect()
+            })
+            .collect();
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(orders[0], orders[1]);
+        assert!(orders[0].windows(2).all(|pair| pair[0].0 < pair[1].0)); // In seq order.
+    }
+
+    #[test]
+    fn shutdown_saves_the_history_for_the_next_start() {
+        let path = env::temp_dir().join(format!("chat-history-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let history_file = path.to_str().unwrap();
+
+        // With autosave off, only the shutdown writes the file.
+        let args = ["--history-file", history_file, "--autosave-secs", "0"];
+        let server = TestServer::with_args(&args);
+        let mut alice = server.connect("alice");
+        alice.say("remember me");
+        alice.sync();
+        server.stop();
+        alice.recv_to_end();
+        assert!(fs::read_to_string(&path).unwrap().contains("remember me"));
+
+        let server = TestServer::with_args(&args);
+        let mut bob = server.join("bob");
+        let replayed = bob.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(replayed.content, "remember me");
+        assert_eq!(replayed.username.as_deref(), Some("alice"));
+        server.stop();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn denied_address_is_disconnected_before_registering() {
+        let server = TestServer::with_args(&["--deny", "127.0.0.0/8"]);
+        let mut client = TestClient::connect(server.addr);
+        assert!(client.try_recv().is_none()); // Closed without a word.
+        assert!(server.state.clients.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejoining_within_the_grace_period_announces_neither_leave_nor_join() {
+        let server = TestServer::with_args(&["--rejoin-grace-ms", "300"]);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        let mut carol = server.connect("carol");
+        alice.sync(); // Reads the join announcements.
+
+        bob.command("/quit");
+        bob.recv_reply(CommandType::Quit);
+        drop(bob);
+        carol.command("/quit"); // Carol stays away.
+        carol.recv_reply(CommandType::Quit);
+        drop(carol);
+        // Once both are cleaned up, the name is free for bob to rejoin with.
+        while server.state.online_usernames().unwrap().len() > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _bob = server.connect("bob");
+        thread::sleep(Duration::from_millis(600)); // Past the grace period.
+
+        let announcements: Vec<String> = alice
+            .sync()
+            .into_iter()
+            .filter(|msg| msg.system)
+            .map(|msg| msg.content)
+            .collect();
+        assert_eq!(announcements, ["carol has left the chat"]);
+    }
+
+    #[test]
+    fn reaper_prunes_a_dead_client_within_one_interval() {
+        let server = TestServer::with_args(&["--reap-interval-secs", "1"]);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        // Writes to bob now fail, as on a socket that died without its handler noticing.
+        let bob_addr = *server
+            .state
+            .usernames
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_, name)| *name == "bob")
+            .unwrap()
+            .0;
+        server.state.clients.read().unwrap()[&bob_addr]
+            .outbox
+            .close();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while server.state.clients.read().unwrap().contains_key(&bob_addr) {
+            assert!(Instant::now() < deadline, "bob was not reaped");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!server
+            .state
+            .usernames
+            .read()
+            .unwrap()
+            .contains_key(&bob_addr));
+        bob.recv_to_end(); // The reaper closed the connection.
+        alice.command("/list");
+        assert_eq!(
+            alice.recv_reply(CommandType::List).content,
+            "Online users: alice"
+        );
+    }
+}
@@ -1,92 +1,70 @@
-// Module imports for client handling, error handling, and message types.
-mod client_handler;
-mod errors;
-mod message;
-
-use client_handler::handle_client; // Function to handle each client connection.
+// The server itself lives in the library crate, so tests can run it in-process.
 use ctrlc::set_handler; // For handling Ctrl+C to gracefully shut down the server.
-use errors::ChatResult; // Custom result type for error handling.
-use message::ChatMessage; // Message type for communication.
-use std::collections::HashMap; // Used to store client connections and usernames.
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream}; // Networking utilities.
-use std::sync::atomic::{AtomicBool, Ordering}; // Atomic flag for thread-safe shutdown.
-use std::sync::{Arc, RwLock}; // Shared data structures for thread-safe access.
-use std::thread; // For spawning threads for each client.
+use rust_tcp_chat::config::ServerConfig; // Server configuration parsed from the command line.
+use rust_tcp_chat::errors::ChatResult; // Custom result type for error handling.
+use rust_tcp_chat::runtime::{bind_server, finish_shutdown, run_server, shutdown, start_services}; // Starting, running and stopping the server.
+use std::sync::Arc; // Shared ownership of the server state across threads.
 
 fn main() -> ChatResult<()> {
+    let config = ServerConfig::load();
+
     // Initialize the logger with Info-level logging for debugging and operational clarity.
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info) // Set the global log level to Info.
         .init();
 
-    // Bind the server to a local address and port (127.0.0.1:8081).
-    let listener = Arc::new(
-        TcpListener::bind("127.0.0.1:8081")
-            .map_err(|_| errors::ChatServerError::NoAvailablePorts)?, // Handle binding errors.
-    );
-    log::info!("Server is running on 127.0.0.1:8081");
+    // Bind the server to the configured address (127.0.0.1:8081 by default).
+    let (listener, local_addr) = bind_server(&config.addr)?;
+    log::info!("Server is running on {}", local_addr);
 
-    // Shared structures for managing clients, usernames, and chat history.
-    let clients = Arc::new(RwLock::new(HashMap::<SocketAddr, TcpStream>::new())); // Client connections.
-    let usernames = Arc::new(RwLock::new(HashMap::<SocketAddr, String>::new())); // Usernames by address.
-    let chat_history = Arc::new(RwLock::new(Vec::<ChatMessage>::new())); // Chat message history.
-
-    // Atomic flag for server shutdown, allowing threads to check if the server is shutting down.
-    let is_shutting_down = Arc::new(AtomicBool::new(false));
+    // Shared state for managing clients, usernames, and chat history, and the threads serving it.
+    let state = start_services(config)?;
 
     // Handle Ctrl+C signal to gracefully shut down the server.
-    let clients_clone = Arc::clone(&clients);
-    let is_shutting_down_clone = Arc::clone(&is_shutting_down);
-    set_handler(move || {
-        if is_shutting_down_clone.load(Ordering::SeqCst) {
-            return; // Prevent multiple shutdown triggers.
-        }
-        is_shutting_down_clone.store(true, Ordering::SeqCst); // Set the shutdown flag.
-        log::info!("Shutting down server...");
+    // The shutdown flag lives in the shared state so client handlers can back off while it runs.
+    // The handler only starts the shutdown; `run_server` returns once the accept loop sees the flag.
+    // Registration fails if the process already has a handler, e.g. when something else set one
+    // first; the server then runs without it and is stopped by whatever owns the signal.
+    let state_clone = Arc::clone(&state);
+    set_ctrl_c_handler(move || shutdown(&state_clone, local_addr), set_handler);
 
-        // Close all client connections gracefully.
-        let clients_lock = clients_clone.read().unwrap();
-        for (_, client) in clients_lock.iter() {
-            if let Err(e) = client.shutdown(Shutdown::Both) {
-                log::error!("Failed to shutdown client connection: {}", e);
-            }
-        }
+    let handlers = run_server(listener, Arc::clone(&state))?;
+    finish_shutdown(&state, handlers);
 
-        log::info!("All clients have been disconnected. Exiting...");
-        std::process::exit(0); // Terminate the process.
-    })
-    .expect("Error setting Ctrl+C handler");
+    log::info!("Server has shut down.");
+    Ok(())
+}
 
-    // Main loop for accepting client connections.
-    for stream in listener.incoming() {
-        // If shutdown is triggered, exit the loop.
-        if is_shutting_down.load(Ordering::SeqCst) {
-            break;
+/// Runs `on_ctrl_c` when Ctrl+C is pressed, installing it with `register`, which is
+/// `ctrlc::set_handler` outside of tests. If the handler can't be set, logs a warning
+/// and returns `false` instead of failing, so the server still runs.
+fn set_ctrl_c_handler<F: FnMut() + Send + 'static>(
+    on_ctrl_c: F,
+    register: impl FnOnce(F) -> Result<(), ctrlc::Error>,
+) -> bool {
+    match register(on_ctrl_c) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!(
+                "Failed to set the Ctrl+C handler, so Ctrl+C won't shut the server down gracefully: {}",
+                e
+            );
+            false
         }
+    }
+}
 
-        match stream {
-            Ok(stream) => {
-                // Clone shared structures for each new thread.
-                let clients = Arc::clone(&clients);
-                let usernames = Arc::clone(&usernames);
-                let chat_history = Arc::clone(&chat_history);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                // Spawn a new thread to handle the client.
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, clients, usernames, chat_history) {
-                        log::error!("Error handling client: {}", e);
-                    }
-                });
-            }
-            Err(e) => {
-                log::error!("Failed to accept connection: {}", e);
-                if is_shutting_down.load(Ordering::SeqCst) {
-                    break; // Exit loop if shutdown is triggered.
-                }
-            }
-        }
+    #[test]
+    fn failing_to_set_the_ctrl_c_handler_is_not_fatal() {
+        // Fakes stand in for `set_handler`, so the test process's real signal handling is untouched.
+        assert!(set_ctrl_c_handler(|| {}, |_| Ok(())));
+        assert!(!set_ctrl_c_handler(
+            || {},
+            |_| Err(ctrlc::Error::MultipleHandlers)
+        ));
     }
-
-    log::info!("Server has shut down.");
-    Ok(())
 }
@@ -0,0 +1,538 @@
+// state.rs
+use crate::audit::{AuditEntry, AuditLog}; // Record of moderation actions.
+use crate::commands::CommandRegistry; // Handlers for registered commands.
+use crate::compression::COMPRESSION_FEATURE; // Advertised so clients can opt in.
+use crate::config::ServerConfig; // Server configuration shared with every handler.
+use crate::errors::ChatResult; // Result type for lock errors.
+use crate::events::{ConnectionLog, SystemEvent}; // Events published to observers, and recent joins and leaves.
+use crate::message::{
+    ChatMessage, ChatMessageType, CommandType, HistoryMode, NotifyMode, Priority, Role,
+}; // Message types stored in the chat history.
+use crate::outbox::Outbox; // Per-client outbound queue.
+use crate::transport::Transport; // Connection handle stored for each client.
+use std::collections::hash_map::DefaultHasher; // Fingerprints chat messages for spam detection.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}; // Used to store client connections, usernames, admins and reactions.
+use std::hash::{Hash, Hasher}; // Feeding message text to the hasher.
+use std::net::SocketAddr; // Address used to identify each client.
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}; // Counters, limits and flags.
+use std::sync::mpsc::{self, Receiver, Sender}; // Channels carrying events and broadcasts.
+use std::sync::{Arc, Mutex, RwLock}; // Thread-safe access to the shared maps and outboxes.
+use std::thread::JoinHandle; // Accept loops recorded for shutdown.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH}; // Timing for ping rounds and audit entries.
+
+/// Shared state of the chat server, handed to every client handler behind a single `Arc`.
+#[derive(Default)]
+pub struct ServerState {
+    pub clients: RwLock<HashMap<SocketAddr, ClientConnection>>, // Client connections by address.
+    pub usernames: RwLock<HashMap<SocketAddr, String>>,         // Usernames by address.
+    pub chat_history: RwLock<Vec<ChatMessage>>,                 // Chat message history.
+    pub next_seq: AtomicU64,                                    // Last sequence number assigned.
+    pub config: ServerConfig,                                   // Server configuration.
+    pub is_shutting_down: AtomicBool,                           // Set once server shutdown begins.
+    pub admins: RwLock<HashSet<SocketAddr>>,                    // Clients authenticated as admins.
+    pub max_message_len: AtomicUsize, // Runtime-adjustable message length limit.
+    pub ping_rounds: RwLock<HashMap<u64, PingRound>>, // In-flight `/ping-all` rounds by id.
+    pub next_ping_id: AtomicU64,      // Last ping round id assigned.
+    pub username_aliases: RwLock<HashMap<String, String>>, // Former usernames mapped to the name they were changed to.
+    pub reactions: RwLock<HashMap<u64, Reactions>>, // Reactions by the seq of the message reacted to.
+    pub commands: CommandRegistry, // Handlers for commands dispatched through the registry.
+    pub muted: RwLock<HashMap<String, Option<Instant>>>, // Muted usernames and when their mute expires.
+    pub pending_leaves: RwLock<HashMap<String, PendingLeave>>, // Leave announcements held back by username.
+    events: Vec<Sender<SystemEvent>>, // One sender per subscribed observer.
+    broadcasts: Option<Sender<Broadcast>>, // Set once the broadcaster thread is started.
+    pub started_at: Option<Instant>,  // When the server started; set by `new`.
+    pub presence_changed: AtomicBool, // Set when the roster changes, until it is broadcast.
+    pub last_file_share: RwLock<Option<Instant>>, // When `/broadcast-file` last sent a file.
+    pub audit: AuditLog, // Moderation actions, for `/auditlog` and the `--audit-log` file.
+    pub scheduled: RwLock<BTreeMap<u64, ScheduledMessage>>, // Pending `/schedule` announcements by id.
+    pub next_schedule_id: AtomicU64,                        // Last announcement id assigned.
+    pub next_connection_id: AtomicU64,                      // Last connection id assigned.
+    pub next_guest_id: AtomicU64,                           // Last number used in a `guest-N` name.
+    pub online: AtomicUsize, // Registered connections; only `register_client` and `cleanup_client` change it.
+    pub history_mode: RwLock<HistoryMode>, // Which joining clients get history replayed.
+    pub offline_messages: RwLock<HashMap<String, VecDeque<ChatMessage>>>, // Private messages waiting for offline users, oldest first.
+    pub connections: ConnectionLog, // Recent joins and leaves, for `/recent`.
+    pub pinned: RwLock<BTreeMap<u64, ChatMessage>>, // Copies of the messages pinned with `/pin`, by seq.
+    pub listeners: Mutex<Vec<Listener>>, // Accept loops besides the main one, e.g. the WebSocket gateway.
+}
+
+/// An accept loop started alongside the main one. `shutdown` wakes it, and
+/// `finish_shutdown` waits for the client handlers it returns.
+pub struct Listener {
+    pub addr: SocketAddr,                             // Address it accepts on.
+    pub accept_loop: JoinHandle<Vec<JoinHandle<()>>>, // Returns the handlers still running.
+}
+
+/// A frame to fan out to every client, queued for the broadcaster thread.
+pub struct Broadcast {
+    pub exclude: Option<SocketAddr>, // Client that doesn't receive the frame, usually the sender.
+    pub frame: String,               // Serialized `Frame`.
+    pub priority: Priority,          // Outbound queue priority.
+}
+
+/// An announcement an admin scheduled with `/schedule`.
+pub struct ScheduledMessage {
+    pub due: Instant,   // When the announcement is broadcast.
+    pub author: String, // Username of the admin who scheduled it.
+    pub text: String,   // The announcement.
+}
+
+/// A leave announcement held back in case the user rejoins right away.
+pub struct PendingLeave {
+    pub sender: SocketAddr, // Address of the connection that left; only a rejoin from its host cancels the leave.
+    pub message_type: ChatMessageType, // Leave or quit.
+    pub due: Option<Instant>, // When the leave is announced if the user hasn't rejoined; `None` holds it until they do.
+}
+
+/// Reactions to a single message: the users who reacted, by emoji.
+/// Ordered so the aggregate reads the same for every client.
+pub type Reactions = BTreeMap<String, BTreeSet<String>>;
+
+/// A connected client: its connection and the outbound queue drained by its writer thread.
+pub struct ClientConnection {
+    pub id: u64, // Tells this connection apart from a later one that reuses its address.
+    pub transport: Box<dyn Transport>, // Handle used to shut the connection down.
+    pub outbox: Arc<Outbox>, // Frames sent to this client by other threads.
+    pub compression: AtomicBool, // Set once the client opts into compressed frames.
+    pub last_seen: Mutex<Instant>, // When a frame, such as a pong, last arrived from the client.
+    pub status: Mutex<Option<String>>, // Set with `/status`; shown next to the name in `/list`.
+    pub notify: Mutex<NotifyMode>, // Set with `/notify`; what the user wants to be notified of. Stored only.
+    pub recent_messages: Mutex<VecDeque<(Instant, u64)>>, // When recent chat messages were sent, with their `spam_fingerprint`.
+}
+
+/// An in-flight `/ping-all` round.
+pub struct PingRound {
+    pub sent_at: Instant,                    // When the pings were sent.
+    pub rtts: HashMap<SocketAddr, Duration>, // Round-trip times of the clients that answered.
+}
+
+impl ServerState {
+    /// Creates an empty server state with the given configuration.
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            max_message_len: AtomicUsize::new(config.max_message_len),
+            config,
+            commands: CommandRegistry::with_builtin_commands(),
+            started_at: Some(Instant::now()),
+            ..Self::default()
+        }
+    }
+
+    /// Subscribes to the server's `SystemEvent`s. Call before sharing the state;
+    /// every subscriber receives every event.
+    pub fn subscribe(&mut self) -> Receiver<SystemEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.events.push(sender);
+        receiver
+    }
+
+    /// Publishes an event to the subscribers, if any. Events are dropped if nobody listens.
+    /// Joins and leaves are also kept in the connection log.
+    pub fn emit(&self, event: SystemEvent) {
+        self.connections.record(&event);
+        for events in &self.events {
+            let _ = events.send(event.clone()); // The subscriber may have gone away; that's fine.
+        }
+    }
+
+    /// Creates the channel feeding the broadcaster thread, which should drain the returned
+    /// receiver. Call before sharing the state; until then broadcasts are delivered inline.
+    pub fn start_broadcasts(&mut self) -> Receiver<Broadcast> {
+        let (sender, receiver) = mpsc::channel();
+        self.broadcasts = Some(sender);
+        receiver
+    }
+
+    /// Queues `broadcast` for the broadcaster thread. Frames are delivered in the order they
+    /// were queued, so every client sees broadcasts in the same order.
+    /// Hands the broadcast back if no broadcaster is running.
+    pub fn queue_broadcast(&self, broadcast: Broadcast) -> Result<(), Broadcast> {
+        match &self.broadcasts {
+            Some(broadcasts) => broadcasts.send(broadcast).map_err(|e| e.0),
+            None => Err(broadcast),
+        }
+    }
+
+    /// Records a moderation action in the audit log and publishes it as an event.
+    pub fn record_moderation(
+        &self,
+        actor: &str,            // Username of the admin who acted.
+        action: CommandType,    // The moderation command.
+        target: &str,           // Username or setting acted on.
+        detail: Option<String>, // Extra context, e.g. a mute's duration.
+    ) {
+        let entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            actor: actor.to_string(),
+            action: action.name(),
+            target: target.to_string(),
+            detail,
+        };
+        self.emit(SystemEvent::Moderation(entry.clone()));
+        self.audit.record(entry);
+    }
+
+    /// Returns `true` if the client at `addr` has authenticated as an admin.
+    pub fn is_admin(&self, addr: &SocketAddr) -> bool {
+        self.admins
+            .read()
+            .map(|admins| admins.contains(addr))
+            .unwrap_or(false)
+    }
+
+    /// How long the server has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started_at
+            .map(|started_at| started_at.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Returns the role of the client at `addr`.
+    pub fn role(&self, addr: &SocketAddr) -> Role {
+        if self.is_admin(addr) {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+
+    /// Builds a JSON snapshot of the connected clients, history and configuration
+    /// for debugging. The admin token is never included.
+    pub fn snapshot(&self) -> ChatResult<serde_json::Value> {
+        // Locked in the same order as cleanup: clients first.
+        let clients_lock = self.clients.read()?;
+        let usernames = self.usernames.read()?;
+        let admins = self.admins.read()?;
+        let mut clients: Vec<serde_json::Value> = clients_lock
+            .keys()
+            .map(|addr| {
+                serde_json::json!({
+                    "addr": addr.to_string(),
+                    "username": usernames.get(addr), // `null` until the client has joined.
+                    "admin": admins.contains(addr),
+                })
+            })
+            .collect();
+        clients.sort_by_key(|client| client["addr"].to_string());
+
+        Ok(serde_json::json!({
+            "clients": clients,
+            "online": self.online.load(Ordering::SeqCst),
+            "history_len": self.chat_history.read()?.len(),
+            "last_seq": self.next_seq.load(Ordering::SeqCst),
+            "max_message_len": self.max_message_len.load(Ordering::SeqCst),
+            "config": self.config,
+            "admin_token_set": self.config.admin_token.is_some(),
+        }))
+    }
+
+    /// Returns the usernames of clients with a live connection, sorted.
+    /// A username whose connection is gone from `clients`, or whose writer has failed,
+    /// is left out even if cleanup hasn't removed it yet.
+    pub fn online_usernames(&self) -> ChatResult<Vec<String>> {
+        Ok(self
+            .online_users()?
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect())
+    }
+
+    /// Like `online_usernames`, but also says whether each user has gone quiet for longer
+    /// than `--heartbeat-timeout-secs`, and gives their `/status`, if any.
+    pub fn online_users(&self) -> ChatResult<Vec<(String, bool, Option<String>)>> {
+        let clients = self.clients.read()?;
+        let mut users: Vec<(String, bool, Option<String>)> = self
+            .usernames
+            .read()?
+            .iter()
+            .filter_map(|(addr, name)| {
+                let client = clients
+                    .get(addr)
+                    .filter(|client| !client.outbox.is_closed())?;
+                let status = client.status.lock().ok().and_then(|status| status.clone());
+                Some((name.clone(), self.is_stale(client, 1), status))
+            })
+            .collect();
+        users.sort();
+        Ok(users)
+    }
+
+    /// Returns `true` if nothing has arrived from `client` for `factor` times
+    /// `--heartbeat-timeout-secs`. Never `true` when heartbeats are disabled.
+    pub fn is_stale(&self, client: &ClientConnection, factor: u32) -> bool {
+        let Some(timeout) = self.config.heartbeat_timeout_secs else {
+            return false;
+        };
+        client
+            .last_seen
+            .lock()
+            .is_ok_and(|last_seen| last_seen.elapsed() > Duration::from_secs(timeout) * factor)
+    }
+
+    /// Notes that a frame just arrived from the client at `addr`.
+    pub fn record_heartbeat(&self, addr: SocketAddr) {
+        if let Ok(clients) = self.clients.read() {
+            if let Some(client) = clients.get(&addr) {
+                if let Ok(mut last_seen) = client.last_seen.lock() {
+                    *last_seen = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Lists the optional features this server has enabled, as reported to clients.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        let mut features = vec![
+            "nick",
+            "rename-events",
+            "reactions",
+            "ping",
+            "e2e",
+            COMPRESSION_FEATURE,
+        ];
+        if self.config.admin_token.is_some() {
+            features.push("admin");
+        }
+        if self.config.replay_window.is_some_and(|window| window > 0) {
+            features.push("replay-window");
+        }
+        if self.config.rejoin_grace_ms > 0 {
+            features.push("rejoin-grace");
+        }
+        if self.config.presence_debounce_ms.is_some() {
+            features.push("presence");
+        }
+        if self.config.offline_message_cap > 0 {
+            features.push("offline-messages");
+        }
+        features
+    }
+
+    /// Follows `/nick` renames from `name` to the username it is currently known by.
+    /// Names that were never renamed resolve to themselves.
+    pub fn resolve_username(&self, name: &str) -> String {
+        let Ok(aliases) = self.username_aliases.read() else {
+            return name.to_string();
+        };
+        let mut current = name;
+        // A rename removes the new name from the alias keys, so chains can't cycle;
+        // the bound is only a safeguard.
+        for _ in 0..=aliases.len() {
+            match aliases.get(current) {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        current.to_string()
+    }
+
+    /// Keeps a private message for `username` until they next join. Returns `false` if
+    /// offline messages are disabled or `--offline-message-cap` messages are already waiting.
+    pub fn store_offline_message(&self, username: &str, message: ChatMessage) -> ChatResult<bool> {
+        let cap = self.config.offline_message_cap;
+        if cap == 0 {
+            return Ok(false);
+        }
+        let mut offline_lock = self.offline_messages.write()?;
+        let inbox = offline_lock.entry(username.to_string()).or_default();
+        if inbox.len() >= cap {
+            return Ok(false);
+        }
+        inbox.push_back(message);
+        Ok(true)
+    }
+
+    /// Removes and returns the private messages kept for `username`, oldest first.
+    pub fn take_offline_messages(&self, username: &str) -> ChatResult<Vec<ChatMessage>> {
+        let mut offline_lock = self.offline_messages.write()?;
+        Ok(offline_lock
+            .remove(username)
+            .map(Vec::from)
+            .unwrap_or_default())
+    }
+
+    /// Returns `true` if `username` is muted, dropping the mute once it has expired.
+    pub fn is_muted(&self, username: &str) -> bool {
+        let Ok(mut muted) = self.muted.write() else {
+            return false;
+        };
+        match muted.get(username) {
+            Some(Some(expires_at)) if Instant::now() >= *expires_at => {
+                muted.remove(username);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Records a chat message from the client at `addr` and returns `true` if it makes
+    /// `--spam-repeats` copies of the same text within `--spam-window-secs`.
+    /// The copies are then forgotten, so the count starts over after the mute.
+    pub fn is_repeated_spam(&self, addr: SocketAddr, content: &str) -> bool {
+        let Some(repeats) = self.config.spam_repeats else {
+            return false;
+        };
+        let Ok(clients) = self.clients.read() else {
+            return false;
+        };
+        let Some(mut recent) = clients
+            .get(&addr)
+            .and_then(|client| client.recent_messages.lock().ok())
+        else {
+            return false;
+        };
+        let window = Duration::from_secs(self.config.spam_window_secs);
+        let now = Instant::now();
+        recent.retain(|(sent_at, _)| now.duration_since(*sent_at) < window);
+        let fingerprint = spam_fingerprint(content);
+        recent.push_back((now, fingerprint));
+        let copies = recent.iter().filter(|(_, f)| *f == fingerprint).count();
+        if copies as u64 >= repeats {
+            recent.clear();
+            return true;
+        }
+        false
+    }
+
+    /// Returns `true` once the server has started shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.is_shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+/// Hashes `content` ignoring case, spacing and punctuation, so near-identical copies of a
+/// message (`Buy now!!`, `buy   now`) count as repeats.
+fn spam_fingerprint(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .for_each(|c| c.hash(&mut hasher));
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::CommandType;
+    use crate::test_support::{test_config, TestClient};
+
+    #[test]
+    fn clients_register_and_broadcast_through_shared_state() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        assert_eq!(state.online.load(Ordering::SeqCst), 2);
+        assert_eq!(state.clients.read().unwrap().len(), 2);
+
+        bob.say("hi alice");
+        let received = alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Message));
+        assert_eq!(received.content, "hi alice");
+        assert_eq!(received.username.as_deref(), Some("bob"));
+        let history = state.chat_history.read().unwrap();
+        assert_eq!(history.last().unwrap().content, "hi alice");
+        assert_eq!(history.last().unwrap().seq, received.seq);
+
+        alice.command("/list");
+        let list = alice.recv_reply(CommandType::List);
+        assert_eq!(list.content, "Online users: alice, bob");
+    }
+
+    #[test]
+    fn list_leaves_out_usernames_without_a_live_connection() {
+        let state = Arc::new(ServerState::new(test_config(&[])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        let (mut bob, _) = TestClient::in_memory(&state, "10.0.0.2:5000");
+        bob.join("bob");
+        bob.sync();
+        // A ghost: a username whose connection is already gone from `clients`...
+        state
+            .usernames
+            .write()
+            .unwrap()
+            .insert("10.0.0.3:5000".parse().unwrap(), "ghost".to_string());
+        // ...and bob, whose writer has failed but who hasn't been cleaned up yet.
+        let bob_addr: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        state.clients.read().unwrap()[&bob_addr].outbox.close();
+
+        assert_eq!(state.online_usernames().unwrap(), ["alice"]);
+        alice.command("/list");
+        assert_eq!(
+            alice.recv_reply(CommandType::List).content,
+            "Online users: alice"
+        );
+    }
+
+    #[test]
+    fn capabilities_follow_the_configuration() {
+        let base = [
+            "nick",
+            "rename-events",
+            "reactions",
+            "ping",
+            "e2e",
+            "compression",
+        ];
+        let defaults = ServerState::new(test_config(&["--rejoin-grace-ms", "0"]));
+        assert_eq!(defaults.capabilities(), base);
+
+        let configured = Arc::new(ServerState::new(test_config(&[
+            "--admin-token",
+            "secret",
+            "--replay-window",
+            "5",
+            "--presence-debounce-ms",
+            "100",
+            "--offline-message-cap",
+            "3",
+        ])));
+        let mut expected = base.to_vec();
+        expected.extend([
+            "admin",
+            "replay-window",
+            "rejoin-grace",
+            "presence",
+            "offline-messages",
+        ]);
+        assert_eq!(configured.capabilities(), expected);
+
+        // The same list is what a joining client is sent.
+        let (mut alice, _) = TestClient::in_memory(&configured, "10.0.0.1:5000");
+        alice.join("alice");
+        let sent =
+            alice.recv_until(|msg| matches!(msg.message_type, ChatMessageType::Capabilities));
+        let sent: Vec<String> = serde_json::from_str(&sent.content).unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn snapshot_lists_clients_and_history_without_the_admin_token() {
+        let state = Arc::new(ServerState::new(test_config(&["--admin-token", "secret"])));
+        let (mut alice, _) = TestClient::in_memory(&state, "10.0.0.1:5000");
+        alice.join("alice");
+        alice.sync();
+        alice.say("hello");
+        alice.sync();
+
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(
+            snapshot["clients"],
+            serde_json::json!([{ "addr": "10.0.0.1:5000", "username": "alice", "admin": false }])
+        );
+        assert_eq!(snapshot["online"], 1);
+        assert_eq!(snapshot["history_len"], 2); // The join announcement and "hello".
+        assert_eq!(snapshot["admin_token_set"], true);
+        assert!(!snapshot.to_string().contains("secret"));
+    }
+}
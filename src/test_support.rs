@@ -0,0 +1,232 @@
+// test_support.rs
+use crate::client_handler::handle_client; // Run directly over memory pipes.
+use crate::config::ServerConfig; // Built from test arguments instead of the command line.
+use crate::errors::ChatResult; // What a handler run over a memory pipe returns.
+use crate::message::{ChatMessage, ChatMessageType, Command, CommandType, Frame}; // What test clients send and receive.
+use crate::runtime::{bind_server, finish_shutdown, run_server, shutdown, start_services}; // The real server, run in-process.
+use crate::state::ServerState; // Shared state, exposed for assertions.
+use crate::transport::{MemoryTransport, TcpTransport, Transport}; // Raw frame connections used by test clients.
+use std::io; // Results of writes that may fail.
+use std::net::{SocketAddr, TcpStream}; // Where test clients connect.
+use std::sync::Arc; // Shared ownership of the server state.
+use std::thread::{self, JoinHandle}; // The accept loop runs on its own thread.
+use std::time::Duration; // Read timeouts, so a missing reply fails the test instead of hanging it.
+
+/// How long a test client waits for a frame before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parses `args` as `chat-server` would, binding to `127.0.0.1:0`.
+pub fn test_config(args: &[&str]) -> ServerConfig {
+    let mut argv = vec!["chat-server", "--addr", "127.0.0.1:0"];
+    argv.extend_from_slice(args);
+    let argv = argv.into_iter().map(String::from).collect();
+    ServerConfig::parse_command_line(argv).expect("invalid test server arguments")
+}
+
+/// A server running in-process on an ephemeral port, shut down when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,        // Address the server is listening on.
+    pub state: Arc<ServerState>, // Shared state, for assertions.
+    accept_loop: Option<JoinHandle<Vec<JoinHandle<()>>>>, // Returns the handlers still running.
+}
+
+impl TestServer {
+    /// Starts a server with the default configuration.
+    pub fn start() -> Self {
+        Self::with_args(&[])
+    }
+
+    /// Starts a server with `args` added to the command line, e.g. `["--replay-window", "2"]`.
+    /// The server always binds to `127.0.0.1:0`.
+    pub fn with_args(args: &[&str]) -> Self {
+        let config = test_config(args);
+        let (listener, addr) = bind_server(&config.addr).expect("failed to bind test server");
+        let state = start_services(config).expect("failed to start test server");
+        let accept_state = Arc::clone(&state);
+        let accept_loop = thread::spawn(move || {
+            run_server(listener, accept_state).expect("test server accept loop failed")
+        });
+        Self {
+            addr,
+            state,
+            accept_loop: Some(accept_loop),
+        }
+    }
+
+    /// Connects a client and joins as `username`, without reading anything yet.
+    pub fn join(&self, username: &str) -> TestClient {
+        let mut client = TestClient::connect(self.addr);
+        client.join(username);
+        client
+    }
+
+    /// Connects a client, joins as `username` and reads the welcome, so the client is
+    /// registered and ready to chat when this returns.
+    pub fn connect(&self, username: &str) -> TestClient {
+        let mut client = self.join(username);
+        client.sync();
+        client
+    }
+
+    /// Shuts the server down as Ctrl+C would: closes every connection, stops the accept loop
+    /// and waits for the handlers.
+    pub fn stop(mut self) {
+        self.stop_inner();
+    }
+
+    fn stop_inner(&mut self) {
+        let Some(accept_loop) = self.accept_loop.take() else {
+            return;
+        };
+        shutdown(&self.state, self.addr);
+        let handlers = accept_loop
+            .join()
+            .expect("test server accept loop panicked");
+        finish_shutdown(&self.state, handlers);
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop_inner();
+    }
+}
+
+/// A raw client speaking the wire protocol, so tests see exactly what the server sends.
+pub struct TestClient {
+    transport: Box<dyn Transport>, // Connection to the test server, or to a handler.
+}
+
+impl TestClient {
+    /// Connects to `addr` without joining.
+    pub fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).expect("failed to connect to test server");
+        stream
+            .set_read_timeout(Some(RECV_TIMEOUT))
+            .expect("failed to set read timeout");
+        Self {
+            transport: Box::new(TcpTransport::new(stream)),
+        }
+    }
+
+    /// Runs `handle_client` on a thread over a memory pipe, as if a client connected from
+    /// `addr`, and returns that client without joining, along with the handler thread.
+    pub fn in_memory(state: &Arc<ServerState>, addr: &str) -> (Self, JoinHandle<ChatResult<()>>) {
+        let server_addr = "127.0.0.1:8081".parse().unwrap();
+        let client_addr = addr.parse().expect("invalid test client address");
+        let (server_end, client_end) = MemoryTransport::pair(server_addr, client_addr);
+        client_end
+            .set_read_timeout(Some(RECV_TIMEOUT))
+            .expect("failed to set read timeout");
+        let state = Arc::clone(state);
+        let handler = thread::spawn(move || handle_client(server_end, state));
+        let client = Self {
+            transport: Box::new(client_end),
+        };
+        (client, handler)
+    }
+
+    /// Sends the join message for `username`.
+    pub fn join(&mut self, username: &str) {
+        self.send(ChatMessage {
+            message_type: ChatMessageType::Join,
+            username: Some(username.to_string()),
+            ..Default::default()
+        });
+    }
+
+    /// Waits until the server has handled everything sent so far, by sending `/version` and
+    /// reading up to its reply. Returns the messages received before the reply.
+    pub fn sync(&mut self) -> Vec<ChatMessage> {
+        self.command("/version");
+        let mut received = Vec::new();
+        loop {
+            let message = self.recv();
+            if matches!(
+                message.message_type,
+                ChatMessageType::Command(CommandType::Version)
+            ) {
+                return received;
+            }
+            received.push(message);
+        }
+    }
+
+    /// Reads messages until a reply to `command_type` arrives and returns it.
+    pub fn recv_reply(&mut self, command_type: CommandType) -> ChatMessage {
+        self.recv_until(
+            |msg| matches!(&msg.message_type, ChatMessageType::Command(reply) if *reply == command_type),
+        )
+    }
+
+    /// Sends `message` in a frame.
+    pub fn send(&mut self, message: ChatMessage) {
+        self.try_send(message)
+            .expect("failed to write to test server");
+    }
+
+    /// Sends `message` in a frame, for tests where the server may already be gone.
+    pub fn try_send(&mut self, message: ChatMessage) -> io::Result<()> {
+        let frame = Frame::encode(&message).expect("failed to encode frame");
+        self.transport.write_frame(&frame)
+    }
+
+    /// Sends `frame` as is, e.g. to test how the server handles malformed input.
+    pub fn send_raw(&mut self, frame: &str) {
+        self.transport
+            .write_frame(frame)
+            .expect("failed to write to test server");
+    }
+
+    /// Sends a chat message with `content`.
+    pub fn say(&mut self, content: &str) {
+        self.send(ChatMessage {
+            content: content.to_string(),
+            ..Default::default()
+        });
+    }
+
+    /// Parses `input`, e.g. `/list`, and sends it as a command.
+    pub fn command(&mut self, input: &str) {
+        let command = Command::parse(input).expect("invalid test command");
+        self.send(command.into_message(""));
+    }
+
+    /// Reads the next message, or `None` once the server has closed the connection.
+    /// Fails the test if nothing arrives within `RECV_TIMEOUT`.
+    pub fn try_recv(&mut self) -> Option<ChatMessage> {
+        let frame = self
+            .transport
+            .read_frame()
+            .expect("timed out waiting for the test server")?;
+        Some(
+            Frame::decode(&frame)
+                .expect("server sent an invalid frame")
+                .message,
+        )
+    }
+
+    /// Reads the next message, failing the test if the connection closes first.
+    pub fn recv(&mut self) -> ChatMessage {
+        self.try_recv().expect("test server closed the connection")
+    }
+
+    /// Reads messages until one matches `predicate` and returns it; earlier ones are skipped.
+    pub fn recv_until(&mut self, predicate: impl Fn(&ChatMessage) -> bool) -> ChatMessage {
+        loop {
+            let message = self.recv();
+            if predicate(&message) {
+                return message;
+            }
+        }
+    }
+
+    /// Reads messages until the server closes the connection, and returns them.
+    pub fn recv_to_end(&mut self) -> Vec<ChatMessage> {
+        let mut messages = Vec::new();
+        while let Some(message) = self.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+}
@@ -0,0 +1,130 @@
+// theme.rs
+use serde::Deserialize; // Theme files are JSON.
+use std::fs; // Reading theme files.
+
+/// Resets every style; ends a styled span.
+const RESET: &str = "\x1B[0m";
+
+/// An ANSI style such as `bold red`, stored as SGR parameters (`1;31`).
+/// The default style is empty and leaves text unstyled.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Style(String);
+
+impl Style {
+    /// Parses space-separated attributes and colors, e.g. `bold red` or `underline on-blue`.
+    /// Raw SGR numbers such as `38;5;208` are accepted too.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let codes = spec
+            .split_whitespace()
+            .map(|word| {
+                let (prefix, name) = match word.strip_prefix("on-") {
+                    Some(name) => (40, name), // Background.
+                    None => (30, word),       // Foreground.
+                };
+                let (offset, name) = match name.strip_prefix("bright-") {
+                    Some(name) => (60, name),
+                    None => (0, name),
+                };
+                let color = [
+                    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+                ]
+                .iter()
+                .position(|color| *color == name);
+                match (word, color) {
+                    (_, Some(color)) => Ok((prefix + offset + color).to_string()),
+                    ("bold", _) => Ok("1".to_string()),
+                    ("dim", _) => Ok("2".to_string()),
+                    ("italic", _) => Ok("3".to_string()),
+                    ("underline", _) => Ok("4".to_string()),
+                    _ if word.split(';').all(|n| n.parse::<u8>().is_ok()) => Ok(word.to_string()),
+                    _ => Err(format!("unknown style '{}'", word)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(codes.join(";")))
+    }
+
+    /// Wraps `text` in the style. Styles that `text` resets partway, e.g. after markdown
+    /// formatting, are restored so the whole text keeps it.
+    pub fn paint(&self, text: &str) -> String {
+        if self.0.is_empty() {
+            return text.to_string();
+        }
+        let start = format!("\x1B[{}m", self.0);
+        let text = text.replace(RESET, &format!("{}{}", RESET, start));
+        format!("{}{}{}", start, text, RESET)
+    }
+}
+
+impl TryFrom<String> for Style {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        Self::parse(&spec)
+    }
+}
+
+/// Styles for each kind of line the client prints, chosen with `--theme`.
+/// A theme file is a JSON object with any of these keys; the rest stay unstyled.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub message: Style,  // Chat messages from other users.
+    pub private: Style,  // Private messages sent to you.
+    pub system: Style,   // Server notices, command replies and the roster.
+    pub join: Style,     // Users joining.
+    pub leave: Style,    // Users leaving or quitting.
+    pub error: Style,    // Requests the server rejected.
+    pub reaction: Style, // Reactions to a message.
+    pub prompt: Style,   // The input prompt.
+}
+
+impl Theme {
+    /// Loads a built-in theme (`dark` or `light`) or the theme file at `spec`.
+    pub fn load(spec: &str) -> Result<Self, String> {
+        match spec {
+            "dark" => Ok(Self::dark()),
+            "light" => Ok(Self::light()),
+            path => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("can't read theme file {}: {}", path, e))?;
+                serde_json::from_str(&text)
+                    .map_err(|e| format!("invalid theme file {}: {}", path, e))
+            }
+        }
+    }
+
+    /// Bright accents for terminals with a dark background.
+    fn dark() -> Self {
+        Self {
+            message: Style::default(),
+            private: builtin("bright-magenta"),
+            system: builtin("bright-cyan"),
+            join: builtin("bright-green"),
+            leave: builtin("bright-yellow"),
+            error: builtin("bold bright-red"),
+            reaction: builtin("dim"),
+            prompt: builtin("bold"),
+        }
+    }
+
+    /// Darker accents for terminals with a light background.
+    fn light() -> Self {
+        Self {
+            message: Style::default(),
+            private: builtin("magenta"),
+            system: builtin("blue"),
+            join: builtin("green"),
+            leave: builtin("red"),
+            error: builtin("bold red"),
+            reaction: builtin("dim"),
+            prompt: builtin("bold blue"),
+        }
+    }
+}
+
+/// Parses a style of a built-in theme, which is known to be valid.
+fn builtin(spec: &str) -> Style {
+    Style::parse(spec).unwrap_or_default()
+}
@@ -0,0 +1,108 @@
+// transcript.rs
+use rust_tcp_chat::message::ChatMessage; // Messages recorded in the transcript.
+use std::fs::{File, OpenOptions}; // The transcript file, opened for appending.
+use std::io::{self, BufWriter, Write}; // Buffered writes to the transcript.
+use std::path::Path; // Location of the transcript file.
+use std::sync::Mutex; // Shared between the input and reader threads.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH}; // Local timestamps and flush pacing.
+
+/// How often buffered transcript lines are flushed to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a recorded message was sent by this client or received from the server.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// The client's personal session log: every sent and received message, appended as JSONL
+/// with a local timestamp. Write errors are reported once and then disable the transcript,
+/// so they never interrupt the chat.
+pub struct Transcript {
+    file: Mutex<TranscriptFile>, // Writer and flush bookkeeping.
+}
+
+struct TranscriptFile {
+    writer: Option<BufWriter<File>>, // `None` when disabled or after a write error.
+    last_flush: Instant,             // When the buffer was last flushed.
+}
+
+impl Transcript {
+    /// Opens (or creates) the transcript at `path`, appending to any existing content.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::with_writer(Some(BufWriter::new(file))))
+    }
+
+    /// A transcript that records nothing, used when `--transcript` isn't given.
+    pub fn disabled() -> Self {
+        Self::with_writer(None)
+    }
+
+    fn with_writer(writer: Option<BufWriter<File>>) -> Self {
+        Self {
+            file: Mutex::new(TranscriptFile {
+                writer,
+                last_flush: Instant::now(),
+            }),
+        }
+    }
+
+    /// Appends `message` to the transcript, flushing if the last flush was a while ago.
+    pub fn record(&self, direction: Direction, message: &ChatMessage) {
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        let Some(writer) = file.writer.as_mut() else {
+            return;
+        };
+
+        let local_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let line = serde_json::json!({
+            "local_time_ms": local_time,
+            "direction": match direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            },
+            "message": message,
+        });
+        let mut result = writeln!(writer, "{}", line);
+        if result.is_ok() && file.last_flush.elapsed() >= FLUSH_INTERVAL {
+            result = file.flush_writer();
+        }
+        if let Err(e) = result {
+            file.disable(e);
+        }
+    }
+
+    /// Flushes any buffered lines, e.g. before exiting.
+    pub fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = file.flush_writer() {
+                file.disable(e);
+            }
+        }
+    }
+}
+
+impl TranscriptFile {
+    fn flush_writer(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn disable(&mut self, error: io::Error) {
+        eprintln!(
+            "\r[Error]: Transcript disabled after a write error: {}",
+            error
+        );
+        self.writer = None;
+    }
+}
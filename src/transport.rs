@@ -0,0 +1,290 @@
+// transport.rs
+use std::io::{self, BufRead, BufReader, Read, Write}; // Buffered reading and writing of frames.
+use std::net::{Shutdown, SocketAddr, TcpStream}; // Networking primitives for the TCP transport.
+use std::ops::RangeInclusive; // Allowed read buffer capacities.
+use std::sync::atomic::{AtomicBool, Ordering}; // Closed flag shared by both ends of a memory pipe.
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender}; // Frames travelling through a memory pipe.
+use std::sync::{Arc, Mutex, PoisonError}; // State shared between clones of a memory transport.
+use std::time::{Duration, Instant}; // Read timeouts.
+
+/// A connection that exchanges newline-delimited frames with a peer.
+/// Abstracts over the underlying stream so handlers don't depend on `TcpStream` directly.
+pub trait Transport: Send + Sync {
+    /// Reads the next frame without its trailing newline.
+    /// Returns `Ok(None)` once the peer has closed the connection.
+    fn read_frame(&mut self) -> io::Result<Option<String>>;
+
+    /// Writes a single frame, followed by the newline delimiter.
+    fn write_frame(&mut self, frame: &str) -> io::Result<()>;
+
+    /// Returns the address of the remote peer.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Closes both directions of the connection.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Sets how long `read_frame` may block before failing; `None` blocks indefinitely.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Creates another handle to the same connection, e.g. for writing from another thread.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+/// Default upper bound on the length of a single frame, in bytes.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Default capacity of the read buffer, in bytes.
+pub const DEFAULT_READ_BUFFER_LEN: usize = 8 * 1024;
+
+/// Capacities the read buffer may be configured with, in bytes.
+pub const READ_BUFFER_BOUNDS: RangeInclusive<usize> = 512..=DEFAULT_MAX_FRAME_LEN;
+
+/// `Transport` over a TCP stream, reading frames through a buffered reader.
+///
+/// The read buffer only sets how much is read from the socket at a time: a frame longer
+/// than the buffer is still read whole, over several reads, up to `max_frame_len`. A buffer
+/// at least as large as the longest expected frame lets most frames arrive in a single read.
+pub struct TcpTransport {
+    reader: BufReader<TcpStream>, // Buffered reader; the inner stream is also used for writes.
+    max_frame_len: usize,         // Longest frame accepted from the peer, in bytes.
+}
+
+impl TcpTransport {
+    /// Wraps a connected `TcpStream` with a read buffer of `DEFAULT_READ_BUFFER_LEN` bytes.
+    pub fn new(stream: TcpStream) -> Self {
+        Self::with_read_buffer(stream, DEFAULT_READ_BUFFER_LEN)
+    }
+
+    /// Wraps a connected `TcpStream` with a read buffer of `capacity` bytes,
+    /// clamped to `READ_BUFFER_BOUNDS`.
+    pub fn with_read_buffer(stream: TcpStream, capacity: usize) -> Self {
+        let capacity = capacity.clamp(*READ_BUFFER_BOUNDS.start(), *READ_BUFFER_BOUNDS.end());
+        Self {
+            reader: BufReader::with_capacity(capacity, stream),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// How often a blocked `MemoryTransport::read_frame` checks whether the pipe was shut down.
+const MEMORY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// `Transport` over an in-memory pipe, for running handlers without sockets, e.g. in tests.
+/// Each end is created by `pair` and reads what the other end writes.
+/// Shutting down either end closes the pipe for both.
+pub struct MemoryTransport {
+    incoming: Arc<Mutex<Receiver<String>>>, // Frames written by the other end; shared by clones.
+    outgoing: Sender<String>,               // Frames for the other end.
+    closed: Arc<AtomicBool>,                // Set by `shutdown` on either end.
+    read_timeout: Arc<Mutex<Option<Duration>>>, // Shared by clones, like a socket's timeout.
+    peer_addr: SocketAddr,                  // Address reported for the other end.
+}
+
+impl MemoryTransport {
+    /// Creates both ends of a pipe. The first end reports `b_addr` as its peer and the second
+    /// `a_addr`, so a server handler given one end sees the other as a client at that address.
+    pub fn pair(a_addr: SocketAddr, b_addr: SocketAddr) -> (Self, Self) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let end = |incoming, outgoing, peer_addr| Self {
+            incoming: Arc::new(Mutex::new(incoming)),
+            outgoing,
+            closed: Arc::clone(&closed),
+            read_timeout: Arc::default(),
+            peer_addr,
+        };
+        (
+            end(a_receiver, a_sender, b_addr),
+            end(b_receiver, b_sender, a_addr),
+        )
+    }
+}
+
+/// Parses a read buffer capacity given on the command line, in bytes.
+pub fn parse_read_buffer_len(s: &str) -> Result<usize, String> {
+    let len: usize = s.parse().map_err(|e| format!("{}", e))?;
+    if READ_BUFFER_BOUNDS.contains(&len) {
+        Ok(len)
+    } else {
+        Err(format!(
+            "must be between {} and {}",
+            READ_BUFFER_BOUNDS.start(),
+            READ_BUFFER_BOUNDS.end()
+        ))
+    }
+}
+
+impl Transport for TcpTransport {
+    /// Frames longer than `max_frame_len` fail with `InvalidData` instead of being buffered
+    /// without bound, so a peer that never sends a newline can't exhaust memory.
+    fn read_frame(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let limit = self.max_frame_len as u64 + 1; // Room for the newline.
+        match (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut line)?
+        {
+            0 => return Ok(None), // Connection closed by the peer.
+            n if n as u64 == limit && !line.ends_with(b"\n") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame exceeds {} bytes", self.max_frame_len),
+                ));
+            }
+            _ => {}
+        }
+        let line =
+            String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        self.reader
+            .get_mut()
+            .write_all(format!("{}\n", frame).as_bytes())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.reader.get_ref().peer_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.reader.get_ref().shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.get_ref().set_read_timeout(timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            reader: BufReader::with_capacity(
+                self.reader.capacity(),
+                self.reader.get_ref().try_clone()?,
+            ),
+            max_frame_len: self.max_frame_len,
+        }))
+    }
+}
+
+impl Transport for MemoryTransport {
+    /// Frames already written are still read after the pipe is shut down, as with a socket.
+    /// A read timeout fails with `TimedOut`.
+    fn read_frame(&mut self) -> io::Result<Option<String>> {
+        let timeout = *self
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let incoming = self.incoming.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            match incoming.recv_timeout(MEMORY_POLL_INTERVAL) {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None), // Other end dropped.
+                Err(RecvTimeoutError::Timeout) if self.closed.load(Ordering::SeqCst) => {
+                    return Ok(None);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        let broken = || io::Error::new(io::ErrorKind::BrokenPipe, "memory pipe closed");
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(broken());
+        }
+        self.outgoing.send(frame.to_string()).map_err(|_| broken())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = timeout;
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            incoming: Arc::clone(&self.incoming),
+            outgoing: self.outgoing.clone(),
+            closed: Arc::clone(&self.closed),
+            read_timeout: Arc::clone(&self.read_timeout),
+            peer_addr: self.peer_addr,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn frame_longer_than_the_limit_is_an_error_not_a_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut longest = vec![b'a'; DEFAULT_MAX_FRAME_LEN];
+            longest.push(b'\n');
+            stream.write_all(&longest).unwrap();
+            // A hostile peer that never sends a newline; the reader hangs up part way.
+            let chunk = vec![b'x'; 64 * 1024];
+            while stream.write_all(&chunk).is_ok() {}
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut transport = TcpTransport::new(stream);
+
+        let frame = transport.read_frame().unwrap().unwrap();
+        assert_eq!(frame.len(), DEFAULT_MAX_FRAME_LEN); // The longest frame allowed is fine.
+        let error = transport.read_frame().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        transport.shutdown().unwrap();
+        drop(transport);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn frame_that_fits_the_read_buffer_arrives_in_one_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let frame = "a".repeat(3000);
+        let sent = format!("{}\nnext\n", frame);
+        let peer = thread::spawn(move || {
+            TcpStream::connect(addr)
+                .unwrap()
+                .write_all(sent.as_bytes())
+                .unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        peer.join().unwrap(); // Everything sent is now waiting in the socket.
+        let mut transport = TcpTransport::with_read_buffer(stream, 4096);
+        assert_eq!(transport.reader.capacity(), 4096);
+
+        assert_eq!(transport.read_frame().unwrap().unwrap(), frame);
+        // The read that returned the frame also fetched the one after it.
+        assert_eq!(transport.reader.buffer(), b"next\n");
+        assert_eq!(transport.read_frame().unwrap().unwrap(), "next");
+
+        // Capacities outside the bounds are clamped.
+        let transport = TcpTransport::with_read_buffer(TcpStream::connect(addr).unwrap(), 16);
+        assert_eq!(transport.reader.capacity(), *READ_BUFFER_BOUNDS.start());
+    }
+}
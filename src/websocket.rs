@@ -0,0 +1,96 @@
+// websocket.rs
+use crate::transport::{Transport, DEFAULT_MAX_FRAME_LEN}; // Frame transport implemented here, and its frame limit.
+use std::io; // Errors reported through the `Transport` interface.
+use std::net::{Shutdown, SocketAddr, TcpStream}; // The underlying connection.
+use std::time::Duration; // Handshake and read timeouts.
+use tungstenite::protocol::{Role, WebSocketConfig}; // Server-side framing and message limits.
+use tungstenite::{Error as WsError, Message, WebSocket}; // WebSocket protocol implementation.
+
+/// `Transport` over a WebSocket, so browsers can join the chat. Every WebSocket text
+/// message carries one frame: the same JSON `Frame` a TCP client sends on a line.
+///
+/// Pings from the browser are answered automatically while reading.
+pub struct WebSocketTransport {
+    socket: WebSocket<TcpStream>, // Handshake completed; frames are read and written through it.
+}
+
+impl WebSocketTransport {
+    /// Completes the WebSocket handshake on a freshly accepted `stream`.
+    /// A client that doesn't finish the handshake within `timeout` is refused.
+    pub fn accept(stream: TcpStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_read_timeout(Some(timeout))?;
+        let socket = tungstenite::accept_with_config(stream, Some(config()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        socket.get_ref().set_read_timeout(None)?;
+        Ok(Self { socket })
+    }
+}
+
+/// Limits messages to the longest frame a TCP client may send.
+fn config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(DEFAULT_MAX_FRAME_LEN),
+        max_frame_size: Some(DEFAULT_MAX_FRAME_LEN),
+        ..Default::default()
+    }
+}
+
+/// Converts a WebSocket error into the `io::Error` the `Transport` interface reports.
+/// I/O errors keep their kind, so read timeouts are still recognized as such.
+fn into_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Transport for WebSocketTransport {
+    /// Binary messages fail with `InvalidData`: the chat protocol is text only.
+    fn read_frame(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => return Ok(Some(text)),
+                Ok(Message::Binary(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "binary WebSocket messages aren't supported",
+                    ));
+                }
+                Ok(Message::Close(_)) => {
+                    let _ = self.socket.flush(); // Sends the queued close reply.
+                    return Ok(None);
+                }
+                Ok(_) => continue, // Pings and pongs are handled by the protocol.
+                Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => return Ok(None),
+                Err(e) => return Err(into_io_error(e)),
+            }
+        }
+    }
+
+    fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        self.socket
+            .send(Message::Text(frame.to_string()))
+            .map_err(into_io_error)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.get_ref().peer_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.socket.get_ref().shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.get_ref().set_read_timeout(timeout)
+    }
+
+    /// The clone frames messages independently over the same connection, so it should
+    /// only be used for writing, e.g. by the client's writer thread.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        let stream = self.socket.get_ref().try_clone()?;
+        Ok(Box::new(Self {
+            socket: WebSocket::from_raw_socket(stream, Role::Server, Some(config())),
+        }))
+    }
+}